@@ -1,33 +1,78 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anchor_lang::prelude::Pubkey;
 use marginfi::state::price::OracleSetup;
-
-use crate::{connection::MeteoraPoolsAndVaults, constants, Error, Wallet};
-
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tracing::warn;
+
+use crate::{
+    connection::MeteoraPoolsAndVaults,
+    constants,
+    state::{MeteoraFarmPool, MeteoraPoolState},
+    Error, Wallet,
+};
+
+/// Without the default keys
+#[derive(Clone, PartialEq)]
 pub enum MarginfiBankOracle {
-    Pyth(Pubkey),
-    Switchboard(Pubkey),
+    Pyth(Vec<Pubkey>),
+    Switchboard(Vec<Pubkey>),
 }
 
 impl MarginfiBankOracle {
-    pub fn address(&self) -> Pubkey {
+    pub fn addresses(&self) -> &[Pubkey] {
         match self {
-            Self::Pyth(addres) => *addres,
-            Self::Switchboard(address) => *address,
+            Self::Pyth(addresses) => addresses,
+            Self::Switchboard(addresses) => addresses,
         }
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct MarginfiBank {
     pub address: Pubkey,
     pub liquidity_vault: Pubkey,
     pub liquidity_vault_authority: Pubkey,
+    pub insurance_vault: Pubkey,
+    pub insurance_vault_authority: Pubkey,
+    pub fee_vault: Pubkey,
+    pub fee_vault_authority: Pubkey,
     pub oracle: MarginfiBankOracle,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PoolId(usize);
+
+#[derive(Debug, Clone)]
+pub struct MeteoraPoolConfig {
+    pub pool: Pubkey,
+    /// Legacy farms can be listed to claim from them
+    pub farms: Vec<Pubkey>,
+    /// Relative share of the borrowed funds deposited to the pool
+    pub weight: u64,
+}
+
+impl Default for MeteoraPoolConfig {
+    fn default() -> Self {
+        Self {
+            pool: constants::meteora::acusd_usdc_pool::id(),
+            farms: vec![constants::meteora::acusd_usdc_farm::id()],
+            weight: 1,
+        }
+    }
+}
+
+#[derive(PartialEq)]
 pub struct MeteoraDynamicPool {
     pub address: Pubkey,
+    pub input_mint: Pubkey,
+    pub weight: u64,
+    /// Stable curve pools accept imbalanced deposits
+    pub is_stable: bool,
 
     // Pool
     pub lp_mint: Pubkey,
@@ -46,13 +91,68 @@ pub struct MeteoraDynamicPool {
     pub b_token_mint: Pubkey,
 }
 
+pub struct MeteoraPoolDeposit {
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub expected_lp: u64,
+    pub minimum_lp: u64,
+}
+
+pub struct MeteoraPoolWithdraw {
+    pub pool_token_amount: u64,
+    pub expected_a: u64,
+    pub expected_b: u64,
+    pub minimum_a: u64,
+    pub minimum_b: u64,
+}
+
+fn apply_slippage(amount: u64, slippage_bps: u16) -> u64 {
+    (amount as u128 * (10_000 - slippage_bps.min(10_000)) as u128 / 10_000) as u64
+}
+
 impl MeteoraDynamicPool {
-    pub fn get_token_for_deposit(&self, amount: u64, mint: &Pubkey) -> (u64, u64) {
-        if mint == &self.a_token_mint {
+    pub fn get_deposit(
+        &self,
+        pool_state: &MeteoraPoolState,
+        amount: u64,
+        mint: &Pubkey,
+        slippage_bps: u16,
+    ) -> Result<MeteoraPoolDeposit, Error> {
+        let (token_a_amount, token_b_amount) = if mint == &self.a_token_mint {
             (amount, 0)
         } else {
             (0, amount)
-        }
+        };
+        let expected_lp = pool_state
+            .get_expected_lp(amount)
+            .ok_or(Error::MathOverflow)?;
+        let minimum_lp = apply_slippage(expected_lp, slippage_bps);
+
+        Ok(MeteoraPoolDeposit {
+            token_a_amount,
+            token_b_amount,
+            expected_lp,
+            minimum_lp,
+        })
+    }
+
+    pub fn get_withdraw(
+        &self,
+        pool_state: &MeteoraPoolState,
+        pool_token_amount: u64,
+        slippage_bps: u16,
+    ) -> Result<MeteoraPoolWithdraw, Error> {
+        let (expected_a, expected_b) = pool_state
+            .get_withdraw_amounts(pool_token_amount)
+            .ok_or(Error::MathOverflow)?;
+
+        Ok(MeteoraPoolWithdraw {
+            pool_token_amount,
+            expected_a,
+            expected_b,
+            minimum_a: apply_slippage(expected_a, slippage_bps),
+            minimum_b: apply_slippage(expected_b, slippage_bps),
+        })
     }
 }
 
@@ -60,46 +160,101 @@ pub struct MeteoraFarmMeta {
     pub address: Pubkey,
     pub staking_vault: Pubkey,
     pub user_account: Pubkey,
+    pub is_active: bool,
+
+    pub reward_a_mint: Pubkey,
+    pub reward_a_vault: Pubkey,
+    pub reward_a_token_account: Pubkey,
+    pub reward_b_mint: Pubkey,
+    pub reward_b_vault: Pubkey,
+    pub reward_b_token_account: Pubkey,
+}
+
+pub struct MintContext<'a> {
+    pub mint: Pubkey,
+    pub token_program: Pubkey,
+    pub token_account: Pubkey,
+    pub bank: Option<&'a MarginfiBank>,
+    pub pools: Vec<(PoolId, &'a MeteoraDynamicPool, Option<&'a MeteoraFarmMeta>)>,
+}
+
+impl<'a> MintContext<'a> {
+    pub fn bank(&self) -> Result<&'a MarginfiBank, Error> {
+        self.bank.ok_or(Error::InvalidMarginfiBank)
+    }
 }
 
 pub struct StaticAddresses {
-    pub wallet_token_accounts: Vec<(Pubkey, Pubkey)>,
+    // key: mint, value: (token program, token account)
+    pub wallet_token_accounts: HashMap<Pubkey, (Pubkey, Pubkey)>,
+    pub token_programs: HashMap<Pubkey, Pubkey>,
     pub marginfi_account: Pubkey,
-    pub marginfi_banks: Vec<(Pubkey, MarginfiBank)>,
-    // key: input mint
-    pub meteora_dynamic_pools: Vec<(Pubkey, MeteoraDynamicPool)>,
-    // key: pool input mint
-    pub meteora_farms: Vec<(Pubkey, MeteoraFarmMeta)>,
+    // key: mint
+    pub marginfi_banks: HashMap<Pubkey, MarginfiBank>,
+    // key: bank address, value: mint
+    marginfi_bank_mints: HashMap<Pubkey, Pubkey>,
+    // index: pool id
+    pub meteora_dynamic_pools: Vec<MeteoraDynamicPool>,
+    // key: pool address
+    meteora_pool_ids: HashMap<Pubkey, PoolId>,
+    // key: LP mint
+    pub meteora_farms: HashMap<Pubkey, Vec<MeteoraFarmMeta>>,
+    pub borrowable_mints: Vec<Pubkey>,
+    pub borrow_caps: HashMap<Pubkey, f64>,
+    pub preferred_input_mint: Option<Pubkey>,
+    pub collateral_mint: Pubkey,
 }
 
 impl StaticAddresses {
-    pub fn new(wallet: &Arc<Wallet>) -> Self {
-        let mut token_accounts = vec![];
+    /// Mints missing from `token_programs` are assumed to be owned by the classic token program
+    pub fn new(wallet: &Arc<Wallet>, token_programs: HashMap<Pubkey, Pubkey>) -> Self {
+        let mut static_addresses = Self {
+            wallet_token_accounts: HashMap::new(),
+            token_programs,
+            marginfi_account: Pubkey::default(),
+            marginfi_banks: HashMap::new(),
+            marginfi_bank_mints: HashMap::new(),
+            meteora_dynamic_pools: vec![],
+            meteora_pool_ids: HashMap::new(),
+            meteora_farms: HashMap::new(),
+            borrowable_mints: vec![],
+            borrow_caps: HashMap::new(),
+            preferred_input_mint: None,
+            collateral_mint: constants::mints::bsol::id(),
+        };
+
         for mint in [
             constants::mints::bsol::id(),
             constants::mints::usdc::id(),
             constants::mints::uxd::id(),
             constants::mints::usdt::id(),
         ] {
-            let token_account_address = Pubkey::find_program_address(
-                &[
-                    wallet.pubkey.as_ref(),
-                    constants::spl_token::id().as_ref(),
-                    mint.as_ref(),
-                ],
-                &constants::associated_token::id(),
-            )
-            .0;
-            token_accounts.push((mint, token_account_address));
+            static_addresses.add_unique_wallet_token_account(&mint, wallet);
         }
 
-        Self {
-            wallet_token_accounts: token_accounts,
-            marginfi_account: Pubkey::default(),
-            marginfi_banks: vec![],
-            meteora_dynamic_pools: vec![],
-            meteora_farms: vec![],
-        }
+        static_addresses
+    }
+
+    pub fn set_borrowable_mints(
+        mut self,
+        wallet: &Arc<Wallet>,
+        borrowable_mints: &Vec<Pubkey>,
+        borrow_caps: &HashMap<Pubkey, f64>,
+        preferred_input_mint: Option<Pubkey>,
+    ) -> Self {
+        borrowable_mints.iter().for_each(|mint| {
+            self.add_unique_wallet_token_account(mint, wallet);
+        });
+        self.borrowable_mints = borrowable_mints.clone();
+        self.borrow_caps = borrow_caps.clone();
+        self.preferred_input_mint = preferred_input_mint;
+        self
+    }
+
+    pub fn set_collateral_mint(mut self, wallet: &Arc<Wallet>, collateral_mint: Pubkey) -> Self {
+        self.add_unique_wallet_token_account(&collateral_mint, wallet);
+        self.collateral_mint = collateral_mint;
+        self
     }
 
     pub fn set_marginfi_account(mut self, marginfi_account: Pubkey) -> Self {
@@ -107,138 +262,353 @@ impl StaticAddresses {
         self
     }
 
+    fn new_marginfi_bank(
+        bank_address: &Pubkey,
+        bank: &marginfi::state::marginfi_group::Bank,
+    ) -> MarginfiBank {
+        let oracle_addresses = bank
+            .config
+            .oracle_keys
+            .iter()
+            .filter(|key| key != &&Pubkey::default())
+            .copied()
+            .collect::<Vec<_>>();
+        let oracle = match bank.config.oracle_setup {
+            OracleSetup::PythEma => MarginfiBankOracle::Pyth(oracle_addresses),
+            OracleSetup::SwitchboardV2 => MarginfiBankOracle::Switchboard(oracle_addresses),
+            OracleSetup::None => unreachable!(),
+        };
+        let derive = |seed: &str| Self::derive_marginfi_bank_pda(seed, bank_address);
+
+        MarginfiBank {
+            address: *bank_address,
+            liquidity_vault: bank.liquidity_vault,
+            liquidity_vault_authority: derive(marginfi::constants::LIQUIDITY_VAULT_AUTHORITY_SEED),
+            insurance_vault: derive(marginfi::constants::INSURANCE_VAULT_SEED),
+            insurance_vault_authority: derive(marginfi::constants::INSURANCE_VAULT_AUTHORITY_SEED),
+            fee_vault: derive(marginfi::constants::FEE_VAULT_SEED),
+            fee_vault_authority: derive(marginfi::constants::FEE_VAULT_AUTHORITY_SEED),
+            oracle,
+        }
+    }
+
+    fn derive_marginfi_bank_pda(seed: &str, bank_address: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[seed.as_bytes(), bank_address.as_ref()], &marginfi::id()).0
+    }
+
+    /// Catches seed drift of the vault PDAs
+    pub fn validate_marginfi_bank_vaults(
+        banks: &Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>,
+    ) -> Result<(), Error> {
+        let mut valid = true;
+
+        for (bank_address, bank) in banks.iter() {
+            for (name, seed, vault) in [
+                (
+                    "liquidity",
+                    marginfi::constants::LIQUIDITY_VAULT_SEED,
+                    bank.liquidity_vault,
+                ),
+                (
+                    "insurance",
+                    marginfi::constants::INSURANCE_VAULT_SEED,
+                    bank.insurance_vault,
+                ),
+                ("fee", marginfi::constants::FEE_VAULT_SEED, bank.fee_vault),
+            ] {
+                let derived = Self::derive_marginfi_bank_pda(seed, bank_address);
+                if derived != vault {
+                    warn!(
+                        "Bank {} {} vault mismatch: derived {}, on chain {}",
+                        bank_address, name, derived, vault
+                    );
+                    valid = false;
+                }
+            }
+        }
+
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidMarginfiBank)
+        }
+    }
+
+    /// Returns whether anything changed
+    pub fn upsert_marginfi_bank(
+        &mut self,
+        bank_address: &Pubkey,
+        bank: &marginfi::state::marginfi_group::Bank,
+    ) -> bool {
+        let new_bank = Self::new_marginfi_bank(bank_address, bank);
+
+        if let Some(mint) = self.marginfi_bank_mints.get(bank_address).copied() {
+            if mint == bank.mint && self.marginfi_banks.get(&mint) == Some(&new_bank) {
+                return false;
+            }
+            self.marginfi_banks.remove(&mint);
+        }
+
+        self.marginfi_bank_mints.insert(*bank_address, bank.mint);
+        self.marginfi_banks.insert(bank.mint, new_bank);
+        true
+    }
+
     pub fn set_marginfi_banks(
         mut self,
         banks: &Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>,
     ) -> Self {
         banks.iter().for_each(|(bank_address, bank)| {
-            let mint = bank.mint;
-            let oracle_address = bank.config.oracle_keys[0];
-            let oracle = match bank.config.oracle_setup {
-                OracleSetup::PythEma => MarginfiBankOracle::Pyth(oracle_address),
-                OracleSetup::SwitchboardV2 => MarginfiBankOracle::Switchboard(oracle_address),
-                OracleSetup::None => unreachable!(),
-            };
-            let liquidity_vault_authority = Pubkey::find_program_address(
-                &[
-                    marginfi::constants::LIQUIDITY_VAULT_AUTHORITY_SEED.as_bytes(),
-                    bank_address.as_ref(),
-                ],
-                &marginfi::id(),
-            )
-            .0;
-            self.marginfi_banks.push((
-                mint,
-                MarginfiBank {
-                    address: *bank_address,
-                    liquidity_vault: bank.liquidity_vault,
-                    liquidity_vault_authority,
-                    oracle,
-                },
-            ));
+            self.upsert_marginfi_bank(bank_address, bank);
         });
         self
     }
 
     fn add_unique_wallet_token_account(&mut self, mint: &Pubkey, wallet: &Arc<Wallet>) {
+        if self.wallet_token_accounts.contains_key(mint) {
+            return;
+        }
+
+        let token_program = self.get_token_program(mint);
         let token_account = Pubkey::find_program_address(
             &[
                 wallet.pubkey.as_ref(),
-                constants::spl_token::id().as_ref(),
+                token_program.as_ref(),
                 mint.as_ref(),
             ],
             &constants::associated_token::id(),
         )
         .0;
 
-        if !self.wallet_token_accounts.contains(&(*mint, token_account)) {
-            self.wallet_token_accounts.push((*mint, token_account));
+        self.wallet_token_accounts
+            .insert(*mint, (token_program, token_account));
+    }
+
+    /// `preferred_input_mint` decides when both sides can be borrowed
+    fn get_meteora_pool_input_mint(
+        &self,
+        pool_address: &Pubkey,
+        pool: &meteora::state::Pool,
+    ) -> Result<Pubkey, Error> {
+        let a_borrowable = self.borrowable_mints.contains(&pool.token_a_mint);
+        let b_borrowable = self.borrowable_mints.contains(&pool.token_b_mint);
+
+        match (a_borrowable, b_borrowable) {
+            (true, true) => {
+                if self.preferred_input_mint == Some(pool.token_b_mint) {
+                    Ok(pool.token_b_mint)
+                } else {
+                    Ok(pool.token_a_mint)
+                }
+            }
+            (true, false) => Ok(pool.token_a_mint),
+            (false, true) => Ok(pool.token_b_mint),
+            (false, false) => Err(Error::NoBorrowablePoolToken {
+                pool: *pool_address,
+                token_a_mint: pool.token_a_mint,
+                token_b_mint: pool.token_b_mint,
+            }),
         }
     }
 
-    fn get_meteora_pool_input_mint(pool: &Pubkey) -> Result<Pubkey, Error> {
-        if pool == &constants::meteora::acusd_usdc_pool::id() {
-            Ok(constants::mints::usdc::id())
-        } else {
-            Err(Error::InvalidMeteoraPool)
+    fn new_meteora_pool(
+        &mut self,
+        wallet: &Arc<Wallet>,
+        pool_address: &Pubkey,
+        input_mint: Option<Pubkey>,
+        weight: u64,
+        pools_and_vaults: &MeteoraPoolsAndVaults,
+    ) -> Result<MeteoraDynamicPool, Error> {
+        let (_, pool) = pools_and_vaults
+            .pools
+            .iter()
+            .find(|(addr, _)| addr == pool_address)
+            .ok_or(Error::InvalidMeteoraPool)?;
+        let input_mint = match input_mint {
+            Some(input_mint) => input_mint,
+            None => self.get_meteora_pool_input_mint(pool_address, pool)?,
+        };
+
+        let (_, a_vault) = pools_and_vaults
+            .vaults
+            .iter()
+            .find(|(addr, _)| addr == &pool.a_vault)
+            .ok_or(Error::InvalidMeteoraPool)?;
+        let (_, b_vault) = pools_and_vaults
+            .vaults
+            .iter()
+            .find(|(addr, _)| addr == &pool.b_vault)
+            .ok_or(Error::InvalidMeteoraPool)?;
+
+        self.add_unique_wallet_token_account(&pool.token_a_mint, wallet);
+        self.add_unique_wallet_token_account(&pool.token_b_mint, wallet);
+        self.add_unique_wallet_token_account(&pool.lp_mint, wallet);
+
+        Ok(MeteoraDynamicPool {
+            address: *pool_address,
+            input_mint,
+            weight,
+            is_stable: matches!(pool.curve_type, meteora::state::CurveType::Stable { .. }),
+            lp_mint: pool.lp_mint,
+            a_vault: pool.a_vault,
+            b_vault: pool.b_vault,
+            a_vault_lp: pool.a_vault_lp,
+            b_vault_lp: pool.b_vault_lp,
+            a_token_mint: pool.token_a_mint,
+            b_token_mint: pool.token_b_mint,
+            vault_a_vault: a_vault.token_vault,
+            vault_b_vault: b_vault.token_vault,
+            vault_a_lp_mint: a_vault.lp_mint,
+            vault_b_lp_mint: b_vault.lp_mint,
+        })
+    }
+
+    pub fn register_meteora_pool(
+        &mut self,
+        wallet: &Arc<Wallet>,
+        pool_address: &Pubkey,
+        weight: u64,
+        pools_and_vaults: &MeteoraPoolsAndVaults,
+    ) -> Result<PoolId, Error> {
+        if let Ok((pool_id, _)) = self.get_meteora_pool_by_address(pool_address) {
+            return Ok(pool_id);
         }
+
+        let pool = self.new_meteora_pool(wallet, pool_address, None, weight, pools_and_vaults)?;
+        let pool_id = PoolId(self.meteora_dynamic_pools.len());
+        self.meteora_dynamic_pools.push(pool);
+        self.meteora_pool_ids.insert(*pool_address, pool_id);
+
+        Ok(pool_id)
+    }
+
+    /// Pools which are not registered yet are added with zero weight, returns whether anything
+    /// changed
+    pub fn upsert_meteora_pools(
+        &mut self,
+        wallet: &Arc<Wallet>,
+        pools_and_vaults: &MeteoraPoolsAndVaults,
+    ) -> Result<bool, Error> {
+        let mut changed = false;
+
+        for (pool_address, _) in pools_and_vaults.pools.iter() {
+            match self.get_meteora_pool_by_address(pool_address) {
+                Ok((pool_id, current_pool)) => {
+                    let input_mint = current_pool.input_mint;
+                    let weight = current_pool.weight;
+                    let new_pool = self.new_meteora_pool(
+                        wallet,
+                        pool_address,
+                        Some(input_mint),
+                        weight,
+                        pools_and_vaults,
+                    )?;
+
+                    if self.meteora_dynamic_pools[pool_id.0] != new_pool {
+                        self.meteora_dynamic_pools[pool_id.0] = new_pool;
+                        changed = true;
+                    }
+                }
+                Err(_) => {
+                    self.register_meteora_pool(wallet, pool_address, 0, pools_and_vaults)?;
+                    changed = true;
+                }
+            }
+        }
+
+        Ok(changed)
     }
 
     pub fn set_meteora_pools_and_vaults(
         mut self,
         wallet: &Arc<Wallet>,
         pools_and_vaults: &MeteoraPoolsAndVaults,
+        pool_configs: &[MeteoraPoolConfig],
     ) -> Result<Self, Error> {
-        for (pool_address, pool) in pools_and_vaults.pools.iter() {
-            let input_mint = Self::get_meteora_pool_input_mint(&pool_address)?;
-
-            let (_, a_vault) = pools_and_vaults
-                .vaults
-                .iter()
-                .find(|(addr, _)| addr == &pool.a_vault)
-                .unwrap();
-            let (_, b_vault) = pools_and_vaults
-                .vaults
-                .iter()
-                .find(|(addr, _)| addr == &pool.b_vault)
-                .unwrap();
-
-            self.add_unique_wallet_token_account(&pool.token_a_mint, wallet);
-            self.add_unique_wallet_token_account(&pool.token_b_mint, wallet);
-            self.add_unique_wallet_token_account(&pool.lp_mint, wallet);
-
-            self.meteora_dynamic_pools.push((
-                input_mint,
-                MeteoraDynamicPool {
-                    address: *pool_address,
-                    lp_mint: pool.lp_mint,
-                    a_vault: pool.a_vault,
-                    b_vault: pool.b_vault,
-                    a_vault_lp: pool.a_vault_lp,
-                    b_vault_lp: pool.b_vault_lp,
-                    a_token_mint: pool.token_a_mint,
-                    b_token_mint: pool.token_b_mint,
-                    vault_a_vault: a_vault.token_vault,
-                    vault_b_vault: b_vault.token_vault,
-                    vault_a_lp_mint: a_vault.lp_mint,
-                    vault_b_lp_mint: b_vault.lp_mint,
-                },
-            ));
+        for config in pool_configs.iter() {
+            self.register_meteora_pool(wallet, &config.pool, config.weight, pools_and_vaults)?;
         }
 
         Ok(self)
     }
 
-    pub fn set_meteora_farms(mut self, wallet: &Arc<Wallet>) -> Self {
-        let farm_address = constants::meteora::acusd_usdc_farm::id();
+    pub fn register_meteora_farm(
+        &mut self,
+        wallet: &Arc<Wallet>,
+        pool_id: PoolId,
+        farm_address: &Pubkey,
+        farm: &MeteoraFarmPool,
+    ) -> Result<(), Error> {
+        let pool = self.get_meteora_pool(pool_id)?;
+        let lp_mint = pool.lp_mint;
+        if farm.staking_mint != lp_mint {
+            warn!(
+                "Meteora farm {} stakes {} instead of pool {} LP mint {}",
+                farm_address, farm.staking_mint, pool.address, pool.lp_mint
+            );
+            return Err(Error::InvalidMeteoraFarm);
+        }
+
         let user_account = Pubkey::find_program_address(
             &[wallet.pubkey.as_ref(), farm_address.as_ref()],
             &constants::meteora::farm::id(),
         )
         .0;
-        let staking_vault = Pubkey::find_program_address(
-            &[b"staking", farm_address.as_ref()],
-            &constants::meteora::farm::id(),
-        )
-        .0;
 
-        self.meteora_farms.push((
-            constants::mints::usdc::id(),
-            MeteoraFarmMeta {
-                address: farm_address,
-                user_account,
-                staking_vault,
-            },
-        ));
+        self.add_unique_wallet_token_account(&farm.reward_a_mint, wallet);
+        self.add_unique_wallet_token_account(&farm.reward_b_mint, wallet);
+        let reward_a_token_account = self.get_token_account(&farm.reward_a_mint)?;
+        let reward_b_token_account = self.get_token_account(&farm.reward_b_mint)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let farm_meta = MeteoraFarmMeta {
+            address: *farm_address,
+            user_account,
+            staking_vault: farm.staking_vault,
+            is_active: farm.is_active(now),
+            reward_a_mint: farm.reward_a_mint,
+            reward_a_vault: farm.reward_a_vault,
+            reward_a_token_account,
+            reward_b_mint: farm.reward_b_mint,
+            reward_b_vault: farm.reward_b_vault,
+            reward_b_token_account,
+        };
+        let farms = self.meteora_farms.entry(lp_mint).or_default();
+        match farms.iter_mut().find(|f| f.address == *farm_address) {
+            Some(current) => *current = farm_meta,
+            None => farms.push(farm_meta),
+        }
 
-        self
+        Ok(())
+    }
+
+    pub fn set_meteora_farms(
+        mut self,
+        wallet: &Arc<Wallet>,
+        pool_configs: &[MeteoraPoolConfig],
+        farms: &Vec<(Pubkey, MeteoraFarmPool)>,
+    ) -> Result<Self, Error> {
+        for config in pool_configs.iter() {
+            let (pool_id, _) = self.get_meteora_pool_by_address(&config.pool)?;
+            for farm_address in config.farms.iter() {
+                let (_, farm) = farms
+                    .iter()
+                    .find(|(address, _)| address == farm_address)
+                    .ok_or(Error::InvalidMeteoraFarm)?;
+                self.register_meteora_farm(wallet, pool_id, farm_address, farm)?;
+            }
+        }
+
+        Ok(self)
     }
 
     pub fn get_marginfi_bank(&self, mint: &Pubkey) -> Result<&MarginfiBank, Error> {
         self.marginfi_banks
-            .iter()
-            .find(|(bank_mint, _)| bank_mint == mint)
-            .map(|(_, bank)| bank)
+            .get(mint)
             .ok_or(Error::InvalidMarginfiBank)
     }
 
@@ -246,34 +616,326 @@ impl StaticAddresses {
         &self,
         address: &Pubkey,
     ) -> Result<&MarginfiBank, Error> {
-        self.marginfi_banks
-            .iter()
-            .find(|(_, bank)| &bank.address == address)
-            .map(|(_, bank)| bank)
+        self.marginfi_bank_mints
+            .get(address)
+            .and_then(|mint| self.marginfi_banks.get(mint))
             .ok_or(Error::InvalidMarginfiBank)
     }
 
     pub fn get_token_account(&self, mint: &Pubkey) -> Result<Pubkey, Error> {
         self.wallet_token_accounts
-            .iter()
-            .find(|(token_mint, _)| token_mint == mint)
+            .get(mint)
             .map(|(_, token_account)| *token_account)
             .ok_or(Error::InvalidTokenAccount)
     }
 
-    pub fn get_meteora_pool(&self, mint: &Pubkey) -> Result<&MeteoraDynamicPool, Error> {
+    pub fn get_token_program(&self, mint: &Pubkey) -> Pubkey {
+        self.token_programs
+            .get(mint)
+            .copied()
+            .unwrap_or(constants::spl_token::id())
+    }
+
+    pub fn get_mint_context(&self, mint: &Pubkey) -> Result<MintContext, Error> {
+        let (token_program, token_account) = self
+            .wallet_token_accounts
+            .get(mint)
+            .copied()
+            .ok_or(Error::InvalidTokenAccount)?;
+
+        Ok(MintContext {
+            mint: *mint,
+            token_program,
+            token_account,
+            bank: self.marginfi_banks.get(mint),
+            pools: self
+                .meteora_dynamic_pools
+                .iter()
+                .enumerate()
+                .filter(|(_, pool)| &pool.input_mint == mint)
+                .map(|(i, pool)| (PoolId(i), pool, self.get_meteora_farm(PoolId(i), None).ok()))
+                .collect(),
+        })
+    }
+
+    pub fn get_meteora_pool(&self, pool_id: PoolId) -> Result<&MeteoraDynamicPool, Error> {
         self.meteora_dynamic_pools
-            .iter()
-            .find(|(inpt_mint, _)| inpt_mint == mint)
-            .map(|(_, p)| p)
+            .get(pool_id.0)
             .ok_or(Error::InvalidMeteoraPool)
     }
 
-    pub fn get_meteora_farm(&self, mint: &Pubkey) -> Result<&MeteoraFarmMeta, Error> {
+    pub fn get_meteora_pool_by_address(
+        &self,
+        address: &Pubkey,
+    ) -> Result<(PoolId, &MeteoraDynamicPool), Error> {
+        let pool_id = self
+            .meteora_pool_ids
+            .get(address)
+            .copied()
+            .ok_or(Error::InvalidMeteoraPool)?;
+        Ok((pool_id, self.get_meteora_pool(pool_id)?))
+    }
+
+    pub fn meteora_pool_ids(&self) -> impl Iterator<Item = PoolId> {
+        (0..self.meteora_dynamic_pools.len()).map(PoolId)
+    }
+
+    /// The farm at `farm_address` is returned regardless of whether it is active
+    pub fn get_meteora_farm(
+        &self,
+        pool_id: PoolId,
+        farm_address: Option<&Pubkey>,
+    ) -> Result<&MeteoraFarmMeta, Error> {
+        let pool = self.get_meteora_pool(pool_id)?;
+        let mut farms = self.get_meteora_farms_by_lp_mint(&pool.lp_mint).iter();
+
+        match farm_address {
+            Some(address) => farms.find(|farm| &farm.address == address),
+            None => farms.find(|farm| farm.is_active),
+        }
+        .ok_or(Error::InvalidMeteoraFarm)
+    }
+
+    pub fn get_meteora_farms_by_lp_mint(&self, lp_mint: &Pubkey) -> &[MeteoraFarmMeta] {
         self.meteora_farms
-            .iter()
-            .find(|(inpt_mint, _)| inpt_mint == mint)
-            .map(|(_, p)| p)
-            .ok_or(Error::InvalidMeteoraFarm)
+            .get(lp_mint)
+            .map(|farms| &farms[..])
+            .unwrap_or(&[])
+    }
+
+    /// The last pool receives the rounding remainder
+    pub fn split_by_pool_weights(&self, amount: u64) -> Vec<(PoolId, u64)> {
+        let total_weight: u64 = self.meteora_dynamic_pools.iter().map(|p| p.weight).sum();
+        if total_weight == 0 {
+            return vec![];
+        }
+
+        let mut remaining = amount;
+        let mut amounts = vec![];
+        for (i, pool) in self.meteora_dynamic_pools.iter().enumerate() {
+            let share = if i == self.meteora_dynamic_pools.len() - 1 {
+                remaining
+            } else {
+                (amount as u128 * pool.weight as u128 / total_weight as u128) as u64
+            };
+            remaining -= share;
+            amounts.push((PoolId(i), share));
+        }
+
+        amounts
+    }
+}
+
+pub struct SharedStaticAddresses {
+    wallet: Arc<Wallet>,
+    inner: RwLock<StaticAddresses>,
+}
+
+impl SharedStaticAddresses {
+    pub fn new(wallet: Arc<Wallet>, static_addresses: StaticAddresses) -> Self {
+        Self {
+            wallet,
+            inner: RwLock::new(static_addresses),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, StaticAddresses> {
+        self.inner.read().await
+    }
+
+    pub async fn refresh_marginfi_banks(
+        &self,
+        banks: &Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>,
+    ) -> bool {
+        let mut static_addresses = self.inner.write().await;
+        let mut changed = false;
+        banks.iter().for_each(|(bank_address, bank)| {
+            changed |= static_addresses.upsert_marginfi_bank(bank_address, bank);
+        });
+        changed
+    }
+
+    pub async fn refresh_meteora(
+        &self,
+        pools_and_vaults: &MeteoraPoolsAndVaults,
+    ) -> Result<bool, Error> {
+        self.inner
+            .write()
+            .await
+            .upsert_meteora_pools(&self.wallet, pools_and_vaults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+
+    fn new_pool() -> MeteoraDynamicPool {
+        MeteoraDynamicPool {
+            address: Pubkey::new_unique(),
+            input_mint: constants::mints::usdc::id(),
+            weight: 1,
+            is_stable: true,
+            lp_mint: Pubkey::new_unique(),
+            a_vault: Pubkey::new_unique(),
+            b_vault: Pubkey::new_unique(),
+            a_vault_lp: Pubkey::new_unique(),
+            b_vault_lp: Pubkey::new_unique(),
+            vault_a_vault: Pubkey::new_unique(),
+            vault_b_vault: Pubkey::new_unique(),
+            vault_a_lp_mint: Pubkey::new_unique(),
+            vault_b_lp_mint: Pubkey::new_unique(),
+            a_token_mint: constants::mints::usdc::id(),
+            b_token_mint: constants::mints::usdt::id(),
+        }
+    }
+
+    fn static_addresses(
+        borrowable_mints: &[Pubkey],
+        preferred_input_mint: Option<Pubkey>,
+    ) -> StaticAddresses {
+        let mut static_addresses = StaticAddresses::new(&testkit::new_wallet(), HashMap::new());
+        static_addresses.borrowable_mints = borrowable_mints.to_vec();
+        static_addresses.preferred_input_mint = preferred_input_mint;
+        static_addresses
+    }
+
+    fn pool_of(token_a_mint: Pubkey, token_b_mint: Pubkey) -> meteora::state::Pool {
+        let mut pool: meteora::state::Pool = testkit::zeroed_borsh();
+        pool.token_a_mint = token_a_mint;
+        pool.token_b_mint = token_b_mint;
+        pool
+    }
+
+    #[test]
+    fn input_mint_is_the_borrowable_side() {
+        let (usdc, usdt, other) = (
+            constants::mints::usdc::id(),
+            constants::mints::usdt::id(),
+            Pubkey::new_unique(),
+        );
+        let static_addresses = static_addresses(&[usdc], None);
+        let address = Pubkey::new_unique();
+
+        assert_eq!(
+            static_addresses
+                .get_meteora_pool_input_mint(&address, &pool_of(usdc, other))
+                .unwrap(),
+            usdc
+        );
+        assert_eq!(
+            static_addresses
+                .get_meteora_pool_input_mint(&address, &pool_of(other, usdc))
+                .unwrap(),
+            usdc
+        );
+        assert_eq!(
+            static_addresses
+                .get_meteora_pool_input_mint(&address, &pool_of(usdt, usdc))
+                .unwrap(),
+            usdc
+        );
+    }
+
+    #[test]
+    fn input_mint_of_two_borrowable_sides_is_the_preferred_one() {
+        let (usdc, usdt) = (constants::mints::usdc::id(), constants::mints::usdt::id());
+        let pool = pool_of(usdc, usdt);
+        let address = Pubkey::new_unique();
+
+        assert_eq!(
+            static_addresses(&[usdc, usdt], Some(usdt))
+                .get_meteora_pool_input_mint(&address, &pool)
+                .unwrap(),
+            usdt
+        );
+        assert_eq!(
+            static_addresses(&[usdc, usdt], Some(usdc))
+                .get_meteora_pool_input_mint(&address, &pool)
+                .unwrap(),
+            usdc
+        );
+        // Token a without a preference
+        assert_eq!(
+            static_addresses(&[usdc, usdt], None)
+                .get_meteora_pool_input_mint(&address, &pool)
+                .unwrap(),
+            usdc
+        );
+    }
+
+    #[test]
+    fn pool_without_a_borrowable_side_is_an_error() {
+        let (token_a, token_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let address = Pubkey::new_unique();
+
+        assert!(matches!(
+            static_addresses(&[constants::mints::usdc::id()], None)
+                .get_meteora_pool_input_mint(&address, &pool_of(token_a, token_b)),
+            Err(Error::NoBorrowablePoolToken { pool, token_a_mint, token_b_mint })
+                if pool == address && token_a_mint == token_a && token_b_mint == token_b
+        ));
+    }
+
+    /// 1500 tokens backing 1000 LP
+    fn pool_state() -> MeteoraPoolState {
+        MeteoraPoolState {
+            a_vault_lp_amount: 1_000,
+            b_vault_lp_amount: 500,
+            a_vault_total_amount: 1_000,
+            b_vault_total_amount: 500,
+            a_vault_lp_supply: 1_000,
+            b_vault_lp_supply: 500,
+            lp_supply: 1_000,
+        }
+    }
+
+    #[test]
+    fn deposit_is_single_sided_in_the_input_mint() {
+        let pool = new_pool();
+
+        let deposit = pool
+            .get_deposit(&pool_state(), 150_000, &constants::mints::usdc::id(), 0)
+            .unwrap();
+        assert_eq!(
+            (deposit.token_a_amount, deposit.token_b_amount),
+            (150_000, 0)
+        );
+
+        let deposit = pool
+            .get_deposit(&pool_state(), 150_000, &constants::mints::usdt::id(), 0)
+            .unwrap();
+        assert_eq!(
+            (deposit.token_a_amount, deposit.token_b_amount),
+            (0, 150_000)
+        );
+    }
+
+    #[test]
+    fn deposit_minimum_lp_applies_slippage_to_the_virtual_price() {
+        let pool = new_pool();
+        let usdc = constants::mints::usdc::id();
+
+        let deposit = pool.get_deposit(&pool_state(), 150_000, &usdc, 0).unwrap();
+        assert_eq!(
+            (deposit.expected_lp, deposit.minimum_lp),
+            (100_000, 100_000)
+        );
+
+        let deposit = pool.get_deposit(&pool_state(), 150_000, &usdc, 50).unwrap();
+        assert_eq!((deposit.expected_lp, deposit.minimum_lp), (100_000, 99_500));
+
+        let deposit = pool
+            .get_deposit(&pool_state(), 150_000, &usdc, 20_000)
+            .unwrap();
+        assert_eq!(deposit.minimum_lp, 0);
+    }
+
+    #[test]
+    fn slippage_rounds_down() {
+        assert_eq!(apply_slippage(199, 50), 198);
+        assert_eq!(apply_slippage(u64::MAX, 0), u64::MAX);
+        assert_eq!(apply_slippage(u64::MAX, 10_000), 0);
     }
 }
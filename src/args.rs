@@ -1,72 +1,1338 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anchor_lang::prelude::Pubkey;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::{commitment_config::CommitmentConfig, signature::Keypair, signer::Signer};
+use solana_sdk::{
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    signature::Keypair,
+    signer::Signer,
+};
 
-use crate::{utils::websocket_client::WebsocketClient, Wallet};
+use crate::{
+    addresses::MeteoraPoolConfig,
+    bot::{BorrowSplit, RebalanceConfig, StopLossConfig},
+    config::{CommitmentPolicy, Config},
+    connection::SwapRouteConfig,
+    constants,
+    ledger::Ledger,
+    nonce,
+    risk::{RiskGuard, RiskLimits},
+    scheduler::MaintenanceIntervals,
+    shutdown::Shutdown,
+    utils::{
+        jupiter_client::JupiterApiConfig,
+        notifier::Notifier,
+        rpc_failover::new_failover_rpc_client,
+        transaction::{CuLimitCache, JitoConfig, PriorityFeeConfig, SendConfig, SendRpcClients},
+        websocket_client::WebsocketClient,
+    },
+    Error, Wallet,
+};
 
 const NAMESPACE: &'static str = "[CONFIG_ERROR]:";
 
-pub fn load_arg(key: &str) -> String {
-    std::env::var(key).expect(&format!("{NAMESPACE} Argument {key} is missing"))
+const DEFAULT_MIN_SOL_BALANCE: u64 = 50_000_000;
+const DEFAULT_POOL_SLIPPAGE_BPS: u16 = 50;
+const DEFAULT_MAX_SEND_RETRIES: u32 = 5;
+const DEFAULT_RPC_MAX_RETRIES: usize = 20;
+const DEFAULT_TX_POLL_INTERVAL_MS: u64 = 2_000;
+const DEFAULT_TX_MAX_CONFIRMATION_WAIT_SECS: u64 = 40;
+const DEFAULT_CU_LIMIT: u32 = 400_000;
+const DEFAULT_CU_PRICE_MICRO_LAMPORTS: u64 = 10_000;
+const DEFAULT_CU_LIMIT_MARGIN_PERCENT: u64 = 20;
+const DEFAULT_PRIORITY_FEE_PERCENTILE: u8 = 75;
+const DEFAULT_PRIORITY_FEE_MIN_MICRO_LAMPORTS: u64 = 1_000;
+const DEFAULT_PRIORITY_FEE_MAX_MICRO_LAMPORTS: u64 = 1_000_000;
+const DEFAULT_JITO_TIP_LAMPORTS: u64 = 10_000;
+const MAX_JITO_TIP_LAMPORTS: u64 = 10_000_000;
+const DEFAULT_MAX_SWAP_SLIPPAGE_BPS: u16 = 100;
+const DEFAULT_MAX_SWAP_REQUOTES: u32 = 3;
+const DEFAULT_MAX_SWAP_SHORTFALL_BPS: u16 = 100;
+const DEFAULT_HEALTH_TRIGGER: f64 = 0.15;
+const DEFAULT_HEALTH_TARGET: f64 = 0.3;
+const DEFAULT_HEALTH_WARNING: f64 = 0.25;
+const DEFAULT_HEALTH_CRITICAL: f64 = 0.1;
+const DEFAULT_TARGET_INIT_HEALTH: f64 = 0.2;
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+const DEFAULT_INTEREST_REFRESH_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_REWARD_COMPOUND_INTERVAL_SECS: u64 = 60 * 60 * 8;
+const DEFAULT_REBALANCE_CHECK_INTERVAL_SECS: u64 = 60 * 5;
+const DEFAULT_ORACLE_AUDIT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_STOP_LOSS_CHECK_INTERVAL_SECS: u64 = 30;
+const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 60 * 10;
+const DEFAULT_RECONCILE_TOLERANCE_BPS: u64 = 10;
+const DEFAULT_STOP_LOSS_MARKER_FILE: &'static str = "stop_loss_triggered";
+const DEFAULT_LEDGER_FILE: &'static str = "position_ledger.json";
+const DEFAULT_REBALANCE_SPREAD_BPS: i64 = 200;
+const DEFAULT_REBALANCE_SPREAD_DURATION_SECS: u64 = 60 * 60 * 6;
+const DEFAULT_REBALANCE_MIN_INTERVAL_SECS: u64 = 60 * 60 * 24;
+const DEFAULT_REBALANCE_MIN_NOTIONAL: f64 = 100.0;
+const DEFAULT_WS_LIVENESS_TIMEOUT_SECS: u64 = 60;
+
+/// Invalid settings are collected so all of them are reported at once
+struct Settings {
+    file: HashMap<String, String>,
+    file_path: Option<PathBuf>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Settings {
+    fn new(config: &Config, file_path: Option<PathBuf>) -> Self {
+        Self {
+            file: config.values(),
+            file_path,
+            errors: RefCell::new(vec![]),
+        }
+    }
+
+    fn optional(&self, key: &str) -> Option<String> {
+        std::env::var(key)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .or_else(|| self.file.get(key).cloned())
+    }
+
+    /// Missing settings are reported and read as empty
+    fn required(&self, key: &str) -> String {
+        self.optional(key).unwrap_or_else(|| {
+            self.errors.borrow_mut().push(format!("{key} is missing"));
+            String::new()
+        })
+    }
+
+    /// Invalid values are reported and read as not set
+    fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.optional(key).and_then(|value| {
+            value
+                .parse()
+                .map_err(|_| self.reject(key, "could not be parsed"))
+                .ok()
+        })
+    }
+
+    fn list(&self, key: &str) -> Option<Vec<String>> {
+        self.optional(key).map(|values| {
+            values
+                .split(",")
+                .map(|value| value.trim().to_string())
+                .collect()
+        })
+    }
+
+    /// processed, confirmed or finalized, `default` when not set
+    fn commitment(&self, key: &str, default: CommitmentConfig) -> CommitmentConfig {
+        self.optional(key)
+            .map(|commitment| {
+                CommitmentPolicy::parse_commitment(&commitment).unwrap_or_else(|| {
+                    self.reject(key, "has to be processed, confirmed or finalized");
+                    default
+                })
+            })
+            .unwrap_or(default)
+    }
+
+    fn flag(&self, cli_flag: bool, key: &str) -> bool {
+        cli_flag || self.parse(key).unwrap_or(false)
+    }
+
+    /// Where the setting was read from, values are left out as they can be secrets
+    fn source(&self, key: &str) -> String {
+        if std::env::var(key).is_ok_and(|value| !value.is_empty()) {
+            "env var".to_string()
+        } else {
+            match &self.file_path {
+                Some(path) => path.display().to_string(),
+                None => "default".to_string(),
+            }
+        }
+    }
+
+    fn reject(&self, key: &str, reason: &str) {
+        let error = format!("{key} ({}): {reason}", self.source(key));
+        self.errors.borrow_mut().push(error);
+    }
+
+    fn finish(self) {
+        let errors = self.errors.into_inner();
+        if !errors.is_empty() {
+            panic!("{NAMESPACE} Invalid settings:\n  {}", errors.join("\n  "));
+        }
+    }
+}
+
+/// Reports an unparsable setting and continues with a placeholder, so the remaining
+/// settings are still validated
+trait OrInvalid<T> {
+    fn or_invalid(self, settings: &Settings, key: &str) -> T;
+}
+
+impl<T: Default, E> OrInvalid<T> for Result<T, E> {
+    fn or_invalid(self, settings: &Settings, key: &str) -> T {
+        self.ok().or_invalid(settings, key)
+    }
+}
+
+impl<T: Default> OrInvalid<T> for Option<T> {
+    fn or_invalid(self, settings: &Settings, key: &str) -> T {
+        self.unwrap_or_else(|| {
+            settings.reject(key, "could not be parsed");
+            T::default()
+        })
+    }
+}
+
+/// Errors never contain key material
+fn parse_keypair(value: &str) -> Result<Keypair, String> {
+    let value = value.trim();
+    let path = Path::new(value);
+    if path.is_file() {
+        return read_keypair_file(path);
+    }
+    if value.contains("/") || value.ends_with(".json") {
+        return Err(format!("keypair file {} does not exist", path.display()));
+    }
+    if let Ok(bytes) = bs58::decode(value).into_vec() {
+        return Keypair::from_bytes(&bytes)
+            .map_err(|_| "base58 secret key is not a valid keypair".to_string());
+    }
+
+    let bytes = value
+        .trim_start_matches("[")
+        .trim_end_matches("]")
+        .split(",")
+        .map(|byte| byte.trim().parse().ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or("is not a keypair file, base58 secret key or comma separated bytes")?;
+    Keypair::from_bytes(&bytes).map_err(|_| "bytes are not a valid keypair".to_string())
+}
+
+/// Keypair files readable by every user are refused
+fn read_keypair_file(path: &Path) -> Result<Keypair, String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?
+            .permissions()
+            .mode();
+        if mode & 0o004 != 0 {
+            return Err(format!(
+                "{} is readable by every user, restrict it with `chmod 600`",
+                path.display()
+            ));
+        }
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let bytes = serde_json::from_str::<Vec<u8>>(&contents)
+        .map_err(|_| format!("{} is not a Solana CLI keypair file", path.display()))?;
+    Keypair::from_bytes(&bytes)
+        .map_err(|_| format!("{} does not hold a valid keypair", path.display()))
 }
 
-pub fn load_and_parse_arg<T, F: Fn(String) -> Result<T, String>>(key: &str, parse_fn: F) -> T {
-    parse_fn(load_arg(key)).expect(&format!("{NAMESPACE} Could not parse {key} argument"))
+#[derive(Debug, Clone, Copy, PartialEq, Subcommand)]
+pub enum Command {
+    /// Enter the position and maintain it until stopped
+    Run(RunArgs),
+    /// Print the position and wallet balances without sending any transactions
+    Status {
+        /// Print the status as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Exit the position: withdraw from farms and pools, repay and withdraw collateral
+    Unwind,
+    /// Reduce the position: withdraw a share of the staked LP and repay the same share
+    /// of every liability
+    Withdraw {
+        /// Share of the position to withdraw, in percent
+        #[arg(
+            long,
+            required_unless_present = "amount_usd",
+            conflicts_with = "amount_usd"
+        )]
+        pct: Option<f64>,
+        /// Value of the staked LP to withdraw, in USD
+        #[arg(long)]
+        amount_usd: Option<f64>,
+        /// Also withdraw the collateral freed by the repay, as far as the init health
+        /// stays at the target
+        #[arg(long, default_value_t = false)]
+        withdraw_collateral: bool,
+    },
+    /// Create or extend the lookup table with the accounts used by the bot
+    UpdateAlt,
+    /// Check the settings, accounts and hardcoded constants against the chain without
+    /// sending any transactions
+    Validate,
+}
+
+/// Arguments of the `run` mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::Args)]
+pub struct RunArgs {
+    /// Amount of the collateral token deposited to marginfi, in UI units, zero by default
+    #[arg(long = "collateral", alias = "bsol")]
+    pub collateral_amount: Option<UiAmount>,
+
+    /// Borrow against the collateral already deposited when the collateral amount is zero
+    #[arg(long, default_value_t = false)]
+    pub use_existing_collateral: bool,
+
+    /// Deposit the wallet balance when it is below the collateral amount instead of aborting
+    #[arg(long, default_value_t = false)]
+    pub clamp_collateral_to_wallet: bool,
+
+    /// Create a marginfi account when the wallet does not own one
+    #[arg(long, default_value_t = false)]
+    pub create_account: bool,
+
+    /// Subscribe to the whole oracle programs instead of the watched oracle accounts,
+    /// for RPCs which limit the number of subscriptions per connection
+    #[arg(long, default_value_t = false)]
+    pub oracle_program_subscribe: bool,
+
+    /// Move the borrow to a cheaper bank when borrow rates diverge
+    #[arg(long, default_value_t = false)]
+    pub rebalance_borrow: bool,
+
+    /// Enter positions again after the stop loss triggered
+    #[arg(long, default_value_t = false)]
+    pub rearm_stop_loss: bool,
+
+    /// Unwind the whole position when stopped with SIGINT or SIGTERM
+    #[arg(long, default_value_t = false)]
+    pub unwind_on_shutdown: bool,
 }
 
 #[derive(Debug, Parser)]
+#[command(subcommand_required = true, arg_required_else_help = true)]
 pub struct CliArgs {
-    #[arg(long = "bsol", default_value_t = 0.0)]
-    bsol_amount: f32,
+    #[command(subcommand)]
+    command: Command,
 
-    #[arg(long, default_value_t = false)]
-    update_alt: bool,
+    /// Config file, `config.toml` is read when it exists and no file is passed
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Only use single hop Jupiter routes
+    #[arg(long, default_value_t = false, global = true)]
+    only_direct_routes: bool,
+
+    /// Maximum number of accounts a Jupiter route can use
+    #[arg(long, global = true)]
+    max_accounts: Option<u64>,
+
+    /// Comma separated list of dexes Jupiter is allowed to route through
+    #[arg(long, value_delimiter = ',', global = true)]
+    dexes: Option<Vec<String>>,
+
+    /// Comma separated list of dexes Jupiter must not route through
+    #[arg(long, value_delimiter = ',', global = true)]
+    exclude_dexes: Option<Vec<String>>,
+
+    /// Slippage in bps failed swaps can be re-quoted with at most [default: 100]
+    #[arg(long, global = true)]
+    max_swap_slippage_bps: Option<u16>,
+
+    /// Number of times a failed swap is re-quoted [default: 3]
+    #[arg(long, global = true)]
+    max_swap_requotes: Option<u32>,
+
+    /// Shortfall in bps of a swap output to its quote which aborts the pool deposit
+    /// [default: 100]
+    #[arg(long, global = true)]
+    max_swap_shortfall_bps: Option<u16>,
+
+    /// Send swaps without lookup tables which could not be loaded instead of failing
+    #[arg(long, default_value_t = false, global = true)]
+    best_effort_alts: bool,
+
+    /// Check bank vaults stored on chain match the derived addresses at startup
+    #[arg(long, default_value_t = false, global = true)]
+    validate_bank_vaults: bool,
+
+    /// Set the compute unit limit of transactions from a simulation
+    #[arg(long, default_value_t = false, global = true)]
+    simulate_cu_limit: bool,
+
+    /// Estimate the compute unit price from recent prioritization fees
+    #[arg(long, default_value_t = false, global = true)]
+    estimate_priority_fee: bool,
+
+    /// Build and simulate transactions without sending them
+    #[arg(long, default_value_t = false, global = true)]
+    dry_run: bool,
+
+    /// Send transactions without simulating them first
+    #[arg(long, default_value_t = false, global = true)]
+    skip_simulation: bool,
+
+    /// Let the RPC simulate transactions before it forwards them
+    #[arg(long, default_value_t = false, global = true)]
+    preflight: bool,
+
+    /// Tip Jito validators with every transaction
+    #[arg(long, default_value_t = false, global = true)]
+    jito_tip: bool,
+
+    /// Send transactions without the memo which tags them as sent by the bot
+    #[arg(long, default_value_t = false, global = true)]
+    skip_memo: bool,
+
+    /// Send transactions with a durable nonce, so they do not expire while retried
+    #[arg(long, default_value_t = false, global = true)]
+    durable_nonce: bool,
+
+    /// Skip validating the hardcoded constants against on-chain state, for offline testing
+    #[arg(long, default_value_t = false, global = true)]
+    skip_constants_validation: bool,
+
+    /// Log as JSON lines, for shipping logs to a collector
+    #[arg(long, default_value_t = false, global = true)]
+    json_logs: bool,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100, disabled when not set
+    #[arg(long, global = true)]
+    metrics_address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CollateralConfig {
+    pub mint: Pubkey,
+    pub ui_amount: UiAmount,
+    /// Zero `ui_amount` borrows against the deposited collateral
+    pub use_existing: bool,
+    /// Deposit the wallet balance when it does not cover `ui_amount`
+    pub clamp_to_wallet: bool,
+}
+
+impl CollateralConfig {
+    pub fn to_amount(&self, decimals: u8) -> Result<u64, Error> {
+        self.ui_amount.to_amount(decimals)
+    }
+}
+
+/// Decimal amount in UI units, kept exact as `digits / 10^scale` so it converts to
+/// native units without float rounding
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UiAmount {
+    digits: u128,
+    scale: u32,
+}
+
+impl FromStr for UiAmount {
+    type Err = String;
+
+    fn from_str(amount: &str) -> Result<Self, Self::Err> {
+        let (integer, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if integer.is_empty()
+            || amount.ends_with('.')
+            || !is_digits(integer)
+            || !is_digits(fraction)
+        {
+            return Err(format!("{} is not a decimal amount", amount));
+        }
+
+        let fraction = fraction.trim_end_matches('0');
+        let digits = integer
+            .bytes()
+            .chain(fraction.bytes())
+            .try_fold(0_u128, |digits, b| {
+                digits.checked_mul(10)?.checked_add((b - b'0') as u128)
+            })
+            .ok_or_else(|| format!("{} is too large", amount))?;
+        Ok(Self {
+            digits,
+            scale: fraction.len() as u32,
+        })
+    }
+}
+
+impl std::fmt::Display for UiAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let divisor = 10_u128.pow(self.scale);
+        write!(f, "{}", self.digits / divisor)?;
+        if self.scale > 0 {
+            write!(
+                f,
+                ".{:0width$}",
+                self.digits % divisor,
+                width = self.scale as usize
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl UiAmount {
+    /// More fraction digits than the mint has are rejected instead of rounded
+    pub fn to_amount(&self, decimals: u8) -> Result<u64, Error> {
+        let decimals = decimals as u32;
+        if self.scale > decimals {
+            return Err(Error::AmountTooPrecise {
+                amount: self.to_string(),
+                decimals: decimals as u8,
+            });
+        }
+
+        10_u128
+            .checked_pow(decimals - self.scale)
+            .and_then(|multiplier| self.digits.checked_mul(multiplier))
+            .and_then(|amount| u64::try_from(amount).ok())
+            .ok_or_else(|| Error::AmountOverflow(self.to_string()))
+    }
 }
 
 pub struct Args {
-    pub bsol_amount: u64,
+    pub command: Command,
+    pub collateral: CollateralConfig,
     pub rpc_client: Arc<RpcClient>,
     pub ws_client: Arc<WebsocketClient>,
     pub wallet: Arc<Wallet>,
-    pub alt_address: Pubkey,
+    pub alt_address: Option<Pubkey>,
+    pub marginfi_account: Option<Pubkey>,
+    pub marginfi_banks: Vec<Pubkey>,
+    pub meteora_pools: Vec<MeteoraPoolConfig>,
+    pub borrowable_mints: Vec<Pubkey>,
+    pub borrow_split: BorrowSplit,
+    /// Mints without a cap are limited by the bank only
+    pub borrow_caps: HashMap<Pubkey, f64>,
+    /// Pool side used when both pool tokens can be borrowed
+    pub preferred_input_mint: Option<Pubkey>,
+    /// Accepted difference between the expected and minted pool LP tokens
+    pub pool_slippage_bps: u16,
+    /// Minimum lamports which have to stay in the wallet for transaction fees
+    pub min_sol_balance: u64,
+    /// Maintenance health below which the position is partially unwound
+    pub health_trigger: f64,
+    /// Maintenance health restored by the partial unwind, above `health_trigger`
+    pub health_target: f64,
+    /// Maintenance health below which a warning notification is sent
+    pub health_warning: f64,
+    /// Maintenance health below which a critical notification is sent
+    pub health_critical: f64,
+    pub maintenance_intervals: MaintenanceIntervals,
+    /// Relative difference of local and on-chain balances reported by reconciliation
+    pub reconcile_tolerance_bps: u64,
+    /// Initial health the borrow is sized to, the free share of the initial weighted
+    /// assets like the maintenance health
+    pub target_init_health: f64,
+    pub rebalance_config: RebalanceConfig,
+    pub stop_loss_config: StopLossConfig,
+    pub create_account: bool,
+    pub oracle_program_subscribe: bool,
+    pub swap_route_config: SwapRouteConfig,
+    pub best_effort_alts: bool,
+    pub validate_bank_vaults: bool,
+    pub skip_constants_validation: bool,
+    pub send_config: SendConfig,
+    pub jupiter_config: JupiterApiConfig,
+    pub metrics_address: Option<SocketAddr>,
+    pub json_logs: bool,
+    pub unwind_on_shutdown: bool,
 }
 
 impl Args {
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.command, Command::Status { .. } | Command::Validate)
+    }
+
     pub fn load() -> Self {
         dotenv::dotenv().ok();
 
-        let rpc_client = load_and_parse_arg("RPC_URL", |url| {
-            Ok(Arc::new(RpcClient::new_with_commitment(
-                url,
-                CommitmentConfig::confirmed(),
-            )))
-        });
-        let ws_client = load_and_parse_arg("WS_URL", |url| Ok(Arc::new(WebsocketClient::new(url))));
-        let wallet = load_and_parse_arg("PRIVATE_KEY", |pk| {
-            let pk = pk
+        // CLI flags override env vars, which override the config file
+        let cli_args = CliArgs::parse();
+        let (config, config_path) =
+            Config::load(cli_args.config.as_deref()).unwrap_or_else(|e| panic!("{NAMESPACE} {e}"));
+        let settings = Settings::new(&config, config_path);
+        let dry_run = settings.flag(cli_args.dry_run, "DRY_RUN");
+        let run_args = match cli_args.command {
+            Command::Run(run_args) => run_args,
+            _ => RunArgs::default(),
+        };
+
+        // Comma separated, in order of priority
+        let commitment_policy = CommitmentPolicy {
+            reads: settings.commitment("READ_COMMITMENT", CommitmentConfig::confirmed()),
+            subscriptions: settings
+                .commitment("SUBSCRIPTION_COMMITMENT", CommitmentConfig::confirmed()),
+            confirmation: settings
+                .commitment("CONFIRMATION_COMMITMENT", CommitmentConfig::confirmed()),
+        };
+        // Transactions can not be fetched at processed commitment
+        if commitment_policy.confirmation.is_processed() {
+            settings.reject(
+                "CONFIRMATION_COMMITMENT",
+                "has to be confirmed or finalized",
+            );
+        } else if !commitment_policy.is_valid() {
+            settings.reject(
+                "CONFIRMATION_COMMITMENT",
+                "has to be at least as strong as READ_COMMITMENT",
+            );
+        }
+
+        let rpc_client = Arc::new(new_failover_rpc_client(
+            settings
+                .required("RPC_URL")
                 .split(",")
-                .map(|x| x.parse().map_err(|_| "Invalid private key"))
-                .collect::<Result<Vec<u8>, &str>>()?;
-            let keypair = Keypair::from_bytes(&pk[..]).map_err(|e| e.to_string())?;
-            let pubkey = keypair.try_pubkey().unwrap();
-            Ok(Arc::new(Wallet { keypair, pubkey }))
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            commitment_policy.reads,
+        ));
+        // Zero disables the liveness check
+        let ws_liveness_timeout = settings
+            .parse("WS_LIVENESS_TIMEOUT")
+            .unwrap_or(DEFAULT_WS_LIVENESS_TIMEOUT_SECS);
+        let ws_client = Arc::new(WebsocketClient::new(
+            settings
+                .required("WS_URL")
+                .split(",")
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
+            commitment_policy.subscriptions,
+            (ws_liveness_timeout > 0).then(|| Duration::from_secs(ws_liveness_timeout)),
+        ));
+        let keypair = settings
+            .optional("PRIVATE_KEY")
+            .map_or(Err("is missing".to_string()), |key| parse_keypair(&key))
+            .unwrap_or_else(|e| {
+                settings.reject("PRIVATE_KEY", &e);
+                Keypair::new()
+            });
+        let pubkey = keypair.try_pubkey().unwrap();
+        let wallet = Arc::new(Wallet { keypair, pubkey });
+        let alt_address = settings
+            .optional("ADDRESS_LOOKUP_TABLE")
+            .map(|alt| Pubkey::from_str(&alt).or_invalid(&settings, "ADDRESS_LOOKUP_TABLE"));
+        let marginfi_account = settings
+            .optional("MARGINFI_ACCOUNT")
+            .map(|address| Pubkey::from_str(&address).or_invalid(&settings, "MARGINFI_ACCOUNT"));
+        let marginfi_banks = settings
+            .optional("MARGINFI_BANKS")
+            .map(|banks| {
+                banks
+                    .split(",")
+                    .map(|address| Pubkey::from_str(address.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .or_invalid(&settings, "MARGINFI_BANKS")
+            })
+            .unwrap_or_default();
+        // Format: <pool>:<farm>[+<farm>...]:<weight>,...
+        let meteora_pools = settings
+            .optional("METEORA_POOLS")
+            .map(|pools| {
+                pools
+                    .split(",")
+                    .map(|pool| {
+                        let parts = pool.trim().split(":").collect::<Vec<_>>();
+                        if parts.len() != 3 {
+                            return None;
+                        }
+
+                        Some(MeteoraPoolConfig {
+                            pool: Pubkey::from_str(parts[0]).ok()?,
+                            farms: parts[1]
+                                .split("+")
+                                .map(|farm| Pubkey::from_str(farm).ok())
+                                .collect::<Option<Vec<_>>>()?,
+                            weight: parts[2].parse().ok().filter(|w| *w > 0)?,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .or_invalid(&settings, "METEORA_POOLS")
+            })
+            .unwrap_or(vec![MeteoraPoolConfig::default()]);
+        let borrowable_mints = settings
+            .optional("BORROWABLE_MINTS")
+            .map(|mints| {
+                mints
+                    .split(",")
+                    .map(|mint| Pubkey::from_str(mint.trim()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .or_invalid(&settings, "BORROWABLE_MINTS")
+            })
+            .unwrap_or(vec![
+                constants::mints::usdc::id(),
+                constants::mints::usdt::id(),
+                constants::mints::uxd::id(),
+            ]);
+        // `equal_rates` or weights as `mint:weight,mint:weight`
+        let borrow_split = settings
+            .optional("BORROW_SPLIT")
+            .map(|split| {
+                if split.trim() == "equal_rates" {
+                    return BorrowSplit::EqualRates;
+                }
+                let weights = split
+                    .split(",")
+                    .map(|weight| {
+                        let (mint, weight) = weight.split_once(":")?;
+                        Some((
+                            Pubkey::from_str(mint.trim()).ok()?,
+                            weight.trim().parse::<u64>().ok()?,
+                        ))
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|weights| {
+                        weights
+                            .iter()
+                            .all(|(mint, _)| borrowable_mints.contains(mint))
+                    })
+                    .unwrap_or_else(|| {
+                        settings.reject(
+                            "BORROW_SPLIT",
+                            "could not be parsed, mints have to be borrowable",
+                        );
+                        vec![]
+                    });
+                BorrowSplit::Weights(weights)
+            })
+            .unwrap_or(BorrowSplit::Single);
+        // Format: <mint>:<max USD>,...
+        let borrow_caps = settings
+            .optional("BORROW_CAPS")
+            .map(|caps| {
+                caps.split(",")
+                    .map(|cap| {
+                        let (mint, usd) = cap.split_once(":")?;
+                        Some((
+                            Pubkey::from_str(mint.trim()).ok()?,
+                            usd.trim().parse::<f64>().ok().filter(|usd| *usd >= 0.0)?,
+                        ))
+                    })
+                    .collect::<Option<HashMap<_, _>>>()
+                    .filter(|caps| caps.keys().all(|mint| borrowable_mints.contains(mint)))
+                    .unwrap_or_else(|| {
+                        settings.reject(
+                            "BORROW_CAPS",
+                            "could not be parsed, mints have to be borrowable",
+                        );
+                        HashMap::new()
+                    })
+            })
+            .unwrap_or_default();
+        let preferred_input_mint = settings
+            .optional("PREFERRED_INPUT_MINT")
+            .map(|mint| Pubkey::from_str(&mint).or_invalid(&settings, "PREFERRED_INPUT_MINT"))
+            .or(Some(constants::mints::usdc::id()));
+        let pool_slippage_bps = settings
+            .optional("POOL_SLIPPAGE_BPS")
+            .map(|bps| bps.parse().or_invalid(&settings, "POOL_SLIPPAGE_BPS"))
+            .unwrap_or(DEFAULT_POOL_SLIPPAGE_BPS);
+        let min_sol_balance = settings
+            .optional("MIN_SOL_BALANCE")
+            .map(|sol| {
+                let sol: f64 = sol.parse().or_invalid(&settings, "MIN_SOL_BALANCE");
+                (sol * 10_f64.powf(9.0)) as u64
+            })
+            .unwrap_or(DEFAULT_MIN_SOL_BALANCE);
+        let health_trigger = settings
+            .optional("HEALTH_TRIGGER")
+            .map(|health| health.parse().or_invalid(&settings, "HEALTH_TRIGGER"))
+            .unwrap_or(DEFAULT_HEALTH_TRIGGER);
+        let health_target = settings
+            .optional("HEALTH_TARGET")
+            .map(|health| health.parse().or_invalid(&settings, "HEALTH_TARGET"))
+            .unwrap_or(DEFAULT_HEALTH_TARGET);
+        if !(0.0..1.0).contains(&health_trigger) || health_target <= health_trigger {
+            settings.reject(
+                "HEALTH_TARGET",
+                "has to be above HEALTH_TRIGGER, both between 0 and 1",
+            );
+        }
+        let health_warning = settings
+            .optional("HEALTH_WARNING")
+            .map(|health| health.parse().or_invalid(&settings, "HEALTH_WARNING"))
+            .unwrap_or(DEFAULT_HEALTH_WARNING);
+        let health_critical = settings
+            .optional("HEALTH_CRITICAL")
+            .map(|health| health.parse().or_invalid(&settings, "HEALTH_CRITICAL"))
+            .unwrap_or(DEFAULT_HEALTH_CRITICAL);
+        let notifier = Notifier::new(
+            settings.optional("NOTIFICATION_WEBHOOK_URL"),
+            settings
+                .optional("NOTIFICATION_MAX_PER_MINUTE")
+                .map(|max| {
+                    max.parse()
+                        .or_invalid(&settings, "NOTIFICATION_MAX_PER_MINUTE")
+                })
+                .unwrap_or(Notifier::DEFAULT_MAX_PER_MINUTE),
+        );
+        let ledger_path = PathBuf::from(
+            settings
+                .optional("LEDGER_FILE")
+                .unwrap_or(DEFAULT_LEDGER_FILE.to_string()),
+        );
+        let ledger = Ledger::load(ledger_path.clone(), !dry_run).unwrap_or_else(|e| {
+            settings.reject("LEDGER_FILE", &e.to_string());
+            Ledger::new(ledger_path, false)
         });
-        let alt_address = load_and_parse_arg("ADDRESS_LOOKUP_TABLE", |alt| {
-            Ok(Pubkey::from_str(&alt).map_err(|_| "Invalid ALT address")?)
+        let target_init_health = settings
+            .optional("TARGET_INIT_HEALTH")
+            .map(|health| health.parse().or_invalid(&settings, "TARGET_INIT_HEALTH"))
+            .unwrap_or(DEFAULT_TARGET_INIT_HEALTH);
+        if !(0.0..1.0).contains(&target_init_health) {
+            settings.reject("TARGET_INIT_HEALTH", "has to be between 0 and 1");
+        }
+        let maintenance_intervals = MaintenanceIntervals {
+            health_check: settings
+                .optional("HEALTH_CHECK_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(secs.parse().or_invalid(&settings, "HEALTH_CHECK_INTERVAL"))
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_HEALTH_CHECK_INTERVAL_SECS)),
+            interest_refresh: settings
+                .optional("INTEREST_REFRESH_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(
+                        secs.parse()
+                            .or_invalid(&settings, "INTEREST_REFRESH_INTERVAL"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_INTEREST_REFRESH_INTERVAL_SECS)),
+            reward_compound: settings
+                .optional("REWARD_COMPOUND_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(
+                        secs.parse()
+                            .or_invalid(&settings, "REWARD_COMPOUND_INTERVAL"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_REWARD_COMPOUND_INTERVAL_SECS)),
+            rebalance_check: settings
+                .optional("REBALANCE_CHECK_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(
+                        secs.parse()
+                            .or_invalid(&settings, "REBALANCE_CHECK_INTERVAL"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_REBALANCE_CHECK_INTERVAL_SECS)),
+            oracle_audit: settings
+                .optional("ORACLE_AUDIT_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(secs.parse().or_invalid(&settings, "ORACLE_AUDIT_INTERVAL"))
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_ORACLE_AUDIT_INTERVAL_SECS)),
+            stop_loss_check: settings
+                .optional("STOP_LOSS_CHECK_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(
+                        secs.parse()
+                            .or_invalid(&settings, "STOP_LOSS_CHECK_INTERVAL"),
+                    )
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_STOP_LOSS_CHECK_INTERVAL_SECS)),
+            reconcile: settings
+                .optional("RECONCILE_INTERVAL")
+                .map(|secs| {
+                    Duration::from_secs(secs.parse().or_invalid(&settings, "RECONCILE_INTERVAL"))
+                })
+                .unwrap_or(Duration::from_secs(DEFAULT_RECONCILE_INTERVAL_SECS)),
+        };
+        let reconcile_tolerance_bps = settings
+            .optional("RECONCILE_TOLERANCE_BPS")
+            .map(|bps| bps.parse().or_invalid(&settings, "RECONCILE_TOLERANCE_BPS"))
+            .unwrap_or(DEFAULT_RECONCILE_TOLERANCE_BPS);
+        let rebalance_spread_bps = settings
+            .optional("REBALANCE_SPREAD_BPS")
+            .map(|bps| bps.parse().or_invalid(&settings, "REBALANCE_SPREAD_BPS"))
+            .unwrap_or(DEFAULT_REBALANCE_SPREAD_BPS);
+        let rebalance_spread_duration = settings
+            .optional("REBALANCE_SPREAD_DURATION")
+            .map(|secs| {
+                Duration::from_secs(
+                    secs.parse()
+                        .or_invalid(&settings, "REBALANCE_SPREAD_DURATION"),
+                )
+            })
+            .unwrap_or(Duration::from_secs(DEFAULT_REBALANCE_SPREAD_DURATION_SECS));
+        let rebalance_min_interval = settings
+            .optional("REBALANCE_MIN_INTERVAL")
+            .map(|secs| {
+                Duration::from_secs(secs.parse().or_invalid(&settings, "REBALANCE_MIN_INTERVAL"))
+            })
+            .unwrap_or(Duration::from_secs(DEFAULT_REBALANCE_MIN_INTERVAL_SECS));
+        let rebalance_min_notional = settings
+            .optional("REBALANCE_MIN_NOTIONAL")
+            .map(|notional| {
+                notional
+                    .parse()
+                    .or_invalid(&settings, "REBALANCE_MIN_NOTIONAL")
+            })
+            .unwrap_or(DEFAULT_REBALANCE_MIN_NOTIONAL);
+        let jupiter_max_requests_per_second = settings
+            .optional("JUPITER_MAX_REQUESTS_PER_SECOND")
+            .map(|rps| {
+                rps.parse()
+                    .or_invalid(&settings, "JUPITER_MAX_REQUESTS_PER_SECOND")
+            })
+            .unwrap_or(JupiterApiConfig::DEFAULT_MAX_REQUESTS_PER_SECOND);
+        let jupiter_config = JupiterApiConfig::new(
+            settings
+                .optional("JUPITER_API_URL")
+                .unwrap_or(JupiterApiConfig::DEFAULT_URL.to_string()),
+            settings.optional("JUPITER_API_KEY"),
+            jupiter_max_requests_per_second,
+        )
+        .unwrap_or_else(|e| {
+            settings.reject("JUPITER_API_URL", &e);
+            JupiterApiConfig::new(
+                JupiterApiConfig::DEFAULT_URL.to_string(),
+                None,
+                JupiterApiConfig::DEFAULT_MAX_REQUESTS_PER_SECOND,
+            )
+            .unwrap()
         });
 
-        let cli_args = CliArgs::parse();
-        let bsol_amount = (cli_args.bsol_amount * 10_f32.powf(9.0)) as u64;
+        let poll_interval = settings
+            .optional("TX_POLL_INTERVAL_MS")
+            .map(|ms| {
+                Duration::from_millis(ms.parse().or_invalid(&settings, "TX_POLL_INTERVAL_MS"))
+            })
+            .unwrap_or(Duration::from_millis(DEFAULT_TX_POLL_INTERVAL_MS));
+        let max_confirmation_wait = settings
+            .optional("TX_MAX_CONFIRMATION_WAIT")
+            .map(|secs| {
+                Duration::from_secs(
+                    secs.parse()
+                        .or_invalid(&settings, "TX_MAX_CONFIRMATION_WAIT"),
+                )
+            })
+            .unwrap_or(Duration::from_secs(DEFAULT_TX_MAX_CONFIRMATION_WAIT_SECS));
+        if poll_interval.is_zero() || poll_interval >= max_confirmation_wait {
+            settings.reject(
+                "TX_POLL_INTERVAL_MS",
+                "has to be above zero and below TX_MAX_CONFIRMATION_WAIT",
+            );
+        }
+        let preflight_commitment = match settings.optional("PREFLIGHT_COMMITMENT").as_deref() {
+            None => None,
+            Some("processed") => Some(CommitmentLevel::Processed),
+            Some("confirmed") => Some(CommitmentLevel::Confirmed),
+            Some("finalized") => Some(CommitmentLevel::Finalized),
+            Some(_) => {
+                settings.reject(
+                    "PREFLIGHT_COMMITMENT",
+                    "has to be processed, confirmed or finalized",
+                );
+                None
+            }
+        };
+
+        let risk_limits = RiskLimits {
+            max_borrow_usd: settings.parse("MAX_BORROW_USD"),
+            max_transaction_usd: settings.parse("MAX_TRANSACTION_USD"),
+            max_daily_loss_usd: settings.parse("MAX_DAILY_LOSS_USD"),
+            kill_switch_file: settings.optional("KILL_SWITCH_FILE").map(PathBuf::from),
+        };
+        for (key, limit) in [
+            ("MAX_BORROW_USD", risk_limits.max_borrow_usd),
+            ("MAX_TRANSACTION_USD", risk_limits.max_transaction_usd),
+            ("MAX_DAILY_LOSS_USD", risk_limits.max_daily_loss_usd),
+        ] {
+            if limit.is_some_and(|limit: f64| limit.is_nan() || limit < 0.0) {
+                settings.reject(key, "has to be a non-negative USD value");
+            }
+        }
+
+        let send_config = SendConfig {
+            dry_run,
+            max_retries: settings
+                .optional("MAX_SEND_RETRIES")
+                .map(|retries| retries.parse().or_invalid(&settings, "MAX_SEND_RETRIES"))
+                .unwrap_or(DEFAULT_MAX_SEND_RETRIES),
+            poll_interval,
+            max_confirmation_wait,
+            commitment_policy,
+            cu_limit: settings
+                .optional("CU_LIMIT")
+                .map(|limit| limit.parse().or_invalid(&settings, "CU_LIMIT"))
+                .unwrap_or(DEFAULT_CU_LIMIT),
+            cu_price_micro_lamports: settings
+                .optional("CU_PRICE")
+                .map(|price| price.parse().or_invalid(&settings, "CU_PRICE"))
+                .unwrap_or(DEFAULT_CU_PRICE_MICRO_LAMPORTS),
+            priority_fee: settings
+                .flag(cli_args.estimate_priority_fee, "ESTIMATE_PRIORITY_FEE")
+                .then(|| {
+                    let percentile = settings
+                        .optional("PRIORITY_FEE_PERCENTILE")
+                        .map(|percentile| {
+                            percentile
+                                .parse()
+                                .or_invalid(&settings, "PRIORITY_FEE_PERCENTILE")
+                        })
+                        .unwrap_or(DEFAULT_PRIORITY_FEE_PERCENTILE);
+                    if percentile > 100 {
+                        settings.reject("PRIORITY_FEE_PERCENTILE", "can not be above 100");
+                    }
+                    let min_micro_lamports = settings
+                        .optional("PRIORITY_FEE_MIN")
+                        .map(|price| price.parse().or_invalid(&settings, "PRIORITY_FEE_MIN"))
+                        .unwrap_or(DEFAULT_PRIORITY_FEE_MIN_MICRO_LAMPORTS);
+                    let max_micro_lamports = settings
+                        .optional("PRIORITY_FEE_MAX")
+                        .map(|price| price.parse().or_invalid(&settings, "PRIORITY_FEE_MAX"))
+                        .unwrap_or(DEFAULT_PRIORITY_FEE_MAX_MICRO_LAMPORTS);
+                    if min_micro_lamports > max_micro_lamports {
+                        settings.reject("PRIORITY_FEE_MIN", "can not be above PRIORITY_FEE_MAX");
+                    }
+                    PriorityFeeConfig {
+                        percentile,
+                        min_micro_lamports,
+                        max_micro_lamports,
+                    }
+                }),
+            simulate_cu_limit: settings.flag(cli_args.simulate_cu_limit, "SIMULATE_CU_LIMIT"),
+            cu_limit_margin_percent: settings
+                .optional("CU_LIMIT_MARGIN_PERCENT")
+                .map(|margin| {
+                    margin
+                        .parse()
+                        .or_invalid(&settings, "CU_LIMIT_MARGIN_PERCENT")
+                })
+                .unwrap_or(DEFAULT_CU_LIMIT_MARGIN_PERCENT),
+            cu_limits: Arc::new(CuLimitCache::default()),
+            notifier: Arc::new(notifier),
+            ledger: Arc::new(ledger),
+            risk_guard: Arc::new(RiskGuard::new(risk_limits)),
+            shutdown: Shutdown::new(),
+            simulate_before_send: !settings.flag(cli_args.skip_simulation, "SKIP_SIMULATION"),
+            jito: settings.flag(cli_args.jito_tip, "JITO_TIP").then(|| {
+                let tip_lamports = settings
+                    .optional("JITO_TIP_LAMPORTS")
+                    .map(|tip| tip.parse().or_invalid(&settings, "JITO_TIP_LAMPORTS"))
+                    .unwrap_or(DEFAULT_JITO_TIP_LAMPORTS);
+                if tip_lamports > MAX_JITO_TIP_LAMPORTS {
+                    settings.reject(
+                        "JITO_TIP_LAMPORTS",
+                        &format!("can not be above {MAX_JITO_TIP_LAMPORTS}"),
+                    );
+                }
+                JitoConfig {
+                    tip_lamports,
+                    block_engine_url: settings.optional("JITO_BLOCK_ENGINE_URL"),
+                    client: reqwest::Client::new(),
+                }
+            }),
+            memo: !settings.flag(cli_args.skip_memo, "SKIP_MEMO"),
+            nonce_account: settings
+                .flag(cli_args.durable_nonce, "DURABLE_NONCE")
+                .then(|| {
+                    settings
+                        .optional("NONCE_ACCOUNT")
+                        .map(|account| {
+                            Pubkey::from_str(&account).or_invalid(&settings, "NONCE_ACCOUNT")
+                        })
+                        .unwrap_or_else(|| nonce::derive_nonce_account(&wallet.pubkey))
+                }),
+            send_rpc_clients: SendRpcClients(
+                settings
+                    .optional("SEND_RPC_URLS")
+                    .map(|urls| {
+                        urls.split(",")
+                            .map(|url| url.trim())
+                            .filter(|url| !url.is_empty())
+                            .map(|url| Arc::new(RpcClient::new(url.to_string())))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+            preflight: settings.flag(cli_args.preflight, "PREFLIGHT"),
+            preflight_commitment,
+            rpc_max_retries: Some(
+                settings
+                    .optional("RPC_MAX_RETRIES")
+                    .map(|retries| retries.parse().or_invalid(&settings, "RPC_MAX_RETRIES"))
+                    .unwrap_or(DEFAULT_RPC_MAX_RETRIES),
+            ),
+            ws_client: Some(ws_client.clone()),
+        };
+        let collateral = CollateralConfig {
+            mint: settings
+                .optional("COLLATERAL_MINT")
+                .map(|mint| Pubkey::from_str(&mint).or_invalid(&settings, "COLLATERAL_MINT"))
+                .unwrap_or(constants::mints::bsol::id()),
+            ui_amount: run_args
+                .collateral_amount
+                .or_else(|| settings.parse("COLLATERAL_AMOUNT"))
+                .unwrap_or_default(),
+            use_existing: settings
+                .flag(run_args.use_existing_collateral, "USE_EXISTING_COLLATERAL"),
+            clamp_to_wallet: settings.flag(
+                run_args.clamp_collateral_to_wallet,
+                "CLAMP_COLLATERAL_TO_WALLET",
+            ),
+        };
+        let rebalance_config = RebalanceConfig {
+            enabled: settings.flag(run_args.rebalance_borrow, "REBALANCE_BORROW"),
+            spread_bps: rebalance_spread_bps,
+            spread_duration: rebalance_spread_duration,
+            min_interval: rebalance_min_interval,
+            min_notional: rebalance_min_notional,
+        };
+        let stop_loss_config = StopLossConfig {
+            max_drawdown_pct: settings
+                .optional("STOP_LOSS_DRAWDOWN_PCT")
+                .map(|pct| pct.parse().or_invalid(&settings, "STOP_LOSS_DRAWDOWN_PCT")),
+            price_floor: settings
+                .optional("STOP_LOSS_PRICE_FLOOR")
+                .map(|price| price.parse().or_invalid(&settings, "STOP_LOSS_PRICE_FLOOR")),
+            marker_path: PathBuf::from(
+                settings
+                    .optional("STOP_LOSS_MARKER_FILE")
+                    .unwrap_or(DEFAULT_STOP_LOSS_MARKER_FILE.to_string()),
+            ),
+            rearm: settings.flag(run_args.rearm_stop_loss, "REARM_STOP_LOSS"),
+        };
+        let swap_route_config = SwapRouteConfig {
+            only_direct_routes: settings.flag(cli_args.only_direct_routes, "ONLY_DIRECT_ROUTES"),
+            max_accounts: cli_args
+                .max_accounts
+                .or_else(|| settings.parse("MAX_ACCOUNTS")),
+            dexes: cli_args.dexes.or_else(|| settings.list("DEXES")),
+            exclude_dexes: cli_args
+                .exclude_dexes
+                .or_else(|| settings.list("EXCLUDE_DEXES")),
+            max_slippage_bps: cli_args
+                .max_swap_slippage_bps
+                .or_else(|| settings.parse("MAX_SWAP_SLIPPAGE_BPS"))
+                .unwrap_or(DEFAULT_MAX_SWAP_SLIPPAGE_BPS),
+            max_requotes: cli_args
+                .max_swap_requotes
+                .or_else(|| settings.parse("MAX_SWAP_REQUOTES"))
+                .unwrap_or(DEFAULT_MAX_SWAP_REQUOTES),
+            max_shortfall_bps: cli_args
+                .max_swap_shortfall_bps
+                .or_else(|| settings.parse("MAX_SWAP_SHORTFALL_BPS"))
+                .unwrap_or(DEFAULT_MAX_SWAP_SHORTFALL_BPS),
+        };
+        let create_account = settings.flag(run_args.create_account, "CREATE_ACCOUNT");
+        let oracle_program_subscribe = settings.flag(
+            run_args.oracle_program_subscribe,
+            "ORACLE_PROGRAM_SUBSCRIBE",
+        );
+        let best_effort_alts = settings.flag(cli_args.best_effort_alts, "BEST_EFFORT_ALTS");
+        let validate_bank_vaults =
+            settings.flag(cli_args.validate_bank_vaults, "VALIDATE_BANK_VAULTS");
+        let skip_constants_validation = settings.flag(
+            cli_args.skip_constants_validation,
+            "SKIP_CONSTANTS_VALIDATION",
+        );
+        let metrics_address = cli_args
+            .metrics_address
+            .or_else(|| settings.parse("METRICS_ADDRESS"));
+        let json_logs = settings.flag(cli_args.json_logs, "JSON_LOGS");
+        let unwind_on_shutdown = settings.flag(run_args.unwind_on_shutdown, "UNWIND_ON_SHUTDOWN");
+        settings.finish();
 
         Self {
-            bsol_amount,
+            command: cli_args.command,
+            collateral,
             rpc_client,
             ws_client,
             wallet,
             alt_address,
+            marginfi_account,
+            marginfi_banks,
+            meteora_pools,
+            borrowable_mints,
+            borrow_split,
+            borrow_caps,
+            preferred_input_mint,
+            pool_slippage_bps,
+            min_sol_balance,
+            health_trigger,
+            health_target,
+            health_warning,
+            health_critical,
+            maintenance_intervals,
+            reconcile_tolerance_bps,
+            target_init_health,
+            rebalance_config,
+            stop_loss_config,
+            create_account,
+            oracle_program_subscribe,
+            swap_route_config,
+            best_effort_alts,
+            validate_bank_vaults,
+            skip_constants_validation,
+            send_config,
+            jupiter_config,
+            metrics_address,
+            json_logs,
+            unwind_on_shutdown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair_file(name: &str, contents: &str, mode: u32) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mmf-keypair-{}-{}.json", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        path
+    }
+
+    fn keypair_bytes(keypair: &Keypair) -> String {
+        keypair
+            .to_bytes()
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    #[test]
+    fn parses_keypair_file() {
+        let keypair = Keypair::new();
+        let path = keypair_file("valid", &format!("[{}]", keypair_bytes(&keypair)), 0o600);
+
+        let parsed = parse_keypair(path.to_str().unwrap()).unwrap();
+        assert_eq!(parsed.pubkey(), keypair.pubkey());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parses_base58_secret_key() {
+        let keypair = Keypair::new();
+
+        let parsed = parse_keypair(&keypair.to_base58_string()).unwrap();
+        assert_eq!(parsed.pubkey(), keypair.pubkey());
+    }
+
+    #[test]
+    fn parses_comma_separated_bytes() {
+        let keypair = Keypair::new();
+
+        for value in [
+            keypair_bytes(&keypair),
+            format!("[{}]", keypair_bytes(&keypair)),
+            format!(" {} \n", keypair_bytes(&keypair).replace(",", ", ")),
+        ] {
+            assert_eq!(parse_keypair(&value).unwrap().pubkey(), keypair.pubkey());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_world_readable_keypair_file() {
+        let keypair = Keypair::new();
+        let path = keypair_file("readable", &format!("[{}]", keypair_bytes(&keypair)), 0o644);
+
+        let error = parse_keypair(path.to_str().unwrap()).unwrap_err();
+        assert!(error.contains("readable by every user"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed_keypairs_are_errors_without_key_material() {
+        let keypair = Keypair::new();
+        let bytes = keypair_bytes(&keypair);
+        let short_bytes = bytes.rsplit_once(",").unwrap().0.to_string();
+        let base58 = keypair.to_base58_string();
+        let short_base58 = base58[..base58.len() - 4].to_string();
+
+        let not_a_keypair = keypair_file("malformed", &format!("{{{}}}", bytes), 0o600);
+        let short_file = keypair_file("short", &format!("[{}]", short_bytes), 0o600);
+        let missing_file = std::env::temp_dir().join("mmf-keypair-missing.json");
+
+        for value in [
+            short_bytes.clone(),
+            format!("{},256", bytes),
+            short_base58.clone(),
+            "not a keypair".to_string(),
+            not_a_keypair.display().to_string(),
+            short_file.display().to_string(),
+            missing_file.display().to_string(),
+        ] {
+            let error = parse_keypair(&value).unwrap_err();
+            assert!(!error.contains(&bytes), "{}", error);
+            assert!(!error.contains(&short_bytes), "{}", error);
+            assert!(!error.contains(&short_base58), "{}", error);
+        }
+        fs::remove_file(not_a_keypair).unwrap();
+        fs::remove_file(short_file).unwrap();
+    }
+
+    fn ui_amount(amount: &str) -> UiAmount {
+        amount.parse().unwrap()
+    }
+
+    #[test]
+    fn ui_amount_converts_exactly() {
+        assert_eq!(
+            ui_amount("1.234567891").to_amount(9).unwrap(),
+            1_234_567_891
+        );
+        assert_eq!(ui_amount("12").to_amount(9).unwrap(), 12_000_000_000);
+        assert_eq!(ui_amount("0.000001").to_amount(6).unwrap(), 1);
+        assert_eq!(ui_amount("0").to_amount(9).unwrap(), 0);
+        // Trailing zeros do not count as precision
+        assert_eq!(
+            ui_amount("1.5000000000000").to_amount(9).unwrap(),
+            1_500_000_000
+        );
+        assert_eq!(ui_amount("0.05").to_string(), "0.05");
+    }
+
+    #[test]
+    fn ui_amount_more_precise_than_the_mint_is_an_error() {
+        assert!(matches!(
+            ui_amount("1.0000001").to_amount(6),
+            Err(Error::AmountTooPrecise { amount, decimals: 6 }) if amount == "1.0000001"
+        ));
+    }
+
+    #[test]
+    fn ui_amount_up_to_u64_max_converts() {
+        assert_eq!(
+            ui_amount("18446744073.709551615").to_amount(9).unwrap(),
+            u64::MAX
+        );
+        assert!(matches!(
+            ui_amount("18446744073.709551616").to_amount(9),
+            Err(Error::AmountOverflow(amount)) if amount == "18446744073.709551616"
+        ));
+        assert!(matches!(
+            ui_amount("18446744074").to_amount(9),
+            Err(Error::AmountOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_ui_amounts_are_errors() {
+        for amount in ["", ".5", "1.", "1.2.3", "-1", "1e9", " 1", "1,5"] {
+            assert!(amount.parse::<UiAmount>().is_err(), "{}", amount);
         }
+        assert!("1".repeat(40).parse::<UiAmount>().is_err());
     }
 }
@@ -1,92 +1,1007 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anchor_lang::prelude::Pubkey;
 use fixed::types::I80F48;
-use reqwest::Client;
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::{
-    address_lookup_table_account::AddressLookupTableAccount, instruction::Instruction,
+    address_lookup_table_account::AddressLookupTableAccount,
+    commitment_config::CommitmentConfig,
+    compute_budget,
+    instruction::{Instruction, InstructionError},
+    signature::{Keypair, Signature},
+    signer::Signer,
+    system_instruction,
+    transaction::{TransactionError, VersionedTransaction},
 };
 use solana_transaction_status::UiTransactionStatusMeta;
 use tokio::{task::JoinHandle, time::sleep};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
 use crate::{
-    addresses::StaticAddresses,
+    addresses::{MarginfiBankOracle, PoolId, SharedStaticAddresses, StaticAddresses},
     args::Args,
-    connection, constants,
-    instructions::InstructionBuilder,
-    state::{MarginfiAccountWithBanks, MarginfiBank, OraclesState},
-    utils::transaction::{
-        build_signed_transaction, parse_transaction_token_change, send_and_confirm_transaction,
-        TransactionResult,
+    connection::{self, SwapInstructions, SwapMode, SwapQuote, SwapRouteConfig},
+    constants,
+    instructions::{InstructionBuilder, MAX_COMPUTE_UNIT_LIMIT},
+    metrics,
+    position::{self, PositionEvidence},
+    reconcile,
+    scheduler::{MaintenanceJob, Scheduler},
+    shutdown::Shutdown,
+    state::{HealthType, MarginfiAccountWithBanks, MarginfiBank, OraclesState},
+    status,
+    unwind::{self, UnwindContext},
+    utils::{
+        jupiter_client::JupiterClient,
+        notifier::{NotificationLevel, Notifier},
+        transaction::{
+            build_signed_transaction_with_nonce, build_signed_transaction_with_signers,
+            estimate_priority_fee, explain_failure_logs, fee_paid, fetch_landed_transaction,
+            fits_in_transaction, get_cu_price, get_failed_program_id, get_wallet_token_changes,
+            get_writable_accounts, has_compute_budget_instructions, log_failure_report,
+            parse_token_received, parse_token_spent, parse_transaction_sol_change, raise_cu_limit,
+            send_and_confirm_transaction, set_cu_limit, set_cu_price, sign_transaction, simulate,
+            simulate_transaction, simulate_units_consumed, split_instructions, SendConfig,
+            TransactionLifetime, TransactionResult,
+        },
+        websocket_client::WebsocketClient,
     },
     Error, Wallet,
 };
 
-async fn force_send_instructions(
+pub const SWAP_SLIPPAGE_BPS: u16 = 10;
+/// Extra LP withdrawn when deleveraging to cover pool and swap slippage
+const DELEVERAGE_BUFFER_BPS: u64 = 100;
+/// Borrowing is refused when sized to an initial health closer to 0
+const MIN_TARGET_INIT_HEALTH: f64 = 0.05;
+
+/// Rebuilds with a finalized blockhash, on top of the send retries
+const MAX_STALE_BLOCKHASH_RETRIES: u32 = 3;
+const STALE_BLOCKHASH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The static price is the fallback when the estimate fails
+async fn estimate_cu_price(
+    rpc_client: &Arc<RpcClient>,
+    instructions: &[Instruction],
+    send_config: &SendConfig,
+) -> u64 {
+    let Some(priority_fee) = &send_config.priority_fee else {
+        return send_config.cu_price_micro_lamports;
+    };
+
+    let writable_accounts = get_writable_accounts(instructions);
+    match estimate_priority_fee(rpc_client, &writable_accounts, priority_fee).await {
+        Ok(cu_price) => {
+            debug!("Estimated compute unit price: {}", cu_price);
+            cu_price
+        }
+        Err(e) => {
+            warn!(
+                "Unable to estimate priority fee, using static price: {:?}",
+                e
+            );
+            send_config.cu_price_micro_lamports
+        }
+    }
+}
+
+async fn measure_cu_limit(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: &[Instruction],
+    alts: &Vec<AddressLookupTableAccount>,
+    send_config: &SendConfig,
+    cu_price: u64,
+) -> Result<Option<u32>, Error> {
+    let simulated_instructions = if has_compute_budget_instructions(instructions) {
+        set_cu_limit(instructions.to_vec(), MAX_COMPUTE_UNIT_LIMIT)
+    } else {
+        InstructionBuilder::compute_budget(MAX_COMPUTE_UNIT_LIMIT, cu_price)
+            .into_iter()
+            .chain(instructions.iter().cloned())
+            .collect()
+    };
+    let tx = build_signed_transaction_with_signers(
+        rpc_client,
+        wallet,
+        extra_signers,
+        &simulated_instructions[..],
+        &alts[..],
+    )
+    .await?;
+
+    Ok(simulate_units_consumed(rpc_client, &tx)
+        .await?
+        .map(|units| {
+            (units * (100 + send_config.cu_limit_margin_percent) / 100)
+                .min(MAX_COMPUTE_UNIT_LIMIT as u64) as u32
+        }))
+}
+
+/// Existing limits, like the one of a Jupiter swap, are only ever raised to the measured
+/// limit
+async fn add_compute_budget_instructions(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: Vec<Instruction>,
+    alts: &Vec<AddressLookupTableAccount>,
+    send_config: &SendConfig,
+    cu_price: u64,
+    step: &str,
+) -> Result<Vec<Instruction>, Error> {
+    let cu_limit = if send_config.simulate_cu_limit {
+        match send_config.cu_limits.get(step) {
+            Some(cu_limit) => Some(cu_limit),
+            None => {
+                let cu_limit = measure_cu_limit(
+                    rpc_client,
+                    wallet,
+                    extra_signers,
+                    &instructions,
+                    alts,
+                    send_config,
+                    cu_price,
+                )
+                .await?;
+                if let Some(cu_limit) = cu_limit {
+                    debug!("Measured compute unit limit of {}: {}", step, cu_limit);
+                    send_config.cu_limits.insert(step, cu_limit);
+                }
+                cu_limit
+            }
+        }
+    } else {
+        None
+    };
+
+    if has_compute_budget_instructions(&instructions) {
+        return Ok(match cu_limit {
+            Some(cu_limit) => raise_cu_limit(instructions, cu_limit),
+            None => instructions,
+        });
+    }
+
+    Ok(
+        InstructionBuilder::compute_budget(cu_limit.unwrap_or(send_config.cu_limit), cu_price)
+            .into_iter()
+            .chain(instructions)
+            .collect(),
+    )
+}
+
+/// Left out with a warning when the transaction would not fit with it
+fn add_jito_tip(
+    wallet: &Arc<Wallet>,
+    instructions: Vec<Instruction>,
+    alts: &[AddressLookupTableAccount],
+    send_config: &SendConfig,
+) -> Vec<Instruction> {
+    let Some(jito) = &send_config.jito else {
+        return instructions;
+    };
+
+    let tip_account = InstructionBuilder::next_jito_tip_account();
+    let tip_ix = InstructionBuilder::jito_tip(&wallet.pubkey, jito.tip_lamports, &tip_account);
+    match with_appended(wallet, &instructions, tip_ix, alts) {
+        Some(with_tip) => with_tip,
+        None => {
+            warn!("Transaction does not fit with the Jito tip, sending it without");
+            instructions
+        }
+    }
+}
+
+/// Left out when the transaction would not fit with it, returns the added memo
+fn add_memo(
+    wallet: &Arc<Wallet>,
+    instructions: Vec<Instruction>,
+    alts: &[AddressLookupTableAccount],
+    send_config: &SendConfig,
+    step: &str,
+) -> (Vec<Instruction>, Option<String>) {
+    if !send_config.memo {
+        return (instructions, None);
+    }
+
+    let memo = format!("mmf:{}", step);
+    match with_appended(wallet, &instructions, InstructionBuilder::memo(&memo), alts) {
+        Some(with_memo) => (with_memo, Some(memo)),
+        None => {
+            debug!(
+                "Transaction does not fit with the memo {}, sending it without",
+                memo
+            );
+            (instructions, None)
+        }
+    }
+}
+
+fn with_appended(
+    wallet: &Arc<Wallet>,
+    instructions: &[Instruction],
+    ix: Instruction,
+    alts: &[AddressLookupTableAccount],
+) -> Option<Vec<Instruction>> {
+    let appended = instructions.iter().cloned().chain([ix]).collect::<Vec<_>>();
+    fits_in_transaction(&wallet.pubkey, &appended, alts).then_some(appended)
+}
+
+fn record_transaction_success(
+    wallet: &Arc<Wallet>,
+    send_config: &SendConfig,
+    tx: &VersionedTransaction,
+    meta: &UiTransactionStatusMeta,
+    memo: Option<&str>,
+) {
+    metrics::inc_counter(metrics::TRANSACTIONS_CONFIRMED, &[]);
+    let signature = &tx.signatures[0];
+    let fee = fee_paid(meta);
+    let sol_change =
+        parse_transaction_sol_change(meta, tx.message.static_account_keys(), &wallet.pubkey);
+    info!(
+        "Transaction fee: {} lamports, SOL balance change: {:?} lamports",
+        fee, sol_change
+    );
+
+    let mut message = format!("Transaction confirmed: {}", signature);
+    match get_wallet_token_changes(meta, wallet) {
+        Ok(changes) => {
+            for (mint, change) in changes {
+                message.push_str(&format!("\n{:+} {}", change, mint));
+            }
+        }
+        Err(e) => {
+            warn!(
+                "Unable to parse the token balance changes of {}: {}",
+                signature, e
+            );
+            message.push_str("\nToken balance changes are unknown");
+        }
+    }
+    send_config
+        .notifier
+        .notify(NotificationLevel::Info, message);
+    send_config
+        .ledger
+        .record(|l| l.record_transaction(signature, fee, sol_change, memo));
+}
+
+/// The limit measured for a step which ran out of compute is measured again next time
+fn record_transaction_error(send_config: &SendConfig, step: &str, error: Error) -> Error {
+    metrics::inc_counter(metrics::TRANSACTIONS_FAILED, &[]);
+    if let Error::TransactionFailed { error, .. } | Error::SimulationFailed { error, .. } = &error {
+        if matches!(
+            error,
+            TransactionError::InstructionError(_, InstructionError::ComputationalBudgetExceeded)
+        ) {
+            send_config.cu_limits.invalidate(step);
+        }
+    }
+    let message = match &error {
+        Error::TransactionFailed {
+            signature,
+            error,
+            report,
+            ..
+        } => format!("Transaction {} failed: {}\n{}", signature, error, report),
+        Error::SimulationFailed { error, report, .. } => {
+            format!("Transaction simulation failed: {}\n{}", error, report)
+        }
+        error => format!("Transaction failed: {:?}", error),
+    };
+    send_config
+        .notifier
+        .notify(NotificationLevel::Warning, message);
+    error
+}
+
+/// The groups left are not sent once a transaction fails
+pub async fn force_send_instruction_groups(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    groups: Vec<Vec<Instruction>>,
+    alts: &Vec<AddressLookupTableAccount>,
+    send_config: &SendConfig,
+    step: &str,
+) -> Result<Vec<(Signature, UiTransactionStatusMeta)>, Error> {
+    // Compute budget instructions with a price and the advance nonce instruction are the
+    // most the send path adds, the Jito tip and memo are left out when they do not fit
+    let mut overhead = InstructionBuilder::compute_budget(MAX_COMPUTE_UNIT_LIMIT, 1);
+    if let Some(nonce_account) = &send_config.nonce_account {
+        overhead.push(system_instruction::advance_nonce_account(
+            nonce_account,
+            &wallet.pubkey,
+        ));
+    }
+    let transactions = split_instructions(&wallet.pubkey, groups, alts, &overhead)?;
+    if transactions.len() > 1 {
+        info!("Sending {} in {} transactions", step, transactions.len());
+    }
+
+    let mut results = vec![];
+    for instructions in transactions {
+        results.push(
+            force_send_instructions(rpc_client, wallet, instructions, alts, send_config, step)
+                .await?,
+        );
+    }
+    Ok(results)
+}
+
+async fn build_transaction(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: &[Instruction],
+    alts: &[AddressLookupTableAccount],
+    send_config: &SendConfig,
+    commitment: CommitmentConfig,
+) -> Result<(VersionedTransaction, TransactionLifetime), Error> {
+    // Nothing is sent in dry run, so the nonce is never needed
+    match send_config.nonce_account.filter(|_| !send_config.dry_run) {
+        Some(account) => {
+            let tx = build_signed_transaction_with_nonce(
+                rpc_client,
+                wallet,
+                extra_signers,
+                &account,
+                instructions,
+                alts,
+            )
+            .await?;
+            let nonce = *tx.message.recent_blockhash();
+            Ok((tx, TransactionLifetime::Nonce { account, nonce }))
+        }
+        None => {
+            let (blockhash, last_valid_block_height) = rpc_client
+                .get_latest_blockhash_with_commitment(commitment)
+                .await?;
+            let tx = sign_transaction(wallet, extra_signers, instructions, alts, blockhash)?;
+            Ok((
+                tx,
+                TransactionLifetime::Blockhash {
+                    blockhash,
+                    last_valid_block_height,
+                },
+            ))
+        }
+    }
+}
+
+pub async fn force_send_instructions(
     rpc_client: &Arc<RpcClient>,
     wallet: &Arc<Wallet>,
     instructions: Vec<Instruction>,
     alts: &Vec<AddressLookupTableAccount>,
-) -> Result<UiTransactionStatusMeta, Error> {
-    let mut tx = build_signed_transaction(rpc_client, wallet, &instructions[..], &alts[..]).await?;
+    send_config: &SendConfig,
+    step: &str,
+) -> Result<(Signature, UiTransactionStatusMeta), Error> {
+    force_send_instructions_with_signers(
+        rpc_client,
+        wallet,
+        &[],
+        instructions,
+        alts,
+        send_config,
+        step,
+    )
+    .await
+}
+
+pub async fn force_send_instructions_with_signers(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: Vec<Instruction>,
+    alts: &Vec<AddressLookupTableAccount>,
+    send_config: &SendConfig,
+    step: &str,
+) -> Result<(Signature, UiTransactionStatusMeta), Error> {
+    // Every flow sends through here, so no transaction bypasses the risk limits
+    if let Err(reason) = send_config
+        .risk_guard
+        .check(&instructions, &send_config.ledger)
+    {
+        error!("Risk limit blocked {}: {}", step, reason);
+        send_config.notifier.notify(
+            NotificationLevel::Critical,
+            format!("Risk limit blocked {}: {}", step, reason),
+        );
+        return Err(Error::RiskLimit(reason));
+    }
+
+    let cu_price = estimate_cu_price(rpc_client, &instructions, send_config).await;
+    let instructions = add_compute_budget_instructions(
+        rpc_client,
+        wallet,
+        extra_signers,
+        instructions,
+        alts,
+        send_config,
+        cu_price,
+        step,
+    )
+    .await?;
+    let instructions = add_jito_tip(wallet, instructions, alts, send_config);
+    let (mut instructions, memo) = add_memo(wallet, instructions, alts, send_config, step);
+    let (mut tx, mut lifetime) = build_transaction(
+        rpc_client,
+        wallet,
+        extra_signers,
+        &instructions,
+        alts,
+        send_config,
+        send_config.commitment_policy.blockhash(),
+    )
+    .await?;
+
+    // Nothing is sent in dry run, this is the only place transactions are sent from
+    if send_config.dry_run {
+        let meta = simulate_transaction(rpc_client, wallet, &tx, &instructions).await?;
+        return Ok((tx.signatures[0], meta));
+    }
+
+    if send_config.simulate_before_send {
+        let simulation = simulate(rpc_client, &tx).await?;
+        if let Some(e) = simulation.err {
+            error!("Transaction simulation failed: {}", e);
+            let report = explain_failure_logs(&e, simulation.logs);
+            log_failure_report(&report);
+            return Err(record_transaction_error(
+                send_config,
+                step,
+                Error::SimulationFailed {
+                    program_id: get_failed_program_id(&tx, &e),
+                    error: e,
+                    report: Box::new(report),
+                },
+            ));
+        }
+        info!(
+            "Simulated transaction, units consumed: {:?}",
+            simulation.units_consumed
+        );
+    }
     let mut retries = 0;
+    let mut stale_blockhash_retries = 0;
 
     loop {
-        if retries % 2 == 0 {
-            tx = build_signed_transaction(rpc_client, wallet, &instructions[..], &[]).await?;
+        if send_config.shutdown.is_triggered() {
+            info!("Shutting down, not sending the transaction");
+            return Err(Error::ShuttingDown);
+        }
+        metrics::inc_counter(metrics::TRANSACTIONS_SENT, &[]);
+        let attempt_span = info_span!(
+            "transaction",
+            attempt = retries,
+            signature = %tx.signatures[0]
+        );
+        let (last_error, expired) =
+            match send_and_confirm_transaction(rpc_client, &tx, &lifetime, send_config)
+                .instrument(attempt_span.clone())
+                .await
+            {
+                Ok(TransactionResult::Success(sig, meta)) => {
+                    info!("Transaction successful: {}", sig);
+                    record_transaction_success(wallet, send_config, &tx, &meta, memo.as_deref());
+                    send_config.risk_guard.record_landed(&instructions);
+                    return Ok((sig, meta));
+                }
+                Ok(TransactionResult::Error(sig, e, report)) => {
+                    error!("Transaction error: {} - {}", sig, e);
+                    log_failure_report(&report);
+                    return Err(record_transaction_error(
+                        send_config,
+                        step,
+                        Error::TransactionFailed {
+                            signature: sig,
+                            program_id: get_failed_program_id(&tx, &e),
+                            error: e,
+                            report: Box::new(report),
+                        },
+                    ));
+                }
+                Ok(TransactionResult::Timeout(sig)) => (Error::TransactionTimeout(sig), false),
+                Ok(TransactionResult::Expired(sig)) => {
+                    warn!("Transaction expired: {}", sig);
+                    (Error::TransactionTimeout(sig), true)
+                }
+                Err(Error::SimulationFailed {
+                    error,
+                    program_id,
+                    report,
+                }) => {
+                    error!("Transaction preflight failed: {}", error);
+                    log_failure_report(&report);
+                    return Err(record_transaction_error(
+                        send_config,
+                        step,
+                        Error::SimulationFailed {
+                            error,
+                            program_id,
+                            report,
+                        },
+                    ));
+                }
+                Err(Error::StaleBlockhash(e))
+                    if stale_blockhash_retries < MAX_STALE_BLOCKHASH_RETRIES =>
+                {
+                    warn!("RPC is behind the blockhash, rebuilding: {}", e);
+                    stale_blockhash_retries += 1;
+                    sleep(STALE_BLOCKHASH_RETRY_DELAY).await;
+                    (tx, lifetime) = build_transaction(
+                        rpc_client,
+                        wallet,
+                        extra_signers,
+                        &instructions,
+                        alts,
+                        send_config,
+                        CommitmentConfig::finalized(),
+                    )
+                    .await?;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Unable to send transaction: {}", e);
+                    (e, false)
+                }
+            };
+
+        if retries >= send_config.max_retries {
+            error!("Giving up on transaction after {} retries", retries);
+            return Err(record_transaction_error(
+                send_config,
+                step,
+                Error::RetriesExhausted {
+                    retries,
+                    last_error: Box::new(last_error),
+                },
+            ));
+        }
+        retries += 1;
+
+        // Copies with the same blockhash or nonce share the signature, so resending can
+        // not execute twice, a new transaction is built only once the old one can not
+        // land, which with a durable nonce is only once the nonce was advanced
+        if !expired && !lifetime.is_expired(rpc_client).await? {
+            continue;
+        }
+
+        // The fee market moved while the transaction did not land, the price is
+        // re-estimated and never drops below the price of the previous attempt
+        if send_config.priority_fee.is_some() {
+            let cu_price = estimate_cu_price(rpc_client, &instructions, send_config).await;
+            if get_cu_price(&instructions).is_some_and(|previous| cu_price > previous)
+                && set_cu_price(&mut instructions, cu_price)
+            {
+                info!("Raised compute unit price to {}", cu_price);
+            }
         }
 
-        match send_and_confirm_transaction(rpc_client, &tx).await? {
-            TransactionResult::Success(sig, meta) => {
-                println!("Transaction successful: {}", sig);
-                break Ok(meta);
+        let (new_tx, new_lifetime) = build_transaction(
+            rpc_client,
+            wallet,
+            extra_signers,
+            &instructions,
+            alts,
+            send_config,
+            send_config.commitment_policy.blockhash(),
+        )
+        .await?;
+        let previous_tx = std::mem::replace(&mut tx, new_tx);
+        let previous_lifetime = std::mem::replace(&mut lifetime, new_lifetime);
+
+        // The previous transaction may have landed right at the end of its lifetime, so
+        // its signature is checked once more before the rebuilt one is sent
+        match fetch_landed_transaction(
+            rpc_client,
+            &previous_tx.signatures[0],
+            &previous_lifetime,
+            send_config,
+        )
+        .instrument(attempt_span)
+        .await?
+        {
+            Some(TransactionResult::Success(sig, meta)) => {
+                warn!("Transaction landed late: {}", sig);
+                record_transaction_success(
+                    wallet,
+                    send_config,
+                    &previous_tx,
+                    &meta,
+                    memo.as_deref(),
+                );
+                send_config.risk_guard.record_landed(&instructions);
+                return Ok((sig, meta));
             }
-            TransactionResult::Timeout(_) => {}
-            TransactionResult::Error(sig, e) => {
-                println!("Transaction error: {} - {}", sig, e);
-                return Err(Error::TransactionError);
+            Some(TransactionResult::Error(sig, e, report)) => {
+                error!("Transaction error: {} - {}", sig, e);
+                log_failure_report(&report);
+                return Err(record_transaction_error(
+                    send_config,
+                    step,
+                    Error::TransactionFailed {
+                        signature: sig,
+                        program_id: get_failed_program_id(&previous_tx, &e),
+                        error: e,
+                        report: Box::new(report),
+                    },
+                ));
             }
+            Some(TransactionResult::Timeout(_) | TransactionResult::Expired(_)) | None => {}
         }
+    }
+}
 
-        retries += 1;
+pub async fn create_marginfi_account(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    instruction_builder: &InstructionBuilder,
+    send_config: &SendConfig,
+) -> Result<Pubkey, Error> {
+    let marginfi_account = Keypair::new();
+    let marginfi_account_address = marginfi_account.pubkey();
+    info!("Creating marginfi account {}", marginfi_account_address);
+
+    let ix = instruction_builder.marginfi_account_initialize(&marginfi_account_address);
+    force_send_instructions_with_signers(
+        rpc_client,
+        wallet,
+        &[&marginfi_account],
+        vec![ix],
+        &vec![],
+        send_config,
+        "setup:marginfi_account",
+    )
+    .await?;
+
+    Ok(marginfi_account_address)
+}
+
+async fn get_max_borrow_amount(
+    account_with_banks: &MarginfiAccountWithBanks,
+    oracles_state: &Arc<OraclesState>,
+    bank: &MarginfiBank,
+    borrow_caps: &HashMap<Pubkey, f64>,
+) -> Result<I80F48, Error> {
+    let max_amount = bank.get_max_borrow_amount().floor();
+    let Some(cap_usd) = borrow_caps.get(&bank.mint) else {
+        return Ok(max_amount);
+    };
+
+    let cap_amount = account_with_banks
+        .get_borrow_amount_for_cap(oracles_state, &bank.mint, *cap_usd)
+        .await?;
+    if cap_amount < max_amount {
+        debug!(
+            "Borrow of {} is capped at {} by the ${} cap",
+            bank.mint, cap_amount, cap_usd
+        );
+    }
+    Ok(max_amount.min(cap_amount))
+}
+
+/// When no bank can cover the size, the one covering its largest share is borrowed from
+/// up to its limit
+async fn get_best_bank_for_borrow(
+    account_with_banks: &MarginfiAccountWithBanks,
+    oracles_state: &Arc<OraclesState>,
+    borrowable_mints: &[Pubkey],
+    borrow_caps: &HashMap<Pubkey, f64>,
+    target_init_health: I80F48,
+) -> Result<Option<(Pubkey, I80F48)>, Error> {
+    // (mint, amount, post-trade rate)
+    let mut best: Option<(Pubkey, I80F48, I80F48)> = None;
+    // (mint, max amount, share of the size it covers)
+    let mut best_partial: Option<(Pubkey, I80F48, I80F48)> = None;
+
+    for mint in borrowable_mints {
+        let Some((_, bank)) = account_with_banks.get_bank_by_mint(mint) else {
+            continue;
+        };
+        let borrow_amount = account_with_banks
+            .get_borrow_amount_for_health(oracles_state, mint, target_init_health)
+            .await?;
+        if borrow_amount <= I80F48::ZERO {
+            continue;
+        }
+
+        let max_borrow_amount =
+            get_max_borrow_amount(account_with_banks, oracles_state, bank, borrow_caps).await?;
+        if max_borrow_amount < borrow_amount {
+            info!(
+                "Bank of {} can lend {} of {}, skipping",
+                mint, max_borrow_amount, borrow_amount
+            );
+            let covered_share = max_borrow_amount / borrow_amount;
+            if max_borrow_amount > I80F48::ZERO
+                && best_partial.is_none_or(|(_, _, share)| covered_share > share)
+            {
+                best_partial = Some((*mint, max_borrow_amount, covered_share));
+            }
+            continue;
+        }
+
+        let rate = bank.get_all_in_borrow_rate_after(borrow_amount);
+        info!(
+            "Borrow rate of {} is {} now, {} all-in after borrowing {}",
+            mint,
+            bank.get_borrow_rate(),
+            rate,
+            borrow_amount
+        );
+        if best.is_none_or(|(_, _, best_rate)| rate < best_rate) {
+            best = Some((*mint, borrow_amount, rate));
+        }
+    }
+
+    if let Some((mint, amount, _)) = best {
+        return Ok(Some((mint, amount)));
+    }
+    Ok(best_partial.map(|(mint, amount, _)| {
+        warn!(
+            "No bank can lend the full amount, borrowing {} {} instead",
+            amount, mint
+        );
+        (mint, amount)
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BorrowSplit {
+    Single,
+    Weights(Vec<(Pubkey, u64)>),
+    EqualRates,
+}
+
+const EQUAL_RATES_SPLIT_STEPS: u32 = 100;
+
+struct BorrowCandidate<'a> {
+    mint: Pubkey,
+    bank: &'a MarginfiBank,
+    weight: u64,
+    /// Amount sized to the target health when borrowing from this bank only
+    size: I80F48,
+    max_amount: I80F48,
+}
+
+impl BorrowCandidate<'_> {
+    fn get_amount_at_step(&self, step: u32) -> I80F48 {
+        (self.size * I80F48::from_num(step) / I80F48::from_num(EQUAL_RATES_SPLIT_STEPS)).floor()
+    }
+}
+
+/// Initial health is linear in the liabilities, so shares of the single bank sizes which
+/// sum up to one end at the target health
+async fn get_borrow_split(
+    account_with_banks: &MarginfiAccountWithBanks,
+    oracles_state: &Arc<OraclesState>,
+    borrow_split: &BorrowSplit,
+    borrowable_mints: &[Pubkey],
+    borrow_caps: &HashMap<Pubkey, f64>,
+    target_init_health: I80F48,
+) -> Result<Vec<(Pubkey, I80F48)>, Error> {
+    let weights = match borrow_split {
+        BorrowSplit::Single => {
+            return Ok(get_best_bank_for_borrow(
+                account_with_banks,
+                oracles_state,
+                borrowable_mints,
+                borrow_caps,
+                target_init_health,
+            )
+            .await?
+            .into_iter()
+            .collect());
+        }
+        BorrowSplit::Weights(weights) => weights.clone(),
+        BorrowSplit::EqualRates => borrowable_mints.iter().map(|mint| (*mint, 1)).collect(),
+    };
+
+    let mut candidates = vec![];
+    for (mint, weight) in weights {
+        let Some((_, bank)) = account_with_banks.get_bank_by_mint(&mint) else {
+            warn!(
+                "Mint {} has no marginfi bank, skipping it in the borrow split",
+                mint
+            );
+            continue;
+        };
+        let size = account_with_banks
+            .get_borrow_amount_for_health(oracles_state, &mint, target_init_health)
+            .await?;
+        if size > I80F48::ZERO && weight > 0 {
+            candidates.push(BorrowCandidate {
+                mint,
+                bank,
+                weight,
+                size,
+                max_amount: get_max_borrow_amount(
+                    account_with_banks,
+                    oracles_state,
+                    bank,
+                    borrow_caps,
+                )
+                .await?,
+            });
+        }
+    }
+
+    let amounts = if let BorrowSplit::Weights(_) = borrow_split {
+        let total_weight = candidates
+            .iter()
+            .map(|candidate| candidate.weight)
+            .sum::<u64>();
+        candidates
+            .iter()
+            .map(|candidate| {
+                let amount = (candidate.size * I80F48::from_num(candidate.weight)
+                    / I80F48::from_num(total_weight))
+                .floor();
+                if amount > candidate.max_amount {
+                    warn!(
+                        "Bank of {} can lend {} of {}, borrowing less",
+                        candidate.mint, candidate.max_amount, amount
+                    );
+                }
+                (candidate.mint, amount.min(candidate.max_amount))
+            })
+            .collect::<Vec<_>>()
+    } else {
+        // Every step goes to the bank with the lowest rate after it, which levels
+        // the post-borrow rates
+        let mut steps = vec![0; candidates.len()];
+        for _ in 0..EQUAL_RATES_SPLIT_STEPS {
+            let next = candidates
+                .iter()
+                .enumerate()
+                .filter_map(|(i, candidate)| {
+                    let amount = candidate.get_amount_at_step(steps[i] + 1);
+                    (amount <= candidate.max_amount)
+                        .then(|| (i, candidate.bank.get_all_in_borrow_rate_after(amount)))
+                })
+                .min_by(|(_, a), (_, b)| a.cmp(b));
+            let Some((i, _)) = next else {
+                warn!("Borrowable banks can not lend the full amount, borrowing less");
+                break;
+            };
+            steps[i] += 1;
+        }
+        candidates
+            .iter()
+            .zip(steps)
+            .map(|(candidate, step)| (candidate.mint, candidate.get_amount_at_step(step)))
+            .collect()
+    };
+
+    for (mint, amount) in &amounts {
+        if let Some((_, bank)) = account_with_banks.get_bank_by_mint(mint) {
+            info!(
+                "Borrowing {} {}, all-in rate after the borrow {}",
+                amount,
+                mint,
+                bank.get_all_in_borrow_rate_after(*amount)
+            );
+        }
     }
+    Ok(amounts
+        .into_iter()
+        .filter(|(_, amount)| *amount > I80F48::ZERO)
+        .collect())
 }
 
-fn get_best_bank_for_borrow(
+async fn check_wallet_balances(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
     account_with_banks: &MarginfiAccountWithBanks,
-) -> (Pubkey, &MarginfiBank) {
-    let mut mint_address = Pubkey::default();
-    let mut lowest_borrow_rate = I80F48::MAX;
-    let mut bank = None;
+    static_addresses: &StaticAddresses,
+    collateral_amount: u64,
+    clamp_to_wallet: bool,
+    min_sol_balance: u64,
+) -> Result<u64, Error> {
+    let lamports = connection::fetch_lamports_balance(rpc_client, wallet).await?;
+    if lamports < min_sol_balance {
+        return Err(Error::InsufficientSolBalance {
+            required: min_sol_balance,
+            available: lamports,
+        });
+    }
 
-    for mint in [
-        constants::mints::usdc::id(),
-        constants::mints::usdt::id(),
-        constants::mints::uxd::id(),
-    ] {
-        let (_, current_bank) = account_with_banks.get_bank_by_mint(&mint).unwrap();
-        let borrow_rate = current_bank.get_borrow_rate();
+    let mint = static_addresses.collateral_mint;
+    let (_, bank) = account_with_banks
+        .get_bank_by_mint(&mint)
+        .ok_or(Error::MissingBank(mint))?;
+    let deposited_amount: u64 = if let Some(balance) = account_with_banks.get_balance_by_mint(&mint)
+    {
+        balance
+            .get_amounts(bank.asset_share_value, bank.liability_share_value)
+            .0
+            .to_num()
+    } else {
+        0
+    };
+    let required = collateral_amount.saturating_sub(deposited_amount);
 
-        if borrow_rate < lowest_borrow_rate {
-            mint_address = mint;
-            lowest_borrow_rate = borrow_rate;
-            bank = Some(current_bank);
+    let balances = connection::fetch_token_balances(rpc_client, static_addresses, &[mint]).await?;
+    let available = balances.get(&mint).copied().unwrap_or(0);
+    if available < required {
+        if !clamp_to_wallet {
+            return Err(Error::InsufficientTokenBalance {
+                mint,
+                required,
+                available,
+            });
         }
+
+        let clamped = deposited_amount + available;
+        warn!(
+            "Wallet holds {} of the {} {} missing in the collateral, depositing up to {} instead of {}",
+            available, required, mint, clamped, collateral_amount
+        );
+        return Ok(clamped);
+    }
+
+    Ok(collateral_amount)
+}
+
+async fn create_missing_token_accounts(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    static_addresses: &StaticAddresses,
+    instruction_builder: &InstructionBuilder,
+    send_config: &SendConfig,
+) -> Result<(), Error> {
+    let missing = connection::find_missing_token_accounts(rpc_client, static_addresses).await?;
+    if missing.is_empty() {
+        return Ok(());
     }
 
-    (mint_address, bank.unwrap())
+    let groups = missing
+        .iter()
+        .map(|(mint, token_program, token_account)| {
+            info!("Creating token account {} for mint {}", token_account, mint);
+            vec![
+                instruction_builder.create_associated_token_account_idempotent(
+                    mint,
+                    &wallet.pubkey,
+                    token_program,
+                ),
+            ]
+        })
+        .collect::<Vec<_>>();
+    force_send_instruction_groups(
+        rpc_client,
+        wallet,
+        groups,
+        &vec![],
+        send_config,
+        "setup:token_accounts",
+    )
+    .await?;
+
+    Ok(())
 }
 
+/// Zero when the account already holds `collateral_amount`
+#[instrument(name = "deposit", skip_all, fields(amount = collateral_amount))]
 fn create_marginfi_deposit_instructions(
     account_with_banks: &mut MarginfiAccountWithBanks,
     static_addresses: &StaticAddresses,
     instruction_builder: &InstructionBuilder,
     instructions: &mut Vec<Instruction>,
-    bsol_amount: u64,
-) -> Result<(), Error> {
-    let mint = constants::mints::bsol::id();
-    let (_, bank) = account_with_banks.get_bank_by_mint(&mint).unwrap();
+    collateral_amount: u64,
+) -> Result<u64, Error> {
+    let mint = static_addresses.collateral_mint;
+    let (_, bank) = account_with_banks
+        .get_bank_by_mint(&mint)
+        .ok_or(Error::MissingBank(mint))?;
     let account_amount = if let Some(balance) = account_with_banks.get_balance_by_mint(&mint) {
         balance
             .get_amounts(bank.asset_share_value, bank.liability_share_value)
@@ -96,10 +1011,30 @@ fn create_marginfi_deposit_instructions(
         0
     };
 
-    if account_amount < bsol_amount {
-        let deposit_amount =
-            bank.get_max_deposit_amount(I80F48::from_num(bsol_amount - account_amount));
-        account_with_banks.deposit(deposit_amount, &mint);
+    if collateral_amount == 0 {
+        if account_amount == 0 {
+            return Err(Error::NoCollateral(mint));
+        }
+        info!(
+            "Collateral amount is zero, borrowing against the {} {} already deposited",
+            account_amount, mint
+        );
+    }
+
+    if account_amount < collateral_amount {
+        let missing_amount = I80F48::from_num(collateral_amount - account_amount);
+        let deposit_amount = bank.get_max_deposit_amount(missing_amount);
+        if deposit_amount == I80F48::ZERO {
+            warn!("Collateral bank of {} is at its deposit limit", mint);
+            return Err(Error::CollateralBankFull(mint));
+        }
+        if deposit_amount < missing_amount {
+            warn!(
+                "Collateral bank deposit limit allows depositing {} of {} {}",
+                deposit_amount, missing_amount, mint
+            );
+        }
+        account_with_banks.deposit(deposit_amount, &mint)?;
 
         instructions.push(instruction_builder.marginfi_deposit(
             static_addresses,
@@ -107,129 +1042,2485 @@ fn create_marginfi_deposit_instructions(
             deposit_amount.to_num(),
             &account_with_banks,
         )?);
+        return Ok(deposit_amount.to_num());
     }
 
-    Ok(())
+    Ok(0)
 }
 
+#[instrument(name = "borrow", skip_all)]
 async fn create_marginfi_borrow_instructions(
     account_with_banks: &mut MarginfiAccountWithBanks,
     oracles_state: &Arc<OraclesState>,
     instructions: &mut Vec<Instruction>,
     static_addresses: &StaticAddresses,
     instruction_builder: &InstructionBuilder,
-) -> Result<(u64, Pubkey), Error> {
-    let (free_amount, _) = account_with_banks
-        .get_total_weighted_amount(oracles_state)
-        .await?;
+    target_init_health: f64,
+    borrow_split: &BorrowSplit,
+) -> Result<Vec<(Pubkey, u64)>, Error> {
+    if target_init_health < MIN_TARGET_INIT_HEALTH {
+        warn!(
+            "Target init health {} is below the minimum of {}, not borrowing",
+            target_init_health, MIN_TARGET_INIT_HEALTH
+        );
+        return Err(Error::UnsafeTargetHealth(target_init_health));
+    }
 
-    let (mint_to_borrow, bank_for_borrow) = get_best_bank_for_borrow(&account_with_banks);
-    // 90% of free amount
-    let borrow_amount = free_amount * 9 / 10;
-    let borrow_amount_weighted = borrow_amount / bank_for_borrow.liability_weight_init;
-    account_with_banks.borrow(borrow_amount_weighted, &mint_to_borrow);
+    let borrows = get_borrow_split(
+        account_with_banks,
+        oracles_state,
+        borrow_split,
+        &static_addresses.borrowable_mints,
+        &static_addresses.borrow_caps,
+        I80F48::from_num(target_init_health),
+    )
+    .await?;
+    if borrows.is_empty() {
+        warn!(
+            "Account is already at or below target init health {} or no bank has liquidity, nothing to borrow",
+            target_init_health
+        );
+        return Err(Error::NothingToBorrow);
+    }
 
-    instructions.push(instruction_builder.marginfi_borrow(
-        static_addresses,
-        &mint_to_borrow,
-        borrow_amount_weighted.to_num(),
-        &account_with_banks,
-    )?);
+    // Health accounts of every borrow include the banks of the previous borrows
+    for (mint, amount) in &borrows {
+        account_with_banks.borrow(*amount, mint)?;
+        instructions.push(instruction_builder.marginfi_borrow(
+            static_addresses,
+            mint,
+            amount.to_num(),
+            &account_with_banks,
+        )?);
+    }
 
-    Ok((borrow_amount_weighted.to_num(), mint_to_borrow))
+    Ok(borrows
+        .into_iter()
+        .map(|(mint, amount)| (mint, amount.to_num()))
+        .collect())
 }
 
-pub fn start(
-    args: Args,
-    initial_marginfi_account: marginfi::state::marginfi_account::MarginfiAccount,
-    initial_marginfi_banks: Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>,
-    oracles_state: Arc<OraclesState>,
-    static_addresses: StaticAddresses,
-    instruction_builder: InstructionBuilder,
-) -> JoinHandle<Result<(), Error>> {
-    tokio::spawn(async move {
-        let reqwest_client = Client::new();
-        let rpc_client = &args.rpc_client;
-        let wallet = &args.wallet;
-
-        let mut account_with_banks =
-            MarginfiAccountWithBanks::new(initial_marginfi_account, initial_marginfi_banks);
-
-        {
-            let mut instructions = vec![];
-            create_marginfi_deposit_instructions(
-                &mut account_with_banks,
-                &static_addresses,
-                &instruction_builder,
-                &mut instructions,
-                args.bsol_amount,
-            )?;
-            let (borrowed_amount, borrowed_mint) = create_marginfi_borrow_instructions(
-                &mut account_with_banks,
-                &oracles_state,
-                &mut instructions,
-                &static_addresses,
-                &instruction_builder,
-            )
-            .await?;
+#[derive(Debug, Clone, Copy)]
+pub struct SwapFill {
+    pub amount: u64,
+    pub quoted_amount: u64,
+    /// `otherAmountThreshold` of the quote
+    pub minimum_amount: u64,
+}
 
-            force_send_instructions(rpc_client, wallet, instructions, &vec![]).await?;
+impl SwapFill {
+    fn unswapped(amount: u64) -> Self {
+        Self {
+            amount,
+            quoted_amount: amount,
+            minimum_amount: amount,
+        }
+    }
+}
 
-            let pool_supply_amount = if borrowed_mint != constants::mints::usdc::id() {
-                let (swap_ixs, alts) = connection::fetch_swap_instructions(
-                    rpc_client,
-                    &reqwest_client,
-                    wallet,
-                    &borrowed_mint,
-                    borrowed_amount,
-                )
-                .await?;
-                let tx_meta = force_send_instructions(rpc_client, wallet, swap_ixs, &alts).await?;
-                parse_transaction_token_change(
-                    &tx_meta,
-                    &wallet,
-                    &constants::mints::usdc::id(),
-                    true,
-                )
-                .unwrap()
-            } else {
-                borrowed_amount
-            };
+#[instrument(
+    name = "swap",
+    skip_all,
+    fields(input_mint = %input_mint, output_mint = %output_mint, amount)
+)]
+pub async fn swap_exact_in(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    jupiter_client: &JupiterClient,
+    route_config: &SwapRouteConfig,
+    best_effort_alts: bool,
+    input_mint: &Pubkey,
+    output_mint: &Pubkey,
+    amount: u64,
+    send_config: &SendConfig,
+) -> Result<SwapFill, Error> {
+    if input_mint == output_mint || amount == 0 {
+        return Ok(SwapFill::unswapped(amount));
+    }
 
-            let farm_supply_amount = {
-                let meteora_pool =
-                    static_addresses.get_meteora_pool(&constants::mints::usdc::id())?;
-                let (token_a_amount, token_b_amount) = meteora_pool
-                    .get_token_for_deposit(pool_supply_amount, &constants::mints::usdc::id());
-
-                dbg!(pool_supply_amount, token_a_amount, token_b_amount);
-                let meteora_deposit_ixs = instruction_builder.meteora_pool_deposit(
-                    &static_addresses,
-                    meteora_pool,
-                    // TODO: Should be based on pool virtual price
-                    token_a_amount * 95 / 100,
-                    token_a_amount,
-                    token_b_amount,
-                )?;
-                let tx_meta =
-                    force_send_instructions(rpc_client, wallet, vec![meteora_deposit_ixs], &vec![])
-                        .await?;
-                parse_transaction_token_change(&tx_meta, &wallet, &meteora_pool.lp_mint, true)
-                    .unwrap()
-            };
+    let max_slippage_bps = route_config.max_slippage_bps.max(SWAP_SLIPPAGE_BPS);
+    let mut requotes = 0;
 
-            {
-                let farm_deposit_ix = instruction_builder.meteora_farm_deposit(
-                    &static_addresses,
-                    &constants::mints::usdc::id(),
-                    farm_supply_amount,
-                )?;
-                force_send_instructions(rpc_client, wallet, vec![farm_deposit_ix], &vec![]).await?;
+    loop {
+        // Slippage doubles with every re-quote
+        let slippage_bps = (SWAP_SLIPPAGE_BPS as u32)
+            .saturating_mul(1 << requotes.min(16))
+            .min(max_slippage_bps as u32) as u16;
+        let quote = connection::fetch_swap_quote(
+            jupiter_client,
+            input_mint,
+            output_mint,
+            amount,
+            SwapMode::ExactIn,
+            slippage_bps,
+            route_config,
+        )
+        .await?;
+        info!(
+            "Swapping {} {} for ~{} {} (min {}, price impact {}%, route {})",
+            amount,
+            input_mint,
+            quote.out_amount,
+            output_mint,
+            quote.other_amount_threshold,
+            quote.price_impact_pct,
+            quote.route_labels.join(" -> ")
+        );
+        let swap = connection::fetch_swap_instructions(
+            rpc_client,
+            jupiter_client,
+            wallet,
+            &quote,
+            best_effort_alts,
+        )
+        .await?;
+        match force_send_instructions(
+            rpc_client,
+            wallet,
+            swap.instructions,
+            &swap.lookup_tables,
+            send_config,
+            "swap",
+        )
+        .await
+        {
+            Ok((signature, tx_meta)) => {
+                let output_amount = parse_token_received(&tx_meta, wallet, output_mint)?
+                    .or(send_config.dry_run.then_some(quote.out_amount))
+                    .ok_or(Error::TokenChangeNotFound {
+                        signature,
+                        mint: *output_mint,
+                    })?;
+                send_config
+                    .ledger
+                    .record(|l| l.record_swap(input_mint, amount, output_mint, output_amount));
+                return Ok(SwapFill {
+                    amount: output_amount,
+                    quoted_amount: quote.out_amount,
+                    minimum_amount: quote.other_amount_threshold,
+                });
             }
+            Err(e) if requotes < route_config.max_requotes && is_requotable_swap_error(&e) => {
+                requotes += 1;
+                warn!(
+                    "Swap failed: {:?}, re-quoting ({}/{})",
+                    e, requotes, route_config.max_requotes
+                );
+            }
+            Err(e) => return Err(e),
         }
+    }
+}
 
-        loop {
-            sleep(Duration::from_secs(60 * 60 * 8)).await;
+/// Errors a fresh quote can fix, like exceeded slippage, and transactions which kept
+/// timing out
+fn is_requotable_swap_error(error: &Error) -> bool {
+    match error {
+        Error::TransactionFailed { program_id, .. }
+        | Error::SimulationFailed { program_id, .. } => {
+            program_id == &Some(constants::jupiter::id())
         }
-    })
+        Error::RetriesExhausted { last_error, .. } => {
+            matches!(**last_error, Error::TransactionTimeout(_))
+        }
+        _ => false,
+    }
+}
+
+/// A shortfall above `max_shortfall_bps` aborts with the funds left in the wallet,
+/// instead of entering a smaller position than planned
+fn reconcile_swap_fill(
+    fill: &SwapFill,
+    mint: &Pubkey,
+    max_shortfall_bps: u16,
+) -> Result<u64, Error> {
+    if fill.amount == fill.quoted_amount {
+        return Ok(fill.amount);
+    }
+
+    let delta = fill.amount as i128 - fill.quoted_amount as i128;
+    info!(
+        "Swap filled {} {}, quoted {} (min {}), delta {}",
+        fill.amount, mint, fill.quoted_amount, fill.minimum_amount, delta
+    );
+    if fill.amount < fill.minimum_amount {
+        warn!(
+            "Swap filled {} below its slippage bound {}",
+            fill.minimum_amount - fill.amount,
+            fill.minimum_amount
+        );
+    }
+
+    let shortfall_bps = (-delta).max(0) * 10_000 / fill.quoted_amount.max(1) as i128;
+    if shortfall_bps > max_shortfall_bps as i128 {
+        warn!(
+            "Swap shortfall of {} bps exceeds {} bps, leaving {} {} in the wallet",
+            shortfall_bps, max_shortfall_bps, fill.amount, mint
+        );
+        return Err(Error::SwapShortfall {
+            mint: *mint,
+            quoted: fill.quoted_amount,
+            realized: fill.amount,
+        });
+    }
+
+    Ok(fill.amount)
+}
+
+fn fits_with_compute_budget(
+    wallet: &Arc<Wallet>,
+    instructions: &[Instruction],
+    alts: &[AddressLookupTableAccount],
+) -> bool {
+    let instructions = InstructionBuilder::compute_budget(MAX_COMPUTE_UNIT_LIMIT, 1)
+        .into_iter()
+        .chain(instructions.iter().cloned())
+        .collect::<Vec<_>>();
+    fits_in_transaction(&wallet.pubkey, &instructions, alts)
+}
+
+/// `None` when nothing was sent, the instructions are then sent separately
+#[instrument(
+    name = "borrow",
+    skip_all,
+    fields(mint = %borrowed_mint, pool = ?pool_id, amount)
+)]
+async fn send_borrow_with_swap(
+    ctx: &UnwindContext<'_>,
+    marginfi_instructions: &[Instruction],
+    pool_id: PoolId,
+    borrowed_mint: &Pubkey,
+    amount: u64,
+) -> Result<Option<SwapFill>, Error> {
+    let pool_input_mint = ctx.static_addresses.get_meteora_pool(pool_id)?.input_mint;
+    if ctx.lookup_tables.is_empty() || borrowed_mint == &pool_input_mint || amount == 0 {
+        return Ok(None);
+    }
+
+    let quote = connection::fetch_swap_quote(
+        ctx.jupiter_client,
+        borrowed_mint,
+        &pool_input_mint,
+        amount,
+        SwapMode::ExactIn,
+        SWAP_SLIPPAGE_BPS,
+        ctx.swap_route_config,
+    )
+    .await?;
+    let swap = connection::fetch_swap_instructions(
+        ctx.rpc_client,
+        ctx.jupiter_client,
+        ctx.wallet,
+        &quote,
+        ctx.best_effort_alts,
+    )
+    .await?;
+
+    // Jupiter compute budget only covers the swap, the merged one is set when sending
+    let instructions = marginfi_instructions
+        .iter()
+        .cloned()
+        .chain(
+            swap.instructions
+                .into_iter()
+                .filter(|ix| ix.program_id != compute_budget::id()),
+        )
+        .collect::<Vec<_>>();
+    let alts = ctx
+        .lookup_tables
+        .iter()
+        .chain(swap.lookup_tables.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    if !fits_with_compute_budget(ctx.wallet, &instructions, &alts) {
+        info!("Borrow and swap do not fit in a single transaction, sending them separately");
+        return Ok(None);
+    }
+
+    info!(
+        "Borrowing and swapping {} {} for ~{} {} (min {}) in a single transaction",
+        amount, borrowed_mint, quote.out_amount, pool_input_mint, quote.other_amount_threshold
+    );
+    match force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        instructions,
+        &alts,
+        ctx.send_config,
+        "entry:borrow_swap",
+    )
+    .await
+    {
+        Ok((signature, tx_meta)) => {
+            let output_amount = parse_token_received(&tx_meta, ctx.wallet, &pool_input_mint)?
+                .or(ctx.send_config.dry_run.then_some(quote.out_amount))
+                .ok_or(Error::TokenChangeNotFound {
+                    signature,
+                    mint: pool_input_mint,
+                })?;
+            ctx.send_config
+                .ledger
+                .record(|l| l.record_swap(borrowed_mint, amount, &pool_input_mint, output_amount));
+            Ok(Some(SwapFill {
+                amount: output_amount,
+                quoted_amount: quote.out_amount,
+                minimum_amount: quote.other_amount_threshold,
+            }))
+        }
+        // The transaction failed as a whole, so the borrow did not land either and
+        // the split flow can re-quote the swap
+        Err(
+            Error::TransactionFailed {
+                program_id: Some(program_id),
+                ..
+            }
+            | Error::SimulationFailed {
+                program_id: Some(program_id),
+                ..
+            },
+        ) if program_id == constants::jupiter::id() => {
+            warn!("Merged swap failed, sending borrow and swap separately");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn supply_to_pool(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+    input_mint: &Pubkey,
+    amount: u64,
+) -> Result<u64, Error> {
+    let pool_input_mint = ctx.static_addresses.get_meteora_pool(pool_id)?.input_mint;
+
+    let fill = swap_exact_in(
+        ctx.rpc_client,
+        ctx.wallet,
+        ctx.jupiter_client,
+        ctx.swap_route_config,
+        ctx.best_effort_alts,
+        input_mint,
+        &pool_input_mint,
+        amount,
+        ctx.send_config,
+    )
+    .await?;
+    let pool_supply_amount = reconcile_swap_fill(
+        &fill,
+        &pool_input_mint,
+        ctx.swap_route_config.max_shortfall_bps,
+    )?;
+
+    deposit_to_pool_and_farm(ctx, pool_id, pool_supply_amount).await
+}
+
+/// With the bot ALT loaded the farm deposit stakes the minimum LP of the pool deposit in
+/// the same transaction and the remaining dust stays in the wallet
+#[instrument(name = "pool_deposit", skip_all, fields(pool = ?pool_id, amount))]
+async fn deposit_to_pool_and_farm(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+    amount: u64,
+) -> Result<u64, Error> {
+    let meteora_pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+    let pool_input_mint = meteora_pool.input_mint;
+
+    let pool_state = connection::fetch_meteora_pool_state(ctx.rpc_client, meteora_pool).await?;
+    let deposit =
+        meteora_pool.get_deposit(&pool_state, amount, &pool_input_mint, ctx.pool_slippage_bps)?;
+    info!(
+        "Depositing {} {} to pool {}, expected LP {} (min {})",
+        amount, pool_input_mint, meteora_pool.address, deposit.expected_lp, deposit.minimum_lp
+    );
+
+    // The deposit is single sided, which stable pools take as an imbalanced deposit
+    let meteora_deposit_ix = if meteora_pool.is_stable {
+        ctx.instruction_builder.meteora_pool_deposit_imbalanced(
+            ctx.static_addresses,
+            meteora_pool,
+            deposit.minimum_lp,
+            deposit.token_a_amount,
+            deposit.token_b_amount,
+        )?
+    } else {
+        ctx.instruction_builder.meteora_pool_deposit(
+            ctx.static_addresses,
+            meteora_pool,
+            deposit.minimum_lp,
+            deposit.token_a_amount,
+            deposit.token_b_amount,
+        )?
+    };
+    let create_farm_user_ix = create_farm_user_instruction(ctx, pool_id).await?;
+
+    if !ctx.lookup_tables.is_empty() {
+        let farm_deposit_ix = ctx.instruction_builder.meteora_farm_deposit(
+            ctx.static_addresses,
+            pool_id,
+            deposit.minimum_lp,
+        )?;
+        let instructions = create_farm_user_ix
+            .iter()
+            .cloned()
+            .chain([meteora_deposit_ix.clone(), farm_deposit_ix])
+            .collect::<Vec<_>>();
+
+        if fits_with_compute_budget(ctx.wallet, &instructions, ctx.lookup_tables) {
+            let (_, tx_meta) = force_send_instructions(
+                ctx.rpc_client,
+                ctx.wallet,
+                instructions,
+                ctx.lookup_tables,
+                ctx.send_config,
+                "deposit:pool_farm",
+            )
+            .await?;
+            // LP left in the wallet is the dust above the staked minimum
+            let dust =
+                parse_token_received(&tx_meta, ctx.wallet, &meteora_pool.lp_mint)?.unwrap_or(0);
+            ctx.send_config.ledger.record(|l| {
+                l.record_lp(
+                    &meteora_pool.address,
+                    deposit.minimum_lp + dust,
+                    deposit.minimum_lp,
+                )
+            });
+            return Ok(deposit.minimum_lp);
+        }
+        info!("Pool and farm deposit do not fit in a single transaction, sending them separately");
+    }
+
+    let farm_supply_amount = {
+        let (signature, tx_meta) = force_send_instructions(
+            ctx.rpc_client,
+            ctx.wallet,
+            vec![meteora_deposit_ix],
+            ctx.lookup_tables,
+            ctx.send_config,
+            "deposit:pool",
+        )
+        .await?;
+        parse_token_received(&tx_meta, ctx.wallet, &meteora_pool.lp_mint)?
+            .or(ctx.send_config.dry_run.then_some(deposit.expected_lp))
+            .ok_or(Error::TokenChangeNotFound {
+                signature,
+                mint: meteora_pool.lp_mint,
+            })?
+    };
+
+    let farm_deposit_ix = ctx.instruction_builder.meteora_farm_deposit(
+        ctx.static_addresses,
+        pool_id,
+        farm_supply_amount,
+    )?;
+    force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        create_farm_user_ix
+            .into_iter()
+            .chain([farm_deposit_ix])
+            .collect(),
+        ctx.lookup_tables,
+        ctx.send_config,
+        "deposit:farm",
+    )
+    .instrument(info_span!("farm_deposit", amount = farm_supply_amount))
+    .await?;
+    ctx.send_config.ledger.record(|l| {
+        l.record_lp(
+            &meteora_pool.address,
+            farm_supply_amount,
+            farm_supply_amount,
+        )
+    });
+
+    Ok(farm_supply_amount)
+}
+
+/// It has to precede the first farm deposit
+async fn create_farm_user_instruction(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+) -> Result<Option<Instruction>, Error> {
+    let farm = ctx.static_addresses.get_meteora_farm(pool_id, None)?;
+    if connection::is_meteora_farm_user_initialized(ctx.rpc_client, farm).await? {
+        return Ok(None);
+    }
+
+    info!(
+        "Farm user account {} of farm {} does not exist, creating it with the deposit",
+        farm.user_account, farm.address
+    );
+    Ok(Some(ctx.instruction_builder.meteora_farm_create_user(farm)))
+}
+
+/// Includes farms which are not active anymore
+async fn claim_farm_rewards(ctx: &UnwindContext<'_>) -> Result<HashMap<Pubkey, u64>, Error> {
+    let mut instructions = vec![];
+    let mut reward_mints = vec![];
+
+    for farm in ctx
+        .static_addresses
+        .meteora_farms
+        .values()
+        .flat_map(|farms| farms.iter())
+    {
+        let farm_user = connection::fetch_meteora_farm_user(ctx.rpc_client, farm).await?;
+        if farm_user.balance_staked == 0 {
+            continue;
+        }
+
+        info!(
+            "Claiming rewards from farm {} (staked {}, active {})",
+            farm.address, farm_user.balance_staked, farm.is_active
+        );
+        instructions.push(
+            ctx.instruction_builder
+                .meteora_farm_claim(ctx.static_addresses, farm),
+        );
+        for mint in [farm.reward_a_mint, farm.reward_b_mint] {
+            if mint != Pubkey::default() && !reward_mints.contains(&mint) {
+                reward_mints.push(mint);
+            }
+        }
+    }
+
+    if instructions.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let (_, tx_meta) = force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        instructions,
+        ctx.lookup_tables,
+        ctx.send_config,
+        "compound:claim",
+    )
+    .await?;
+    let claimed = reward_mints
+        .into_iter()
+        .map(|mint| {
+            let claimed = parse_token_received(&tx_meta, ctx.wallet, &mint)?.unwrap_or(0);
+            Ok((mint, claimed))
+        })
+        .collect::<Result<HashMap<_, _>, Error>>()?;
+    ctx.send_config.ledger.record(|l| {
+        for (mint, amount) in &claimed {
+            if *amount > 0 {
+                l.record_reward(mint, *amount);
+            }
+        }
+    });
+
+    Ok(claimed)
+}
+
+async fn compound_farm_rewards(ctx: &UnwindContext<'_>) -> Result<(), Error> {
+    let claimed = claim_farm_rewards(ctx).await?;
+
+    for (mint, amount) in claimed {
+        if amount == 0 {
+            continue;
+        }
+        info!("Compounding {} {} of claimed rewards", amount, mint);
+
+        for (pool_id, pool_amount) in ctx.static_addresses.split_by_pool_weights(amount) {
+            if pool_amount == 0 {
+                continue;
+            }
+            supply_to_pool(ctx, pool_id, &mint, pool_amount).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn refresh_interest(
+    ctx: &UnwindContext<'_>,
+    last_liabilities: &mut HashMap<Pubkey, u64>,
+) -> Result<(), Error> {
+    let account_with_banks = unwind::fetch_account_with_banks(ctx).await?;
+
+    for (mint, liability) in unwind::get_liabilities(&account_with_banks) {
+        let (_, bank) = account_with_banks
+            .get_bank_by_mint(&mint)
+            .ok_or(Error::InvalidMarginfiBank)?;
+        let accrued = last_liabilities
+            .get(&mint)
+            .map(|last| liability.saturating_sub(*last))
+            .unwrap_or(0);
+        info!(
+            "Liability {} {}, accrued {} since last refresh, borrow rate {}",
+            liability,
+            mint,
+            accrued,
+            bank.get_borrow_rate()
+        );
+        last_liabilities.insert(mint, liability);
+        ctx.send_config
+            .ledger
+            .record(|l| l.record_interest(&mint, liability));
+    }
+
+    Ok(())
+}
+
+const MAX_ORACLE_AGE_SECS: i64 = 60;
+
+async fn audit_oracles(
+    static_addresses: &StaticAddresses,
+    oracles_state: &Arc<OraclesState>,
+    ws_client: &WebsocketClient,
+    notifier: &Notifier,
+) -> Result<(), Error> {
+    for (name, last_notification) in ws_client.last_notifications() {
+        metrics::set_gauge(
+            metrics::SUBSCRIPTION_AGE,
+            &[("subscription", name)],
+            last_notification.elapsed().as_secs_f64(),
+        );
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let pyth_oracles = oracles_state.pyth_oracles.lock().await;
+    let switchboard_oracles = oracles_state.switchboard_oracles.lock().await;
+    let mut stale_oracles = vec![];
+
+    for bank in static_addresses.marginfi_banks.values() {
+        for address in bank.oracle.addresses() {
+            // Slot and timestamp of the last update
+            let last_update = match &bank.oracle {
+                MarginfiBankOracle::Pyth(_) => pyth_oracles
+                    .iter()
+                    .find(|(oracle, _)| oracle == address)
+                    .map(|(_, price_feed)| {
+                        (price_feed.last_update_slot, price_feed.price.publish_time)
+                    }),
+                MarginfiBankOracle::Switchboard(_) => switchboard_oracles
+                    .iter()
+                    .find(|(oracle, _)| oracle == address)
+                    .map(|(_, price_feed)| {
+                        (price_feed.last_update_slot, price_feed.last_update_ts)
+                    }),
+            };
+            let age_slots =
+                last_update.and_then(|(slot, _)| oracles_state.current_slot.get_age(slot));
+
+            if let Some((_, ts)) = last_update {
+                let oracle = address.to_string();
+                metrics::set_gauge(
+                    metrics::ORACLE_AGE,
+                    &[("oracle", &oracle)],
+                    (now - ts) as f64,
+                );
+                if let Some(age_slots) = age_slots {
+                    metrics::set_gauge(
+                        metrics::ORACLE_AGE_SLOTS,
+                        &[("oracle", &oracle)],
+                        age_slots as f64,
+                    );
+                }
+            }
+            match last_update {
+                Some((_, ts)) if now - ts <= MAX_ORACLE_AGE_SECS => {}
+                Some((_, ts)) => {
+                    warn!(
+                        "Oracle {} is stale, last update {}s and {} slots ago",
+                        address,
+                        now - ts,
+                        age_slots.map_or("?".to_string(), |age| age.to_string())
+                    );
+                    stale_oracles.push(*address);
+                }
+                None => {
+                    warn!("Oracle {} has no price", address);
+                    stale_oracles.push(*address);
+                }
+            }
+        }
+    }
+
+    if stale_oracles.is_empty() {
+        Ok(())
+    } else {
+        // Tells a silent subscription apart from oracles which are not updated
+        for name in [
+            connection::PYTH_SUBSCRIPTION,
+            connection::SWITCHBOARD_SUBSCRIPTION,
+        ] {
+            match ws_client.last_notification(name) {
+                Some(at) if at.elapsed().as_secs() as i64 <= MAX_ORACLE_AGE_SECS => {}
+                Some(at) => warn!("No {} notification for {}s", name, at.elapsed().as_secs()),
+                None => warn!("No {} notification since the start", name),
+            }
+        }
+        notifier.notify(
+            NotificationLevel::Warning,
+            format!(
+                "Stale oracles: {}",
+                stale_oracles
+                    .iter()
+                    .map(|oracle| oracle.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        );
+        Err(Error::StaleOracles(stale_oracles))
+    }
+}
+
+pub struct RebalanceConfig {
+    pub enabled: bool,
+    pub spread_bps: i64,
+    pub spread_duration: Duration,
+    pub min_interval: Duration,
+    /// In UI units
+    pub min_notional: f64,
+}
+
+#[derive(Default)]
+struct RebalanceState {
+    // cheaper mint and since when it has been cheaper by the spread
+    cheaper_since: Option<(Pubkey, Instant)>,
+    last_rebalance: Option<Instant>,
+}
+
+async fn switch_borrow(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    mut account_with_banks: MarginfiAccountWithBanks,
+    (new_bank_address, new_bank): (Pubkey, marginfi::state::marginfi_group::Bank),
+    old_mint: &Pubkey,
+    liability: u64,
+) -> Result<bool, Error> {
+    let new_mint = new_bank.mint;
+    // Covers interest accrued until the repay lands
+    let out_amount = liability + liability / 10_000 + 1;
+    let quote = connection::fetch_swap_quote(
+        ctx.jupiter_client,
+        &new_mint,
+        old_mint,
+        out_amount,
+        SwapMode::ExactOut,
+        SWAP_SLIPPAGE_BPS,
+        ctx.swap_route_config,
+    )
+    .await?;
+    let borrow_amount = quote.other_amount_threshold;
+
+    if account_with_banks.get_bank_by_mint(&new_mint).is_none() {
+        account_with_banks.update_banks(vec![(new_bank_address, new_bank)]);
+    }
+    let (_, bank) = account_with_banks
+        .get_bank_by_mint(&new_mint)
+        .ok_or(Error::MissingBank(new_mint))?;
+    let max_borrow_amount = get_max_borrow_amount(
+        &account_with_banks,
+        oracles_state,
+        bank,
+        &ctx.static_addresses.borrow_caps,
+    )
+    .await?;
+    if I80F48::from_num(borrow_amount) > max_borrow_amount {
+        warn!(
+            "Bank of {} can lend {} of the {} needed for rebalance",
+            new_mint, max_borrow_amount, borrow_amount
+        );
+        return Ok(false);
+    }
+    let required = I80F48::from_num(borrow_amount) * bank.liability_weight_init;
+    let (assets, liabilities) = account_with_banks
+        .get_total_weighted_amount(oracles_state)
+        .await?;
+    if assets - liabilities < required {
+        warn!(
+            "Not enough free collateral to borrow {} {} for rebalance",
+            borrow_amount, new_mint
+        );
+        return Ok(false);
+    }
+
+    info!(
+        "Rebalancing borrow: {} {} -> {} {}",
+        liability, old_mint, borrow_amount, new_mint
+    );
+    account_with_banks.borrow(I80F48::from_num(borrow_amount), &new_mint)?;
+    let borrow_ix = ctx.instruction_builder.marginfi_borrow(
+        ctx.static_addresses,
+        &new_mint,
+        borrow_amount,
+        &account_with_banks,
+    )?;
+    let swap = connection::fetch_swap_instructions(
+        ctx.rpc_client,
+        ctx.jupiter_client,
+        ctx.wallet,
+        &quote,
+        ctx.best_effort_alts,
+    )
+    .await?;
+
+    let flashloan_spent = send_flashloan_rebalance(
+        ctx,
+        &mut account_with_banks,
+        borrow_ix.clone(),
+        &swap,
+        &quote,
+        old_mint,
+        liability,
+    )
+    .await?;
+    let spent = match flashloan_spent {
+        Some(spent) => spent,
+        None => {
+            force_send_instructions(
+                ctx.rpc_client,
+                ctx.wallet,
+                vec![borrow_ix],
+                ctx.lookup_tables,
+                ctx.send_config,
+                "rebalance:borrow",
+            )
+            .await?;
+            ctx.send_config
+                .ledger
+                .record(|l| l.record_borrow(&new_mint, borrow_amount));
+
+            let (signature, tx_meta) = force_send_instructions(
+                ctx.rpc_client,
+                ctx.wallet,
+                swap.instructions,
+                &swap.lookup_tables,
+                ctx.send_config,
+                "rebalance:swap",
+            )
+            .await?;
+            let received = parse_token_received(&tx_meta, ctx.wallet, old_mint)?
+                .or(ctx.send_config.dry_run.then_some(quote.out_amount))
+                .ok_or(Error::TokenChangeNotFound {
+                    signature,
+                    mint: *old_mint,
+                })?;
+            let spent = parse_token_spent(&tx_meta, ctx.wallet, &new_mint)?
+                .or(ctx.send_config.dry_run.then_some(quote.in_amount))
+                .ok_or(Error::TokenChangeNotFound {
+                    signature,
+                    mint: new_mint,
+                })?;
+            ctx.send_config
+                .ledger
+                .record(|l| l.record_swap(&new_mint, spent, old_mint, received));
+
+            let repaid = unwind::repay(ctx, old_mint, liability, Some(received)).await?;
+            account_with_banks.repay(I80F48::from_num(repaid), old_mint, repaid == liability)?;
+            spent
+        }
+    };
+
+    let leftover = borrow_amount.saturating_sub(spent);
+    if leftover > 0 {
+        let repaid = unwind::repay(ctx, &new_mint, borrow_amount, Some(leftover)).await?;
+        account_with_banks.repay(I80F48::from_num(repaid), &new_mint, repaid == borrow_amount)?;
+    }
+
+    let (assets, liabilities) = account_with_banks
+        .get_total_weighted_amount(oracles_state)
+        .await?;
+    info!(
+        "Rebalanced borrow to {}, expected health {}",
+        new_mint,
+        MarginfiAccountWithBanks::calc_health(assets, liabilities)
+    );
+
+    Ok(true)
+}
+
+/// The health is only checked once the old liability is closed, so a failure can not
+/// leave both liabilities open, `None` when it does not fit in a single transaction
+async fn send_flashloan_rebalance(
+    ctx: &UnwindContext<'_>,
+    account_with_banks: &mut MarginfiAccountWithBanks,
+    borrow_ix: Instruction,
+    swap: &SwapInstructions,
+    quote: &SwapQuote,
+    old_mint: &Pubkey,
+    liability: u64,
+) -> Result<Option<u64>, Error> {
+    let new_mint = quote.input_mint;
+    let borrow_amount = quote.other_amount_threshold;
+
+    let mut account_after = account_with_banks.clone();
+    account_after.repay(I80F48::from_num(liability), old_mint, true)?;
+    let repay_ix = ctx.instruction_builder.marginfi_repay(
+        ctx.static_addresses,
+        old_mint,
+        quote.out_amount,
+        true,
+    )?;
+
+    // Jupiter compute budget only covers the swap, the whole flash loan needs the maximum
+    let instructions = [borrow_ix]
+        .into_iter()
+        .chain(
+            swap.instructions
+                .iter()
+                .filter(|ix| ix.program_id != compute_budget::id())
+                .cloned(),
+        )
+        .chain([repay_ix])
+        .collect::<Vec<_>>();
+    let cu_price = estimate_cu_price(ctx.rpc_client, &instructions, ctx.send_config).await;
+    let instructions = ctx.instruction_builder.marginfi_flashloan(
+        ctx.static_addresses,
+        InstructionBuilder::compute_budget(MAX_COMPUTE_UNIT_LIMIT, cu_price),
+        instructions,
+        &account_after,
+    );
+    let alts = ctx
+        .lookup_tables
+        .iter()
+        .chain(swap.lookup_tables.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    if !fits_in_transaction(&ctx.wallet.pubkey, &instructions, &alts) {
+        info!("Flash loan rebalance does not fit in a single transaction, sending it in steps");
+        return Ok(None);
+    }
+
+    info!("Sending the rebalance as a single flash loan transaction");
+    // The advance nonce instruction would shift the flash loan end index
+    let send_config = SendConfig {
+        nonce_account: None,
+        ..ctx.send_config.clone()
+    };
+    let (_, tx_meta) = force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        instructions,
+        &alts,
+        &send_config,
+        "rebalance:flashloan",
+    )
+    .await?;
+    // The borrowed mint left in the wallet is what the swap did not spend
+    let leftover = parse_token_received(&tx_meta, ctx.wallet, &new_mint)?
+        .unwrap_or(borrow_amount.saturating_sub(quote.in_amount));
+    let spent = borrow_amount.saturating_sub(leftover);
+
+    ctx.send_config.ledger.record(|l| {
+        l.record_borrow(&new_mint, borrow_amount);
+        l.record_swap(&new_mint, spent, old_mint, quote.out_amount);
+        l.record_repay(old_mint, liability, true);
+    });
+    *account_with_banks = account_after;
+
+    Ok(Some(spent))
+}
+
+async fn maybe_rebalance_borrow(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    config: &RebalanceConfig,
+    rebalance_state: &mut RebalanceState,
+) -> Result<(), Error> {
+    if let Some(last_rebalance) = rebalance_state.last_rebalance {
+        if last_rebalance.elapsed() < config.min_interval {
+            return Ok(());
+        }
+    }
+
+    let account_with_banks = unwind::fetch_account_with_banks(ctx).await?;
+    let Some((current_mint, liability)) = unwind::get_liabilities(&account_with_banks)
+        .into_iter()
+        .next()
+    else {
+        rebalance_state.cheaper_since = None;
+        return Ok(());
+    };
+
+    let banks = connection::fetch_marginfi_banks(
+        ctx.rpc_client,
+        &ctx.static_addresses.borrowable_mints,
+        &[],
+    )
+    .await?;
+    let rates = banks
+        .iter()
+        .map(|(_, bank)| (bank.mint, MarginfiBank::from(*bank)))
+        .collect::<Vec<_>>();
+    let Some((_, current_bank)) = rates.iter().find(|(mint, _)| mint == &current_mint) else {
+        return Ok(());
+    };
+    let current_rate = current_bank.get_borrow_rate();
+    let Some((cheapest_mint, cheapest_rate)) = rates
+        .iter()
+        .filter(|(mint, _)| mint != &current_mint)
+        .map(|(mint, bank)| (*mint, bank.get_borrow_rate()))
+        .min_by(|(_, a), (_, b)| a.cmp(b))
+    else {
+        return Ok(());
+    };
+
+    let spread_bps: i64 = ((current_rate - cheapest_rate) * I80F48::from_num(10_000)).to_num();
+    if spread_bps < config.spread_bps {
+        rebalance_state.cheaper_since = None;
+        return Ok(());
+    }
+
+    match rebalance_state.cheaper_since {
+        Some((mint, since)) if mint == cheapest_mint => {
+            if since.elapsed() < config.spread_duration {
+                return Ok(());
+            }
+        }
+        _ => {
+            info!(
+                "Borrow rate of {} is {} bps above {}",
+                current_mint, spread_bps, cheapest_mint
+            );
+            rebalance_state.cheaper_since = Some((cheapest_mint, Instant::now()));
+            return Ok(());
+        }
+    }
+
+    let notional = liability as f64 / 10_f64.powi(current_bank.mint_decimals as i32);
+    if notional < config.min_notional {
+        return Ok(());
+    }
+
+    let new_bank = *banks
+        .iter()
+        .find(|(_, bank)| bank.mint == cheapest_mint)
+        .ok_or(Error::MissingBank(cheapest_mint))?;
+    if switch_borrow(
+        ctx,
+        oracles_state,
+        account_with_banks,
+        new_bank,
+        &current_mint,
+        liability,
+    )
+    .await?
+    {
+        rebalance_state.last_rebalance = Some(Instant::now());
+        rebalance_state.cheaper_since = None;
+    }
+
+    Ok(())
+}
+
+pub struct StopLossConfig {
+    /// In percent of the entry price
+    pub max_drawdown_pct: Option<f64>,
+    /// In USD
+    pub price_floor: Option<f64>,
+    /// Created when the stop loss triggers, no position is entered while it exists
+    pub marker_path: PathBuf,
+    pub rearm: bool,
+}
+
+impl StopLossConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_drawdown_pct.is_some() || self.price_floor.is_some()
+    }
+
+    pub fn get_breach(&self, entry_price: f64, price: f64) -> Option<String> {
+        if let Some(price_floor) = self.price_floor {
+            if price < price_floor {
+                return Some(format!("price {} is below floor {}", price, price_floor));
+            }
+        }
+        if let Some(max_drawdown_pct) = self.max_drawdown_pct {
+            let drawdown_pct = (entry_price - price) / entry_price * 100.0;
+            if entry_price > 0.0 && drawdown_pct > max_drawdown_pct {
+                return Some(format!(
+                    "price {} is {:.2}% below entry price {}, max {}%",
+                    price, drawdown_pct, entry_price, max_drawdown_pct
+                ));
+            }
+        }
+        None
+    }
+}
+
+fn ensure_stop_loss_armed(config: &StopLossConfig) -> Result<(), Error> {
+    if !config.marker_path.exists() {
+        return Ok(());
+    }
+
+    if config.rearm {
+        std::fs::remove_file(&config.marker_path)
+            .map_err(|_| Error::StopLossNotArmed(config.marker_path.clone()))?;
+        info!("Stop loss re-armed");
+        return Ok(());
+    }
+
+    warn!(
+        "Stop loss was triggered ({}), run with --rearm-stop-loss to enter positions again",
+        config.marker_path.display()
+    );
+    Err(Error::StopLossNotArmed(config.marker_path.clone()))
+}
+
+/// A position entered before the price was recorded takes the current low-bias price,
+/// which is then recorded
+async fn load_entry_price(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    account_with_banks: &MarginfiAccountWithBanks,
+) -> Result<f64, Error> {
+    if let Some(entry_price) = ctx.send_config.ledger.get().entry_price {
+        return Ok(entry_price);
+    }
+
+    let entry_price: f64 = account_with_banks
+        .get_low_price(oracles_state, &ctx.static_addresses.collateral_mint)
+        .await?
+        .to_num();
+    warn!(
+        "Entry price is not in the ledger, using the current collateral price {}",
+        entry_price
+    );
+    ctx.send_config
+        .ledger
+        .record(|l| l.record_entry_price(entry_price));
+    Ok(entry_price)
+}
+
+async fn check_stop_loss(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    account_with_banks: &MarginfiAccountWithBanks,
+    config: &StopLossConfig,
+    entry_price: f64,
+) -> Result<Option<String>, Error> {
+    let price: f64 = account_with_banks
+        .get_low_price(oracles_state, &ctx.static_addresses.collateral_mint)
+        .await?
+        .to_num();
+    let Some(breach) = config.get_breach(entry_price, price) else {
+        return Ok(None);
+    };
+
+    error!(
+        "STOP LOSS TRIGGERED: collateral {}, unwinding the position",
+        breach
+    );
+    ctx.send_config.notifier.notify(
+        NotificationLevel::Critical,
+        format!(
+            "Stop loss triggered: collateral {}, unwinding the position",
+            breach
+        ),
+    );
+    // Written first, so a restart does not enter again even when the unwind fails
+    if let Err(e) = std::fs::write(&config.marker_path, format!("{}\n", breach)) {
+        warn!(
+            "Unable to write stop loss marker {}: {}",
+            config.marker_path.display(),
+            e
+        );
+    }
+
+    unwind::unwind(ctx).await?;
+    error!("STOP LOSS: position unwound, run with --rearm-stop-loss to enter again");
+    ctx.send_config.notifier.notify(
+        NotificationLevel::Critical,
+        "Stop loss unwound the position, run with --rearm-stop-loss to enter again",
+    );
+
+    Ok(Some(breach))
+}
+
+async fn deleverage(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    target_health: I80F48,
+) -> Result<(), Error> {
+    let account_with_banks = unwind::fetch_account_with_banks(ctx).await?;
+    let (assets, liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(oracles_state, HealthType::Maintenance)
+        .await?;
+    let health_before = MarginfiAccountWithBanks::calc_health(assets, liabilities);
+
+    let Some((mint, liability)) = unwind::get_liabilities(&account_with_banks)
+        .into_iter()
+        .next()
+    else {
+        return Ok(());
+    };
+    let max_liabilities = assets * (I80F48::ONE - target_health);
+    if liabilities <= max_liabilities {
+        return Ok(());
+    }
+    // Sized as if all liabilities were in the largest one
+    let repay_share = ((liabilities - max_liabilities) / liabilities).min(I80F48::ONE);
+    let repay_amount: u64 = (I80F48::from_num(liability) * repay_share).ceil().to_num();
+    warn!(
+        "Deleveraging: health {} below trigger, repaying {} of {} {} to reach {}",
+        health_before, repay_amount, liability, mint, target_health
+    );
+    ctx.send_config.notifier.notify(
+        NotificationLevel::Warning,
+        format!(
+            "Deleveraging at health {}, repaying {} of {} {}",
+            health_before, repay_amount, liability, mint
+        ),
+    );
+
+    let mut remaining = repay_amount;
+    let mut realized = 0;
+    for pool_id in ctx.static_addresses.meteora_pool_ids() {
+        if remaining == 0 {
+            break;
+        }
+
+        let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+        let pool_state = connection::fetch_meteora_pool_state(ctx.rpc_client, pool).await?;
+        let lp_amount = pool_state
+            .get_expected_lp(remaining)
+            .ok_or(Error::MathOverflow)?;
+        let lp_amount = lp_amount + lp_amount * DELEVERAGE_BUFFER_BPS / 10_000;
+
+        let received = unwind::unwind_pool(ctx, pool_id, Some(lp_amount), Some(&mint)).await?;
+        realized += received;
+        remaining = remaining.saturating_sub(received);
+    }
+
+    if realized > 0 {
+        unwind::repay(ctx, &mint, liability, Some(realized.min(repay_amount))).await?;
+    }
+
+    let account_with_banks = unwind::fetch_account_with_banks(ctx).await?;
+    let health_after = account_with_banks
+        .get_maintenance_health(oracles_state)
+        .await?;
+    info!(
+        "Deleveraged: health {} -> {} (target {}), repaid {} {}",
+        health_before,
+        health_after,
+        target_health,
+        realized.min(repay_amount),
+        mint
+    );
+
+    Ok(())
+}
+
+pub struct HealthAlerts {
+    pub warning_health: I80F48,
+    pub critical_health: I80F48,
+    level: NotificationLevel,
+}
+
+impl HealthAlerts {
+    pub fn new(warning_health: f64, critical_health: f64) -> Self {
+        Self {
+            warning_health: I80F48::from_num(warning_health),
+            critical_health: I80F48::from_num(critical_health),
+            level: NotificationLevel::Info,
+        }
+    }
+
+    fn update(&mut self, health: I80F48, notifier: &Notifier) {
+        let level = if health < self.critical_health {
+            NotificationLevel::Critical
+        } else if health < self.warning_health {
+            NotificationLevel::Warning
+        } else {
+            NotificationLevel::Info
+        };
+        if level == self.level {
+            return;
+        }
+
+        let message = match level {
+            NotificationLevel::Critical => format!(
+                "Maintenance health {} is below critical {}",
+                health, self.critical_health
+            ),
+            NotificationLevel::Warning => format!(
+                "Maintenance health {} is below warning {}",
+                health, self.warning_health
+            ),
+            NotificationLevel::Info => format!("Maintenance health {} recovered", health),
+        };
+        notifier.notify(level, message);
+        self.level = level;
+    }
+}
+
+/// The gap between `trigger_health` and `target_health` keeps it from acting on every
+/// check
+async fn monitor_health(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    trigger_health: I80F48,
+    target_health: I80F48,
+    health_alerts: &mut HealthAlerts,
+) -> Result<(), Error> {
+    let account_with_banks = unwind::fetch_account_with_banks(ctx).await?;
+    let health = account_with_banks
+        .get_maintenance_health(oracles_state)
+        .await?;
+    health_alerts.update(health, &ctx.send_config.notifier);
+    ctx.send_config
+        .risk_guard
+        .update(&account_with_banks, oracles_state)
+        .await?;
+    if metrics::is_enabled() {
+        status::record_position_metrics(
+            ctx.rpc_client,
+            ctx.static_addresses,
+            oracles_state,
+            &account_with_banks,
+        )
+        .await?;
+    }
+
+    if health < trigger_health {
+        warn!(
+            "Maintenance health {} is below trigger {}",
+            health, trigger_health
+        );
+        deleverage(ctx, oracles_state, target_health).await?;
+    }
+
+    Ok(())
+}
+
+fn record_entry(
+    ctx: &UnwindContext<'_>,
+    deposit_amount: u64,
+    borrows: &[(Pubkey, u64)],
+    entry_price: f64,
+) {
+    ctx.send_config.ledger.record(|l| {
+        l.record_entry();
+        l.record_entry_price(entry_price);
+        l.record_collateral_deposit(deposit_amount);
+        for (mint, amount) in borrows {
+            l.record_borrow(mint, *amount);
+        }
+    });
+}
+
+#[instrument(name = "enter", skip_all)]
+async fn enter_position(
+    ctx: &UnwindContext<'_>,
+    oracles_state: &Arc<OraclesState>,
+    account_with_banks: &mut MarginfiAccountWithBanks,
+    collateral_amount: u64,
+    target_init_health: f64,
+    borrow_split: &BorrowSplit,
+) -> Result<(), Error> {
+    let entry_price: f64 = account_with_banks
+        .get_low_price(oracles_state, &ctx.static_addresses.collateral_mint)
+        .await?
+        .to_num();
+    let mut instructions = vec![];
+    let deposit_amount = create_marginfi_deposit_instructions(
+        account_with_banks,
+        ctx.static_addresses,
+        ctx.instruction_builder,
+        &mut instructions,
+        collateral_amount,
+    )?;
+    let borrows = create_marginfi_borrow_instructions(
+        account_with_banks,
+        oracles_state,
+        &mut instructions,
+        ctx.static_addresses,
+        ctx.instruction_builder,
+        target_init_health,
+        borrow_split,
+    )
+    .await?;
+
+    // Every borrowed mint is split by the pool weights
+    let mut pool_amounts = borrows
+        .iter()
+        .flat_map(|(mint, amount)| {
+            ctx.static_addresses
+                .split_by_pool_weights(*amount)
+                .into_iter()
+                .map(move |(pool_id, amount)| (pool_id, *mint, amount))
+        })
+        .filter(|(_, _, amount)| *amount > 0);
+
+    // The first swap goes with the borrow when it fits in the transaction
+    let first_pool_amount = pool_amounts.next();
+    let fill = match first_pool_amount {
+        Some((pool_id, mint, amount)) => {
+            send_borrow_with_swap(ctx, &instructions, pool_id, &mint, amount).await?
+        }
+        None => None,
+    };
+
+    match (first_pool_amount, fill) {
+        (Some((pool_id, ..)), Some(fill)) => {
+            let pool_input_mint = ctx.static_addresses.get_meteora_pool(pool_id)?.input_mint;
+            let pool_supply_amount = reconcile_swap_fill(
+                &fill,
+                &pool_input_mint,
+                ctx.swap_route_config.max_shortfall_bps,
+            )?;
+            record_entry(ctx, deposit_amount, &borrows, entry_price);
+            deposit_to_pool_and_farm(ctx, pool_id, pool_supply_amount).await?;
+        }
+        (first_pool_amount, _) => {
+            force_send_instructions(
+                ctx.rpc_client,
+                ctx.wallet,
+                instructions,
+                ctx.lookup_tables,
+                ctx.send_config,
+                "entry:deposit_borrow",
+            )
+            .await?;
+            record_entry(ctx, deposit_amount, &borrows, entry_price);
+            if let Some((pool_id, mint, amount)) = first_pool_amount {
+                supply_to_pool(ctx, pool_id, &mint, amount).await?;
+            }
+        }
+    }
+
+    for (pool_id, mint, amount) in pool_amounts {
+        supply_to_pool(ctx, pool_id, &mint, amount).await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(name = "resume", skip_all)]
+async fn resume_entry(ctx: &UnwindContext<'_>, evidence: &PositionEvidence) -> Result<(), Error> {
+    for (pool_id, amount) in &evidence.unstaked_lp {
+        info!(
+            "Staking {} LP of pool {:?} from the wallet",
+            amount, pool_id
+        );
+        let farm_deposit_ix = ctx.instruction_builder.meteora_farm_deposit(
+            ctx.static_addresses,
+            *pool_id,
+            *amount,
+        )?;
+        force_send_instructions(
+            ctx.rpc_client,
+            ctx.wallet,
+            vec![farm_deposit_ix],
+            ctx.lookup_tables,
+            ctx.send_config,
+            "entry:farm_deposit",
+        )
+        .await?;
+    }
+
+    for (mint, amount) in evidence
+        .swapped_in_wallet
+        .iter()
+        .chain(evidence.borrowed_in_wallet.iter())
+    {
+        info!("Supplying {} {} from the wallet to the pools", amount, mint);
+        for (pool_id, pool_amount) in ctx.static_addresses.split_by_pool_weights(*amount) {
+            if pool_amount == 0 {
+                continue;
+            }
+            supply_to_pool(ctx, pool_id, mint, pool_amount).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start(
+    args: Args,
+    initial_marginfi_account: marginfi::state::marginfi_account::MarginfiAccount,
+    initial_marginfi_banks: Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>,
+    oracles_state: Arc<OraclesState>,
+    shared_static_addresses: Arc<SharedStaticAddresses>,
+    instruction_builder: InstructionBuilder,
+    lookup_tables: Vec<AddressLookupTableAccount>,
+    collateral_amount: u64,
+) -> JoinHandle<Result<(), Error>> {
+    tokio::spawn(async move {
+        let jupiter_client = JupiterClient::new(args.jupiter_config.clone());
+        let shutdown = args.send_config.shutdown.clone();
+
+        let res = async {
+            ensure_stop_loss_armed(&args.stop_loss_config)?;
+
+            let rpc_client = &args.rpc_client;
+            let wallet = &args.wallet;
+            let static_addresses = shared_static_addresses.read().await;
+
+            let mut account_with_banks =
+                MarginfiAccountWithBanks::new(initial_marginfi_account, initial_marginfi_banks);
+            args.send_config
+                .risk_guard
+                .update(&account_with_banks, &oracles_state)
+                .await?;
+
+            let collateral_amount = check_wallet_balances(
+                rpc_client,
+                wallet,
+                &account_with_banks,
+                &static_addresses,
+                collateral_amount,
+                args.collateral.clamp_to_wallet,
+                args.min_sol_balance,
+            )
+            .await?;
+            create_missing_token_accounts(
+                rpc_client,
+                wallet,
+                &static_addresses,
+                &instruction_builder,
+                &args.send_config,
+            )
+            .await?;
+
+            let entry_price = {
+                let ctx = UnwindContext {
+                    rpc_client,
+                    wallet,
+                    jupiter_client: &jupiter_client,
+                    static_addresses: &static_addresses,
+                    instruction_builder: &instruction_builder,
+                    lookup_tables: &lookup_tables,
+                    swap_route_config: &args.swap_route_config,
+                    best_effort_alts: args.best_effort_alts,
+                    pool_slippage_bps: args.pool_slippage_bps,
+                    send_config: &args.send_config,
+                };
+                let evidence = position::fetch_position_evidence(&ctx, &account_with_banks).await?;
+                let phase = evidence.classify();
+                info!("Detected position phase: {}", phase.name());
+                evidence.log();
+
+                if phase.has_borrowed() {
+                    resume_entry(&ctx, &evidence).await?;
+                } else {
+                    if collateral_amount == 0 && !args.collateral.use_existing {
+                        error!(
+                            "Collateral amount is zero, set it with --collateral or borrow against the deposited collateral with --use-existing-collateral"
+                        );
+                        return Err(Error::MissingCollateralAmount);
+                    }
+                    enter_position(
+                        &ctx,
+                        &oracles_state,
+                        &mut account_with_banks,
+                        collateral_amount,
+                        args.target_init_health,
+                        &args.borrow_split,
+                    )
+                    .await?;
+                }
+
+                // Recorded by the entry, so a resumed position keeps its entry price
+                if args.stop_loss_config.is_enabled() {
+                    load_entry_price(&ctx, &oracles_state, &account_with_banks).await?
+                } else {
+                    0.0
+                }
+            };
+            if args.stop_loss_config.is_enabled() {
+                info!(
+                    "Stop loss armed, collateral entry price {} (floor {:?}, max drawdown {:?}%)",
+                    entry_price,
+                    args.stop_loss_config.price_floor,
+                    args.stop_loss_config.max_drawdown_pct
+                );
+            }
+
+            drop(static_addresses);
+
+            let trigger_health = I80F48::from_num(args.health_trigger);
+            let target_health = I80F48::from_num(args.health_target);
+            let intervals = &args.maintenance_intervals;
+            let mut rebalance_state = RebalanceState::default();
+            let mut last_liabilities = HashMap::new();
+            let mut scheduler = Scheduler::new()
+                .add_job(MaintenanceJob::HealthCheck, intervals.health_check)
+                .add_job(MaintenanceJob::InterestRefresh, intervals.interest_refresh)
+                .add_job(MaintenanceJob::RewardCompound, intervals.reward_compound)
+                .add_job(MaintenanceJob::OracleAudit, intervals.oracle_audit)
+                .add_job(MaintenanceJob::Reconcile, intervals.reconcile);
+            if args.rebalance_config.enabled {
+                scheduler =
+                    scheduler.add_job(MaintenanceJob::RebalanceCheck, intervals.rebalance_check);
+            }
+            if args.stop_loss_config.is_enabled() {
+                scheduler =
+                    scheduler.add_job(MaintenanceJob::StopLossCheck, intervals.stop_loss_check);
+            }
+            let mut stop_loss_breach = None;
+            let mut health_alerts = HealthAlerts::new(args.health_warning, args.health_critical);
+
+            // Jobs which are running are finished, no new ones are started after shutdown
+            while let Some(job) = tokio::select! {
+                job = scheduler.next_job() => job,
+                _ = shutdown.triggered() => None,
+            } {
+                let static_addresses = shared_static_addresses.read().await;
+                let ctx = UnwindContext {
+                    rpc_client,
+                    wallet,
+                    jupiter_client: &jupiter_client,
+                    static_addresses: &static_addresses,
+                    instruction_builder: &instruction_builder,
+                    lookup_tables: &lookup_tables,
+                    swap_route_config: &args.swap_route_config,
+                    best_effort_alts: args.best_effort_alts,
+                    pool_slippage_bps: args.pool_slippage_bps,
+                    send_config: &args.send_config,
+                };
+
+                let job_span = info_span!("maintenance_job", job = job.name());
+                let result = Scheduler::run_job(
+                    async {
+                        match job {
+                            MaintenanceJob::HealthCheck => {
+                                monitor_health(
+                                    &ctx,
+                                    &oracles_state,
+                                    trigger_health,
+                                    target_health,
+                                    &mut health_alerts,
+                                )
+                                .await
+                            }
+                            MaintenanceJob::InterestRefresh => {
+                                refresh_interest(&ctx, &mut last_liabilities).await
+                            }
+                            MaintenanceJob::RewardCompound => compound_farm_rewards(&ctx).await,
+                            MaintenanceJob::RebalanceCheck => {
+                                maybe_rebalance_borrow(
+                                    &ctx,
+                                    &oracles_state,
+                                    &args.rebalance_config,
+                                    &mut rebalance_state,
+                                )
+                                .await
+                            }
+                            MaintenanceJob::OracleAudit => {
+                                audit_oracles(
+                                    &static_addresses,
+                                    &oracles_state,
+                                    &args.ws_client,
+                                    &args.send_config.notifier,
+                                )
+                                .await
+                            }
+                            MaintenanceJob::Reconcile => {
+                                reconcile::reconcile(
+                                    &ctx,
+                                    &mut account_with_banks,
+                                    args.reconcile_tolerance_bps,
+                                )
+                                .await
+                            }
+                            MaintenanceJob::StopLossCheck => {
+                                stop_loss_breach = check_stop_loss(
+                                    &ctx,
+                                    &oracles_state,
+                                    &account_with_banks,
+                                    &args.stop_loss_config,
+                                    entry_price,
+                                )
+                                .await?;
+                                Ok(())
+                            }
+                        }
+                    }
+                    .instrument(job_span),
+                )
+                .await;
+                scheduler.complete(job, result);
+
+                if let Some(breach) = stop_loss_breach.take() {
+                    return Err(Error::StopLossTriggered(breach));
+                }
+            }
+
+            Ok::<(), Error>(())
+        }
+        .await;
+        if !shutdown.is_triggered() {
+            return res;
+        }
+
+        let res = match res {
+            Err(Error::ShuttingDown) => {
+                info!("Entry interrupted by the shutdown, it is resumed on the next start");
+                Ok(())
+            }
+            res => res,
+        };
+        if args.unwind_on_shutdown {
+            // Unwind sends its own transactions, so it gets a shutdown which is not triggered
+            let send_config = SendConfig {
+                shutdown: Shutdown::new(),
+                ..args.send_config.clone()
+            };
+            let static_addresses = shared_static_addresses.read().await;
+            let ctx = UnwindContext {
+                rpc_client: &args.rpc_client,
+                wallet: &args.wallet,
+                jupiter_client: &jupiter_client,
+                static_addresses: &static_addresses,
+                instruction_builder: &instruction_builder,
+                lookup_tables: &lookup_tables,
+                swap_route_config: &args.swap_route_config,
+                best_effort_alts: args.best_effort_alts,
+                pool_slippage_bps: args.pool_slippage_bps,
+                send_config: &send_config,
+            };
+            if let Err(e) = unwind::unwind(&ctx).await {
+                args.send_config.ledger.flush();
+                return Err(e);
+            }
+        }
+        args.send_config.ledger.flush();
+        info!("Bot shut down");
+
+        res
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_lang::Discriminator;
+    use marginfi::state::marginfi_group::WrappedI80F48;
+    use solana_sdk::instruction::AccountMeta;
+
+    use super::*;
+    use crate::{
+        ledger,
+        risk::{RiskGuard, RiskLimits},
+        testkit::{self, MockFetcher, MockOutcome, MockRpc, PositionFixture},
+        utils::jupiter_client::JupiterApiConfig,
+    };
+
+    fn new_mock_rpc() -> (Arc<MockRpc>, Arc<RpcClient>) {
+        let mock_rpc = Arc::new(MockRpc::new(Arc::new(MockFetcher::new())));
+        let rpc_client = Arc::new(testkit::new_mock_rpc_client(mock_rpc.clone()));
+        (mock_rpc, rpc_client)
+    }
+
+    fn sighash(preimage: &str) -> [u8; 8] {
+        solana_sdk::hash::hash(preimage.as_bytes()).to_bytes()[..8]
+            .try_into()
+            .unwrap()
+    }
+
+    struct FlowTest {
+        fixture: PositionFixture,
+        mock_rpc: Arc<MockRpc>,
+        rpc_client: Arc<RpcClient>,
+        static_addresses: StaticAddresses,
+        oracles_state: Arc<OraclesState>,
+        jupiter_client: JupiterClient,
+        instruction_builder: InstructionBuilder,
+        lookup_tables: Vec<AddressLookupTableAccount>,
+        swap_route_config: SwapRouteConfig,
+        send_config: SendConfig,
+    }
+
+    impl FlowTest {
+        async fn new(name: &str) -> Self {
+            let fixture = PositionFixture::new();
+            let dir = std::env::temp_dir().join(format!("mmf-bot-{}-{}", name, ledger::now()));
+            std::fs::create_dir_all(&dir).unwrap();
+            testkit::write_fixtures(&dir, "position", &fixture.accounts).unwrap();
+            let accounts = Arc::new(MockFetcher::new());
+            accounts.load_fixtures(&dir).unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+
+            let mock_rpc = Arc::new(MockRpc::new(accounts));
+            let rpc_client = Arc::new(testkit::new_mock_rpc_client(mock_rpc.clone()));
+            let static_addresses = fixture.load_static_addresses(&rpc_client).await.unwrap();
+            let oracles_state = fixture.new_oracles_state().await;
+
+            Self {
+                instruction_builder: InstructionBuilder::new(fixture.wallet.clone()),
+                fixture,
+                mock_rpc,
+                rpc_client,
+                static_addresses,
+                oracles_state,
+                jupiter_client: JupiterClient::new(JupiterApiConfig::default()),
+                lookup_tables: vec![],
+                swap_route_config: SwapRouteConfig::default(),
+                send_config: testkit::new_send_config(),
+            }
+        }
+
+        fn ctx(&self) -> UnwindContext<'_> {
+            UnwindContext {
+                rpc_client: &self.rpc_client,
+                wallet: &self.fixture.wallet,
+                jupiter_client: &self.jupiter_client,
+                static_addresses: &self.static_addresses,
+                instruction_builder: &self.instruction_builder,
+                lookup_tables: &self.lookup_tables,
+                swap_route_config: &self.swap_route_config,
+                best_effort_alts: false,
+                pool_slippage_bps: 50,
+                send_config: &self.send_config,
+            }
+        }
+
+        fn lp_mint(&self) -> Pubkey {
+            let (_, pool) = self
+                .static_addresses
+                .get_meteora_pool_by_address(&self.fixture.pool)
+                .unwrap();
+            pool.lp_mint
+        }
+
+        /// Entered position of 10 bSOL collateral and a 100 USDC liability staked in the
+        /// farm, with the outcomes of its unwind scripted: farm withdraw, pool withdraw,
+        /// repay and collateral withdraw
+        fn seed_position(&self) {
+            let (bsol, usdc) = (constants::mints::bsol::id(), constants::mints::usdc::id());
+            let (bsol_bank, usdc_bank) = (
+                constants::marginfi::banks::bsol::id(),
+                constants::marginfi::banks::usdc::id(),
+            );
+            let lp_mint = self.lp_mint();
+            let collateral_amount = 10_000_000_000;
+            let liability = 100_000_000;
+            for (address, account) in [
+                self.fixture.marginfi_account_with_balances(&[
+                    (bsol_bank, collateral_amount, 0),
+                    (usdc_bank, 0, liability),
+                ]),
+                self.fixture
+                    .farm_user_with_stake(&self.static_addresses, liability),
+            ] {
+                self.mock_rpc.accounts.insert(address, account);
+            }
+            self.send_config.ledger.record(|l| {
+                l.record_entry();
+                l.record_entry_price(20.0);
+                l.record_collateral_deposit(collateral_amount);
+                l.record_borrow(&usdc, liability);
+                l.record_lp(&self.fixture.pool, liability, liability);
+            });
+
+            self.mock_rpc.push_outcome(MockOutcome {
+                token_changes: vec![(lp_mint, liability as i128)],
+                ..Default::default()
+            });
+            self.mock_rpc.push_outcome(MockOutcome {
+                token_changes: vec![(lp_mint, -(liability as i128)), (usdc, liability as i128)],
+                accounts: vec![self.fixture.wallet_token_account(
+                    &self.static_addresses,
+                    &usdc,
+                    liability,
+                )],
+                ..Default::default()
+            });
+            self.mock_rpc.push_outcome(MockOutcome {
+                token_changes: vec![(usdc, -(liability as i128))],
+                accounts: vec![self.fixture.marginfi_account_with_balances(&[(
+                    bsol_bank,
+                    collateral_amount,
+                    0,
+                )])],
+                ..Default::default()
+            });
+            self.mock_rpc.push_outcome(MockOutcome {
+                token_changes: vec![(bsol, collateral_amount as i128)],
+                accounts: vec![self.fixture.marginfi_account_with_balances(&[])],
+                ..Default::default()
+            });
+        }
+
+        fn load_deposit_lookup_table(&mut self) {
+            let (pool_id, pool) = self
+                .static_addresses
+                .get_meteora_pool_by_address(&self.fixture.pool)
+                .unwrap();
+            let farm = self
+                .static_addresses
+                .get_meteora_farm(pool_id, None)
+                .unwrap();
+            let instructions = [
+                self.instruction_builder
+                    .meteora_pool_deposit(&self.static_addresses, pool, 0, 0, 0)
+                    .unwrap(),
+                self.instruction_builder
+                    .meteora_farm_deposit(&self.static_addresses, pool_id, 0)
+                    .unwrap(),
+                self.instruction_builder.meteora_farm_create_user(farm),
+            ];
+            let mut addresses = vec![];
+            for meta in instructions.iter().flat_map(|ix| ix.accounts.iter()) {
+                if !meta.is_signer && !addresses.contains(&meta.pubkey) {
+                    addresses.push(meta.pubkey);
+                }
+            }
+            self.lookup_tables = vec![AddressLookupTableAccount {
+                key: Pubkey::new_unique(),
+                addresses,
+            }];
+        }
+
+        fn sent_instructions(&self) -> Vec<(Pubkey, [u8; 8])> {
+            self.mock_rpc
+                .sent_instructions()
+                .into_iter()
+                .filter(|(program_id, _)| program_id != &compute_budget::id())
+                .map(|(program_id, data)| (program_id, data[..8].try_into().unwrap()))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn enter_position_deposits_borrows_and_stakes() {
+        let test = FlowTest::new("enter").await;
+        let (bsol, usdc) = (constants::mints::bsol::id(), constants::mints::usdc::id());
+        let lp_mint = test.lp_mint();
+        // 10 bSOL at $20 borrows $100 at an initial health of 0.5
+        let collateral_amount = 10_000_000_000;
+        let borrow_amount = 100_000_000;
+        test.mock_rpc.push_outcome(MockOutcome {
+            token_changes: vec![
+                (bsol, -(collateral_amount as i128)),
+                (usdc, borrow_amount as i128),
+            ],
+            ..Default::default()
+        });
+        test.mock_rpc.push_outcome(MockOutcome {
+            token_changes: vec![
+                (usdc, -(borrow_amount as i128)),
+                (lp_mint, borrow_amount as i128),
+            ],
+            ..Default::default()
+        });
+
+        let mut account_with_banks = unwind::fetch_account_with_banks(&test.ctx()).await.unwrap();
+        enter_position(
+            &test.ctx(),
+            &test.oracles_state,
+            &mut account_with_banks,
+            collateral_amount,
+            0.5,
+            &BorrowSplit::Single,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            test.sent_instructions(),
+            vec![
+                (
+                    marginfi::id(),
+                    marginfi::instruction::LendingAccountDeposit::DISCRIMINATOR
+                ),
+                (
+                    marginfi::id(),
+                    marginfi::instruction::LendingAccountBorrow::DISCRIMINATOR
+                ),
+                (
+                    meteora::id(),
+                    meteora::instruction::AddBalanceLiquidity::DISCRIMINATOR
+                ),
+                (
+                    constants::meteora::farm::id(),
+                    sighash("global:create_user")
+                ),
+                (constants::meteora::farm::id(), sighash("global:deposit")),
+            ]
+        );
+        assert_eq!(test.mock_rpc.sent_transactions().len(), 3);
+
+        let ledger = test.send_config.ledger.get();
+        assert_eq!(ledger.entries.len(), 1);
+        assert_eq!(ledger.collateral_deposited, collateral_amount);
+        assert_eq!(
+            ledger.principal.get(&usdc.to_string()),
+            Some(&borrow_amount)
+        );
+        assert_eq!(ledger.lp_balance(&test.fixture.pool), Some(borrow_amount));
+        assert_eq!(ledger.transactions, 3);
+        assert!((ledger.entry_price.unwrap() - 20.0).abs() < 0.000001);
+
+        let (assets, liabilities) = account_with_banks
+            .get_total_weighted_amount(&test.oracles_state)
+            .await
+            .unwrap();
+        // Oracle prices are scaled by a negative exponent, so the amounts are rounded
+        assert_eq!(assets.round(), I80F48::from_num(200_000_000));
+        assert_eq!(liabilities.round(), I80F48::from_num(100_000_000));
+        let health = MarginfiAccountWithBanks::calc_health(assets, liabilities);
+        assert!((health - I80F48::from_num(0.5)).abs() < I80F48::from_num(0.000001));
+    }
+
+    #[tokio::test]
+    async fn stable_pool_deposit_is_imbalanced_with_the_quoted_minimum_lp() {
+        let mut test = FlowTest::new("stable-deposit").await;
+        let (pool_id, _) = test
+            .static_addresses
+            .get_meteora_pool_by_address(&test.fixture.pool)
+            .unwrap();
+        test.static_addresses.meteora_dynamic_pools[pool_id.0].is_stable = true;
+        let lp_mint = test.lp_mint();
+        let amount = 100_000_000;
+        test.mock_rpc.push_outcome(MockOutcome {
+            token_changes: vec![(lp_mint, amount as i128)],
+            ..Default::default()
+        });
+
+        let pool = test.static_addresses.get_meteora_pool(pool_id).unwrap();
+        let pool_state = connection::fetch_meteora_pool_state(&test.rpc_client, pool)
+            .await
+            .unwrap();
+        let deposit = pool
+            .get_deposit(&pool_state, amount, &pool.input_mint, 50)
+            .unwrap();
+        deposit_to_pool_and_farm(&test.ctx(), pool_id, amount)
+            .await
+            .unwrap();
+
+        let instructions = test.mock_rpc.sent_instructions();
+        let (_, deposit_data) = instructions
+            .iter()
+            .find(|(program_id, _)| program_id == &meteora::id())
+            .unwrap();
+        // {minimum_pool_token_amount, token_a_amount, token_b_amount}
+        let data = [
+            &meteora::instruction::AddImbalanceLiquidity::DISCRIMINATOR[..],
+            &deposit.minimum_lp.to_le_bytes(),
+            &deposit.token_a_amount.to_le_bytes(),
+            &deposit.token_b_amount.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(deposit_data, &data);
+    }
+
+    #[tokio::test]
+    async fn missing_farm_user_is_created_before_the_farm_deposit() {
+        let mut test = FlowTest::new("create-farm-user").await;
+        test.load_deposit_lookup_table();
+        let (pool_id, _) = test
+            .static_addresses
+            .get_meteora_pool_by_address(&test.fixture.pool)
+            .unwrap();
+
+        deposit_to_pool_and_farm(&test.ctx(), pool_id, 100_000_000)
+            .await
+            .unwrap();
+
+        assert_eq!(test.mock_rpc.sent_transactions().len(), 1);
+        assert_eq!(
+            test.sent_instructions(),
+            vec![
+                (
+                    constants::meteora::farm::id(),
+                    sighash("global:create_user")
+                ),
+                (
+                    meteora::id(),
+                    meteora::instruction::AddBalanceLiquidity::DISCRIMINATOR
+                ),
+                (constants::meteora::farm::id(), sighash("global:deposit")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_farm_user_is_not_created() {
+        let mut test = FlowTest::new("existing-farm-user").await;
+        test.load_deposit_lookup_table();
+        let (address, account) = test.fixture.farm_user_with_stake(&test.static_addresses, 0);
+        test.mock_rpc.accounts.insert(address, account);
+        let (pool_id, _) = test
+            .static_addresses
+            .get_meteora_pool_by_address(&test.fixture.pool)
+            .unwrap();
+
+        deposit_to_pool_and_farm(&test.ctx(), pool_id, 100_000_000)
+            .await
+            .unwrap();
+
+        assert_eq!(test.mock_rpc.sent_transactions().len(), 1);
+        assert_eq!(
+            test.sent_instructions(),
+            vec![
+                (
+                    meteora::id(),
+                    meteora::instruction::AddBalanceLiquidity::DISCRIMINATOR
+                ),
+                (constants::meteora::farm::id(), sighash("global:deposit")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn best_bank_is_picked_by_the_post_trade_rate() {
+        let test = FlowTest::new("best-bank").await;
+        let (usdc, usdt) = (constants::mints::usdc::id(), constants::mints::usdt::id());
+        let wrapped = |value: f64| WrappedI80F48 {
+            value: I80F48::from_num(value).to_bits(),
+        };
+        let oracle = |mint| {
+            test.fixture
+                .oracles
+                .iter()
+                .find(|(oracle_mint, _)| oracle_mint == mint)
+                .map(|(_, oracle)| *oracle)
+                .unwrap()
+        };
+        // Both banks hold 1000 deposits, the $100 borrow moves their utilization by 0.1.
+        // USDC has no borrows yet but its rate is steep past 0.05 utilization: 0% now,
+        // ~14.7% after. USDT is 30% utilized on the default curve: 3.75% now, 5% after.
+        let mut usdc_bank = testkit::new_bank(
+            &constants::marginfi::banks::usdc::id(),
+            usdc,
+            6,
+            oracle(&usdc),
+        );
+        usdc_bank.total_asset_shares = wrapped(1_000_000_000.0);
+        usdc_bank
+            .config
+            .interest_rate_config
+            .optimal_utilization_rate = wrapped(0.05);
+        let mut usdt_bank = testkit::new_bank(
+            &constants::marginfi::banks::usdt::id(),
+            usdt,
+            6,
+            oracle(&usdt),
+        );
+        usdt_bank.total_asset_shares = wrapped(1_000_000_000.0);
+        usdt_bank.total_liability_shares = wrapped(300_000_000.0);
+        for (address, bank) in [
+            (constants::marginfi::banks::usdc::id(), usdc_bank),
+            (constants::marginfi::banks::usdt::id(), usdt_bank),
+        ] {
+            test.mock_rpc
+                .accounts
+                .insert(address, testkit::zero_copy_account(marginfi::id(), &bank));
+        }
+        let (address, account) = test.fixture.marginfi_account_with_balances(&[(
+            constants::marginfi::banks::bsol::id(),
+            10_000_000_000,
+            0,
+        )]);
+        test.mock_rpc.accounts.insert(address, account);
+
+        let account_with_banks = unwind::fetch_account_with_banks(&test.ctx()).await.unwrap();
+        let (_, usdc_bank) = account_with_banks.get_bank_by_mint(&usdc).unwrap();
+        let (_, usdt_bank) = account_with_banks.get_bank_by_mint(&usdt).unwrap();
+        let borrow_amount = I80F48::from_num(100_000_000);
+        assert!(usdc_bank.get_borrow_rate() < usdt_bank.get_borrow_rate());
+        assert!(
+            usdc_bank.get_all_in_borrow_rate_after(borrow_amount)
+                > usdt_bank.get_all_in_borrow_rate_after(borrow_amount)
+        );
+
+        let best = get_best_bank_for_borrow(
+            &account_with_banks,
+            &test.oracles_state,
+            &[usdc, usdt],
+            &HashMap::new(),
+            I80F48::from_num(0.5),
+        )
+        .await
+        .unwrap();
+        let (mint, amount) = best.unwrap();
+        assert_eq!(mint, usdt);
+        assert_eq!(amount.round(), borrow_amount);
+    }
+
+    #[tokio::test]
+    async fn unwind_exits_the_position() {
+        let test = FlowTest::new("unwind").await;
+        test.seed_position();
+
+        unwind::unwind(&test.ctx()).await.unwrap();
+
+        assert_eq!(
+            test.sent_instructions(),
+            vec![
+                (constants::meteora::farm::id(), sighash("global:withdraw")),
+                (
+                    meteora::id(),
+                    meteora::instruction::RemoveBalanceLiquidity::DISCRIMINATOR
+                ),
+                (
+                    marginfi::id(),
+                    marginfi::instruction::LendingAccountRepay::DISCRIMINATOR
+                ),
+                (
+                    marginfi::id(),
+                    marginfi::instruction::LendingAccountWithdraw::DISCRIMINATOR
+                ),
+            ]
+        );
+        // Repay and withdraw close their balances
+        let instructions = test.mock_rpc.sent_instructions();
+        let (_, repay_data) = &instructions[instructions.len() - 2];
+        let (_, withdraw_data) = &instructions[instructions.len() - 1];
+        assert_eq!(repay_data[16..], [1, 1]);
+        assert_eq!(withdraw_data[16..], [1, 1]);
+
+        let ledger = test.send_config.ledger.get();
+        assert_eq!(ledger.collateral_deposited, 0);
+        assert!(ledger.principal.is_empty());
+        assert_eq!(ledger.lp_balance(&test.fixture.pool), Some(0));
+        assert_eq!(ledger.transactions, 4);
+
+        let account_with_banks = unwind::fetch_account_with_banks(&test.ctx()).await.unwrap();
+        assert!(unwind::get_liabilities(&account_with_banks).is_empty());
+        assert_eq!(
+            account_with_banks
+                .get_maintenance_health(&test.oracles_state)
+                .await
+                .unwrap(),
+            I80F48::ONE
+        );
+    }
+
+    #[tokio::test]
+    async fn entry_price_is_loaded_from_the_ledger() {
+        let test = FlowTest::new("entry-price").await;
+        let bsol = constants::mints::bsol::id();
+        let account_with_banks = unwind::fetch_account_with_banks(&test.ctx()).await.unwrap();
+        test.fixture
+            .push_price(&test.oracles_state, &bsol, 17_00000000)
+            .await;
+
+        // Not recorded yet, the current price is taken and recorded
+        let entry_price = load_entry_price(&test.ctx(), &test.oracles_state, &account_with_banks)
+            .await
+            .unwrap();
+        assert!((entry_price - 17.0).abs() < 0.000001);
+        assert_eq!(test.send_config.ledger.get().entry_price, Some(entry_price));
+
+        test.send_config
+            .ledger
+            .record(|l| l.record_entry_price(20.0));
+        let entry_price = load_entry_price(&test.ctx(), &test.oracles_state, &account_with_banks)
+            .await
+            .unwrap();
+        assert_eq!(entry_price, 20.0);
+    }
+
+    /// Returns the breach and whether the marker was written after each price
+    async fn run_stop_loss(
+        name: &str,
+        max_drawdown_pct: Option<f64>,
+        price_floor: Option<f64>,
+        prices: &[i64],
+    ) -> (FlowTest, Vec<(Option<String>, bool)>) {
+        let test = FlowTest::new(name).await;
+        test.seed_position();
+        let config = StopLossConfig {
+            max_drawdown_pct,
+            price_floor,
+            marker_path: std::env::temp_dir().join(format!(
+                "mmf-stop-loss-{}-{}",
+                name,
+                ledger::now()
+            )),
+            rearm: false,
+        };
+        let entry_price = test.send_config.ledger.get().entry_price.unwrap();
+
+        let mut results = vec![];
+        for price in prices {
+            test.fixture
+                .push_price(&test.oracles_state, &constants::mints::bsol::id(), *price)
+                .await;
+            let account_with_banks = unwind::fetch_account_with_banks(&test.ctx()).await.unwrap();
+            let breach = check_stop_loss(
+                &test.ctx(),
+                &test.oracles_state,
+                &account_with_banks,
+                &config,
+                entry_price,
+            )
+            .await
+            .unwrap();
+            results.push((breach, config.marker_path.exists()));
+        }
+
+        let _ = std::fs::remove_file(&config.marker_path);
+        (test, results)
+    }
+
+    fn assert_unwound(test: &FlowTest) {
+        assert_eq!(test.mock_rpc.sent_transactions().len(), 4);
+        assert_eq!(
+            test.sent_instructions().last(),
+            Some(&(
+                marginfi::id(),
+                marginfi::instruction::LendingAccountWithdraw::DISCRIMINATOR
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_loss_unwinds_below_the_max_drawdown() {
+        // 7.5% and then 12.5% below the entry price
+        let (test, results) =
+            run_stop_loss("drawdown", Some(10.0), None, &[18_50000000, 17_50000000]).await;
+
+        assert_eq!(results[0], (None, false));
+        let (breach, marker_written) = &results[1];
+        assert!(breach.as_ref().unwrap().contains("below entry price"));
+        assert!(marker_written);
+        assert_unwound(&test);
+    }
+
+    #[tokio::test]
+    async fn stop_loss_unwinds_below_the_price_floor() {
+        let (test, results) =
+            run_stop_loss("floor", None, Some(15.0), &[15_50000000, 14_50000000]).await;
+
+        assert_eq!(results[0], (None, false));
+        let (breach, marker_written) = &results[1];
+        assert!(breach.as_ref().unwrap().contains("below floor 15"));
+        assert!(marker_written);
+        assert_unwound(&test);
+    }
+
+    #[tokio::test]
+    async fn stop_loss_within_both_thresholds_does_not_unwind() {
+        let (test, results) = run_stop_loss(
+            "within",
+            Some(10.0),
+            Some(15.0),
+            &[20_00000000, 19_00000000, 18_10000000],
+        )
+        .await;
+
+        assert!(results.iter().all(|result| result == &(None, false)));
+        assert!(test.mock_rpc.sent_transactions().is_empty());
+    }
+
+    fn marginfi_deposit_ix(bank: Pubkey, amount: u64) -> Instruction {
+        let mut accounts = (0..3)
+            .map(|_| AccountMeta::new(Pubkey::new_unique(), false))
+            .collect::<Vec<_>>();
+        accounts.push(AccountMeta::new(bank, false));
+        Instruction {
+            program_id: marginfi::id(),
+            accounts,
+            data: [
+                &marginfi::instruction::LendingAccountDeposit::DISCRIMINATOR[..],
+                &amount.to_le_bytes(),
+            ]
+            .concat(),
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_simulates_without_sending() {
+        let (mock_rpc, rpc_client) = new_mock_rpc();
+        let wallet = testkit::new_wallet();
+        let send_config = SendConfig {
+            dry_run: true,
+            ..testkit::new_send_config()
+        };
+        let ix = system_instruction::transfer(&wallet.pubkey, &Pubkey::new_unique(), 1);
+
+        let (_, meta) = force_send_instructions(
+            &rpc_client,
+            &wallet,
+            vec![ix],
+            &vec![],
+            &send_config,
+            "test:transfer",
+        )
+        .await
+        .unwrap();
+        assert!(meta.err.is_none());
+        assert!(mock_rpc.sent_transactions().is_empty());
+        let ledger = send_config.ledger.get();
+        assert_eq!(ledger.transactions, 0);
+        assert!(ledger.transaction_records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn kill_switch_blocks_before_sending() {
+        let (mock_rpc, rpc_client) = new_mock_rpc();
+        let wallet = testkit::new_wallet();
+        let path = std::env::temp_dir().join(format!("mmf-bot-kill-switch-{}", ledger::now()));
+        std::fs::write(&path, "").unwrap();
+        let send_config = SendConfig {
+            risk_guard: Arc::new(RiskGuard::new(RiskLimits {
+                kill_switch_file: Some(path.clone()),
+                ..Default::default()
+            })),
+            ..testkit::new_send_config()
+        };
+        let ix = system_instruction::transfer(&wallet.pubkey, &Pubkey::new_unique(), 1);
+
+        let res = force_send_instructions(
+            &rpc_client,
+            &wallet,
+            vec![ix.clone()],
+            &vec![],
+            &send_config,
+            "test:transfer",
+        )
+        .await;
+        assert!(matches!(res, Err(Error::RiskLimit(_))));
+        assert!(mock_rpc.sent_transactions().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        force_send_instructions(
+            &rpc_client,
+            &wallet,
+            vec![ix],
+            &vec![],
+            &send_config,
+            "test:transfer",
+        )
+        .await
+        .unwrap();
+        assert_eq!(mock_rpc.sent_transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn value_limit_blocks_before_sending() {
+        let (mock_rpc, rpc_client) = new_mock_rpc();
+        let wallet = testkit::new_wallet();
+        let send_config = SendConfig {
+            risk_guard: Arc::new(RiskGuard::new(RiskLimits {
+                max_transaction_usd: Some(1_000.0),
+                ..Default::default()
+            })),
+            ..testkit::new_send_config()
+        };
+
+        // No price is known for the bank, so the deposit can not be valued
+        let res = force_send_instructions(
+            &rpc_client,
+            &wallet,
+            vec![marginfi_deposit_ix(Pubkey::new_unique(), 1)],
+            &vec![],
+            &send_config,
+            "test:deposit",
+        )
+        .await;
+        let Err(Error::RiskLimit(reason)) = res else {
+            panic!("deposit was not blocked");
+        };
+        assert!(
+            reason.starts_with("no price is known for bank"),
+            "{}",
+            reason
+        );
+        assert!(mock_rpc.sent_transactions().is_empty());
+    }
 }
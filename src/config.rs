@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use solana_sdk::commitment_config::CommitmentConfig;
+
+/// Optional, read from the working directory when no path is passed
+pub const DEFAULT_CONFIG_FILE: &'static str = "config.toml";
+
+/// Settings are named like their env vars in lower case, lists can be arrays and `key:value`
+/// lists tables instead of comma separated strings
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Connection
+    pub rpc_url: Option<String>,
+    pub ws_url: Option<String>,
+    pub ws_liveness_timeout: Option<u64>,
+    pub send_rpc_urls: Option<Vec<String>>,
+    pub private_key: Option<String>,
+    pub address_lookup_table: Option<String>,
+    pub metrics_address: Option<String>,
+    pub json_logs: Option<bool>,
+
+    // Position
+    pub collateral_mint: Option<String>,
+    pub collateral_amount: Option<f64>,
+    pub use_existing_collateral: Option<bool>,
+    pub clamp_collateral_to_wallet: Option<bool>,
+    pub marginfi_account: Option<String>,
+    pub marginfi_banks: Option<Vec<String>>,
+    pub meteora_pools: Option<Vec<String>>,
+    pub borrowable_mints: Option<Vec<String>>,
+    pub borrow_split: Option<String>,
+    pub borrow_caps: Option<HashMap<String, f64>>,
+    pub preferred_input_mint: Option<String>,
+    pub pool_slippage_bps: Option<u16>,
+    pub min_sol_balance: Option<f64>,
+    pub target_init_health: Option<f64>,
+    pub create_account: Option<bool>,
+    pub oracle_program_subscribe: Option<bool>,
+    pub best_effort_alts: Option<bool>,
+    pub validate_bank_vaults: Option<bool>,
+    pub skip_constants_validation: Option<bool>,
+    pub unwind_on_shutdown: Option<bool>,
+
+    // Health
+    pub health_trigger: Option<f64>,
+    pub health_target: Option<f64>,
+    pub health_warning: Option<f64>,
+    pub health_critical: Option<f64>,
+
+    // Maintenance intervals in seconds
+    pub health_check_interval: Option<u64>,
+    pub interest_refresh_interval: Option<u64>,
+    pub reward_compound_interval: Option<u64>,
+    pub rebalance_check_interval: Option<u64>,
+    pub oracle_audit_interval: Option<u64>,
+    pub stop_loss_check_interval: Option<u64>,
+    pub reconcile_interval: Option<u64>,
+    pub reconcile_tolerance_bps: Option<u64>,
+
+    // Borrow rebalancing
+    pub rebalance_borrow: Option<bool>,
+    pub rebalance_spread_bps: Option<i64>,
+    pub rebalance_spread_duration: Option<u64>,
+    pub rebalance_min_interval: Option<u64>,
+    pub rebalance_min_notional: Option<f64>,
+
+    // Stop loss
+    pub stop_loss_drawdown_pct: Option<f64>,
+    pub stop_loss_price_floor: Option<f64>,
+    pub stop_loss_marker_file: Option<String>,
+    pub rearm_stop_loss: Option<bool>,
+
+    // Swaps
+    pub jupiter_api_url: Option<String>,
+    pub jupiter_api_key: Option<String>,
+    pub jupiter_max_requests_per_second: Option<f64>,
+    pub only_direct_routes: Option<bool>,
+    pub max_accounts: Option<u64>,
+    pub dexes: Option<Vec<String>>,
+    pub exclude_dexes: Option<Vec<String>>,
+    pub max_swap_slippage_bps: Option<u16>,
+    pub max_swap_requotes: Option<u32>,
+    pub max_swap_shortfall_bps: Option<u16>,
+
+    // Transactions
+    pub dry_run: Option<bool>,
+    pub skip_simulation: Option<bool>,
+    pub preflight: Option<bool>,
+    pub preflight_commitment: Option<String>,
+    pub rpc_max_retries: Option<usize>,
+    pub max_send_retries: Option<u32>,
+    pub tx_poll_interval_ms: Option<u64>,
+    pub tx_max_confirmation_wait: Option<u64>,
+    pub confirmation_commitment: Option<String>,
+    pub read_commitment: Option<String>,
+    pub subscription_commitment: Option<String>,
+    pub cu_limit: Option<u32>,
+    pub cu_price: Option<u64>,
+    pub simulate_cu_limit: Option<bool>,
+    pub cu_limit_margin_percent: Option<u64>,
+    pub estimate_priority_fee: Option<bool>,
+    pub priority_fee_percentile: Option<u8>,
+    pub priority_fee_min: Option<u64>,
+    pub priority_fee_max: Option<u64>,
+    pub jito_tip: Option<bool>,
+    pub jito_tip_lamports: Option<u64>,
+    pub jito_block_engine_url: Option<String>,
+    pub skip_memo: Option<bool>,
+    pub durable_nonce: Option<bool>,
+    pub nonce_account: Option<String>,
+
+    // Risk limits
+    pub max_borrow_usd: Option<f64>,
+    pub max_transaction_usd: Option<f64>,
+    pub max_daily_loss_usd: Option<f64>,
+    pub kill_switch_file: Option<String>,
+
+    // Records and notifications
+    pub ledger_file: Option<String>,
+    pub notification_webhook_url: Option<String>,
+    pub notification_max_per_minute: Option<usize>,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => PathBuf::from(DEFAULT_CONFIG_FILE),
+            None => return Ok((Self::default(), None)),
+        };
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+        let config = toml::from_str(&contents)
+            .map_err(|e| format!("Could not parse config file {}: {}", path.display(), e))?;
+        Ok((config, Some(path)))
+    }
+
+    /// As the env vars would hold them
+    pub fn values(&self) -> HashMap<String, String> {
+        let Ok(Value::Object(values)) = serde_json::to_value(self) else {
+            return HashMap::new();
+        };
+
+        values
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value,
+                    Value::Bool(value) => value.to_string(),
+                    Value::Number(value) => value.to_string(),
+                    Value::Array(values) => values
+                        .iter()
+                        .filter_map(|value| value.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    Value::Object(values) => values
+                        .iter()
+                        .map(|(key, value)| format!("{}:{}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    _ => return None,
+                };
+                Some((key.to_uppercase(), value))
+            })
+            .collect()
+    }
+}
+
+/// Lower commitments see state sooner but can show state of a fork which is dropped later.
+/// Transactions are only treated as landed at `confirmation`, which has to be at least as
+/// strong as `reads`, so a read never observes a transaction the bot still treats as pending.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommitmentPolicy {
+    pub reads: CommitmentConfig,
+    /// Signature subscriptions notify at `confirmation`
+    pub subscriptions: CommitmentConfig,
+    pub confirmation: CommitmentConfig,
+}
+
+impl Default for CommitmentPolicy {
+    fn default() -> Self {
+        Self {
+            reads: CommitmentConfig::confirmed(),
+            subscriptions: CommitmentConfig::confirmed(),
+            confirmation: CommitmentConfig::confirmed(),
+        }
+    }
+}
+
+impl CommitmentPolicy {
+    pub fn parse_commitment(commitment: &str) -> Option<CommitmentConfig> {
+        match commitment {
+            "processed" => Some(CommitmentConfig::processed()),
+            "confirmed" => Some(CommitmentConfig::confirmed()),
+            "finalized" => Some(CommitmentConfig::finalized()),
+            _ => None,
+        }
+    }
+
+    fn get_rank(commitment: CommitmentConfig) -> u8 {
+        if commitment.is_finalized() {
+            2
+        } else if commitment.is_confirmed() {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        Self::get_rank(self.confirmation) >= Self::get_rank(self.reads)
+    }
+
+    /// Blockhashes of transactions are fetched at `reads`, but never at processed, whose
+    /// blockhash can belong to a dropped fork
+    pub fn blockhash(&self) -> CommitmentConfig {
+        if self.reads.is_finalized() {
+            self.reads
+        } else {
+            CommitmentConfig::confirmed()
+        }
+    }
+}
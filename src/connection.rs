@@ -1,14 +1,24 @@
-use std::{str::FromStr, sync::Arc, time::SystemTime};
+use std::{
+    collections::HashMap,
+    future::ready,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anchor_lang::{
     prelude::{AccountMeta, Pubkey},
     AccountDeserialize, Discriminator,
 };
 use base64::{engine::general_purpose, Engine};
-use futures_util::StreamExt;
+use fixed::types::I80F48;
+use futures_util::{
+    stream::{pending, select_all, BoxStream},
+    StreamExt,
+};
 use marginfi::{constants::PYTH_ID, state::marginfi_account::MarginfiAccount};
 use serde::{de::Visitor, Deserialize};
-use solana_account_decoder::{UiAccount, UiAccountData, UiAccountEncoding};
+use solana_account_decoder::{UiAccount, UiAccountData, UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
     nonblocking::rpc_client::RpcClient,
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
@@ -19,13 +29,29 @@ use solana_sdk::{
     commitment_config::CommitmentConfig, instruction::Instruction,
 };
 use switchboard_v2::AggregatorAccountData;
-use tokio::{sync::mpsc, task::JoinHandle};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
+};
+use tracing::{debug, info, warn};
 
 use crate::{
-    addresses::{MarginfiBank, MarginfiBankOracle},
+    addresses::{
+        MarginfiBank, MarginfiBankOracle, MeteoraDynamicPool, MeteoraFarmMeta,
+        SharedStaticAddresses, StaticAddresses,
+    },
     constants,
-    state::{PythPriceFeed, StateUpdate, SwitchboardPriceFeed},
-    utils::websocket_client::WebsocketClient,
+    shutdown::Shutdown,
+    state::{
+        CurrentSlot, MarginfiAccountWithBanks, MeteoraFarmPool, MeteoraFarmUser, MeteoraPoolState,
+        OraclesState, PythPriceFeed, StateUpdate, SwitchboardPriceFeed,
+    },
+    utils::{
+        account_fetcher::AccountFetcher,
+        jupiter_client::{self, JupiterClient},
+        websocket_client::{AccountNotification, WebsocketClient},
+    },
     Error, Wallet,
 };
 
@@ -63,7 +89,10 @@ impl<'a> AccountData<'a> {
     }
 
     pub fn deserialize<T: AccountDeserialize + Discriminator>(data: &Vec<u8>) -> Result<T, Error> {
-        T::try_deserialize(&mut &data[..]).map_err(|_| Error::UnableToDeserialize)
+        T::try_deserialize(&mut &data[..]).map_err(|_| Error::UnableToDeserialize {
+            expected: std::any::type_name::<T>(),
+            account: None,
+        })
     }
 
     pub fn parse<T: AccountDeserialize + Discriminator>(&self) -> Result<T, Error> {
@@ -84,15 +113,21 @@ pub enum Update {
 
 pub type SubscriptionHandle = JoinHandle<Result<(), Error>>;
 
-fn new_margin_fi_account_config(wallet: &Arc<Wallet>) -> RpcProgramAccountsConfig {
+fn new_margin_fi_account_config(
+    wallet: &Arc<Wallet>,
+    commitment: CommitmentConfig,
+) -> RpcProgramAccountsConfig {
     RpcProgramAccountsConfig {
-        filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
-            40,
-            wallet.pubkey.to_bytes().to_vec(),
-        ))]),
+        filters: Some(vec![
+            RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
+                8,
+                constants::marginfi::group::id().to_bytes().to_vec(),
+            )),
+            RpcFilterType::Memcmp(Memcmp::new_raw_bytes(40, wallet.pubkey.to_bytes().to_vec())),
+        ]),
         account_config: RpcAccountInfoConfig {
             encoding: Some(UiAccountEncoding::Base64),
-            commitment: Some(CommitmentConfig::confirmed()),
+            commitment: Some(commitment),
             data_slice: None,
             min_context_slot: None,
         },
@@ -103,6 +138,7 @@ fn new_margin_fi_account_config(wallet: &Arc<Wallet>) -> RpcProgramAccountsConfi
 fn new_config_by_discriminator(
     discriminator: Vec<u8>,
     filters: Option<Vec<RpcFilterType>>,
+    commitment: CommitmentConfig,
 ) -> RpcProgramAccountsConfig {
     let mut config = RpcProgramAccountsConfig {
         filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
@@ -111,7 +147,7 @@ fn new_config_by_discriminator(
         ))]),
         account_config: RpcAccountInfoConfig {
             encoding: Some(UiAccountEncoding::Base64),
-            commitment: Some(CommitmentConfig::confirmed()),
+            commitment: Some(commitment),
             data_slice: None,
             min_context_slot: None,
         },
@@ -128,15 +164,222 @@ fn new_config_by_discriminator(
     config
 }
 
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+pub async fn get_multiple_accounts_chunked<F: AccountFetcher>(
+    rpc_client: &F,
+    addresses: &[Pubkey],
+) -> Result<Vec<Option<Account>>, Error> {
+    let mut accounts = Vec::with_capacity(addresses.len());
+
+    for chunk in addresses.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        accounts.extend(rpc_client.get_multiple_accounts(chunk).await?);
+    }
+
+    Ok(accounts)
+}
+
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+pub fn parse_token_account_amount(data: &[u8]) -> Result<u64, Error> {
+    data.get(TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(Error::InvalidTokenAccount)
+}
+
+/// Token accounts which do not exist yet are returned as zero
+pub async fn fetch_token_balances<F: AccountFetcher>(
+    rpc_client: &F,
+    static_addresses: &StaticAddresses,
+    mints: &[Pubkey],
+) -> Result<HashMap<Pubkey, u64>, Error> {
+    let token_accounts = mints
+        .iter()
+        .map(|mint| static_addresses.get_token_account(mint))
+        .collect::<Result<Vec<Pubkey>, Error>>()?;
+    let accounts = get_multiple_accounts_chunked(rpc_client, &token_accounts).await?;
+
+    mints
+        .iter()
+        .zip(accounts.iter())
+        .map(|(mint, ai)| {
+            let amount = match ai {
+                Some(ai) => parse_token_account_amount(&ai.data)?,
+                None => 0,
+            };
+            Ok((*mint, amount))
+        })
+        .collect()
+}
+
+const MINT_SUPPLY_OFFSET: usize = 36;
+
+pub fn parse_mint_supply(data: &[u8]) -> Result<u64, Error> {
+    data.get(MINT_SUPPLY_OFFSET..MINT_SUPPLY_OFFSET + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(Error::UnableToDeserialize {
+            expected: "mint supply",
+            account: None,
+        })
+}
+
+const MINT_DECIMALS_OFFSET: usize = 44;
+
+pub fn parse_mint_decimals(data: &[u8]) -> Result<u8, Error> {
+    data.get(MINT_DECIMALS_OFFSET)
+        .copied()
+        .ok_or(Error::UnableToDeserialize {
+            expected: "mint decimals",
+            account: None,
+        })
+}
+
+/// Mints which do not exist are skipped
+pub async fn fetch_mints_decimals<F: AccountFetcher>(
+    rpc_client: &F,
+    mints: &[Pubkey],
+) -> Result<HashMap<Pubkey, u8>, Error> {
+    let accounts = get_multiple_accounts_chunked(rpc_client, mints).await?;
+
+    mints
+        .iter()
+        .zip(accounts.iter())
+        .filter_map(|(mint, ai)| ai.as_ref().map(|ai| (mint, ai)))
+        .map(|(mint, ai)| {
+            let decimals = parse_mint_decimals(&ai.data).map_err(|e| e.with_account(*mint))?;
+            Ok((*mint, decimals))
+        })
+        .collect()
+}
+
+pub async fn fetch_mint_decimals<F: AccountFetcher>(
+    rpc_client: &F,
+    mint: &Pubkey,
+) -> Result<u8, Error> {
+    let ai = rpc_client
+        .get_multiple_accounts(&[*mint])
+        .await?
+        .pop()
+        .flatten()
+        .ok_or(Error::UnableToFetchAccount(*mint))?;
+    parse_mint_decimals(&ai.data).map_err(|e| e.with_account(*mint))
+}
+
+pub async fn fetch_meteora_pool_state<F: AccountFetcher>(
+    rpc_client: &F,
+    pool: &MeteoraDynamicPool,
+) -> Result<MeteoraPoolState, Error> {
+    let addresses = [
+        pool.a_vault_lp,
+        pool.b_vault_lp,
+        pool.a_vault,
+        pool.b_vault,
+        pool.vault_a_lp_mint,
+        pool.vault_b_lp_mint,
+        pool.lp_mint,
+    ];
+    let ais = rpc_client
+        .get_multiple_accounts(&addresses)
+        .await?
+        .into_iter()
+        .zip(addresses.iter())
+        .map(|(ai, address)| ai.ok_or(Error::UnableToFetchAccount(*address)))
+        .collect::<Result<Vec<Account>, Error>>()?;
+    let supply =
+        |i: usize| parse_mint_supply(&ais[i].data).map_err(|e| e.with_account(addresses[i]));
+
+    let a_vault: meteora_vault::state::Vault = AccountData::from(&ais[2])
+        .parse()
+        .map_err(|e| e.with_account(pool.a_vault))?;
+    let b_vault: meteora_vault::state::Vault = AccountData::from(&ais[3])
+        .parse()
+        .map_err(|e| e.with_account(pool.b_vault))?;
+
+    Ok(MeteoraPoolState {
+        a_vault_lp_amount: parse_token_account_amount(&ais[0].data)?,
+        b_vault_lp_amount: parse_token_account_amount(&ais[1].data)?,
+        a_vault_total_amount: a_vault.total_amount,
+        b_vault_total_amount: b_vault.total_amount,
+        a_vault_lp_supply: supply(4)?,
+        b_vault_lp_supply: supply(5)?,
+        lp_supply: supply(6)?,
+    })
+}
+
+/// As `(mint, token program, token account)`
+pub async fn find_missing_token_accounts<F: AccountFetcher>(
+    rpc_client: &F,
+    static_addresses: &StaticAddresses,
+) -> Result<Vec<(Pubkey, Pubkey, Pubkey)>, Error> {
+    let token_accounts = static_addresses
+        .wallet_token_accounts
+        .iter()
+        .map(|(mint, (token_program, token_account))| (*mint, *token_program, *token_account))
+        .collect::<Vec<_>>();
+    let accounts = get_multiple_accounts_chunked(
+        rpc_client,
+        &token_accounts
+            .iter()
+            .map(|(_, _, token_account)| *token_account)
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+
+    Ok(token_accounts
+        .into_iter()
+        .zip(accounts.iter())
+        .filter(|(_, ai)| ai.is_none())
+        .map(|(token_account, _)| token_account)
+        .collect())
+}
+
+pub async fn fetch_mint_token_programs<F: AccountFetcher>(
+    rpc_client: &F,
+    mints: &[Pubkey],
+) -> Result<HashMap<Pubkey, Pubkey>, Error> {
+    let accounts = get_multiple_accounts_chunked(rpc_client, mints).await?;
+
+    mints
+        .iter()
+        .zip(accounts.iter())
+        .map(|(mint, ai)| match ai {
+            Some(ai)
+                if ai.owner == constants::spl_token::id()
+                    || ai.owner == constants::token_2022::id() =>
+            {
+                Ok((*mint, ai.owner))
+            }
+            Some(ai) => {
+                warn!("Mint {} is owned by unknown program {}", mint, ai.owner);
+                Err(Error::UnableToDeserialize {
+                    expected: "token mint",
+                    account: Some(*mint),
+                })
+            }
+            None => {
+                warn!("Mint does not exist: {}", mint);
+                Err(Error::UnableToFetchAccount(*mint))
+            }
+        })
+        .collect()
+}
+
+pub async fn fetch_lamports_balance(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+) -> Result<u64, Error> {
+    Ok(rpc_client.get_balance(&wallet.pubkey).await?)
+}
+
 pub struct MeteoraPoolsAndVaults {
     pub pools: Vec<(Pubkey, meteora::state::Pool)>,
     pub vaults: Vec<(Pubkey, meteora_vault::state::Vault)>,
 }
 
-pub async fn fetch_meteora_pools_and_vaults(
-    rpc_client: &Arc<RpcClient>,
+pub async fn fetch_meteora_pools_and_vaults<F: AccountFetcher>(
+    rpc_client: &F,
+    pools_addresses: &[Pubkey],
 ) -> Result<MeteoraPoolsAndVaults, Error> {
-    let pools_addresses = vec![constants::meteora::acusd_usdc_pool::id()];
     let mut vaults_addresses = vec![];
 
     let mut pools_and_vaults = MeteoraPoolsAndVaults {
@@ -144,13 +387,15 @@ pub async fn fetch_meteora_pools_and_vaults(
         vaults: vec![],
     };
 
-    let pools_ais = rpc_client.get_multiple_accounts(&pools_addresses).await?;
+    let pools_ais = rpc_client.get_multiple_accounts(pools_addresses).await?;
 
     for (i, ai) in pools_ais.iter().enumerate() {
         let address = pools_addresses[i];
 
         if let Some(ai) = ai {
-            let pool: meteora::state::Pool = AccountData::from(ai).parse()?;
+            let pool: meteora::state::Pool = AccountData::from(ai)
+                .parse()
+                .map_err(|e| e.with_account(address))?;
 
             if !vaults_addresses.contains(&pool.a_vault) {
                 vaults_addresses.push(pool.a_vault);
@@ -161,8 +406,8 @@ pub async fn fetch_meteora_pools_and_vaults(
 
             pools_and_vaults.pools.push((address, pool));
         } else {
-            println!("Meteora pool does not exist: {}", address);
-            return Err(Error::UnableToFetchAccount);
+            warn!("Meteora pool does not exist: {}", address);
+            return Err(Error::UnableToFetchAccount(address));
         }
     }
 
@@ -172,168 +417,974 @@ pub async fn fetch_meteora_pools_and_vaults(
         let address = vaults_addresses[i];
 
         if let Some(ai) = ai {
-            pools_and_vaults
-                .vaults
-                .push((address, AccountData::from(ai).parse()?))
+            pools_and_vaults.vaults.push((
+                address,
+                AccountData::from(ai)
+                    .parse()
+                    .map_err(|e| e.with_account(address))?,
+            ))
         } else {
-            println!("Meteora vault does not exist: {}", address);
-            return Err(Error::UnableToFetchAccount);
+            warn!("Meteora vault does not exist: {}", address);
+            return Err(Error::UnableToFetchAccount(address));
         }
     }
 
     Ok(pools_and_vaults)
 }
 
-pub async fn fetch_marginfi_account(
-    rpc_client: &Arc<RpcClient>,
+fn log_marginfi_account(address: &Pubkey, account: &MarginfiAccount) {
+    let active_balances = account
+        .lending_account
+        .balances
+        .iter()
+        .filter(|balance| balance.active)
+        .collect::<Vec<_>>();
+
+    info!(
+        "Marginfi account {} ({} active balances)",
+        address,
+        active_balances.len()
+    );
+    for balance in active_balances {
+        debug!(
+            "    bank {}: asset shares {}, liability shares {}",
+            balance.bank_pk,
+            I80F48::from_bits(balance.asset_shares.value),
+            I80F48::from_bits(balance.liability_shares.value),
+        );
+    }
+}
+
+fn is_marginfi_account_empty(account: &MarginfiAccount) -> bool {
+    !account
+        .lending_account
+        .balances
+        .iter()
+        .any(|balance| balance.active)
+}
+
+/// Without a pinned address, the only non-empty account owned by the wallet is selected
+pub async fn fetch_marginfi_account<F: AccountFetcher>(
+    rpc_client: &F,
     wallet: &Arc<Wallet>,
+    pinned_address: Option<Pubkey>,
 ) -> Result<(Pubkey, MarginfiAccount), Error> {
-    let config = new_margin_fi_account_config(wallet);
+    if let Some(address) = pinned_address {
+        let ai = rpc_client.get_account(&address).await?;
+        let account: MarginfiAccount = AccountData::from(&ai)
+            .parse()
+            .map_err(|e| e.with_account(address))?;
+
+        if account.authority != wallet.pubkey || account.group != constants::marginfi::group::id() {
+            warn!(
+                "Marginfi account {} is not owned by {} in group {}",
+                address,
+                wallet.pubkey,
+                constants::marginfi::group::id()
+            );
+            return Err(Error::ForeignMarginfiAccount(address));
+        }
+
+        log_marginfi_account(&address, &account);
+        return Ok((address, account));
+    }
+
+    let config = new_margin_fi_account_config(wallet, rpc_client.commitment());
 
     let accounts = rpc_client
         .get_program_accounts_with_config(&marginfi::id(), config)
         .await?;
 
     if accounts.is_empty() {
-        println!(
+        warn!(
             "Marginfi account for {} does not exist",
             wallet.pubkey.to_string()
         );
-        return Err(Error::UnableToFetchAccount);
+        return Err(Error::MissingMarginfiAccount);
     }
 
-    Ok((accounts[0].0, AccountData::from(&accounts[0].1).parse()?))
+    let mut accounts = accounts
+        .iter()
+        .map(|(address, ai)| {
+            AccountData::from(ai)
+                .parse::<MarginfiAccount>()
+                .map(|account| (*address, account))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    // RPC ordering is not stable
+    accounts.sort_by_key(|(address, _)| *address);
+
+    info!("Found {} marginfi accounts", accounts.len());
+    accounts
+        .iter()
+        .for_each(|(address, account)| log_marginfi_account(address, account));
+
+    let non_empty_count = accounts
+        .iter()
+        .filter(|(_, account)| !is_marginfi_account_empty(account))
+        .count();
+
+    if non_empty_count > 1 {
+        warn!("Multiple non-empty marginfi accounts found, set MARGINFI_ACCOUNT to select one");
+        return Err(Error::AmbiguousMarginfiAccount);
+    }
+
+    let i = accounts
+        .iter()
+        .position(|(_, account)| !is_marginfi_account_empty(account))
+        .unwrap_or(0);
+
+    Ok(accounts.swap_remove(i))
 }
 
-pub async fn fetch_marginfi_banks(
-    rpc_client: &Arc<RpcClient>,
+pub async fn fetch_marginfi_account_with_banks<F: AccountFetcher>(
+    rpc_client: &F,
+    wallet: &Arc<Wallet>,
+    marginfi_account: &Pubkey,
+) -> Result<MarginfiAccountWithBanks, Error> {
+    let (_, account) = fetch_marginfi_account(rpc_client, wallet, Some(*marginfi_account)).await?;
+    let bank_addresses = account
+        .lending_account
+        .balances
+        .iter()
+        .filter(|balance| balance.active)
+        .map(|balance| balance.bank_pk)
+        .collect::<Vec<_>>();
+    let banks = fetch_marginfi_banks(rpc_client, &[], &bank_addresses).await?;
+
+    Ok(MarginfiAccountWithBanks::new(account, banks))
+}
+
+pub async fn fetch_meteora_farms<F: AccountFetcher>(
+    rpc_client: &F,
+    farm_addresses: &[Pubkey],
+) -> Result<Vec<(Pubkey, MeteoraFarmPool)>, Error> {
+    let farm_ais = get_multiple_accounts_chunked(rpc_client, farm_addresses).await?;
+
+    farm_addresses
+        .iter()
+        .zip(farm_ais.iter())
+        .map(|(address, ai)| match ai {
+            Some(ai) if ai.owner == constants::meteora::farm::id() => Ok((
+                *address,
+                AccountData::from(ai)
+                    .parse()
+                    .map_err(|e| e.with_account(*address))?,
+            )),
+            Some(ai) => {
+                warn!(
+                    "Meteora farm {} is owned by {} instead of the farm program",
+                    address, ai.owner
+                );
+                Err(Error::InvalidMeteoraFarm)
+            }
+            None => {
+                warn!("Meteora farm does not exist: {}", address);
+                Err(Error::InvalidMeteoraFarm)
+            }
+        })
+        .collect()
+}
+
+/// Returns zeroed state when the farm user account was not created yet
+pub async fn fetch_meteora_farm_user<F: AccountFetcher>(
+    rpc_client: &F,
+    farm_meta: &MeteoraFarmMeta,
+) -> Result<MeteoraFarmUser, Error> {
+    let ai = rpc_client
+        .get_multiple_accounts(&[farm_meta.user_account])
+        .await?
+        .pop()
+        .flatten();
+
+    match ai {
+        Some(ai) => AccountData::from(&ai).parse(),
+        None => Ok(MeteoraFarmUser::default()),
+    }
+}
+
+/// Farm user account is created before the first deposit to the farm
+pub async fn is_meteora_farm_user_initialized<F: AccountFetcher>(
+    rpc_client: &F,
+    farm_meta: &MeteoraFarmMeta,
+) -> Result<bool, Error> {
+    let ai = rpc_client
+        .get_multiple_accounts(&[farm_meta.user_account])
+        .await?
+        .pop()
+        .flatten();
+    Ok(ai.is_some())
+}
+
+/// Bank mint is stored right after the discriminator
+const MARGINFI_BANK_MINT_OFFSET: usize = 8;
+
+/// Only the bank mints are fetched first, then full accounts of banks with one of `mints`
+/// or listed in `required_banks`
+pub async fn fetch_marginfi_banks<F: AccountFetcher>(
+    rpc_client: &F,
+    mints: &[Pubkey],
+    required_banks: &[Pubkey],
 ) -> Result<Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>, Error> {
-    let config = new_config_by_discriminator(
+    let mut config = new_config_by_discriminator(
         marginfi::state::marginfi_group::Bank::DISCRIMINATOR.to_vec(),
         Some(vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
             41,
             constants::marginfi::group::id().to_bytes().to_vec(),
         ))]),
+        rpc_client.commitment(),
     );
-    let accounts = rpc_client
+    config.account_config.data_slice = Some(UiDataSliceConfig {
+        offset: MARGINFI_BANK_MINT_OFFSET,
+        length: 32,
+    });
+
+    let start = Instant::now();
+    let sliced_accounts = match rpc_client
         .get_program_accounts_with_config(&marginfi::id(), config)
-        .await?;
+        .await
+    {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            warn!(
+                "Unable to enumerate marginfi banks ({:?}), falling back to static bank list",
+                e
+            );
+            return fetch_static_marginfi_banks(rpc_client, required_banks).await;
+        }
+    };
+    debug!(
+        "Enumerated {} marginfi banks in {:?}",
+        sliced_accounts.len(),
+        start.elapsed()
+    );
 
-    accounts
+    let mut bank_addresses = vec![];
+    for (address, account) in sliced_accounts.iter() {
+        let mint = account
+            .data
+            .get(..32)
+            .and_then(|bytes| Pubkey::try_from(bytes).ok())
+            .ok_or(Error::UnableToDeserialize {
+                expected: "bank mint",
+                account: Some(*address),
+            })?;
+
+        if mints.contains(&mint) || required_banks.contains(address) {
+            bank_addresses.push(*address);
+        }
+    }
+
+    let start = Instant::now();
+    let accounts = get_multiple_accounts_chunked(rpc_client, &bank_addresses).await?;
+    debug!(
+        "Fetched {} marginfi banks in {:?}",
+        bank_addresses.len(),
+        start.elapsed()
+    );
+
+    bank_addresses
         .iter()
+        .zip(accounts.iter())
         .map(|(address, account)| {
+            let account = account
+                .as_ref()
+                .ok_or(Error::UnableToFetchAccount(*address))?;
             let bank = AccountData::from(account).parse();
             bank.map(|bank| (*address, bank))
+                .map_err(|e| e.with_account(*address))
         })
         .collect()
 }
 
+/// For RPCs without getProgramAccounts support
+async fn fetch_static_marginfi_banks<F: AccountFetcher>(
+    rpc_client: &F,
+    additional_banks: &[Pubkey],
+) -> Result<Vec<(Pubkey, marginfi::state::marginfi_group::Bank)>, Error> {
+    let mut bank_addresses = vec![
+        constants::marginfi::banks::bsol::id(),
+        constants::marginfi::banks::usdc::id(),
+        constants::marginfi::banks::uxd::id(),
+        constants::marginfi::banks::usdt::id(),
+    ];
+    additional_banks.iter().for_each(|address| {
+        if !bank_addresses.contains(address) {
+            bank_addresses.push(*address);
+        }
+    });
+
+    let accounts = get_multiple_accounts_chunked(rpc_client, &bank_addresses).await?;
+
+    bank_addresses
+        .iter()
+        .zip(accounts.iter())
+        .map(|(address, account)| {
+            let account = account
+                .as_ref()
+                .ok_or(Error::UnableToFetchAccount(*address))?;
+            let bank: marginfi::state::marginfi_group::Bank = AccountData::from(account)
+                .parse()
+                .map_err(|e| e.with_account(*address))?;
+
+            if bank.group != constants::marginfi::group::id() {
+                warn!("Bank {} does not belong to the marginfi group", address);
+                return Err(Error::InvalidMarginfiBank);
+            }
+
+            Ok((*address, bank))
+        })
+        .collect()
+}
+
+pub const PYTH_SUBSCRIPTION: &'static str = "pyth oracles";
+pub const SWITCHBOARD_SUBSCRIPTION: &'static str = "switchboard oracles";
+pub const STATIC_ACCOUNTS_SUBSCRIPTION: &'static str = "static accounts";
+pub const SLOT_SUBSCRIPTION: &'static str = "slots";
+
+/// Drops reconnect events received before the subscription was made, the subscription
+/// was made on the new connection already and a later reconnect ends its stream anyway
+fn skip_reconnect_events(reconnect_events: &mut broadcast::Receiver<u64>) {
+    loop {
+        match reconnect_events.try_recv() {
+            Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+async fn subscribe_to_accounts<'a>(
+    ws_client: &'a WebsocketClient,
+    addresses: &[Pubkey],
+) -> Result<BoxStream<'a, AccountNotification>, Error> {
+    let mut streams = vec![];
+
+    for address in addresses {
+        let (_, stream) = ws_client
+            .account_subscribe(*address, RpcAccountInfoConfig::default())
+            .await?;
+        streams.push(stream);
+    }
+
+    Ok(select_all(streams).boxed())
+}
+
+async fn subscribe_to_program_accounts<'a>(
+    ws_client: &'a WebsocketClient,
+    program_id: Pubkey,
+    config: RpcProgramAccountsConfig,
+    addresses: &[Pubkey],
+) -> Result<BoxStream<'a, AccountNotification>, Error> {
+    let watched = addresses.to_vec();
+    let (_, stream) = ws_client.program_subscribe(program_id, config).await?;
+
+    Ok(stream
+        .filter(move |notification| ready(watched.contains(&notification.pubkey)))
+        .boxed())
+}
+
+const MAX_CONSECUTIVE_ORACLE_PARSE_FAILURES: u32 = 10;
+
+/// So a single malformed notification does not kill the subscription
+#[derive(Default)]
+struct OracleParseFailures(HashMap<Pubkey, u32>);
+
+impl OracleParseFailures {
+    fn handle<T>(&mut self, pubkey: &Pubkey, res: Result<T, Error>) -> Result<Option<T>, Error> {
+        match res {
+            Ok(value) => {
+                self.0.remove(pubkey);
+                Ok(Some(value))
+            }
+            Err(e) => {
+                let failures = self.0.entry(*pubkey).or_default();
+                *failures += 1;
+                warn!(
+                    "Unable to parse oracle {} ({} consecutive failures): {:?}",
+                    pubkey, failures, e
+                );
+
+                if *failures >= MAX_CONSECUTIVE_ORACLE_PARSE_FAILURES {
+                    Err(e)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// Pyth prices published more slots ago than this are dropped, ~60s of 400ms slots
+pub const MAX_PYTH_AGE_SLOTS: u64 = 150;
+/// Pyth prices older than this are dropped while the current slot is not known
+const MAX_PYTH_AGE_SECS: u64 = 60;
+
+fn parse_pyth_price_feed(
+    pubkey: &Pubkey,
+    current_slot: &CurrentSlot,
+    bytes: &[u8],
+) -> Result<Option<PythPriceFeed>, Error> {
+    let price_account = pyth_sdk_solana::state::load_price_account(bytes)
+        .map_err(|_| Error::UnableToParsePythOracle)?;
+    let publish_slot = price_account.agg.pub_slot;
+    let price_feed = price_account.to_price_feed(pubkey);
+
+    let is_stale = match current_slot.get_age(publish_slot) {
+        Some(age) => age > MAX_PYTH_AGE_SLOTS,
+        None => {
+            let now_ts = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            price_feed
+                .get_ema_price_no_older_than(now_ts as i64, MAX_PYTH_AGE_SECS)
+                .is_none()
+        }
+    };
+
+    Ok((!is_stale).then(|| PythPriceFeed {
+        price: price_feed.get_ema_price_unchecked(),
+        last_update_slot: publish_slot,
+    }))
+}
+
+fn parse_switchboard_price_feed(data: AccountData) -> Result<SwitchboardPriceFeed, Error> {
+    let aggregator_account = data
+        .parse::<AggregatorAccountData>()
+        .map_err(|_| Error::UnableToParseSwitchboardOracle)?;
+    Ok(SwitchboardPriceFeed::from(&aggregator_account))
+}
+
+async fn sync_pyth_oracles(
+    rpc_client: &Arc<RpcClient>,
+    watched_oracles: &[Pubkey],
+    current_slot: &CurrentSlot,
+    state_update_sender: &mpsc::UnboundedSender<StateUpdate>,
+) -> Result<(), Error> {
+    current_slot.update(rpc_client.get_slot().await?);
+    let accounts = get_multiple_accounts_chunked(rpc_client, watched_oracles).await?;
+
+    for (pubkey, ai) in watched_oracles.iter().zip(accounts.iter()) {
+        let Some(ai) = ai else {
+            return Err(Error::UnableToFetchAccount(*pubkey));
+        };
+
+        if let Some(price_feed) = parse_pyth_price_feed(pubkey, current_slot, &ai.data)? {
+            state_update_sender
+                .send(StateUpdate::PythOracle((*pubkey, price_feed)))
+                .ok();
+        }
+    }
+
+    Ok(())
+}
+
+async fn sync_switchboard_oracles(
+    rpc_client: &Arc<RpcClient>,
+    watched_oracles: &[Pubkey],
+    state_update_sender: &mpsc::UnboundedSender<StateUpdate>,
+) -> Result<(), Error> {
+    let accounts = get_multiple_accounts_chunked(rpc_client, watched_oracles).await?;
+
+    for (pubkey, ai) in watched_oracles.iter().zip(accounts.iter()) {
+        let Some(ai) = ai else {
+            return Err(Error::UnableToFetchAccount(*pubkey));
+        };
+        let price_feed = parse_switchboard_price_feed(AccountData::from(ai))?;
+
+        state_update_sender
+            .send(StateUpdate::SwitchboardOracle((*pubkey, price_feed)))
+            .ok();
+    }
+
+    Ok(())
+}
+
+/// For read-only modes which do not subscribe
+pub async fn fetch_oracles_state(
+    rpc_client: &Arc<RpcClient>,
+    banks: &HashMap<Pubkey, MarginfiBank>,
+) -> Result<Arc<OraclesState>, Error> {
+    let (pyth_oracles, switchboard_oracles): (Vec<_>, Vec<_>) = banks
+        .values()
+        .map(|bank| &bank.oracle)
+        .partition(|oracle| matches!(oracle, MarginfiBankOracle::Pyth(_)));
+    let pyth_oracles = pyth_oracles
+        .iter()
+        .flat_map(|oracle| oracle.addresses().iter().copied())
+        .collect::<Vec<_>>();
+    let switchboard_oracles = switchboard_oracles
+        .iter()
+        .flat_map(|oracle| oracle.addresses().iter().copied())
+        .collect::<Vec<_>>();
+
+    let (state_update_sender, mut state_update_receiver) = mpsc::unbounded_channel();
+    let oracles_state = Arc::new(OraclesState::new());
+
+    sync_pyth_oracles(
+        rpc_client,
+        &pyth_oracles,
+        &oracles_state.current_slot,
+        &state_update_sender,
+    )
+    .await?;
+    sync_switchboard_oracles(rpc_client, &switchboard_oracles, &state_update_sender).await?;
+    drop(state_update_sender);
+    while let Some(update) = state_update_receiver.recv().await {
+        oracles_state.apply_update(update).await;
+    }
+
+    Ok(oracles_state)
+}
+
+/// Missing prices are fetched from the RPC after it
+const ORACLES_READY_TIMEOUT: Duration = Duration::from_secs(30);
+const ORACLES_READY_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+const ORACLES_READY_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn get_missing_oracles(
+    banks: &HashMap<Pubkey, MarginfiBank>,
+    oracles_state: &OraclesState,
+) -> Vec<Pubkey> {
+    let pyth_oracles = oracles_state.pyth_oracles.lock().await;
+    let switchboard_oracles = oracles_state.switchboard_oracles.lock().await;
+
+    let mut missing = banks
+        .values()
+        .flat_map(|bank| {
+            bank.oracle
+                .addresses()
+                .iter()
+                .filter(|address| match &bank.oracle {
+                    MarginfiBankOracle::Pyth(_) => !pyth_oracles.iter().any(|(oracle, feed)| {
+                        oracle == *address
+                            && oracles_state
+                                .current_slot
+                                .get_age(feed.last_update_slot)
+                                .is_none_or(|age| age <= MAX_PYTH_AGE_SLOTS)
+                    }),
+                    MarginfiBankOracle::Switchboard(_) => !switchboard_oracles
+                        .iter()
+                        .any(|(oracle, _)| oracle == *address),
+                })
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    missing.sort();
+    missing.dedup();
+    missing
+}
+
+pub async fn wait_for_oracles(
+    rpc_client: &Arc<RpcClient>,
+    banks: &HashMap<Pubkey, MarginfiBank>,
+    oracles_state: &OraclesState,
+) -> Result<(), Error> {
+    let started_at = Instant::now();
+    let mut logged_at = Instant::now();
+
+    let missing = loop {
+        let missing = get_missing_oracles(banks, oracles_state).await;
+        if missing.is_empty() {
+            info!("Oracles are ready after {:?}", started_at.elapsed());
+            return Ok(());
+        }
+        if started_at.elapsed() >= ORACLES_READY_TIMEOUT {
+            break missing;
+        }
+        if logged_at.elapsed() >= ORACLES_READY_LOG_INTERVAL {
+            info!("Waiting for prices of {} oracles", missing.len());
+            logged_at = Instant::now();
+        }
+        sleep(ORACLES_READY_CHECK_INTERVAL).await;
+    };
+
+    warn!(
+        "No prices of {} oracles after {:?}, fetching them",
+        missing.len(),
+        ORACLES_READY_TIMEOUT
+    );
+    let (pyth_oracles, switchboard_oracles): (Vec<_>, Vec<_>) =
+        missing.into_iter().partition(|address| {
+            banks.values().any(|bank| {
+                matches!(&bank.oracle, MarginfiBankOracle::Pyth(addresses) if addresses.contains(address))
+            })
+        });
+    let (state_update_sender, mut state_update_receiver) = mpsc::unbounded_channel();
+    sync_pyth_oracles(
+        rpc_client,
+        &pyth_oracles,
+        &oracles_state.current_slot,
+        &state_update_sender,
+    )
+    .await?;
+    sync_switchboard_oracles(rpc_client, &switchboard_oracles, &state_update_sender).await?;
+    drop(state_update_sender);
+    while let Some(update) = state_update_receiver.recv().await {
+        oracles_state.apply_update(update).await;
+    }
+
+    let missing = get_missing_oracles(banks, oracles_state).await;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::StaleOracles(missing))
+    }
+}
+
+const SLOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The RPC is polled while the subscription is unavailable or silent
+pub fn track_current_slot(
+    rpc_client: Arc<RpcClient>,
+    ws_client: Arc<WebsocketClient>,
+    current_slot: Arc<CurrentSlot>,
+    shutdown: Shutdown,
+) -> SubscriptionHandle {
+    tokio::spawn(async move {
+        let mut reconnect_events = ws_client.reconnect_events();
+        let mut poll_interval = interval(SLOT_POLL_INTERVAL);
+
+        loop {
+            // A subscription which fails or waits for a reconnect is retried after the
+            // next reconnect, the slot is polled until then
+            let mut stream = match timeout(SLOT_POLL_INTERVAL, ws_client.slot_subscribe()).await {
+                Ok(Ok((_, stream))) => stream,
+                Ok(Err(e)) => {
+                    warn!("Unable to subscribe to slots, polling the RPC: {}", e);
+                    pending().boxed()
+                }
+                Err(_) => {
+                    warn!("Slot subscription timed out, polling the RPC");
+                    pending().boxed()
+                }
+            };
+            skip_reconnect_events(&mut reconnect_events);
+
+            loop {
+                tokio::select! {
+                    item = stream.next() => match item {
+                        Some(slot_info) => {
+                            ws_client.record_notification(SLOT_SUBSCRIPTION);
+                            current_slot.update(slot_info.slot);
+                        }
+                        None => break,
+                    },
+                    _ = poll_interval.tick() => {
+                        let is_silent = ws_client
+                            .last_notification(SLOT_SUBSCRIPTION)
+                            .is_none_or(|at| at.elapsed() > SLOT_POLL_INTERVAL);
+                        if is_silent {
+                            match rpc_client.get_slot().await {
+                                Ok(slot) => current_slot.update(slot),
+                                Err(e) => warn!("Unable to poll the current slot: {}", e),
+                            }
+                        }
+                    }
+                    Ok(_) = reconnect_events.recv() => break,
+                    _ = shutdown.triggered() => return Ok(()),
+                }
+            }
+
+            if shutdown.is_triggered() {
+                return Ok(());
+            }
+        }
+    })
+}
+
 pub fn subscribe_to_pyth_oracles(
+    rpc_client: Arc<RpcClient>,
     ws_client: Arc<WebsocketClient>,
-    banks: &Vec<(Pubkey, MarginfiBank)>,
+    banks: &HashMap<Pubkey, MarginfiBank>,
+    current_slot: Arc<CurrentSlot>,
     state_update_sender: mpsc::UnboundedSender<StateUpdate>,
+    use_program_subscribe: bool,
+    shutdown: Shutdown,
 ) -> SubscriptionHandle {
     let magic = pyth_sdk_solana::state::MAGIC.to_le_bytes();
-    let config = new_config_by_discriminator(magic.to_vec(), None);
+    let config = new_config_by_discriminator(magic.to_vec(), None, ws_client.commitment());
     let watched_oracles = banks
         .iter()
-        .filter_map(|(_, bank)| match bank.oracle {
-            MarginfiBankOracle::Pyth(addr) => Some(addr),
-            _ => None,
+        .flat_map(|(_, bank)| match &bank.oracle {
+            MarginfiBankOracle::Pyth(addresses) => addresses.clone(),
+            _ => vec![],
         })
         .collect::<Vec<Pubkey>>();
 
     tokio::spawn(async move {
+        let mut parse_failures = OracleParseFailures::default();
+        let mut reconnects: u64 = 0;
+        let mut reconnect_events = ws_client.reconnect_events();
+
         loop {
-            let (_, mut stream) = ws_client.program_subscribe(PYTH_ID, config.clone()).await?;
+            let mut stream = if use_program_subscribe {
+                subscribe_to_program_accounts(&ws_client, PYTH_ID, config.clone(), &watched_oracles)
+                    .await?
+            } else {
+                subscribe_to_accounts(&ws_client, &watched_oracles).await?
+            };
+            skip_reconnect_events(&mut reconnect_events);
+
+            // Fill the gap between the previous stream ending and the new subscription
+            if let Err(e) = sync_pyth_oracles(
+                &rpc_client,
+                &watched_oracles,
+                &current_slot,
+                &state_update_sender,
+            )
+            .await
+            {
+                warn!("Unable to sync pyth oracles: {:?}", e);
+            }
 
-            while let Some(payload) = stream.next().await {
-                let pubkey = Pubkey::from_str(&payload.value.pubkey).unwrap();
+            while let Some(AccountNotification {
+                pubkey,
+                slot,
+                account,
+            }) = tokio::select! {
+                item = stream.next() => item,
+                Ok(_) = reconnect_events.recv() => None,
+                _ = shutdown.triggered() => return Ok(()),
+            } {
+                ws_client.record_notification(PYTH_SUBSCRIPTION);
+                current_slot.update(slot);
+                let res = AccountData::decode(&account.data)
+                    .and_then(|bytes| parse_pyth_price_feed(&pubkey, &current_slot, &bytes));
+
+                if let Some(Some(price_feed)) = parse_failures.handle(&pubkey, res)? {
+                    state_update_sender
+                        .send(StateUpdate::PythOracle((pubkey, price_feed)))
+                        .ok();
+                }
+            }
 
-                if !watched_oracles.contains(&pubkey) {
-                    continue;
+            if shutdown.is_triggered() {
+                return Ok(());
+            }
+            reconnects += 1;
+            warn!(
+                "Pyth oracles stream ended or the websocket reconnected, resubscribing ({} reconnects)",
+                reconnects
+            );
+        }
+    })
+}
+
+async fn sync_static_accounts(
+    rpc_client: &Arc<RpcClient>,
+    static_addresses: &SharedStaticAddresses,
+    bank_addresses: &[Pubkey],
+    pool_addresses: &[Pubkey],
+) -> Result<(), Error> {
+    let accounts = get_multiple_accounts_chunked(rpc_client, bank_addresses).await?;
+    let banks = bank_addresses
+        .iter()
+        .zip(accounts.iter())
+        .filter_map(|(address, account)| {
+            let bank = AccountData::from(account.as_ref()?)
+                .parse::<marginfi::state::marginfi_group::Bank>()
+                .ok()?;
+            Some((*address, bank))
+        })
+        .collect::<Vec<_>>();
+    if static_addresses.refresh_marginfi_banks(&banks).await {
+        debug!("Marginfi banks changed while the stream was down");
+    }
+
+    let pools_and_vaults = fetch_meteora_pools_and_vaults(rpc_client, pool_addresses).await?;
+    if static_addresses.refresh_meteora(&pools_and_vaults).await? {
+        debug!("Meteora pools changed while the stream was down");
+    }
+    Ok(())
+}
+
+pub fn subscribe_to_static_accounts(
+    rpc_client: Arc<RpcClient>,
+    ws_client: Arc<WebsocketClient>,
+    static_addresses: Arc<SharedStaticAddresses>,
+    shutdown: Shutdown,
+) -> SubscriptionHandle {
+    tokio::spawn(async move {
+        let mut reconnects: u64 = 0;
+        let mut reconnect_events = ws_client.reconnect_events();
+
+        loop {
+            let (bank_addresses, pool_addresses) = {
+                let static_addresses = static_addresses.read().await;
+                (
+                    static_addresses
+                        .marginfi_banks
+                        .iter()
+                        .map(|(_, bank)| bank.address)
+                        .collect::<Vec<_>>(),
+                    static_addresses
+                        .meteora_dynamic_pools
+                        .iter()
+                        .map(|pool| pool.address)
+                        .collect::<Vec<_>>(),
+                )
+            };
+            let watched_accounts = [&bank_addresses[..], &pool_addresses[..]].concat();
+            let mut stream = subscribe_to_accounts(&ws_client, &watched_accounts).await?;
+            skip_reconnect_events(&mut reconnect_events);
+
+            if reconnects > 0 {
+                if let Err(e) = sync_static_accounts(
+                    &rpc_client,
+                    &static_addresses,
+                    &bank_addresses,
+                    &pool_addresses,
+                )
+                .await
+                {
+                    warn!("Unable to sync static accounts: {:?}", e);
                 }
+            }
 
-                let bytes = AccountData::decode(&payload.value.account.data).unwrap();
-                let price_feed = pyth_sdk_solana::state::load_price_account(&bytes[..])
-                    .unwrap()
-                    .to_price_feed(&pubkey);
-                let now_ts = SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                if let Some(price) = price_feed.get_ema_price_no_older_than(now_ts as i64, 60) {
-                    let price_feed = PythPriceFeed {
-                        price,
-                        last_update_slot: payload.context.slot,
+            while let Some(AccountNotification {
+                pubkey, account, ..
+            }) = tokio::select! {
+                item = stream.next() => item,
+                Ok(_) = reconnect_events.recv() => None,
+                _ = shutdown.triggered() => return Ok(()),
+            } {
+                ws_client.record_notification(STATIC_ACCOUNTS_SUBSCRIPTION);
+                let account_data = AccountData::Encoded(&account.data);
+
+                if bank_addresses.contains(&pubkey) {
+                    let Ok(bank) = account_data.parse::<marginfi::state::marginfi_group::Bank>()
+                    else {
+                        continue;
                     };
-                    state_update_sender
-                        .send(StateUpdate::PythOracle((pubkey, price_feed)))
-                        .ok();
+                    if static_addresses
+                        .refresh_marginfi_banks(&vec![(pubkey, bank)])
+                        .await
+                    {
+                        debug!(
+                            "Marginfi bank {} changed, refreshed static addresses",
+                            pubkey
+                        );
+                    }
+                } else {
+                    let Ok(pool) = account_data.parse::<meteora::state::Pool>() else {
+                        continue;
+                    };
+                    let changed = {
+                        let static_addresses = static_addresses.read().await;
+                        match static_addresses.get_meteora_pool_by_address(&pubkey) {
+                            Ok((_, current_pool)) => {
+                                current_pool.lp_mint != pool.lp_mint
+                                    || current_pool.a_vault != pool.a_vault
+                                    || current_pool.b_vault != pool.b_vault
+                                    || current_pool.a_vault_lp != pool.a_vault_lp
+                                    || current_pool.b_vault_lp != pool.b_vault_lp
+                            }
+                            Err(_) => true,
+                        }
+                    };
+                    if !changed {
+                        continue;
+                    }
+
+                    let pools_and_vaults =
+                        fetch_meteora_pools_and_vaults(&rpc_client, &[pubkey]).await?;
+                    if static_addresses.refresh_meteora(&pools_and_vaults).await? {
+                        debug!(
+                            "Meteora pool {} changed, refreshed static addresses",
+                            pubkey
+                        );
+                    }
                 }
             }
+
+            if shutdown.is_triggered() {
+                return Ok(());
+            }
+            reconnects += 1;
+            warn!(
+                "Static accounts stream ended or the websocket reconnected, resubscribing ({} reconnects)",
+                reconnects
+            );
         }
     })
 }
 
-pub async fn init_and_subscribe_to_switchboard_oracles(
+pub fn subscribe_to_switchboard_oracles(
     rpc_client: Arc<RpcClient>,
     ws_client: Arc<WebsocketClient>,
-    banks: &Vec<(Pubkey, MarginfiBank)>,
+    banks: &HashMap<Pubkey, MarginfiBank>,
     state_update_sender: mpsc::UnboundedSender<StateUpdate>,
-) -> Result<SubscriptionHandle, Error> {
-    let config = new_config_by_discriminator(AggregatorAccountData::DISCRIMINATOR.to_vec(), None);
+    use_program_subscribe: bool,
+    shutdown: Shutdown,
+) -> SubscriptionHandle {
+    let config = new_config_by_discriminator(
+        AggregatorAccountData::DISCRIMINATOR.to_vec(),
+        None,
+        ws_client.commitment(),
+    );
     let watched_oracles = banks
         .iter()
-        .filter_map(|(_, bank)| match bank.oracle {
-            MarginfiBankOracle::Switchboard(addr) => Some(addr),
-            _ => None,
+        .flat_map(|(_, bank)| match &bank.oracle {
+            MarginfiBankOracle::Switchboard(addresses) => addresses.clone(),
+            _ => vec![],
         })
         .collect::<Vec<Pubkey>>();
 
-    let accounts = rpc_client.get_multiple_accounts(&watched_oracles).await?;
-    for (i, ai) in accounts.iter().enumerate() {
-        if let Some(ai) = ai {
-            let pubkey = &watched_oracles[i];
-            let aggregator_account = AccountData::from(ai)
-                .parse::<AggregatorAccountData>()
-                .unwrap();
-            let price_feed = SwitchboardPriceFeed::from(&aggregator_account);
-
-            state_update_sender
-                .send(StateUpdate::SwitchboardOracle((*pubkey, price_feed)))
-                .ok();
-        } else {
-            return Err(Error::UnableToFetchAccount);
-        }
-    }
+    tokio::spawn(async move {
+        let mut parse_failures = OracleParseFailures::default();
+        let mut reconnects: u64 = 0;
+        let mut reconnect_events = ws_client.reconnect_events();
 
-    let handle = tokio::spawn(async move {
         loop {
-            let (_, mut stream) = ws_client
-                .program_subscribe(switchboard_v2::SWITCHBOARD_V2_MAINNET, config.clone())
-                .await?;
-
-            while let Some(payload) = stream.next().await {
-                let pubkey = Pubkey::from_str(&payload.value.pubkey).unwrap();
+            let mut stream = if use_program_subscribe {
+                subscribe_to_program_accounts(
+                    &ws_client,
+                    switchboard_v2::SWITCHBOARD_V2_MAINNET,
+                    config.clone(),
+                    &watched_oracles,
+                )
+                .await?
+            } else {
+                subscribe_to_accounts(&ws_client, &watched_oracles).await?
+            };
+            skip_reconnect_events(&mut reconnect_events);
+
+            // Rounds which happened before the subscription would be lost otherwise
+            if let Err(e) =
+                sync_switchboard_oracles(&rpc_client, &watched_oracles, &state_update_sender).await
+            {
+                warn!("Unable to sync switchboard oracles: {:?}", e);
+            }
 
-                if !watched_oracles.contains(&pubkey) {
-                    continue;
+            while let Some(AccountNotification {
+                pubkey, account, ..
+            }) = tokio::select! {
+                item = stream.next() => item,
+                Ok(_) = reconnect_events.recv() => None,
+                _ = shutdown.triggered() => return Ok(()),
+            } {
+                ws_client.record_notification(SWITCHBOARD_SUBSCRIPTION);
+                let res = parse_switchboard_price_feed(AccountData::from(&account));
+
+                if let Some(price_feed) = parse_failures.handle(&pubkey, res)? {
+                    state_update_sender
+                        .send(StateUpdate::SwitchboardOracle((pubkey, price_feed)))
+                        .ok();
                 }
+            }
 
-                let aggregator_account = AccountData::from(&payload.value.account)
-                    .parse::<AggregatorAccountData>()
-                    .unwrap();
-                let price_feed = SwitchboardPriceFeed::from(&aggregator_account);
-
-                state_update_sender
-                    .send(StateUpdate::SwitchboardOracle((pubkey, price_feed)))
-                    .ok();
+            if shutdown.is_triggered() {
+                return Ok(());
             }
+            reconnects += 1;
+            warn!(
+                "Switchboard oracles stream ended or the websocket reconnected, resubscribing ({} reconnects)",
+                reconnects
+            );
         }
-    });
-    Ok(handle)
+    })
 }
 
 struct PubkeyVisitor;
@@ -448,62 +1499,690 @@ impl Into<Vec<Instruction>> for JupiterIxsResponse {
     }
 }
 
-pub async fn fetch_swap_instructions(
-    rpc_client: &Arc<RpcClient>,
-    client: &reqwest::Client,
-    wallet: &Arc<Wallet>,
-    input_mint: &Pubkey,
-    input_amount: u64,
-) -> Result<(Vec<Instruction>, Vec<AddressLookupTableAccount>), Error> {
-    const API_URL: &'static str = "https://quote-api.jup.ag/v6";
+#[derive(Debug, Clone, Default)]
+pub struct SwapRouteConfig {
+    pub only_direct_routes: bool,
+    pub max_accounts: Option<u64>,
+    pub dexes: Option<Vec<String>>,
+    pub exclude_dexes: Option<Vec<String>>,
+    /// Upper bound of the slippage widened on every re-quote
+    pub max_slippage_bps: u16,
+    pub max_requotes: u32,
+    pub max_shortfall_bps: u16,
+}
+
+impl SwapRouteConfig {
+    pub fn to_query_params(&self) -> String {
+        let mut params = format!("&onlyDirectRoutes={}", self.only_direct_routes);
+
+        if let Some(max_accounts) = self.max_accounts {
+            params.push_str(&format!("&maxAccounts={max_accounts}"));
+        }
+        if let Some(dexes) = &self.dexes {
+            params.push_str(&format!("&dexes={}", dexes.join(",")));
+        }
+        if let Some(exclude_dexes) = &self.exclude_dexes {
+            params.push_str(&format!("&excludeDexes={}", exclude_dexes.join(",")));
+        }
+
+        params
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterSwapInfo {
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterRoutePlanStep {
+    swap_info: JupiterSwapInfo,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterQuoteResponse {
+    in_amount: String,
+    out_amount: String,
+    other_amount_threshold: String,
+    price_impact_pct: String,
+    route_plan: Vec<JupiterRoutePlanStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapMode {
+    ExactIn,
+    ExactOut,
+}
+
+impl SwapMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::ExactIn => "ExactIn",
+            Self::ExactOut => "ExactOut",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SwapQuote {
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    pub in_amount: u64,
+    /// Before slippage
+    pub out_amount: u64,
+    /// Minimum output for ExactIn, maximum input for ExactOut
+    pub other_amount_threshold: u64,
+    pub price_impact_pct: f64,
+    pub route_labels: Vec<String>,
+    /// Posted back when building the swap
+    raw: String,
+}
+
+pub struct SwapInstructions {
+    pub instructions: Vec<Instruction>,
+    pub lookup_tables: Vec<AddressLookupTableAccount>,
+}
+
+fn parse_lookup_table(
+    address: &Pubkey,
+    account: &Option<Account>,
+) -> Option<AddressLookupTableAccount> {
+    let account = account.as_ref()?;
+    let alt =
+        solana_address_lookup_table_program::state::AddressLookupTable::deserialize(&account.data)
+            .ok()?;
+
+    Some(AddressLookupTableAccount {
+        key: *address,
+        addresses: alt.addresses.to_vec(),
+    })
+}
+
+const LOOKUP_TABLE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Tables which fail to load are retried once since recently extended tables can lag
+/// behind, with `best_effort` tables which still fail are skipped
+pub async fn fetch_lookup_tables<F: AccountFetcher>(
+    rpc_client: &F,
+    addresses: &[Pubkey],
+    best_effort: bool,
+) -> Result<Vec<AddressLookupTableAccount>, Error> {
+    let ais = rpc_client.get_multiple_accounts(addresses).await?;
+    let mut tables = addresses
+        .iter()
+        .zip(ais.iter())
+        .map(|(address, ai)| parse_lookup_table(address, ai))
+        .collect::<Vec<_>>();
+
+    let failed = addresses
+        .iter()
+        .zip(tables.iter())
+        .filter(|(_, table)| table.is_none())
+        .map(|(address, _)| *address)
+        .collect::<Vec<_>>();
+    if failed.is_empty() {
+        return Ok(tables.into_iter().flatten().collect());
+    }
 
+    sleep(LOOKUP_TABLE_RETRY_DELAY).await;
+    let retried_ais = rpc_client.get_multiple_accounts(&failed).await?;
+    for (address, ai) in failed.iter().zip(retried_ais.iter()) {
+        let table = parse_lookup_table(address, ai);
+        if table.is_none() {
+            if !best_effort {
+                return Err(Error::UnableToLoadLookupTable(*address));
+            }
+            warn!("Unable to load lookup table {}, skipping", address);
+            continue;
+        }
+
+        let i = addresses.iter().position(|a| a == address).unwrap();
+        tables[i] = table;
+    }
+
+    Ok(tables.into_iter().flatten().collect())
+}
+
+fn parse_amount(amount: &str) -> Result<u64, Error> {
+    amount
+        .parse()
+        .map_err(|_| Error::InvalidTokenAmount(amount.to_string()))
+}
+
+pub async fn fetch_swap_quote(
+    jupiter_client: &JupiterClient,
+    input_mint: &Pubkey,
+    output_mint: &Pubkey,
+    amount: u64,
+    mode: SwapMode,
+    slippage_bps: u16,
+    route_config: &SwapRouteConfig,
+) -> Result<SwapQuote, Error> {
     let get_url_params = format!(
-        "?inputMint={}&outputMint={}&amount={}&slippageBps=10&onlyDirectRoutes=false&asLegacyTransaction=false",
+        "inputMint={}&outputMint={}&amount={}&swapMode={}&slippageBps={}&asLegacyTransaction=false{}",
         input_mint.to_string(),
-        constants::mints::usdc::id().to_string(),
-        input_amount,
+        output_mint.to_string(),
+        amount,
+        mode.as_str(),
+        slippage_bps,
+        route_config.to_query_params(),
     );
-    let quote_res = client
-        .get(format!("{API_URL}/quote{get_url_params}"))
-        .send()
-        .await?
-        .text()
+    let quote_res = jupiter_client
+        .get(jupiter_client.config().quote_url(&get_url_params))
         .await?;
+    let quote: JupiterQuoteResponse = jupiter_client::parse_response(&quote_res)?;
+
+    Ok(SwapQuote {
+        input_mint: *input_mint,
+        output_mint: *output_mint,
+        in_amount: parse_amount(&quote.in_amount)?,
+        out_amount: parse_amount(&quote.out_amount)?,
+        other_amount_threshold: parse_amount(&quote.other_amount_threshold)?,
+        price_impact_pct: quote.price_impact_pct.parse().unwrap_or_default(),
+        route_labels: quote
+            .route_plan
+            .into_iter()
+            .filter_map(|step| step.swap_info.label)
+            .collect(),
+        raw: quote_res,
+    })
+}
 
+pub async fn fetch_swap_instructions<F: AccountFetcher>(
+    rpc_client: &F,
+    jupiter_client: &JupiterClient,
+    wallet: &Arc<Wallet>,
+    quote: &SwapQuote,
+    best_effort_alts: bool,
+) -> Result<SwapInstructions, Error> {
     let body = format!(
-        "{{\"userPublicKey\":\"{}\",\"quoteResponse\":{quote_res}}}",
-        wallet.pubkey.to_string()
+        "{{\"userPublicKey\":\"{}\",\"quoteResponse\":{}}}",
+        wallet.pubkey.to_string(),
+        quote.raw
     );
-    let res = client
-        .post(format!("{API_URL}/swap-instructions"))
-        .body(body)
-        .send()
-        .await?
-        .json::<JupiterIxsResponse>()
-        .await?;
+    let res: JupiterIxsResponse = jupiter_client::parse_response(
+        &jupiter_client
+            .post(jupiter_client.config().swap_instructions_url(), body)
+            .await?,
+    )?;
 
     let alt_addresses = res
         .address_lookup_table_addresses
         .iter()
-        .map(|str| Pubkey::from_str(str).unwrap())
-        .collect::<Vec<Pubkey>>();
-    let alt_ais = rpc_client.get_multiple_accounts(&alt_addresses).await?;
-    let mut alt_accounts: Vec<AddressLookupTableAccount> = vec![];
-    for (i, ai) in alt_ais.iter().enumerate() {
-        if let Some(ai) = ai {
-            let alt = solana_address_lookup_table_program::state::AddressLookupTable::deserialize(
-                &ai.data,
-            );
-            if let Ok(alt) = alt {
-                alt_accounts.push(AddressLookupTableAccount {
-                    key: alt_addresses[i],
-                    addresses: alt.addresses.to_vec(),
-                });
-            }
+        .map(|str| {
+            Pubkey::from_str(str).map_err(|_| Error::UnableToDeserialize {
+                expected: "lookup table address",
+                account: None,
+            })
+        })
+        .collect::<Result<Vec<Pubkey>, Error>>()?;
+    let alt_accounts = fetch_lookup_tables(rpc_client, &alt_addresses, best_effort_alts).await?;
+
+    Ok(SwapInstructions {
+        instructions: res.into(),
+        lookup_tables: alt_accounts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{self, MockFetcher, MockRpc, MockWsServer, PositionFixture};
+
+    fn base64_data(bytes: &[u8]) -> UiAccountData {
+        UiAccountData::Binary(
+            general_purpose::STANDARD.encode(bytes),
+            UiAccountEncoding::Base64,
+        )
+    }
+
+    #[test]
+    fn invalid_base64_is_a_decode_error() {
+        let data = UiAccountData::Binary("not base64!".to_string(), UiAccountEncoding::Base64);
+        assert!(matches!(
+            AccountData::decode(&data),
+            Err(Error::UnableToDecode)
+        ));
+
+        let data = UiAccountData::Binary(
+            general_purpose::STANDARD.encode([1, 2, 3]),
+            UiAccountEncoding::Base58,
+        );
+        assert!(matches!(
+            AccountData::decode(&data),
+            Err(Error::UnableToDecode)
+        ));
+    }
+
+    #[test]
+    fn garbage_pyth_account_is_a_parse_error() {
+        let current_slot = CurrentSlot::default();
+        let bytes = AccountData::decode(&base64_data(&[7; 64])).unwrap();
+        assert!(matches!(
+            parse_pyth_price_feed(&Pubkey::new_unique(), &current_slot, &bytes),
+            Err(Error::UnableToParsePythOracle)
+        ));
+        assert!(matches!(
+            parse_pyth_price_feed(&Pubkey::new_unique(), &current_slot, &[]),
+            Err(Error::UnableToParsePythOracle)
+        ));
+    }
+
+    #[test]
+    fn garbage_switchboard_account_is_a_parse_error() {
+        let data = base64_data(&[7; 64]);
+        assert!(matches!(
+            parse_switchboard_price_feed(AccountData::Encoded(&data)),
+            Err(Error::UnableToParseSwitchboardOracle)
+        ));
+
+        let data = UiAccountData::Binary("not base64!".to_string(), UiAccountEncoding::Base64);
+        assert!(matches!(
+            parse_switchboard_price_feed(AccountData::Encoded(&data)),
+            Err(Error::UnableToParseSwitchboardOracle)
+        ));
+    }
+
+    #[test]
+    fn oracle_fails_after_consecutive_parse_failures() {
+        let oracle = Pubkey::new_unique();
+        let mut parse_failures = OracleParseFailures::default();
+        let failure = || Err::<u64, _>(Error::UnableToParsePythOracle);
+
+        for _ in 1..MAX_CONSECUTIVE_ORACLE_PARSE_FAILURES {
+            assert!(matches!(
+                parse_failures.handle(&oracle, failure()),
+                Ok(None)
+            ));
         }
+        // A parsed notification resets the count
+        assert!(matches!(parse_failures.handle(&oracle, Ok(1)), Ok(Some(1))));
+        for _ in 1..MAX_CONSECUTIVE_ORACLE_PARSE_FAILURES {
+            assert!(matches!(
+                parse_failures.handle(&oracle, failure()),
+                Ok(None)
+            ));
+        }
+        // Failures of other oracles are counted separately
+        assert!(matches!(
+            parse_failures.handle(&Pubkey::new_unique(), failure()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_failures.handle(&oracle, failure()),
+            Err(Error::UnableToParsePythOracle)
+        ));
+    }
+
+    fn fixture_fetcher(fixture: &PositionFixture) -> MockFetcher {
+        let fetcher = MockFetcher::new();
+        for (address, account) in fixture.accounts.clone() {
+            fetcher.insert(address, account);
+        }
+        fetcher
+    }
+
+    fn marginfi_account(authority: &Pubkey, balances: &[(Pubkey, u64, u64)]) -> (Pubkey, Account) {
+        let account = testkit::new_marginfi_account(authority, balances);
+        (
+            Pubkey::new_unique(),
+            testkit::zero_copy_account(marginfi::id(), &account),
+        )
+    }
+
+    #[tokio::test]
+    async fn marginfi_banks_are_filtered_by_mint_and_group() {
+        let fixture = PositionFixture::new();
+        let fetcher = fixture_fetcher(&fixture);
+        let foreign_bank = Pubkey::new_unique();
+        let mut bank = testkit::new_bank(
+            &foreign_bank,
+            constants::mints::bsol::id(),
+            9,
+            Pubkey::new_unique(),
+        );
+        bank.group = Pubkey::new_unique();
+        fetcher.insert(
+            foreign_bank,
+            testkit::zero_copy_account(marginfi::id(), &bank),
+        );
+
+        let banks = fetch_marginfi_banks(&fetcher, &[constants::mints::bsol::id()], &[])
+            .await
+            .unwrap();
+        assert_eq!(
+            banks
+                .iter()
+                .map(|(address, _)| *address)
+                .collect::<Vec<_>>(),
+            vec![constants::marginfi::banks::bsol::id()]
+        );
+        assert_eq!(banks[0].1.mint, constants::mints::bsol::id());
+
+        let mut banks = fetch_marginfi_banks(
+            &fetcher,
+            &[constants::mints::bsol::id()],
+            &[constants::marginfi::banks::usdc::id()],
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(address, _)| address)
+        .collect::<Vec<_>>();
+        banks.sort();
+        let mut expected = vec![
+            constants::marginfi::banks::bsol::id(),
+            constants::marginfi::banks::usdc::id(),
+        ];
+        expected.sort();
+        assert_eq!(banks, expected);
     }
 
-    let instructions: Vec<Instruction> = res.into();
+    #[tokio::test]
+    async fn marginfi_account_is_selected_by_balances() {
+        let fixture = PositionFixture::new();
+        let wallet = &fixture.wallet;
+        let fetcher = MockFetcher::new();
+        assert!(matches!(
+            fetch_marginfi_account(&fetcher, wallet, None).await,
+            Err(Error::MissingMarginfiAccount)
+        ));
+
+        let bank = constants::marginfi::banks::bsol::id();
+        let (empty, account) = marginfi_account(&wallet.pubkey, &[]);
+        fetcher.insert(empty, account);
+        let (active, account) = marginfi_account(&wallet.pubkey, &[(bank, 1_000, 0)]);
+        fetcher.insert(active, account);
+        // Accounts of other wallets are not matched
+        let (other, account) = marginfi_account(&Pubkey::new_unique(), &[(bank, 1_000, 0)]);
+        fetcher.insert(other, account);
+
+        let (address, _) = fetch_marginfi_account(&fetcher, wallet, None)
+            .await
+            .unwrap();
+        assert_eq!(address, active);
+
+        let (second_active, account) = marginfi_account(&wallet.pubkey, &[(bank, 1, 0)]);
+        fetcher.insert(second_active, account);
+        assert!(matches!(
+            fetch_marginfi_account(&fetcher, wallet, None).await,
+            Err(Error::AmbiguousMarginfiAccount)
+        ));
+
+        // A pinned account skips the selection but has to belong to the wallet
+        let (address, _) = fetch_marginfi_account(&fetcher, wallet, Some(second_active))
+            .await
+            .unwrap();
+        assert_eq!(address, second_active);
+        assert!(matches!(
+            fetch_marginfi_account(&fetcher, wallet, Some(other)).await,
+            Err(Error::ForeignMarginfiAccount(address)) if address == other
+        ));
+    }
 
-    Ok((instructions, alt_accounts))
+    #[tokio::test]
+    async fn token_balances_of_missing_accounts_are_zero() {
+        let fixture = PositionFixture::new();
+        let fetcher = fixture_fetcher(&fixture);
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        let (bsol, usdc) = (constants::mints::bsol::id(), constants::mints::usdc::id());
+        let (address, account) = fixture.wallet_token_account(&static_addresses, &usdc, 42);
+        fetcher.insert(address, account);
+
+        let balances = fetch_token_balances(&fetcher, &static_addresses, &[bsol, usdc])
+            .await
+            .unwrap();
+        assert_eq!(balances.get(&bsol), Some(&0));
+        assert_eq!(balances.get(&usdc), Some(&42));
+
+        let missing = find_missing_token_accounts(&fetcher, &static_addresses)
+            .await
+            .unwrap();
+        assert!(missing.iter().any(|(mint, ..)| mint == &bsol));
+        assert!(!missing.iter().any(|(mint, ..)| mint == &usdc));
+    }
+
+    #[tokio::test]
+    async fn mint_decimals_skip_missing_mints() {
+        let fetcher = MockFetcher::new();
+        let (mint, missing) = (Pubkey::new_unique(), Pubkey::new_unique());
+        fetcher.insert(mint, testkit::mint_account(1_000, 6));
+
+        let decimals = fetch_mints_decimals(&fetcher, &[mint, missing])
+            .await
+            .unwrap();
+        assert_eq!(decimals, HashMap::from([(mint, 6)]));
+        assert_eq!(fetch_mint_decimals(&fetcher, &mint).await.unwrap(), 6);
+        assert!(matches!(
+            fetch_mint_decimals(&fetcher, &missing).await,
+            Err(Error::UnableToFetchAccount(address)) if address == missing
+        ));
+    }
+
+    #[tokio::test]
+    async fn meteora_pool_state_is_read_from_vaults_and_mints() {
+        let fixture = PositionFixture::new();
+        let fetcher = fixture_fetcher(&fixture);
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        let (_, pool) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+
+        let pool_state = fetch_meteora_pool_state(&fetcher, pool).await.unwrap();
+        assert_eq!(pool_state.a_vault_lp_amount, testkit::POOL_LIQUIDITY);
+        assert_eq!(pool_state.a_vault_total_amount, testkit::POOL_LIQUIDITY);
+        assert_eq!(pool_state.a_vault_lp_supply, testkit::POOL_LIQUIDITY);
+        assert_eq!(pool_state.b_vault_total_amount, 0);
+        assert_eq!(pool_state.lp_supply, testkit::POOL_LIQUIDITY);
+
+        fetcher.accounts.lock().unwrap().remove(&pool.a_vault);
+        assert!(matches!(
+            fetch_meteora_pool_state(&fetcher, pool).await,
+            Err(Error::UnableToFetchAccount(address)) if address == pool.a_vault
+        ));
+    }
+
+    /// Returns (fetcher, valid table, corrupted table), the corrupted one misses its last
+    /// byte
+    fn lookup_table_fetcher() -> (MockFetcher, Pubkey, Pubkey) {
+        let fetcher = MockFetcher::new();
+        let (valid, corrupted) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let account = testkit::lookup_table_account(&[Pubkey::new_unique(), Pubkey::new_unique()]);
+        let mut corrupted_account = account.clone();
+        corrupted_account.data.pop();
+        fetcher.insert(valid, account);
+        fetcher.insert(corrupted, corrupted_account);
+        (fetcher, valid, corrupted)
+    }
+
+    #[tokio::test]
+    async fn lookup_tables_are_parsed() {
+        let fetcher = MockFetcher::new();
+        let table = Pubkey::new_unique();
+        let addresses = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        fetcher.insert(table, testkit::lookup_table_account(&addresses));
+
+        let tables = fetch_lookup_tables(&fetcher, &[table], false)
+            .await
+            .unwrap();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].key, table);
+        assert_eq!(tables[0].addresses, addresses);
+        assert_eq!(fetcher.multiple_accounts_requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupted_lookup_table_is_an_error_after_a_retry() {
+        let (fetcher, valid, corrupted) = lookup_table_fetcher();
+
+        assert!(matches!(
+            fetch_lookup_tables(&fetcher, &[valid, corrupted], false).await,
+            Err(Error::UnableToLoadLookupTable(address)) if address == corrupted
+        ));
+        // Only the failed table is fetched again
+        assert_eq!(
+            fetcher.multiple_accounts_requests(),
+            vec![vec![valid, corrupted], vec![corrupted]]
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_lookup_table_is_skipped_in_best_effort_mode() {
+        let (fetcher, valid, corrupted) = lookup_table_fetcher();
+
+        let tables = fetch_lookup_tables(&fetcher, &[corrupted, valid], true)
+            .await
+            .unwrap();
+        assert_eq!(
+            tables.iter().map(|table| table.key).collect::<Vec<_>>(),
+            vec![valid]
+        );
+        assert_eq!(fetcher.multiple_accounts_requests().len(), 2);
+    }
+
+    async fn fixture_with_farm() -> (MockFetcher, StaticAddresses, PositionFixture) {
+        let fixture = PositionFixture::new();
+        let fetcher = fixture_fetcher(&fixture);
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        (fetcher, static_addresses, fixture)
+    }
+
+    fn farm_of<'a>(
+        static_addresses: &'a StaticAddresses,
+        fixture: &PositionFixture,
+    ) -> &'a MeteoraFarmMeta {
+        let (pool_id, _) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        static_addresses
+            .get_meteora_farm(pool_id, Some(&fixture.farm))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn farm_user_is_parsed_from_the_program_layout() {
+        let (fetcher, static_addresses, fixture) = fixture_with_farm().await;
+        let farm = farm_of(&static_addresses, &fixture);
+        let (pool, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        // Laid out by hand in the order of the farming program's User account, so a
+        // reordered or resized field in the borsh struct fails here
+        let data = [
+            &MeteoraFarmUser::DISCRIMINATOR[..],
+            pool.as_ref(),
+            owner.as_ref(),
+            &7_u128.to_le_bytes(),
+            &(u64::MAX as u128 + 1).to_le_bytes(),
+            &11_u64.to_le_bytes(),
+            &13_u64.to_le_bytes(),
+            &100_000_000_u64.to_le_bytes(),
+            &[254],
+        ]
+        .concat();
+        assert_eq!(data.len(), 129);
+        fetcher.insert(
+            farm.user_account,
+            testkit::new_account(constants::meteora::farm::id(), data),
+        );
+
+        let user = fetch_meteora_farm_user(&fetcher, farm).await.unwrap();
+        assert_eq!(user.pool, pool);
+        assert_eq!(user.owner, owner);
+        assert_eq!(user.reward_a_per_token_complete, 7);
+        assert_eq!(user.reward_b_per_token_complete, u64::MAX as u128 + 1);
+        assert_eq!(user.reward_a_per_token_pending, 11);
+        assert_eq!(user.reward_b_per_token_pending, 13);
+        assert_eq!(user.balance_staked, 100_000_000);
+        assert_eq!(user.nonce, 254);
+        assert!(is_meteora_farm_user_initialized(&fetcher, farm)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_farm_user_is_zeroed() {
+        let (fetcher, static_addresses, fixture) = fixture_with_farm().await;
+        let farm = farm_of(&static_addresses, &fixture);
+
+        let user = fetch_meteora_farm_user(&fetcher, farm).await.unwrap();
+        assert_eq!(user.owner, Pubkey::default());
+        assert_eq!(user.balance_staked, 0);
+        assert_eq!(user.reward_a_per_token_pending, 0);
+        assert_eq!(user.reward_b_per_token_pending, 0);
+        assert!(!is_meteora_farm_user_initialized(&fetcher, farm)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn static_accounts_are_resubscribed_and_refetched_after_a_reconnect() {
+        let fixture = PositionFixture::new();
+        let mock_rpc = Arc::new(MockRpc::new(Arc::new(fixture_fetcher(&fixture))));
+        let rpc_client = Arc::new(testkit::new_mock_rpc_client(mock_rpc.clone()));
+        let static_addresses = fixture.load_static_addresses(&rpc_client).await.unwrap();
+        let watched_accounts =
+            static_addresses.marginfi_banks.len() + static_addresses.meteora_dynamic_pools.len();
+        let static_addresses = Arc::new(SharedStaticAddresses::new(
+            fixture.wallet.clone(),
+            static_addresses,
+        ));
+        let mut server = MockWsServer::spawn(0).await;
+        let (ws_client, shutdown) = testkit::connect_mock_ws(vec![server.url.clone()], None).await;
+        let mut reconnect_events = ws_client.reconnect_events();
+
+        let handle = subscribe_to_static_accounts(
+            rpc_client,
+            ws_client,
+            static_addresses.clone(),
+            shutdown.clone(),
+        );
+        let requests = server
+            .next_requests_of("accountSubscribe", watched_accounts)
+            .await;
+        assert_eq!(requests.len(), watched_accounts);
+
+        // The USDC bank switches its oracle while the websocket is down, no notification
+        // reports it
+        let usdc = constants::mints::usdc::id();
+        let usdc_bank = constants::marginfi::banks::usdc::id();
+        let oracle = Pubkey::new_unique();
+        mock_rpc.accounts.insert(
+            usdc_bank,
+            testkit::zero_copy_account(
+                marginfi::id(),
+                &testkit::new_bank(&usdc_bank, usdc, 6, oracle),
+            ),
+        );
+        server.disconnect();
+        timeout(Duration::from_secs(10), reconnect_events.recv())
+            .await
+            .expect("no reconnect")
+            .unwrap();
+
+        let requests = server
+            .next_requests_of("accountSubscribe", watched_accounts)
+            .await;
+        assert_eq!(requests.len(), watched_accounts);
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let refetched = static_addresses
+                    .read()
+                    .await
+                    .marginfi_banks
+                    .get(&usdc)
+                    .is_some_and(|bank| bank.oracle.addresses() == [oracle]);
+                if refetched {
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("bank not refetched");
+
+        shutdown.trigger();
+        handle.await.unwrap().unwrap();
+    }
 }
@@ -4,13 +4,51 @@ pub mod spl_token {
     declare_id!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
 }
 
+pub mod token_2022 {
+    use solana_sdk::declare_id;
+
+    declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+}
+
 pub mod associated_token {
     use solana_sdk::declare_id;
 
     declare_id!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
 }
 
+pub mod jupiter {
+    use solana_sdk::declare_id;
+
+    declare_id!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+}
+
+pub mod memo {
+    use solana_sdk::declare_id;
+
+    declare_id!("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr");
+}
+
+pub mod jito {
+    /// Any of them can be tipped
+    pub const TIP_ACCOUNTS: [&str; 8] = [
+        "96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5",
+        "HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe",
+        "Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY",
+        "ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49",
+        "DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh",
+        "ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt",
+        "DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL",
+        "3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT",
+    ];
+}
+
 pub mod mints {
+    pub mod sol {
+        use solana_sdk::declare_id;
+
+        declare_id!("So11111111111111111111111111111111111111112");
+    }
+
     pub mod bsol {
         use solana_sdk::declare_id;
 
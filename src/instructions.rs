@@ -1,13 +1,22 @@
-use std::sync::Arc;
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use anchor_lang::{
     prelude::{borsh, AccountMeta, Pubkey},
     AnchorSerialize, Discriminator,
 };
-use solana_sdk::instruction::Instruction;
+use solana_sdk::{
+    compute_budget::ComputeBudgetInstruction, instruction::Instruction, system_instruction,
+    system_program, sysvar,
+};
 
 use crate::{
-    addresses::{MeteoraDynamicPool, StaticAddresses},
+    addresses::{MeteoraDynamicPool, MeteoraFarmMeta, PoolId, StaticAddresses},
     constants,
     state::MarginfiAccountWithBanks,
     Error, Wallet,
@@ -26,6 +35,23 @@ struct MeteoraDeposit {
     token_b_amount: u64,
 }
 
+#[derive(AnchorSerialize)]
+struct MeteoraWithdraw {
+    pool_token_amount: u64,
+    minimum_a_token_out: u64,
+    minimum_b_token_out: u64,
+}
+
+#[derive(AnchorSerialize)]
+struct MarginfiAmountWithFlag {
+    amount: u64,
+    all: Option<bool>,
+}
+
+pub const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+static NEXT_JITO_TIP_ACCOUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub struct InstructionBuilder {
     wallet: Arc<Wallet>,
 }
@@ -35,6 +61,99 @@ impl InstructionBuilder {
         Self { wallet }
     }
 
+    /// The price is skipped when zero
+    pub fn compute_budget(cu_limit: u32, cu_price_micro_lamports: u64) -> Vec<Instruction> {
+        let mut instructions = vec![ComputeBudgetInstruction::set_compute_unit_limit(
+            cu_limit.min(MAX_COMPUTE_UNIT_LIMIT),
+        )];
+        if cu_price_micro_lamports > 0 {
+            instructions.push(ComputeBudgetInstruction::set_compute_unit_price(
+                cu_price_micro_lamports,
+            ));
+        }
+        instructions
+    }
+
+    /// Round-robin spreads the write locks of the tip accounts
+    pub fn next_jito_tip_account() -> Pubkey {
+        let tip_accounts = &constants::jito::TIP_ACCOUNTS;
+        let index = NEXT_JITO_TIP_ACCOUNT.fetch_add(1, Ordering::Relaxed) % tip_accounts.len();
+        Pubkey::from_str(tip_accounts[index]).unwrap()
+    }
+
+    pub fn jito_tip(payer: &Pubkey, tip_lamports: u64, tip_account: &Pubkey) -> Instruction {
+        system_instruction::transfer(payer, tip_account, tip_lamports)
+    }
+
+    pub fn memo(text: &str) -> Instruction {
+        Instruction::new_with_bytes(constants::memo::id(), text.as_bytes(), vec![])
+    }
+
+    pub fn marginfi_account_initialize(&self, marginfi_account: &Pubkey) -> Instruction {
+        let accounts = vec![
+            AccountMeta::new_readonly(constants::marginfi::group::id(), false),
+            AccountMeta::new(*marginfi_account, true),
+            AccountMeta::new_readonly(self.wallet.pubkey, true),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+
+        Instruction::new_with_bytes(
+            marginfi::id(),
+            &marginfi::instruction::MarginfiAccountInitialize::DISCRIMINATOR,
+            accounts,
+        )
+    }
+
+    pub fn create_associated_token_account_idempotent(
+        &self,
+        mint: &Pubkey,
+        owner: &Pubkey,
+        token_program: &Pubkey,
+    ) -> Instruction {
+        let token_account = Pubkey::find_program_address(
+            &[owner.as_ref(), token_program.as_ref(), mint.as_ref()],
+            &constants::associated_token::id(),
+        )
+        .0;
+
+        let accounts = vec![
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new(token_account, false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+        ];
+
+        // CreateIdempotent
+        Instruction::new_with_bytes(constants::associated_token::id(), &[1], accounts)
+    }
+
+    /// `closed_bank` is skipped as its balance is closed by the instruction
+    fn push_health_accounts(
+        static_addresses: &StaticAddresses,
+        marginfi_account: &MarginfiAccountWithBanks,
+        closed_bank: Option<&Pubkey>,
+        accounts: &mut Vec<AccountMeta>,
+    ) {
+        marginfi_account.balances.iter().for_each(|(_, balance)| {
+            if balance.is_active && Some(&balance.bank_address) != closed_bank {
+                if let Ok(bank) =
+                    static_addresses.get_marginfi_bank_by_bank_address(&balance.bank_address)
+                {
+                    accounts.push(AccountMeta::new_readonly(bank.address, false));
+                    accounts.extend(
+                        bank.oracle
+                            .addresses()
+                            .iter()
+                            .map(|oracle| AccountMeta::new_readonly(*oracle, false)),
+                    );
+                }
+            }
+        });
+    }
+
     pub fn marginfi_deposit(
         &self,
         static_addresses: &StaticAddresses,
@@ -47,29 +166,20 @@ impl InstructionBuilder {
             data: amount,
         };
 
-        let bank_accounts = static_addresses.get_marginfi_bank(mint)?;
-        let token_account = static_addresses.get_token_account(mint)?;
+        let mint_context = static_addresses.get_mint_context(mint)?;
+        let bank_accounts = mint_context.bank()?;
 
         let mut accounts: Vec<AccountMeta> = vec![
             AccountMeta::new_readonly(constants::marginfi::group::id(), false),
             AccountMeta::new(static_addresses.marginfi_account, false),
             AccountMeta::new(self.wallet.pubkey, true),
             AccountMeta::new(bank_accounts.address, false),
-            AccountMeta::new(token_account, false),
+            AccountMeta::new(mint_context.token_account, false),
             AccountMeta::new(bank_accounts.liquidity_vault, false),
-            AccountMeta::new_readonly(constants::spl_token::id(), false),
+            AccountMeta::new_readonly(mint_context.token_program, false),
         ];
 
-        marginfi_account.balances.iter().for_each(|(_, balance)| {
-            if balance.is_active {
-                if let Ok(bank) =
-                    static_addresses.get_marginfi_bank_by_bank_address(&balance.bank_address)
-                {
-                    accounts.push(AccountMeta::new_readonly(bank.address, false));
-                    accounts.push(AccountMeta::new_readonly(bank.oracle.address(), false));
-                }
-            }
-        });
+        Self::push_health_accounts(static_addresses, marginfi_account, None, &mut accounts);
 
         Ok(Instruction::new_with_borsh(marginfi::id(), &data, accounts))
     }
@@ -86,56 +196,167 @@ impl InstructionBuilder {
             data: amount,
         };
 
-        let bank_accounts = static_addresses.get_marginfi_bank(mint)?;
-        let token_account = static_addresses.get_token_account(mint)?;
+        let mint_context = static_addresses.get_mint_context(mint)?;
+        let bank_accounts = mint_context.bank()?;
 
         let mut accounts: Vec<AccountMeta> = vec![
             AccountMeta::new_readonly(constants::marginfi::group::id(), false),
             AccountMeta::new(static_addresses.marginfi_account, false),
             AccountMeta::new(self.wallet.pubkey, true),
             AccountMeta::new(bank_accounts.address, false),
-            AccountMeta::new(token_account, false),
+            AccountMeta::new(mint_context.token_account, false),
             AccountMeta::new(bank_accounts.liquidity_vault_authority, false),
             AccountMeta::new(bank_accounts.liquidity_vault, false),
-            AccountMeta::new_readonly(constants::spl_token::id(), false),
+            AccountMeta::new_readonly(mint_context.token_program, false),
         ];
 
-        marginfi_account.balances.iter().for_each(|(_, balance)| {
-            if balance.is_active {
-                if let Ok(bank) =
-                    static_addresses.get_marginfi_bank_by_bank_address(&balance.bank_address)
-                {
-                    accounts.push(AccountMeta::new_readonly(bank.address, false));
-                    accounts.push(AccountMeta::new_readonly(bank.oracle.address(), false));
-                }
-            }
-        });
+        Self::push_health_accounts(static_addresses, marginfi_account, None, &mut accounts);
 
         Ok(Instruction::new_with_borsh(marginfi::id(), &data, accounts))
     }
 
-    pub fn meteora_pool_deposit(
+    pub fn marginfi_repay(
         &self,
         static_addresses: &StaticAddresses,
-        pool: &MeteoraDynamicPool,
-        minimum_pool_token_amount: u64,
-        token_a_amount: u64,
-        token_b_amount: u64,
+        mint: &Pubkey,
+        amount: u64,
+        repay_all: bool,
     ) -> Result<Instruction, Error> {
         let data = AnchorIxData {
-            discriminator: meteora::instruction::AddBalanceLiquidity::DISCRIMINATOR,
-            data: MeteoraDeposit {
-                minimum_pool_token_amount,
-                token_a_amount,
-                token_b_amount,
+            discriminator: marginfi::instruction::LendingAccountRepay::DISCRIMINATOR,
+            data: MarginfiAmountWithFlag {
+                amount,
+                all: Some(repay_all),
             },
         };
 
+        let mint_context = static_addresses.get_mint_context(mint)?;
+        let bank_accounts = mint_context.bank()?;
+
+        let accounts = vec![
+            AccountMeta::new_readonly(constants::marginfi::group::id(), false),
+            AccountMeta::new(static_addresses.marginfi_account, false),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new(bank_accounts.address, false),
+            AccountMeta::new(mint_context.token_account, false),
+            AccountMeta::new(bank_accounts.liquidity_vault, false),
+            AccountMeta::new_readonly(mint_context.token_program, false),
+        ];
+
+        Ok(Instruction::new_with_borsh(marginfi::id(), &data, accounts))
+    }
+
+    pub fn marginfi_withdraw(
+        &self,
+        static_addresses: &StaticAddresses,
+        mint: &Pubkey,
+        amount: u64,
+        withdraw_all: bool,
+        marginfi_account: &MarginfiAccountWithBanks,
+    ) -> Result<Instruction, Error> {
+        let data = AnchorIxData {
+            discriminator: marginfi::instruction::LendingAccountWithdraw::DISCRIMINATOR,
+            data: MarginfiAmountWithFlag {
+                amount,
+                all: Some(withdraw_all),
+            },
+        };
+
+        let mint_context = static_addresses.get_mint_context(mint)?;
+        let bank_accounts = mint_context.bank()?;
+
+        let mut accounts: Vec<AccountMeta> = vec![
+            AccountMeta::new_readonly(constants::marginfi::group::id(), false),
+            AccountMeta::new(static_addresses.marginfi_account, false),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new(bank_accounts.address, false),
+            AccountMeta::new(mint_context.token_account, false),
+            AccountMeta::new(bank_accounts.liquidity_vault_authority, false),
+            AccountMeta::new(bank_accounts.liquidity_vault, false),
+            AccountMeta::new_readonly(mint_context.token_program, false),
+        ];
+
+        let closed_bank = withdraw_all.then_some(&bank_accounts.address);
+        Self::push_health_accounts(
+            static_addresses,
+            marginfi_account,
+            closed_bank,
+            &mut accounts,
+        );
+
+        Ok(Instruction::new_with_borsh(marginfi::id(), &data, accounts))
+    }
+
+    /// `end_index` is the index of the end instruction in the transaction
+    pub fn marginfi_flashloan_begin(
+        &self,
+        static_addresses: &StaticAddresses,
+        end_index: u64,
+    ) -> Instruction {
+        let data = AnchorIxData {
+            discriminator: marginfi::instruction::LendingAccountStartFlashloan::DISCRIMINATOR,
+            data: end_index,
+        };
+
+        let accounts = vec![
+            AccountMeta::new(static_addresses.marginfi_account, false),
+            AccountMeta::new_readonly(self.wallet.pubkey, true),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        ];
+
+        Instruction::new_with_borsh(marginfi::id(), &data, accounts)
+    }
+
+    pub fn marginfi_flashloan_end(
+        &self,
+        static_addresses: &StaticAddresses,
+        marginfi_account: &MarginfiAccountWithBanks,
+    ) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new(static_addresses.marginfi_account, false),
+            AccountMeta::new_readonly(self.wallet.pubkey, true),
+        ];
+
+        Self::push_health_accounts(static_addresses, marginfi_account, None, &mut accounts);
+
+        Instruction::new_with_bytes(
+            marginfi::id(),
+            &marginfi::instruction::LendingAccountEndFlashloan::DISCRIMINATOR,
+            accounts,
+        )
+    }
+
+    /// Compute budget instructions go first since the begin instruction refers to the end one
+    /// by its index
+    pub fn marginfi_flashloan(
+        &self,
+        static_addresses: &StaticAddresses,
+        compute_budget: Vec<Instruction>,
+        instructions: Vec<Instruction>,
+        marginfi_account: &MarginfiAccountWithBanks,
+    ) -> Vec<Instruction> {
+        let end_index = (compute_budget.len() + 1 + instructions.len()) as u64;
+        let begin = self.marginfi_flashloan_begin(static_addresses, end_index);
+        let end = self.marginfi_flashloan_end(static_addresses, marginfi_account);
+
+        compute_budget
+            .into_iter()
+            .chain([begin])
+            .chain(instructions)
+            .chain([end])
+            .collect()
+    }
+
+    fn meteora_pool_accounts(
+        &self,
+        static_addresses: &StaticAddresses,
+        pool: &MeteoraDynamicPool,
+    ) -> Result<Vec<AccountMeta>, Error> {
         let lp_token_account = static_addresses.get_token_account(&pool.lp_mint)?;
         let a_token_account = static_addresses.get_token_account(&pool.a_token_mint)?;
         let b_token_account = static_addresses.get_token_account(&pool.b_token_mint)?;
 
-        let accounts = vec![
+        Ok(vec![
             AccountMeta::new(pool.address, false),
             AccountMeta::new(pool.lp_mint, false),
             AccountMeta::new(lp_token_account, false),
@@ -151,8 +372,73 @@ impl InstructionBuilder {
             AccountMeta::new(b_token_account, false),
             AccountMeta::new(self.wallet.pubkey, true),
             AccountMeta::new_readonly(meteora_vault::id(), false),
-            AccountMeta::new_readonly(constants::spl_token::id(), false),
-        ];
+            AccountMeta::new_readonly(static_addresses.get_token_program(&pool.lp_mint), false),
+        ])
+    }
+
+    pub fn meteora_pool_deposit(
+        &self,
+        static_addresses: &StaticAddresses,
+        pool: &MeteoraDynamicPool,
+        minimum_pool_token_amount: u64,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> Result<Instruction, Error> {
+        let data = AnchorIxData {
+            discriminator: meteora::instruction::AddBalanceLiquidity::DISCRIMINATOR,
+            data: MeteoraDeposit {
+                minimum_pool_token_amount,
+                token_a_amount,
+                token_b_amount,
+            },
+        };
+
+        let accounts = self.meteora_pool_accounts(static_addresses, pool)?;
+
+        Ok(Instruction::new_with_borsh(meteora::id(), &data, accounts))
+    }
+
+    /// Only stable pools support it
+    pub fn meteora_pool_deposit_imbalanced(
+        &self,
+        static_addresses: &StaticAddresses,
+        pool: &MeteoraDynamicPool,
+        minimum_pool_token_amount: u64,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> Result<Instruction, Error> {
+        let data = AnchorIxData {
+            discriminator: meteora::instruction::AddImbalanceLiquidity::DISCRIMINATOR,
+            data: MeteoraDeposit {
+                minimum_pool_token_amount,
+                token_a_amount,
+                token_b_amount,
+            },
+        };
+
+        let accounts = self.meteora_pool_accounts(static_addresses, pool)?;
+
+        Ok(Instruction::new_with_borsh(meteora::id(), &data, accounts))
+    }
+
+    pub fn meteora_pool_withdraw(
+        &self,
+        static_addresses: &StaticAddresses,
+        pool: &MeteoraDynamicPool,
+        pool_token_amount: u64,
+        minimum_a_token_out: u64,
+        minimum_b_token_out: u64,
+    ) -> Result<Instruction, Error> {
+        let data = AnchorIxData {
+            discriminator: meteora::instruction::RemoveBalanceLiquidity::DISCRIMINATOR,
+            data: MeteoraWithdraw {
+                pool_token_amount,
+                minimum_a_token_out,
+                minimum_b_token_out,
+            },
+        };
+
+        let accounts = self.meteora_pool_accounts(static_addresses, pool)?;
 
         Ok(Instruction::new_with_borsh(meteora::id(), &data, accounts))
     }
@@ -166,10 +452,25 @@ impl InstructionBuilder {
         discriminator
     }
 
+    pub fn meteora_farm_create_user(&self, farm: &MeteoraFarmMeta) -> Instruction {
+        let accounts = vec![
+            AccountMeta::new_readonly(farm.address, false),
+            AccountMeta::new(farm.user_account, false),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+
+        Instruction::new_with_bytes(
+            constants::meteora::farm::id(),
+            &Self::generate_discriminator("global:create_user"),
+            accounts,
+        )
+    }
+
     pub fn meteora_farm_deposit(
         &self,
         static_addresses: &StaticAddresses,
-        mint: &Pubkey,
+        pool_id: PoolId,
         amount: u64,
     ) -> Result<Instruction, Error> {
         let data = AnchorIxData {
@@ -177,8 +478,8 @@ impl InstructionBuilder {
             data: amount,
         };
 
-        let farm = static_addresses.get_meteora_farm(mint)?;
-        let pool = static_addresses.get_meteora_pool(mint)?;
+        let farm = static_addresses.get_meteora_farm(pool_id, None)?;
+        let pool = static_addresses.get_meteora_pool(pool_id)?;
         let lp_token_account = static_addresses.get_token_account(&pool.lp_mint)?;
 
         let accounts = vec![
@@ -187,7 +488,7 @@ impl InstructionBuilder {
             AccountMeta::new(farm.user_account, false),
             AccountMeta::new(self.wallet.pubkey, true),
             AccountMeta::new(lp_token_account, false),
-            AccountMeta::new_readonly(constants::spl_token::id(), false),
+            AccountMeta::new_readonly(static_addresses.get_token_program(&pool.lp_mint), false),
         ];
 
         Ok(Instruction::new_with_borsh(
@@ -196,4 +497,422 @@ impl InstructionBuilder {
             accounts,
         ))
     }
+
+    pub fn meteora_farm_withdraw(
+        &self,
+        static_addresses: &StaticAddresses,
+        farm: &MeteoraFarmMeta,
+        lp_mint: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, Error> {
+        let data = AnchorIxData {
+            discriminator: Self::generate_discriminator("global:withdraw"),
+            data: amount,
+        };
+
+        let lp_token_account = static_addresses.get_token_account(lp_mint)?;
+
+        let accounts = vec![
+            AccountMeta::new(farm.address, false),
+            AccountMeta::new(farm.staking_vault, false),
+            AccountMeta::new(farm.user_account, false),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new(lp_token_account, false),
+            AccountMeta::new_readonly(static_addresses.get_token_program(lp_mint), false),
+        ];
+
+        Ok(Instruction::new_with_borsh(
+            constants::meteora::farm::id(),
+            &data,
+            accounts,
+        ))
+    }
+
+    pub fn meteora_farm_claim(
+        &self,
+        static_addresses: &StaticAddresses,
+        farm: &MeteoraFarmMeta,
+    ) -> Instruction {
+        let accounts = vec![
+            AccountMeta::new(farm.address, false),
+            AccountMeta::new(farm.staking_vault, false),
+            AccountMeta::new(farm.reward_a_vault, false),
+            AccountMeta::new(farm.reward_b_vault, false),
+            AccountMeta::new(farm.user_account, false),
+            AccountMeta::new(self.wallet.pubkey, true),
+            AccountMeta::new(farm.reward_a_token_account, false),
+            AccountMeta::new(farm.reward_b_token_account, false),
+            AccountMeta::new_readonly(
+                static_addresses.get_token_program(&farm.reward_a_mint),
+                false,
+            ),
+        ];
+
+        Instruction::new_with_bytes(
+            constants::meteora::farm::id(),
+            &Self::generate_discriminator("global:claim"),
+            accounts,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use marginfi::state::price::OracleSetup;
+
+    use super::*;
+    use crate::{
+        connection,
+        state::MeteoraPoolState,
+        testkit::{self, MockFetcher, PositionFixture},
+    };
+
+    async fn load_fixture() -> (PositionFixture, MockFetcher, StaticAddresses) {
+        let fixture = PositionFixture::new();
+        let fetcher = MockFetcher::new();
+        for (address, account) in fixture.accounts.clone() {
+            fetcher.insert(address, account);
+        }
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        (fixture, fetcher, static_addresses)
+    }
+
+    fn oracle_of(fixture: &PositionFixture, mint: &Pubkey) -> Pubkey {
+        let (_, oracle) = fixture.oracles.iter().find(|(m, _)| m == mint).unwrap();
+        *oracle
+    }
+
+    #[tokio::test]
+    async fn marginfi_borrow_passes_every_oracle_key() {
+        let fixture = PositionFixture::new();
+        let fetcher = MockFetcher::new();
+        for (address, account) in fixture.accounts.clone() {
+            fetcher.insert(address, account);
+        }
+        let (bsol, usdc) = (constants::mints::bsol::id(), constants::mints::usdc::id());
+        let (bsol_bank, usdc_bank) = (
+            constants::marginfi::banks::bsol::id(),
+            constants::marginfi::banks::usdc::id(),
+        );
+        // Switchboard bank with a second oracle key
+        let extra_oracle = Pubkey::new_unique();
+        let mut bank = testkit::new_bank(&usdc_bank, usdc, 6, oracle_of(&fixture, &usdc));
+        bank.config.oracle_setup = OracleSetup::SwitchboardV2;
+        bank.config.oracle_keys[1] = extra_oracle;
+        fetcher.insert(usdc_bank, testkit::zero_copy_account(marginfi::id(), &bank));
+        let (address, account) = fixture.marginfi_account_with_balances(&[
+            (bsol_bank, 10_000_000_000, 0),
+            (usdc_bank, 0, 100_000_000),
+        ]);
+        fetcher.insert(address, account);
+
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        let account_with_banks =
+            connection::fetch_marginfi_account_with_banks(&fetcher, &fixture.wallet, &address)
+                .await
+                .unwrap();
+        let ix = InstructionBuilder::new(fixture.wallet.clone())
+            .marginfi_borrow(&static_addresses, &usdc, 1_000, &account_with_banks)
+            .unwrap();
+
+        assert_eq!(
+            ix.accounts[8..],
+            [
+                AccountMeta::new_readonly(bsol_bank, false),
+                AccountMeta::new_readonly(oracle_of(&fixture, &bsol), false),
+                AccountMeta::new_readonly(usdc_bank, false),
+                AccountMeta::new_readonly(oracle_of(&fixture, &usdc), false),
+                AccountMeta::new_readonly(extra_oracle, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn marginfi_repay_accounts_and_data() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let usdc = constants::mints::usdc::id();
+        let mint_context = static_addresses.get_mint_context(&usdc).unwrap();
+        let bank = mint_context.bank().unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        for repay_all in [false, true] {
+            let ix = builder
+                .marginfi_repay(&static_addresses, &usdc, 42, repay_all)
+                .unwrap();
+
+            assert_eq!(ix.program_id, marginfi::id());
+            assert_eq!(
+                ix.data[..8],
+                marginfi::instruction::LendingAccountRepay::DISCRIMINATOR
+            );
+            assert_eq!(ix.data[8..16], 42u64.to_le_bytes());
+            assert_eq!(ix.data[16..], [1, repay_all as u8]);
+            // Repaying needs no health check, so no remaining accounts
+            assert_eq!(
+                ix.accounts,
+                vec![
+                    AccountMeta::new_readonly(constants::marginfi::group::id(), false),
+                    AccountMeta::new(fixture.marginfi_account, false),
+                    AccountMeta::new(fixture.wallet.pubkey, true),
+                    AccountMeta::new(constants::marginfi::banks::usdc::id(), false),
+                    AccountMeta::new(mint_context.token_account, false),
+                    AccountMeta::new(bank.liquidity_vault, false),
+                    AccountMeta::new_readonly(constants::spl_token::id(), false),
+                ]
+            );
+        }
+
+        assert!(matches!(
+            builder.marginfi_repay(&static_addresses, &Pubkey::new_unique(), 42, true),
+            Err(Error::InvalidTokenAccount)
+        ));
+    }
+
+    #[tokio::test]
+    async fn marginfi_withdraw_all_excludes_the_closed_bank() {
+        let (fixture, fetcher, static_addresses) = load_fixture().await;
+        let (bsol, usdc) = (constants::mints::bsol::id(), constants::mints::usdc::id());
+        let (bsol_bank, usdc_bank) = (
+            constants::marginfi::banks::bsol::id(),
+            constants::marginfi::banks::usdc::id(),
+        );
+        let (address, account) = fixture.marginfi_account_with_balances(&[
+            (bsol_bank, 10_000_000_000, 0),
+            (usdc_bank, 0, 100_000_000),
+        ]);
+        fetcher.insert(address, account);
+        let account_with_banks =
+            connection::fetch_marginfi_account_with_banks(&fetcher, &fixture.wallet, &address)
+                .await
+                .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        let remaining_accounts = |withdraw_all: bool| {
+            let ix = builder
+                .marginfi_withdraw(
+                    &static_addresses,
+                    &bsol,
+                    10_000_000_000,
+                    withdraw_all,
+                    &account_with_banks,
+                )
+                .unwrap();
+            ix.accounts[8..]
+                .iter()
+                .map(|meta| meta.pubkey)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            remaining_accounts(false),
+            vec![
+                bsol_bank,
+                oracle_of(&fixture, &bsol),
+                usdc_bank,
+                oracle_of(&fixture, &usdc),
+            ]
+        );
+        assert_eq!(
+            remaining_accounts(true),
+            vec![usdc_bank, oracle_of(&fixture, &usdc)]
+        );
+    }
+
+    #[tokio::test]
+    async fn marginfi_withdraw_encodes_withdraw_all_as_an_option() {
+        let (fixture, fetcher, static_addresses) = load_fixture().await;
+        let account_with_banks = connection::fetch_marginfi_account_with_banks(
+            &fetcher,
+            &fixture.wallet,
+            &fixture.marginfi_account,
+        )
+        .await
+        .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        for withdraw_all in [false, true] {
+            let ix = builder
+                .marginfi_withdraw(
+                    &static_addresses,
+                    &constants::mints::bsol::id(),
+                    42,
+                    withdraw_all,
+                    &account_with_banks,
+                )
+                .unwrap();
+
+            assert_eq!(
+                ix.data[..8],
+                marginfi::instruction::LendingAccountWithdraw::DISCRIMINATOR
+            );
+            assert_eq!(ix.data[8..16], 42u64.to_le_bytes());
+            // Borsh `Some(bool)` is the variant byte followed by the bool
+            assert_eq!(ix.data[16..], [1, withdraw_all as u8]);
+            assert_eq!(
+                ix.data[8..],
+                (42u64, Some(withdraw_all)).try_to_vec().unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn meteora_pool_withdraw_accounts_and_data() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let (_, pool) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        let ix = builder
+            .meteora_pool_withdraw(&static_addresses, pool, 1_000, 400, 500)
+            .unwrap();
+
+        assert_eq!(ix.program_id, meteora::id());
+        let token_account = |mint| static_addresses.get_token_account(mint).unwrap();
+        assert_eq!(
+            ix.accounts,
+            vec![
+                AccountMeta::new(pool.address, false),
+                AccountMeta::new(pool.lp_mint, false),
+                AccountMeta::new(token_account(&pool.lp_mint), false),
+                AccountMeta::new(pool.a_vault_lp, false),
+                AccountMeta::new(pool.b_vault_lp, false),
+                AccountMeta::new(pool.a_vault, false),
+                AccountMeta::new(pool.b_vault, false),
+                AccountMeta::new(pool.vault_a_lp_mint, false),
+                AccountMeta::new(pool.vault_b_lp_mint, false),
+                AccountMeta::new(pool.vault_a_vault, false),
+                AccountMeta::new(pool.vault_b_vault, false),
+                AccountMeta::new(token_account(&pool.a_token_mint), false),
+                AccountMeta::new(token_account(&pool.b_token_mint), false),
+                AccountMeta::new(fixture.wallet.pubkey, true),
+                AccountMeta::new_readonly(meteora_vault::id(), false),
+                AccountMeta::new_readonly(constants::spl_token::id(), false),
+            ]
+        );
+
+        // {pool_token_amount, minimum_a_token_out, minimum_b_token_out}
+        let data = [
+            &meteora::instruction::RemoveBalanceLiquidity::DISCRIMINATOR[..],
+            &1_000u64.to_le_bytes(),
+            &400u64.to_le_bytes(),
+            &500u64.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(ix.data, data);
+    }
+
+    #[tokio::test]
+    async fn meteora_pool_deposit_imbalanced_accounts_and_data() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let (_, pool) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        let ix = builder
+            .meteora_pool_deposit_imbalanced(&static_addresses, pool, 990, 1_000, 0)
+            .unwrap();
+
+        assert_eq!(ix.program_id, meteora::id());
+        // Same accounts as the balanced deposit and the withdrawal
+        let withdraw_ix = builder
+            .meteora_pool_withdraw(&static_addresses, pool, 0, 0, 0)
+            .unwrap();
+        assert_eq!(ix.accounts, withdraw_ix.accounts);
+
+        // {minimum_pool_token_amount, token_a_amount, token_b_amount}
+        let data = [
+            &meteora::instruction::AddImbalanceLiquidity::DISCRIMINATOR[..],
+            &990u64.to_le_bytes(),
+            &1_000u64.to_le_bytes(),
+            &0u64.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(ix.data, data);
+    }
+
+    #[tokio::test]
+    async fn meteora_pool_get_withdraw_applies_slippage() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let (_, pool) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        // The pool owns 1000 of token a and 2000 of token b for 1000 LP
+        let pool_state = MeteoraPoolState {
+            a_vault_lp_amount: 1_000,
+            b_vault_lp_amount: 1_000,
+            a_vault_total_amount: 1_000,
+            b_vault_total_amount: 2_000,
+            a_vault_lp_supply: 1_000,
+            b_vault_lp_supply: 1_000,
+            lp_supply: 1_000,
+        };
+
+        let withdraw = pool.get_withdraw(&pool_state, 100, 50).unwrap();
+        assert_eq!(withdraw.pool_token_amount, 100);
+        assert_eq!((withdraw.expected_a, withdraw.expected_b), (100, 200));
+        assert_eq!((withdraw.minimum_a, withdraw.minimum_b), (99, 199));
+
+        let withdraw = pool.get_withdraw(&pool_state, 100, 0).unwrap();
+        assert_eq!((withdraw.minimum_a, withdraw.minimum_b), (100, 200));
+    }
+
+    #[tokio::test]
+    async fn meteora_farm_withdraw_sighash_and_data() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let (pool_id, pool) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        let farm = static_addresses
+            .get_meteora_farm(pool_id, Some(&fixture.farm))
+            .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        let ix = builder
+            .meteora_farm_withdraw(&static_addresses, farm, &pool.lp_mint, 1_000)
+            .unwrap();
+
+        assert_eq!(ix.program_id, constants::meteora::farm::id());
+        // sha256("global:withdraw")[..8]
+        assert_eq!(ix.data[..8], [183, 18, 70, 156, 148, 109, 161, 34]);
+        assert_eq!(ix.data[8..], 1_000u64.to_le_bytes());
+        assert_eq!(
+            ix.accounts[4].pubkey,
+            static_addresses.get_token_account(&pool.lp_mint).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn meteora_farm_claim_sighash_and_reward_accounts() {
+        let (fixture, _, static_addresses) = load_fixture().await;
+        let (pool_id, _) = static_addresses
+            .get_meteora_pool_by_address(&fixture.pool)
+            .unwrap();
+        let farm = static_addresses
+            .get_meteora_farm(pool_id, Some(&fixture.farm))
+            .unwrap();
+        let builder = InstructionBuilder::new(fixture.wallet.clone());
+
+        let ix = builder.meteora_farm_claim(&static_addresses, farm);
+
+        assert_eq!(ix.program_id, constants::meteora::farm::id());
+        // sha256("global:claim")[..8]
+        assert_eq!(ix.data, [62, 198, 214, 193, 213, 159, 108, 210]);
+        assert_eq!(
+            ix.accounts,
+            vec![
+                AccountMeta::new(farm.address, false),
+                AccountMeta::new(farm.staking_vault, false),
+                AccountMeta::new(farm.reward_a_vault, false),
+                AccountMeta::new(farm.reward_b_vault, false),
+                AccountMeta::new(farm.user_account, false),
+                AccountMeta::new(fixture.wallet.pubkey, true),
+                AccountMeta::new(farm.reward_a_token_account, false),
+                AccountMeta::new(farm.reward_b_token_account, false),
+                AccountMeta::new_readonly(constants::spl_token::id(), false),
+            ]
+        );
+        assert_ne!(farm.reward_a_token_account, farm.reward_b_token_account);
+    }
 }
@@ -0,0 +1,577 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anchor_lang::prelude::Pubkey;
+use serde::{Deserialize, Serialize};
+use solana_sdk::signature::Signature;
+use tracing::warn;
+
+use crate::{constants, Error};
+
+const LAMPORTS_PER_SOL: f64 = 1_000_000_000.0;
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionRecord {
+    pub timestamp: u64,
+    pub signature: String,
+    pub fee_lamports: u64,
+    /// Change of the wallet lamports, fees and wrapped SOL unwraps included
+    #[serde(default)]
+    pub sol_change_lamports: Option<i64>,
+    pub memo: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwapRecord {
+    pub timestamp: u64,
+    pub input_mint: String,
+    pub input_amount: u64,
+    pub output_mint: String,
+    pub output_amount: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LpRecord {
+    pub timestamp: u64,
+    pub pool: String,
+    pub minted: u64,
+    pub staked: u64,
+    #[serde(default)]
+    pub burned: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewardRecord {
+    pub timestamp: u64,
+    pub mint: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterestSnapshot {
+    pub timestamp: u64,
+    pub liability: u64,
+    pub accrued: u64,
+}
+
+/// Amounts are in native units and mints are keyed by their base58 address
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PositionLedger {
+    pub entries: Vec<u64>,
+    pub collateral_deposited: u64,
+    pub principal: BTreeMap<String, u64>,
+    pub fees_lamports: u64,
+    pub transactions: u64,
+    #[serde(default)]
+    pub transaction_records: Vec<TransactionRecord>,
+    pub swaps: Vec<SwapRecord>,
+    pub lp: Vec<LpRecord>,
+    pub rewards: Vec<RewardRecord>,
+    /// The last snapshot is the current accrued interest
+    pub interest: BTreeMap<String, Vec<InterestSnapshot>>,
+    /// Low-bias collateral price in USD at the last entry, the stop loss drawdown is
+    /// measured from it
+    #[serde(default)]
+    pub entry_price: Option<f64>,
+}
+
+/// Amounts without a price are listed in `unpriced` and left out of the totals
+#[derive(Debug, Default)]
+pub struct LedgerSummary {
+    pub rewards_usd: f64,
+    pub interest_usd: f64,
+    pub fees_usd: f64,
+    pub swaps_usd: f64,
+    pub net_usd: f64,
+    pub unpriced: Vec<String>,
+}
+
+impl PositionLedger {
+    pub fn record_entry(&mut self) {
+        self.entries.push(now());
+    }
+
+    pub fn record_entry_price(&mut self, price: f64) {
+        self.entry_price = Some(price);
+    }
+
+    pub fn record_collateral_deposit(&mut self, amount: u64) {
+        self.collateral_deposited += amount;
+    }
+
+    pub fn record_collateral_withdraw(&mut self, amount: u64) {
+        self.collateral_deposited = self.collateral_deposited.saturating_sub(amount);
+    }
+
+    pub fn record_borrow(&mut self, mint: &Pubkey, amount: u64) {
+        *self.principal.entry(mint.to_string()).or_default() += amount;
+    }
+
+    /// Repayments reduce the principal, what is left once the liability is repaid in full
+    /// was interest
+    pub fn record_repay(&mut self, mint: &Pubkey, amount: u64, repay_all: bool) {
+        let key = mint.to_string();
+        if repay_all {
+            self.principal.remove(&key);
+        } else if let Some(principal) = self.principal.get_mut(&key) {
+            *principal = principal.saturating_sub(amount);
+        }
+    }
+
+    pub fn record_transaction(
+        &mut self,
+        signature: &Signature,
+        fee: u64,
+        sol_change: Option<i64>,
+        memo: Option<&str>,
+    ) {
+        self.fees_lamports += fee;
+        self.transactions += 1;
+        self.transaction_records.push(TransactionRecord {
+            timestamp: now(),
+            signature: signature.to_string(),
+            fee_lamports: fee,
+            sol_change_lamports: sol_change,
+            memo: memo.map(|memo| memo.to_string()),
+        });
+    }
+
+    pub fn record_swap(
+        &mut self,
+        input_mint: &Pubkey,
+        input_amount: u64,
+        output_mint: &Pubkey,
+        output_amount: u64,
+    ) {
+        self.swaps.push(SwapRecord {
+            timestamp: now(),
+            input_mint: input_mint.to_string(),
+            input_amount,
+            output_mint: output_mint.to_string(),
+            output_amount,
+        });
+    }
+
+    pub fn record_lp(&mut self, pool: &Pubkey, minted: u64, staked: u64) {
+        self.lp.push(LpRecord {
+            timestamp: now(),
+            pool: pool.to_string(),
+            minted,
+            staked,
+            burned: 0,
+        });
+    }
+
+    pub fn record_lp_burn(&mut self, pool: &Pubkey, burned: u64) {
+        self.lp.push(LpRecord {
+            timestamp: now(),
+            pool: pool.to_string(),
+            minted: 0,
+            staked: 0,
+            burned,
+        });
+    }
+
+    /// `None` without any record
+    pub fn lp_balance(&self, pool: &Pubkey) -> Option<u64> {
+        let pool = pool.to_string();
+        let records = self
+            .lp
+            .iter()
+            .filter(|record| record.pool == pool)
+            .collect::<Vec<_>>();
+        if records.is_empty() {
+            return None;
+        }
+
+        Some(records.iter().fold(0, |balance, record| {
+            (balance + record.minted).saturating_sub(record.burned)
+        }))
+    }
+
+    pub fn record_reward(&mut self, mint: &Pubkey, amount: u64) {
+        self.rewards.push(RewardRecord {
+            timestamp: now(),
+            mint: mint.to_string(),
+            amount,
+        });
+    }
+
+    pub fn record_interest(&mut self, mint: &Pubkey, liability: u64) {
+        let key = mint.to_string();
+        let accrued = liability.saturating_sub(self.principal.get(&key).copied().unwrap_or(0));
+        self.interest
+            .entry(key)
+            .or_default()
+            .push(InterestSnapshot {
+                timestamp: now(),
+                liability,
+                accrued,
+            });
+    }
+
+    /// The principal, collateral and interest are left out
+    pub fn since(&self, timestamp: u64) -> PositionLedger {
+        let transaction_records = self
+            .transaction_records
+            .iter()
+            .filter(|record| record.timestamp >= timestamp)
+            .cloned()
+            .collect::<Vec<_>>();
+        PositionLedger {
+            entries: self
+                .entries
+                .iter()
+                .copied()
+                .filter(|entry| *entry >= timestamp)
+                .collect(),
+            fees_lamports: transaction_records
+                .iter()
+                .map(|record| record.fee_lamports)
+                .sum(),
+            transactions: transaction_records.len() as u64,
+            transaction_records,
+            swaps: self
+                .swaps
+                .iter()
+                .filter(|swap| swap.timestamp >= timestamp)
+                .cloned()
+                .collect(),
+            lp: self
+                .lp
+                .iter()
+                .filter(|lp| lp.timestamp >= timestamp)
+                .cloned()
+                .collect(),
+            rewards: self
+                .rewards
+                .iter()
+                .filter(|reward| reward.timestamp >= timestamp)
+                .cloned()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// `prices` are USD per whole token by mint, SOL fees are valued at the wrapped SOL price
+    pub fn summary(
+        &self,
+        prices: &HashMap<Pubkey, f64>,
+        decimals: &HashMap<Pubkey, u8>,
+    ) -> LedgerSummary {
+        let mut summary = LedgerSummary::default();
+        let mut value = |mint: &str, amount: u64| -> Option<f64> {
+            let mint = Pubkey::from_str(mint).ok()?;
+            let price = prices.get(&mint);
+            let decimals = decimals.get(&mint);
+            match (price, decimals) {
+                (Some(price), Some(decimals)) => {
+                    Some(amount as f64 / 10_f64.powi(*decimals as i32) * price)
+                }
+                _ => {
+                    summary.unpriced.push(format!("{} {}", amount, mint));
+                    None
+                }
+            }
+        };
+
+        let rewards_usd = self
+            .rewards
+            .iter()
+            .filter_map(|reward| value(&reward.mint, reward.amount))
+            .sum::<f64>();
+        let interest_usd = self
+            .interest
+            .iter()
+            .filter_map(|(mint, snapshots)| value(mint, snapshots.last()?.accrued))
+            .sum::<f64>();
+        let swaps_usd = self
+            .swaps
+            .iter()
+            .filter_map(|swap| {
+                Some(
+                    value(&swap.output_mint, swap.output_amount)?
+                        - value(&swap.input_mint, swap.input_amount)?,
+                )
+            })
+            .sum::<f64>();
+        let fees_usd = prices
+            .get(&constants::mints::sol::id())
+            .map(|price| self.fees_lamports as f64 / LAMPORTS_PER_SOL * price);
+        if fees_usd.is_none() {
+            summary
+                .unpriced
+                .push(format!("{} lamports of fees", self.fees_lamports));
+        }
+
+        summary.rewards_usd = rewards_usd;
+        summary.interest_usd = interest_usd;
+        summary.swaps_usd = swaps_usd;
+        summary.fees_usd = fees_usd.unwrap_or(0.0);
+        summary.net_usd = rewards_usd + swaps_usd - interest_usd - summary.fees_usd;
+        summary
+    }
+}
+
+/// Every record is written to `path` right away
+#[derive(Debug)]
+pub struct Ledger {
+    path: PathBuf,
+    /// Records are kept in memory only, in dry run
+    persist: bool,
+    state: Mutex<PositionLedger>,
+}
+
+impl Ledger {
+    pub fn new(path: PathBuf, persist: bool) -> Self {
+        Self {
+            path,
+            persist,
+            state: Default::default(),
+        }
+    }
+
+    pub fn load(path: PathBuf, persist: bool) -> Result<Self, Error> {
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|_| Error::UnableToLoadLedger(path.clone()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PositionLedger::default(),
+            Err(_) => return Err(Error::UnableToLoadLedger(path)),
+        };
+
+        Ok(Self {
+            path,
+            persist,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn get(&self) -> PositionLedger {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Written to a temp file which is renamed over the ledger, so a crash never leaves
+    /// a partially written ledger
+    fn save(&self, ledger: &PositionLedger) -> std::io::Result<()> {
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let contents = serde_json::to_string_pretty(ledger)?;
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)
+    }
+
+    fn save_or_warn(&self, ledger: &PositionLedger) {
+        if !self.persist {
+            return;
+        }
+        if let Err(e) = self.save(ledger) {
+            warn!("Unable to save ledger {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// A failed save is logged and never fails the caller
+    pub fn record(&self, f: impl FnOnce(&mut PositionLedger)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        self.save_or_warn(&state);
+    }
+
+    /// Saves the ledger again, so a previously failed save does not lose records on exit
+    pub fn flush(&self) {
+        self.save_or_warn(&self.state.lock().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mmf-ledger-{}-{}.json", name, now()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn prices(entries: &[(Pubkey, f64, u8)]) -> (HashMap<Pubkey, f64>, HashMap<Pubkey, u8>) {
+        (
+            entries
+                .iter()
+                .map(|(mint, price, _)| (*mint, *price))
+                .collect(),
+            entries
+                .iter()
+                .map(|(mint, _, decimals)| (*mint, *decimals))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn records_survive_a_reload() {
+        let path = temp_path("round-trip");
+        let usdc = Pubkey::new_unique();
+        let pool = Pubkey::new_unique();
+        let signature = Signature::new_unique();
+
+        let ledger = Ledger::load(path.clone(), true).unwrap();
+        ledger.record(|ledger| {
+            ledger.record_entry();
+            ledger.record_entry_price(20.5);
+            ledger.record_collateral_deposit(1_000);
+            ledger.record_borrow(&usdc, 500);
+            ledger.record_transaction(&signature, 5_000, Some(-5_000), Some("mmf:entry:deposit"));
+            ledger.record_swap(&usdc, 500, &pool, 499);
+            ledger.record_lp(&pool, 10, 10);
+            ledger.record_reward(&usdc, 3);
+            ledger.record_interest(&usdc, 520);
+        });
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+
+        let reloaded = Ledger::load(path.clone(), true).unwrap().get();
+        let original = ledger.get();
+        assert_eq!(
+            serde_json::to_value(&reloaded).unwrap(),
+            serde_json::to_value(&original).unwrap()
+        );
+        assert_eq!(reloaded.collateral_deposited, 1_000);
+        assert_eq!(reloaded.entry_price, Some(20.5));
+        assert_eq!(reloaded.principal.get(&usdc.to_string()), Some(&500));
+        assert_eq!(reloaded.fees_lamports, 5_000);
+        assert_eq!(
+            reloaded.transaction_records[0].signature,
+            signature.to_string()
+        );
+        assert_eq!(reloaded.interest[&usdc.to_string()][0].accrued, 20);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_starts_empty_and_corrupt_file_fails() {
+        let path = temp_path("missing");
+        let ledger = Ledger::load(path.clone(), true).unwrap().get();
+        assert!(ledger.entries.is_empty());
+        assert_eq!(ledger.transactions, 0);
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(matches!(
+            Ledger::load(path.clone(), true),
+            Err(Error::UnableToLoadLedger(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_ledger_is_not_written() {
+        let path = temp_path("dry-run");
+        let ledger = Ledger::load(path.clone(), false).unwrap();
+        ledger.record(|ledger| ledger.record_entry());
+        ledger.flush();
+        assert!(!path.exists());
+        assert_eq!(ledger.get().entries.len(), 1);
+    }
+
+    #[test]
+    fn repayments_reduce_the_principal() {
+        let usdc = Pubkey::new_unique();
+        let mut ledger = PositionLedger::default();
+        ledger.record_borrow(&usdc, 1_000);
+        ledger.record_borrow(&usdc, 500);
+        ledger.record_repay(&usdc, 600, false);
+        assert_eq!(ledger.principal[&usdc.to_string()], 900);
+
+        ledger.record_interest(&usdc, 950);
+        assert_eq!(ledger.interest[&usdc.to_string()][0].accrued, 50);
+
+        ledger.record_repay(&usdc, 0, true);
+        assert!(!ledger.principal.contains_key(&usdc.to_string()));
+    }
+
+    #[test]
+    fn lp_balance_nets_burned_tokens() {
+        let pool = Pubkey::new_unique();
+        let mut ledger = PositionLedger::default();
+        assert_eq!(ledger.lp_balance(&pool), None);
+
+        ledger.record_lp(&pool, 100, 100);
+        ledger.record_lp(&pool, 50, 50);
+        ledger.record_lp_burn(&pool, 120);
+        assert_eq!(ledger.lp_balance(&pool), Some(30));
+    }
+
+    #[test]
+    fn summary_nets_rewards_swaps_interest_and_fees() {
+        let usdc = Pubkey::new_unique();
+        let reward = Pubkey::new_unique();
+        let sol = constants::mints::sol::id();
+        let (prices, decimals) = prices(&[(usdc, 1.0, 6), (reward, 2.0, 6), (sol, 20.0, 9)]);
+
+        let mut ledger = PositionLedger::default();
+        ledger.record_borrow(&usdc, 100_000_000);
+        ledger.record_interest(&usdc, 101_000_000);
+        ledger.record_reward(&reward, 5_000_000);
+        ledger.record_swap(&usdc, 10_000_000, &reward, 4_900_000);
+        ledger.record_transaction(&Signature::new_unique(), 50_000_000, None, None);
+
+        let summary = ledger.summary(&prices, &decimals);
+        assert_eq!(summary.rewards_usd, 10.0);
+        assert_eq!(summary.interest_usd, 1.0);
+        assert!((summary.swaps_usd - -0.2).abs() < 1e-9);
+        assert!((summary.fees_usd - 1.0).abs() < 1e-9);
+        assert!((summary.net_usd - 7.8).abs() < 1e-9);
+        assert!(summary.unpriced.is_empty());
+    }
+
+    #[test]
+    fn summary_lists_unpriced_amounts() {
+        let unknown = Pubkey::new_unique();
+        let mut ledger = PositionLedger::default();
+        ledger.record_reward(&unknown, 7);
+        ledger.record_transaction(&Signature::new_unique(), 5_000, None, None);
+
+        let summary = ledger.summary(&HashMap::new(), &HashMap::new());
+        assert_eq!(summary.net_usd, 0.0);
+        assert_eq!(
+            summary.unpriced,
+            vec![
+                format!("7 {}", unknown),
+                "5000 lamports of fees".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn since_keeps_only_later_records() {
+        let usdc = Pubkey::new_unique();
+        let mut ledger = PositionLedger::default();
+        ledger.record_borrow(&usdc, 100);
+        ledger.record_reward(&usdc, 1);
+        ledger.record_transaction(&Signature::new_unique(), 5_000, None, None);
+        ledger.rewards[0].timestamp = 10;
+        ledger.transaction_records[0].timestamp = 10;
+
+        let recent = ledger.since(20);
+        assert!(recent.rewards.is_empty());
+        assert_eq!(recent.fees_lamports, 0);
+        assert_eq!(recent.transactions, 0);
+        assert!(recent.principal.is_empty());
+
+        let all = ledger.since(0);
+        assert_eq!(all.rewards.len(), 1);
+        assert_eq!(all.fees_lamports, 5_000);
+    }
+}
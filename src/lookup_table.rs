@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use anchor_lang::prelude::Pubkey;
+use solana_address_lookup_table_program::instruction::{create_lookup_table, extend_lookup_table};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    address_lookup_table_account::AddressLookupTableAccount, commitment_config::CommitmentConfig,
+    system_program,
+};
+use tracing::{info, warn};
+
+use crate::{
+    addresses::StaticAddresses, bot, connection, constants, utils::transaction::SendConfig, Error,
+    Wallet,
+};
+
+/// So the extend transaction fits
+const MAX_ADDRESSES_PER_EXTEND: usize = 20;
+
+pub fn collect_static_accounts(static_addresses: &StaticAddresses) -> Vec<Pubkey> {
+    let mut accounts = vec![
+        system_program::id(),
+        constants::spl_token::id(),
+        constants::token_2022::id(),
+        constants::associated_token::id(),
+        marginfi::id(),
+        meteora::id(),
+        meteora_vault::id(),
+        constants::meteora::farm::id(),
+        constants::marginfi::group::id(),
+        static_addresses.marginfi_account,
+    ];
+
+    static_addresses
+        .wallet_token_accounts
+        .values()
+        .for_each(|(_, token_account)| accounts.push(*token_account));
+    static_addresses
+        .marginfi_banks
+        .iter()
+        .for_each(|(_, bank)| {
+            accounts.extend([
+                bank.address,
+                bank.liquidity_vault,
+                bank.liquidity_vault_authority,
+            ]);
+            accounts.extend(bank.oracle.addresses());
+        });
+    static_addresses
+        .meteora_dynamic_pools
+        .iter()
+        .for_each(|pool| {
+            accounts.extend([
+                pool.address,
+                pool.lp_mint,
+                pool.a_vault,
+                pool.b_vault,
+                pool.a_vault_lp,
+                pool.b_vault_lp,
+                pool.vault_a_vault,
+                pool.vault_b_vault,
+                pool.vault_a_lp_mint,
+                pool.vault_b_lp_mint,
+            ]);
+        });
+    static_addresses
+        .meteora_farms
+        .iter()
+        .flat_map(|(_, farms)| farms.iter())
+        .for_each(|farm| {
+            accounts.extend([
+                farm.address,
+                farm.staking_vault,
+                farm.user_account,
+                farm.reward_a_vault,
+                farm.reward_b_vault,
+            ]);
+        });
+
+    let mut unique = Vec::with_capacity(accounts.len());
+    accounts.into_iter().for_each(|account| {
+        if account != Pubkey::default() && !unique.contains(&account) {
+            unique.push(account);
+        }
+    });
+    unique
+}
+
+pub async fn load_lookup_table(
+    rpc_client: &Arc<RpcClient>,
+    address: &Pubkey,
+) -> Result<AddressLookupTableAccount, Error> {
+    let mut tables = connection::fetch_lookup_tables(rpc_client, &[*address], false).await?;
+    tables.pop().ok_or(Error::UnableToLoadLookupTable(*address))
+}
+
+/// Creates the lookup table when `alt_address` is not set
+pub async fn update_lookup_table(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    alt_address: Option<Pubkey>,
+    static_addresses: &StaticAddresses,
+    send_config: &SendConfig,
+) -> Result<Pubkey, Error> {
+    let (alt_address, existing_addresses) = match alt_address {
+        Some(alt_address) => {
+            let table = load_lookup_table(rpc_client, &alt_address).await?;
+            (alt_address, table.addresses)
+        }
+        None => {
+            let recent_slot = rpc_client
+                .get_slot_with_commitment(CommitmentConfig::finalized())
+                .await?;
+            let (ix, alt_address) = create_lookup_table(wallet.pubkey, wallet.pubkey, recent_slot);
+            bot::force_send_instructions(
+                rpc_client,
+                wallet,
+                vec![ix],
+                &vec![],
+                send_config,
+                "setup:lookup_table",
+            )
+            .await?;
+            info!(
+                "Created lookup table {}, set it as ADDRESS_LOOKUP_TABLE",
+                alt_address
+            );
+            (alt_address, vec![])
+        }
+    };
+
+    let missing = collect_static_accounts(static_addresses)
+        .into_iter()
+        .filter(|address| !existing_addresses.contains(address))
+        .collect::<Vec<_>>();
+    warn!(
+        "Lookup table {} is missing {} addresses",
+        alt_address,
+        missing.len()
+    );
+
+    for chunk in missing.chunks(MAX_ADDRESSES_PER_EXTEND) {
+        let ix = extend_lookup_table(
+            alt_address,
+            wallet.pubkey,
+            Some(wallet.pubkey),
+            chunk.to_vec(),
+        );
+        bot::force_send_instructions(
+            rpc_client,
+            wallet,
+            vec![ix],
+            &vec![],
+            send_config,
+            "setup:lookup_table",
+        )
+        .await?;
+    }
+
+    Ok(alt_address)
+}
@@ -1,28 +1,64 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
 
 use anchor_lang::prelude::Pubkey;
-use args::Args;
-use connection::{fetch_marginfi_account, fetch_marginfi_banks};
+use args::{Args, Command};
+use connection::{fetch_marginfi_account, fetch_marginfi_banks, fetch_oracles_state};
 use solana_client::client_error::ClientError;
-use solana_sdk::signature::Keypair;
-use state::OraclesState;
-use tokio::{sync::mpsc, time::sleep};
-use utils::transaction::ClientTransactionError;
+use solana_sdk::{
+    signature::{Keypair, Signature},
+    transaction::TransactionError,
+};
+use state::{MarginfiAccountWithBanks, OraclesState};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinError,
+};
+use tracing::{error, info, warn};
+use unwind::{UnwindContext, UnwindStep, WithdrawShare};
+use utils::{
+    jupiter_client::{JupiterClient, JupiterError},
+    transaction::ClientTransactionError,
+};
 
 use crate::{
-    addresses::StaticAddresses,
-    connection::fetch_meteora_pools_and_vaults,
+    addresses::{SharedStaticAddresses, StaticAddresses},
+    connection::{
+        fetch_meteora_farms, fetch_meteora_pools_and_vaults, fetch_mint_decimals,
+        fetch_mint_token_programs,
+    },
     instructions::InstructionBuilder,
-    utils::websocket_client::{create_persisted_websocket_connection, WebsocketError},
+    scheduler::get_panic_message,
+    supervisor::supervise,
+    utils::{
+        notifier::{NotificationLevel, Notifier},
+        transaction::FailureReport,
+        websocket_client::{create_persisted_websocket_connection, WebsocketError},
+    },
 };
 
 pub mod addresses;
 pub mod args;
 pub mod bot;
+pub mod config;
 pub mod connection;
 pub mod constants;
 pub mod instructions;
+pub mod ledger;
+pub mod lookup_table;
+pub mod metrics;
+pub mod nonce;
+pub mod position;
+pub mod reconcile;
+pub mod risk;
+pub mod scheduler;
+pub mod shutdown;
+pub mod startup;
 pub mod state;
+pub mod status;
+pub mod supervisor;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod unwind;
 pub mod utils;
 
 #[derive(Debug)]
@@ -31,32 +67,188 @@ pub struct Wallet {
     pub pubkey: Pubkey,
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("Unable to decode account data, it is not base64 encoded")]
     UnableToDecode,
-    UnableToDeserialize,
-    UnableToFetchAccount,
+    #[error(
+        "Unable to deserialize {expected}{}",
+        .account.map(|account| format!(" from account {}", account)).unwrap_or_default()
+    )]
+    UnableToDeserialize {
+        expected: &'static str,
+        account: Option<Pubkey>,
+    },
+    #[error("Account {0} does not exist")]
+    UnableToFetchAccount(Pubkey),
+    #[error("Unable to parse Pyth oracle price")]
     UnableToParsePythOracle,
+    #[error("Unable to parse Switchboard oracle price")]
     UnableToParseSwitchboardOracle,
 
+    #[error("Marginfi bank is unknown or does not belong to the marginfi group")]
     InvalidMarginfiBank,
+    #[error("No marginfi bank is known for mint {0}")]
+    MissingBank(Pubkey),
+    #[error("No price is known for oracle {0}")]
+    MissingOracle(Pubkey),
+    #[error("Wallet owns more than one marginfi account, set MARGINFI_ACCOUNT to pick one")]
+    AmbiguousMarginfiAccount,
+    #[error("Wallet does not own a marginfi account, pass --create-account to run to create one")]
+    MissingMarginfiAccount,
+    #[error("Marginfi account {0} is not owned by the wallet in the marginfi group")]
+    ForeignMarginfiAccount(Pubkey),
+    #[error("Token account data is too short to hold an amount")]
     InvalidTokenAccount,
+    #[error("Wallet holds {available} of mint {mint}, {required} is required")]
+    InsufficientTokenBalance {
+        mint: Pubkey,
+        required: u64,
+        available: u64,
+    },
+    #[error("Wallet holds {available} lamports, {required} lamports are required")]
+    InsufficientSolBalance { required: u64, available: u64 },
+    #[error("Meteora pool is not configured or its accounts were not loaded")]
     InvalidMeteoraPool,
+    #[error("Meteora pool {pool} has no borrowable token (token a: {token_a_mint}, token b: {token_b_mint})")]
+    NoBorrowablePoolToken {
+        pool: Pubkey,
+        token_a_mint: Pubkey,
+        token_b_mint: Pubkey,
+    },
+    #[error("Meteora farm is not configured or does not exist")]
     InvalidMeteoraFarm,
+    #[error("Constants do not match the chain: {}", .0.join(", "))]
+    InvalidConstants(Vec<String>),
+    #[error("Unwind stopped at the {step} step: {error}")]
+    UnwindFailed { step: UnwindStep, error: Box<Error> },
+    #[error("Collateral mint {0} has no marginfi bank which counts it towards health")]
+    InvalidCollateral(Pubkey),
+    /// Collateral amount is zero and using the existing collateral was not confirmed
+    #[error("Collateral amount is zero, pass --collateral or --use-existing-collateral")]
+    MissingCollateralAmount,
+    #[error("No collateral of mint {0} is deposited and none is going to be")]
+    NoCollateral(Pubkey),
+    #[error("Collateral bank {0} is at its deposit limit")]
+    CollateralBankFull(Pubkey),
+    #[error("Target init health {0} is below the safe minimum")]
+    UnsafeTargetHealth(f64),
+    #[error("Collateral does not allow borrowing anything")]
+    NothingToBorrow,
+    /// Swap filled below the quote by more than the allowed shortfall
+    #[error("Swap to mint {mint} filled {realized} of the quoted {quoted}")]
+    SwapShortfall {
+        mint: Pubkey,
+        quoted: u64,
+        realized: u64,
+    },
+    #[error(
+        "Oracles are stale: {}",
+        .0.iter().map(|oracle| oracle.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    StaleOracles(Vec<Pubkey>),
+    /// Withdraw percentage outside of (0, 100] or a non-positive USD amount
+    #[error("Withdraw amount {0} has to be a percentage in (0, 100] or a positive USD value")]
+    InvalidWithdrawAmount(f64),
+    #[error("Stop loss triggered: {0}")]
+    StopLossTriggered(String),
+    #[error(
+        "Stop loss triggered before, run with --rearm-stop-loss or remove {}",
+        .0.display()
+    )]
+    StopLossNotArmed(PathBuf),
 
+    #[error("Fetched transaction has no status meta")]
     TransactionError,
+    #[error("Transaction {signature} did not change the wallet balance of mint {mint}")]
+    TokenChangeNotFound { signature: Signature, mint: Pubkey },
+    #[error("Token amount {0} is not a number")]
+    InvalidTokenAmount(String),
+    #[error("Wallet balance of mint {mint} changed by {change}, the other way than expected")]
+    UnexpectedTokenChange { mint: Pubkey, change: i128 },
+    /// Simulation before the first send or the preflight of the RPC failed, nothing was
+    /// sent
+    #[error("Transaction simulation failed with {error}: {report}")]
+    SimulationFailed {
+        error: TransactionError,
+        program_id: Option<Pubkey>,
+        report: Box<FailureReport>,
+    },
+    #[error("Transaction {signature} failed with {error}: {report}")]
+    TransactionFailed {
+        signature: Signature,
+        error: TransactionError,
+        program_id: Option<Pubkey>,
+        report: Box<FailureReport>,
+    },
+    #[error("Transaction {0} was not confirmed in time")]
+    TransactionTimeout(Signature),
+    /// RPC has not seen the blockhash yet or is behind the cluster
+    #[error("RPC has not seen the blockhash or is behind the cluster: {0}")]
+    StaleBlockhash(Box<ClientError>),
+    #[error("Every RPC rejected the transaction: {}", .0.join("; "))]
+    BroadcastFailed(Vec<String>),
+    #[error(
+        "{instructions} instructions which have to be sent together do not fit in a transaction"
+    )]
+    InstructionGroupTooLarge { instructions: usize },
+    #[error("Transaction did not land after {retries} retries, the last attempt failed with: {last_error}")]
+    RetriesExhausted {
+        retries: u32,
+        last_error: Box<Error>,
+    },
 
+    #[error("Math overflow")]
     MathOverflow,
+    #[error("Amount {amount} has more than the {decimals} decimals of its mint")]
+    AmountTooPrecise { amount: String, decimals: u8 },
+    #[error("Amount {0} is too large for its mint")]
+    AmountOverflow(String),
+    #[error("Unable to build transaction: {0}")]
     ClientTransactionError(ClientTransactionError),
 
+    #[error("Jupiter API request failed: {0}")]
     JupiterApiError(reqwest::Error),
-    RpcError,
+    #[error("Jupiter returned an error: {0}")]
+    JupiterError(JupiterError),
+    #[error("Unable to load lookup table {0}")]
+    UnableToLoadLookupTable(Pubkey),
+    #[error("Nonce account {0} does not exist or is not initialized")]
+    MissingNonceAccount(Pubkey),
+    #[error("Unable to load ledger {}", .0.display())]
+    UnableToLoadLedger(PathBuf),
+    #[error("Risk limit blocked the transaction: {0}")]
+    RiskLimit(String),
+    #[error("Shutting down")]
+    ShuttingDown,
+    #[error("RPC request failed: {0}")]
+    RpcError(Box<ClientError>),
+    #[error("Websocket error: {0}")]
     WebsocketError(WebsocketError),
+    #[error("Unable to start the metrics server: {0}")]
+    MetricsServerError(std::io::Error),
+    #[error("Task {task} {reason}")]
+    TaskFailed { task: &'static str, reason: String },
+}
+
+impl Error {
+    pub fn with_account(self, pubkey: Pubkey) -> Self {
+        match self {
+            Self::UnableToDeserialize {
+                expected,
+                account: None,
+            } => Self::UnableToDeserialize {
+                expected,
+                account: Some(pubkey),
+            },
+            error => error,
+        }
+    }
 }
 
 impl From<ClientError> for Error {
-    fn from(_: ClientError) -> Self {
-        Self::RpcError
+    fn from(value: ClientError) -> Self {
+        Self::RpcError(Box::new(value))
     }
 }
 
@@ -72,66 +264,460 @@ impl From<ClientTransactionError> for Error {
     }
 }
 
+impl From<JupiterError> for Error {
+    fn from(value: JupiterError) -> Self {
+        Self::JupiterError(value)
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
         Self::JupiterApiError(value)
     }
 }
 
+/// A panic or a cancellation is an error too
+async fn report_bot_result(
+    notifier: &Notifier,
+    task: &'static str,
+    res: Result<Result<(), Error>, JoinError>,
+) -> Result<(), Error> {
+    let res = res.unwrap_or_else(|e| {
+        let is_panic = e.is_panic();
+        let reason = if is_panic {
+            format!("panicked: {}", get_panic_message(&*e.into_panic()))
+        } else {
+            format!("was cancelled: {}", e)
+        };
+        error!(task, is_panic, "Task {} {}", task, reason);
+        Err(Error::TaskFailed { task, reason })
+    });
+    if let Err(e) = &res {
+        error!("Bot stopped: {}", e);
+        notifier
+            .notify_and_wait(NotificationLevel::Critical, format!("Bot stopped: {}", e))
+            .await;
+    }
+    res
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<(), Error> {
     let args = Args::load();
+    utils::logging::init(args.json_logs);
+    info!(
+        "Confirming transactions at {:?} commitment, polling every {:?} for up to {:?}, with {} retries",
+        args.send_config.commitment_policy.confirmation.commitment,
+        args.send_config.poll_interval,
+        args.send_config.max_confirmation_wait,
+        args.send_config.max_retries
+    );
+    if let Some(metrics_address) = args.metrics_address {
+        metrics::start_server(metrics_address).await?;
+    }
+
+    let instruction_builder = InstructionBuilder::new(args.wallet.clone());
 
     let (marginfi_account_address, initial_marginfi_account) =
-        fetch_marginfi_account(&args.rpc_client, &args.wallet).await?;
-    let initial_marginfi_banks = fetch_marginfi_banks(&args.rpc_client).await?;
-    let meteora_pools_and_vaults = fetch_meteora_pools_and_vaults(&args.rpc_client).await?;
+        match fetch_marginfi_account(&args.rpc_client, &args.wallet, args.marginfi_account).await {
+            Err(Error::MissingMarginfiAccount)
+                if args.create_account && matches!(args.command, Command::Run(_)) =>
+            {
+                let address = bot::create_marginfi_account(
+                    &args.rpc_client,
+                    &args.wallet,
+                    &instruction_builder,
+                    &args.send_config,
+                )
+                .await?;
+                fetch_marginfi_account(&args.rpc_client, &args.wallet, Some(address)).await?
+            }
+            res => res?,
+        };
+    let bank_mints = [args.collateral.mint]
+        .into_iter()
+        .chain(args.borrowable_mints.iter().copied())
+        .collect::<Vec<_>>();
+    let required_banks = initial_marginfi_account
+        .lending_account
+        .balances
+        .iter()
+        .filter(|balance| balance.active)
+        .map(|balance| balance.bank_pk)
+        .chain(args.marginfi_banks.iter().copied())
+        .collect::<Vec<_>>();
+    let initial_marginfi_banks =
+        fetch_marginfi_banks(&args.rpc_client, &bank_mints, &required_banks).await?;
+    startup::validate_collateral(&args.collateral.mint, &initial_marginfi_banks)?;
+    startup::validate_borrowable_mints(&args.borrowable_mints, &initial_marginfi_banks)?;
+    let collateral_decimals = fetch_mint_decimals(&args.rpc_client, &args.collateral.mint).await?;
+    let collateral_amount = args.collateral.to_amount(collateral_decimals)?;
+    if args.validate_bank_vaults || args.command == Command::Validate {
+        StaticAddresses::validate_marginfi_bank_vaults(&initial_marginfi_banks)?;
+    }
+    let meteora_pools_and_vaults = fetch_meteora_pools_and_vaults(
+        &args.rpc_client,
+        &args
+            .meteora_pools
+            .iter()
+            .map(|config| config.pool)
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+
+    let meteora_farms = fetch_meteora_farms(
+        &args.rpc_client,
+        &args
+            .meteora_pools
+            .iter()
+            .flat_map(|config| config.farms.iter().copied())
+            .collect::<Vec<_>>(),
+    )
+    .await?;
 
-    let static_addresses = StaticAddresses::new(&args.wallet)
+    let mut mints = vec![
+        constants::mints::usdc::id(),
+        constants::mints::uxd::id(),
+        constants::mints::usdt::id(),
+    ];
+    bank_mints
+        .iter()
+        .chain(initial_marginfi_banks.iter().map(|(_, bank)| &bank.mint))
+        .chain(
+            meteora_pools_and_vaults
+                .pools
+                .iter()
+                .flat_map(|(_, pool)| [&pool.token_a_mint, &pool.token_b_mint, &pool.lp_mint]),
+        )
+        .chain(
+            meteora_farms
+                .iter()
+                .flat_map(|(_, farm)| [&farm.reward_a_mint, &farm.reward_b_mint]),
+        )
+        .for_each(|mint| {
+            if !mints.contains(mint) {
+                mints.push(*mint);
+            }
+        });
+    let token_programs = fetch_mint_token_programs(&args.rpc_client, &mints).await?;
+
+    let static_addresses = StaticAddresses::new(&args.wallet, token_programs)
+        .set_collateral_mint(&args.wallet, args.collateral.mint)
         .set_marginfi_account(marginfi_account_address)
         .set_marginfi_banks(&initial_marginfi_banks)
-        .set_meteora_pools_and_vaults(&args.wallet, &meteora_pools_and_vaults)?
-        .set_meteora_farms(&args.wallet);
+        .set_borrowable_mints(
+            &args.wallet,
+            &args.borrowable_mints,
+            &args.borrow_caps,
+            args.preferred_input_mint,
+        )
+        .set_meteora_pools_and_vaults(&args.wallet, &meteora_pools_and_vaults, &args.meteora_pools)?
+        .set_meteora_farms(&args.wallet, &args.meteora_pools, &meteora_farms)?;
+
+    if !args.skip_constants_validation || args.command == Command::Validate {
+        startup::validate_constants(&args.rpc_client, &static_addresses).await?;
+    }
+
+    match args.command {
+        Command::Status { json } => {
+            return status::print_status(
+                &args.rpc_client,
+                &args.wallet,
+                &static_addresses,
+                &args.send_config.ledger,
+                json,
+            )
+            .await;
+        }
+        Command::Validate => {
+            if let Some(address) = args.alt_address {
+                lookup_table::load_lookup_table(&args.rpc_client, &address).await?;
+            }
+            info!("Settings, accounts and constants are valid");
+            return Ok(());
+        }
+        Command::UpdateAlt => {
+            let address = lookup_table::update_lookup_table(
+                &args.rpc_client,
+                &args.wallet,
+                args.alt_address,
+                &static_addresses,
+                &args.send_config,
+            )
+            .await?;
+            info!("Lookup table {} is up to date", address);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let lookup_tables = match args.alt_address {
+        Some(address) => vec![lookup_table::load_lookup_table(&args.rpc_client, &address).await?],
+        None => vec![],
+    };
+
+    if let Some(nonce_account) = args.send_config.nonce_account {
+        nonce::ensure_nonce_account(
+            &args.rpc_client,
+            &args.wallet,
+            &nonce_account,
+            &args.send_config,
+        )
+        .await?;
+    }
+
+    if matches!(args.command, Command::Unwind | Command::Withdraw { .. }) {
+        // The bot updates the prices of the risk limits on every health check, unwinds
+        // are checked at the prices of the start
+        if args.send_config.risk_guard.is_enabled() {
+            let oracles_state =
+                fetch_oracles_state(&args.rpc_client, &static_addresses.marginfi_banks).await?;
+            let account_with_banks = MarginfiAccountWithBanks::new(
+                initial_marginfi_account.clone(),
+                initial_marginfi_banks.clone(),
+            );
+            args.send_config
+                .risk_guard
+                .update(&account_with_banks, &oracles_state)
+                .await?;
+        }
+        let ctx = UnwindContext {
+            rpc_client: &args.rpc_client,
+            wallet: &args.wallet,
+            jupiter_client: &JupiterClient::new(args.jupiter_config.clone()),
+            static_addresses: &static_addresses,
+            instruction_builder: &instruction_builder,
+            lookup_tables: &lookup_tables,
+            swap_route_config: &args.swap_route_config,
+            best_effort_alts: args.best_effort_alts,
+            pool_slippage_bps: args.pool_slippage_bps,
+            send_config: &args.send_config,
+        };
+        return match args.command {
+            Command::Withdraw {
+                pct,
+                amount_usd,
+                withdraw_collateral,
+            } => {
+                let share = match (pct, amount_usd) {
+                    (Some(pct), _) => WithdrawShare::Pct(pct),
+                    (None, amount_usd) => WithdrawShare::AmountUsd(amount_usd.unwrap_or(0.0)),
+                };
+                unwind::withdraw(&ctx, share, withdraw_collateral, args.target_init_health).await
+            }
+            _ => unwind::unwind(&ctx).await,
+        };
+    }
 
-    let websocket_handle = create_persisted_websocket_connection(args.ws_client.clone()).await?;
+    let static_addresses = Arc::new(SharedStaticAddresses::new(
+        args.wallet.clone(),
+        static_addresses,
+    ));
 
+    let shutdown = args.send_config.shutdown.clone();
+    let notifier = args.send_config.notifier.clone();
     let (oracles_state_update_sender, oracles_state_update_receiver) = mpsc::unbounded_channel();
+    let oracles_state_update_receiver = Arc::new(Mutex::new(oracles_state_update_receiver));
     let oracles_state = Arc::new(OraclesState::new());
-    let state_updates_handle =
-        OraclesState::listen_to_updates(oracles_state.clone(), oracles_state_update_receiver);
-
-    let pyth_subscription_handle = connection::subscribe_to_pyth_oracles(
-        args.ws_client.clone(),
-        &static_addresses.marginfi_banks,
-        oracles_state_update_sender.clone(),
-    );
-    let switchboard_subscription_handle = connection::init_and_subscribe_to_switchboard_oracles(
-        args.rpc_client.clone(),
-        args.ws_client.clone(),
-        &static_addresses.marginfi_banks,
-        oracles_state_update_sender.clone(),
-    )
-    .await?;
+    let marginfi_banks = static_addresses.read().await.marginfi_banks.clone();
 
-    let instruction_builder = InstructionBuilder::new(args.wallet.clone());
+    // Background tasks are restarted when they exit or panic, so a failure of one of them
+    // does not stop the bot while the position is open
+    let mut tasks = vec![
+        supervise("websocket", shutdown.clone(), notifier.clone(), {
+            let (ws_client, shutdown) = (args.ws_client.clone(), shutdown.clone());
+            move || {
+                create_persisted_websocket_connection(ws_client.clone(), shutdown.clone())
+                    .unwrap_or_else(|e| tokio::spawn(async move { Err(e) }))
+            }
+        }),
+        supervise("state updates", shutdown.clone(), notifier.clone(), {
+            let (oracles_state, shutdown) = (oracles_state.clone(), shutdown.clone());
+            move || {
+                OraclesState::listen_to_updates(
+                    oracles_state.clone(),
+                    oracles_state_update_receiver.clone(),
+                    shutdown.clone(),
+                )
+            }
+        }),
+        supervise("slot tracker", shutdown.clone(), notifier.clone(), {
+            let rpc_client = args.rpc_client.clone();
+            let ws_client = args.ws_client.clone();
+            let current_slot = oracles_state.current_slot.clone();
+            let shutdown = shutdown.clone();
+            move || {
+                connection::track_current_slot(
+                    rpc_client.clone(),
+                    ws_client.clone(),
+                    current_slot.clone(),
+                    shutdown.clone(),
+                )
+            }
+        }),
+        supervise("pyth subscription", shutdown.clone(), notifier.clone(), {
+            let rpc_client = args.rpc_client.clone();
+            let ws_client = args.ws_client.clone();
+            let marginfi_banks = marginfi_banks.clone();
+            let current_slot = oracles_state.current_slot.clone();
+            let state_update_sender = oracles_state_update_sender.clone();
+            let use_program_subscribe = args.oracle_program_subscribe;
+            let shutdown = shutdown.clone();
+            move || {
+                connection::subscribe_to_pyth_oracles(
+                    rpc_client.clone(),
+                    ws_client.clone(),
+                    &marginfi_banks,
+                    current_slot.clone(),
+                    state_update_sender.clone(),
+                    use_program_subscribe,
+                    shutdown.clone(),
+                )
+            }
+        }),
+        supervise(
+            "switchboard subscription",
+            shutdown.clone(),
+            notifier.clone(),
+            {
+                let rpc_client = args.rpc_client.clone();
+                let ws_client = args.ws_client.clone();
+                let marginfi_banks = marginfi_banks.clone();
+                let state_update_sender = oracles_state_update_sender.clone();
+                let use_program_subscribe = args.oracle_program_subscribe;
+                let shutdown = shutdown.clone();
+                move || {
+                    connection::subscribe_to_switchboard_oracles(
+                        rpc_client.clone(),
+                        ws_client.clone(),
+                        &marginfi_banks,
+                        state_update_sender.clone(),
+                        use_program_subscribe,
+                        shutdown.clone(),
+                    )
+                }
+            },
+        ),
+        supervise(
+            "static accounts subscription",
+            shutdown.clone(),
+            notifier.clone(),
+            {
+                let rpc_client = args.rpc_client.clone();
+                let ws_client = args.ws_client.clone();
+                let static_addresses = static_addresses.clone();
+                let shutdown = shutdown.clone();
+                move || {
+                    connection::subscribe_to_static_accounts(
+                        rpc_client.clone(),
+                        ws_client.clone(),
+                        static_addresses.clone(),
+                        shutdown.clone(),
+                    )
+                }
+            },
+        ),
+    ];
 
-    sleep(Duration::from_secs(5)).await;
+    connection::wait_for_oracles(&args.rpc_client, &marginfi_banks, &oracles_state).await?;
 
-    tokio::select! {
-        main_process_res = bot::start(args, initial_marginfi_account, initial_marginfi_banks, oracles_state, static_addresses, instruction_builder) => {
-            main_process_res.unwrap()
-        }
-        websocket_process_res = websocket_handle => {
-            websocket_process_res.unwrap().map_err(|e| e.into())
-        }
-        state_process_res = state_updates_handle => {
-            Ok(state_process_res.unwrap())
+    let mut bot_handle = bot::start(
+        args,
+        initial_marginfi_account,
+        initial_marginfi_banks,
+        oracles_state,
+        static_addresses,
+        instruction_builder,
+        lookup_tables,
+        collateral_amount,
+    );
+    let res = tokio::select! {
+        main_process_res = &mut bot_handle => {
+            report_bot_result(&notifier, "bot", main_process_res).await
         }
-        pyth_subscription_res = pyth_subscription_handle => {
-            pyth_subscription_res.unwrap()
+        signal = shutdown::wait_for_signal() => {
+            match signal {
+                Ok(signal) => info!("Received {}, shutting down", signal),
+                Err(e) => error!("Unable to listen for signals: {}, shutting down", e),
+            }
+            shutdown.trigger();
+            // The bot finishes in-flight transactions, flushes the ledger and unwinds
+            // when requested, another signal exits right away
+            tokio::select! {
+                main_process_res = &mut bot_handle => {
+                    report_bot_result(&notifier, "bot", main_process_res).await
+                }
+                _ = shutdown::wait_for_signal() => {
+                    warn!("Received another signal, exiting without waiting for the bot");
+                    bot_handle.abort();
+                    Ok(())
+                }
+            }
         }
-        switchboard_subscription_res = switchboard_subscription_handle => {
-            switchboard_subscription_res.unwrap()
+    };
+
+    // Tasks which are still running are told to stop and given time to exit
+    shutdown.trigger();
+    drop(oracles_state_update_sender);
+    shutdown::wait_for_task("bot", &mut bot_handle).await;
+    // The websocket and the state listener started first and stop last
+    for task in tasks.iter_mut().rev() {
+        shutdown::wait_for_task(task.name, &mut task.handle).await;
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn panicked_bot_is_an_error() {
+        let handle: JoinHandle<Result<(), Error>> = tokio::spawn(async { panic!("bot panicked") });
+        let res = report_bot_result(&Notifier::disabled(), "bot", handle.await).await;
+
+        match res {
+            Err(Error::TaskFailed { task, reason }) => {
+                assert_eq!(task, "bot");
+                assert_eq!(reason, "panicked: bot panicked");
+            }
+            res => panic!("unexpected result {:?}", res),
         }
     }
+
+    #[tokio::test]
+    async fn cancelled_bot_is_an_error() {
+        let handle = tokio::spawn(std::future::pending::<Result<(), Error>>());
+        handle.abort();
+        let res = report_bot_result(&Notifier::disabled(), "bot", handle.await).await;
+
+        assert!(matches!(res, Err(Error::TaskFailed { task: "bot", .. })));
+    }
+
+    #[tokio::test]
+    async fn bot_result_is_passed_through() {
+        let handle = tokio::spawn(async { Err(Error::ShuttingDown) });
+        let res = report_bot_result(&Notifier::disabled(), "bot", handle.await).await;
+        assert!(matches!(res, Err(Error::ShuttingDown)));
+
+        let handle = tokio::spawn(async { Ok(()) });
+        assert!(
+            report_bot_result(&Notifier::disabled(), "bot", handle.await)
+                .await
+                .is_ok()
+        );
+    }
 }
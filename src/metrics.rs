@@ -0,0 +1,262 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::Error;
+
+pub const INIT_HEALTH: &'static str = "farmer_init_health";
+pub const MAINT_HEALTH: &'static str = "farmer_maint_health";
+pub const WEIGHTED_ASSETS: &'static str = "farmer_weighted_assets_usd";
+pub const WEIGHTED_LIABILITIES: &'static str = "farmer_weighted_liabilities_usd";
+pub const BORROW_RATE: &'static str = "farmer_bank_borrow_rate";
+pub const COLLATERAL_PRICE: &'static str = "farmer_collateral_price_usd";
+pub const STAKED_LP: &'static str = "farmer_staked_lp";
+pub const PENDING_REWARDS: &'static str = "farmer_pending_rewards";
+pub const ORACLE_AGE: &'static str = "farmer_oracle_age_seconds";
+pub const ORACLE_AGE_SLOTS: &'static str = "farmer_oracle_age_slots";
+pub const WEBSOCKET_RECONNECTS: &'static str = "farmer_websocket_reconnects_total";
+pub const WEBSOCKET_DISCONNECTS: &'static str = "farmer_websocket_disconnects_total";
+pub const WEBSOCKET_SUBSCRIPTIONS: &'static str = "farmer_websocket_subscriptions";
+pub const WEBSOCKET_FAILOVERS: &'static str = "farmer_websocket_failovers_total";
+pub const TRANSACTIONS_SENT: &'static str = "farmer_transactions_sent_total";
+pub const TRANSACTIONS_CONFIRMED: &'static str = "farmer_transactions_confirmed_total";
+pub const TRANSACTIONS_FAILED: &'static str = "farmer_transactions_failed_total";
+pub const JUPITER_REQUESTS: &'static str = "farmer_jupiter_requests_total";
+pub const JUPITER_REQUEST_DURATION: &'static str = "farmer_jupiter_request_duration_seconds";
+pub const RECONCILE_DRIFTS: &'static str = "farmer_reconcile_drifts_total";
+pub const RPC_FAILOVERS: &'static str = "farmer_rpc_failovers_total";
+pub const SUBSCRIPTION_AGE: &'static str = "farmer_subscription_age_seconds";
+pub const TASK_RESTARTS: &'static str = "farmer_task_restarts_total";
+
+/// (name, type, help)
+const DEFINITIONS: [(&'static str, &'static str, &'static str); 23] = [
+    (
+        INIT_HEALTH,
+        "gauge",
+        "Initial health of the marginfi account",
+    ),
+    (
+        MAINT_HEALTH,
+        "gauge",
+        "Maintenance health of the marginfi account",
+    ),
+    (WEIGHTED_ASSETS, "gauge", "Maintenance weighted assets"),
+    (
+        WEIGHTED_LIABILITIES,
+        "gauge",
+        "Maintenance weighted liabilities",
+    ),
+    (BORROW_RATE, "gauge", "Borrow rate of the bank"),
+    (
+        COLLATERAL_PRICE,
+        "gauge",
+        "Low-bias oracle price of the collateral",
+    ),
+    (STAKED_LP, "gauge", "LP tokens staked in the farm"),
+    (PENDING_REWARDS, "gauge", "Pending farm rewards"),
+    (ORACLE_AGE, "gauge", "Seconds since the last oracle update"),
+    (
+        ORACLE_AGE_SLOTS,
+        "gauge",
+        "Slots since the last oracle update",
+    ),
+    (WEBSOCKET_RECONNECTS, "counter", "Websocket reconnects"),
+    (
+        WEBSOCKET_DISCONNECTS,
+        "counter",
+        "Websocket disconnects by reason",
+    ),
+    (
+        WEBSOCKET_SUBSCRIPTIONS,
+        "gauge",
+        "Active websocket subscriptions",
+    ),
+    (
+        WEBSOCKET_FAILOVERS,
+        "counter",
+        "Websocket failovers by the endpoint failed over to",
+    ),
+    (
+        TRANSACTIONS_SENT,
+        "counter",
+        "Transactions sent, resends included",
+    ),
+    (TRANSACTIONS_CONFIRMED, "counter", "Transactions confirmed"),
+    (
+        TRANSACTIONS_FAILED,
+        "counter",
+        "Transactions failed or given up on",
+    ),
+    (
+        JUPITER_REQUESTS,
+        "counter",
+        "Jupiter API requests by status",
+    ),
+    (
+        JUPITER_REQUEST_DURATION,
+        "summary",
+        "Jupiter API request latency",
+    ),
+    (
+        RECONCILE_DRIFTS,
+        "counter",
+        "Local state drifts from chain found by reconciliation",
+    ),
+    (
+        RPC_FAILOVERS,
+        "counter",
+        "RPC failovers by the endpoint failed over to",
+    ),
+    (
+        SUBSCRIPTION_AGE,
+        "gauge",
+        "Seconds since the last notification of the subscription",
+    ),
+    (
+        TASK_RESTARTS,
+        "counter",
+        "Restarts of background tasks by task",
+    ),
+];
+
+#[derive(Default)]
+struct Registry {
+    enabled: AtomicBool,
+    /// Series keyed by their labels, e.g. `name{label="value"}`
+    series: Mutex<BTreeMap<&'static str, BTreeMap<String, f64>>>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::default();
+}
+
+/// Metrics are only recorded once the server is started
+pub fn is_enabled() -> bool {
+    REGISTRY.enabled.load(Ordering::Relaxed)
+}
+
+fn series_name(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{}{{{}}}", name, labels)
+}
+
+fn update(name: &'static str, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+    if !is_enabled() {
+        return;
+    }
+
+    let mut series = REGISTRY.series.lock().unwrap();
+    let value = series
+        .entry(name)
+        .or_default()
+        .entry(series_name(name, labels))
+        .or_insert(0.0);
+    f(value);
+}
+
+pub fn set_gauge(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, labels, |v| *v = value);
+}
+
+pub fn inc_counter(name: &'static str, labels: &[(&str, &str)]) {
+    update(name, labels, |v| *v += 1.0);
+}
+
+pub fn observe_duration(name: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    if !is_enabled() {
+        return;
+    }
+
+    let mut series = REGISTRY.series.lock().unwrap();
+    let metric_series = series.entry(name).or_default();
+    *metric_series
+        .entry(series_name(&format!("{}_sum", name), labels))
+        .or_insert(0.0) += duration.as_secs_f64();
+    *metric_series
+        .entry(series_name(&format!("{}_count", name), labels))
+        .or_insert(0.0) += 1.0;
+}
+
+fn render() -> String {
+    let series = REGISTRY.series.lock().unwrap();
+    let mut output = String::new();
+
+    for (name, kind, help) in DEFINITIONS {
+        let Some(metric_series) = series.get(name) else {
+            continue;
+        };
+        output.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for (series_name, value) in metric_series {
+            output.push_str(&format!("{} {}\n", series_name, value));
+        }
+    }
+
+    output
+}
+
+async fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn start_server(address: SocketAddr) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(Error::MetricsServerError)?;
+    REGISTRY.enabled.store(true, Ordering::Relaxed);
+    info!("Serving metrics on http://{}/metrics", address);
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream).await {
+                            warn!("Metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Unable to accept metrics connection: {}", e),
+            }
+        }
+    }))
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use anchor_lang::prelude::Pubkey;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    nonce::state::{State, Versions},
+    system_instruction, system_program,
+};
+use tracing::info;
+
+use crate::{bot, utils::transaction::SendConfig, Error, Wallet};
+
+const NONCE_ACCOUNT_SEED: &str = "mmf-nonce";
+
+/// The wallet is also the authority of the nonce account
+pub fn derive_nonce_account(wallet: &Pubkey) -> Pubkey {
+    Pubkey::create_with_seed(wallet, NONCE_ACCOUNT_SEED, &system_program::id()).unwrap()
+}
+
+/// `None` when the account does not exist or is not initialized
+pub async fn fetch_nonce(
+    rpc_client: &Arc<RpcClient>,
+    nonce_account: &Pubkey,
+) -> Result<Option<Hash>, Error> {
+    let Some(account) = rpc_client
+        .get_account_with_commitment(nonce_account, CommitmentConfig::confirmed())
+        .await?
+        .value
+    else {
+        return Ok(None);
+    };
+
+    let versions: Versions =
+        bincode::deserialize(&account.data).map_err(|_| Error::UnableToDeserialize {
+            expected: "nonce account",
+            account: Some(*nonce_account),
+        })?;
+    match versions.state() {
+        State::Initialized(data) => Ok(Some(data.blockhash())),
+        State::Uninitialized => Ok(None),
+    }
+}
+
+/// Any other nonce account than the derived one has to exist already
+pub async fn ensure_nonce_account(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    nonce_account: &Pubkey,
+    send_config: &SendConfig,
+) -> Result<(), Error> {
+    if fetch_nonce(rpc_client, nonce_account).await?.is_some() {
+        return Ok(());
+    }
+    if *nonce_account != derive_nonce_account(&wallet.pubkey) {
+        return Err(Error::MissingNonceAccount(*nonce_account));
+    }
+
+    let lamports = rpc_client
+        .get_minimum_balance_for_rent_exemption(State::size())
+        .await?;
+    let instructions = system_instruction::create_nonce_account_with_seed(
+        &wallet.pubkey,
+        nonce_account,
+        &wallet.pubkey,
+        NONCE_ACCOUNT_SEED,
+        &wallet.pubkey,
+        lamports,
+    );
+    // The nonce account does not exist yet, so this transaction can not use it
+    let send_config = SendConfig {
+        nonce_account: None,
+        ..send_config.clone()
+    };
+    bot::force_send_instructions(
+        rpc_client,
+        wallet,
+        instructions,
+        &vec![],
+        &send_config,
+        "setup:nonce_account",
+    )
+    .await?;
+    info!("Created nonce account {}", nonce_account);
+
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use anchor_lang::prelude::Pubkey;
+use tracing::info;
+
+use crate::{
+    addresses::PoolId,
+    connection,
+    state::MarginfiAccountWithBanks,
+    unwind::{self, UnwindContext},
+    Error,
+};
+
+/// Wallet balances below this share of the liability are treated as dust
+const MIN_UNSUPPLIED_SHARE_BPS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionPhase {
+    NoPosition,
+    CollateralDeposited,
+    Borrowed,
+    Swapped,
+    Pooled,
+    Entered,
+}
+
+impl PositionPhase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoPosition => "no position",
+            Self::CollateralDeposited => "collateral deposited",
+            Self::Borrowed => "borrowed but not swapped",
+            Self::Swapped => "swapped but not pooled",
+            Self::Pooled => "pooled but not staked",
+            Self::Entered => "fully entered",
+        }
+    }
+
+    pub fn has_borrowed(&self) -> bool {
+        !matches!(self, Self::NoPosition | Self::CollateralDeposited)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PositionEvidence {
+    pub collateral: u64,
+    pub liabilities: Vec<(Pubkey, u64)>,
+    pub borrowed_in_wallet: Vec<(Pubkey, u64)>,
+    pub swapped_in_wallet: Vec<(Pubkey, u64)>,
+    /// Only pools with an active farm
+    pub unstaked_lp: Vec<(PoolId, u64)>,
+    pub staked_lp: Vec<(PoolId, u64)>,
+}
+
+impl PositionEvidence {
+    pub fn classify(&self) -> PositionPhase {
+        if self.liabilities.is_empty() {
+            return if self.collateral > 0 {
+                PositionPhase::CollateralDeposited
+            } else {
+                PositionPhase::NoPosition
+            };
+        }
+
+        if !self.borrowed_in_wallet.is_empty() {
+            PositionPhase::Borrowed
+        } else if !self.swapped_in_wallet.is_empty() {
+            PositionPhase::Swapped
+        } else if !self.unstaked_lp.is_empty() {
+            PositionPhase::Pooled
+        } else {
+            PositionPhase::Entered
+        }
+    }
+
+    pub fn log(&self) {
+        info!("  collateral: {}", self.collateral);
+        for (mint, amount) in &self.liabilities {
+            info!("  liability: {} {}", amount, mint);
+        }
+        for (mint, amount) in &self.borrowed_in_wallet {
+            info!("  borrowed in wallet: {} {}", amount, mint);
+        }
+        for (mint, amount) in &self.swapped_in_wallet {
+            info!("  swapped in wallet: {} {}", amount, mint);
+        }
+        for (pool_id, amount) in &self.unstaked_lp {
+            info!("  unstaked LP of pool {:?}: {}", pool_id, amount);
+        }
+        for (pool_id, amount) in &self.staked_lp {
+            info!("  staked LP of pool {:?}: {}", pool_id, amount);
+        }
+    }
+}
+
+fn is_above_dust(amount: f64, liability: f64) -> bool {
+    amount > 0.0 && amount * 10_000.0 >= liability * MIN_UNSUPPLIED_SHARE_BPS as f64
+}
+
+/// Wallet balances are compared to the total liability in UI units, as the borrowed and
+/// pool input mints are all stablecoins, and capped at it
+pub async fn fetch_position_evidence(
+    ctx: &UnwindContext<'_>,
+    account_with_banks: &MarginfiAccountWithBanks,
+) -> Result<PositionEvidence, Error> {
+    let static_addresses = ctx.static_addresses;
+    let collateral_mint = static_addresses.collateral_mint;
+    let collateral = match (
+        account_with_banks.get_balance_by_mint(&collateral_mint),
+        account_with_banks.get_bank_by_mint(&collateral_mint),
+    ) {
+        (Some(balance), Some((_, bank))) => balance
+            .get_amounts(bank.asset_share_value, bank.liability_share_value)
+            .0
+            .to_num(),
+        _ => 0,
+    };
+    let liabilities = unwind::get_liabilities(account_with_banks);
+
+    let mut evidence = PositionEvidence {
+        collateral,
+        liabilities,
+        ..Default::default()
+    };
+    if evidence.liabilities.is_empty() {
+        return Ok(evidence);
+    }
+
+    let pools = static_addresses
+        .meteora_pool_ids()
+        .map(|pool_id| Ok((pool_id, static_addresses.get_meteora_pool(pool_id)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut mints = evidence
+        .liabilities
+        .iter()
+        .map(|(mint, _)| *mint)
+        .collect::<Vec<_>>();
+    for (_, pool) in &pools {
+        for mint in [pool.input_mint, pool.lp_mint] {
+            if !mints.contains(&mint) {
+                mints.push(mint);
+            }
+        }
+    }
+    let balances =
+        connection::fetch_token_balances(ctx.rpc_client, static_addresses, &mints).await?;
+    let decimals = connection::fetch_mints_decimals(ctx.rpc_client, &mints).await?;
+    let to_ui = |mint: &Pubkey, amount: u64| {
+        amount as f64 / 10_f64.powi(decimals.get(mint).copied().unwrap_or(0) as i32)
+    };
+
+    let total_liability = evidence
+        .liabilities
+        .iter()
+        .map(|(mint, amount)| to_ui(mint, *amount))
+        .sum::<f64>();
+    for (mint, liability) in &evidence.liabilities {
+        let amount = balances.get(mint).copied().unwrap_or(0).min(*liability);
+        if is_above_dust(to_ui(mint, amount), total_liability) {
+            evidence.borrowed_in_wallet.push((*mint, amount));
+        }
+    }
+
+    for (pool_id, pool) in &pools {
+        let input_mint = pool.input_mint;
+        if !evidence
+            .liabilities
+            .iter()
+            .any(|(mint, _)| mint == &input_mint)
+            && !evidence
+                .swapped_in_wallet
+                .iter()
+                .any(|(mint, _)| mint == &input_mint)
+        {
+            let amount = balances.get(&input_mint).copied().unwrap_or(0);
+            let max_amount = (total_liability
+                * 10_f64.powi(decimals.get(&input_mint).copied().unwrap_or(0) as i32))
+                as u64;
+            let amount = amount.min(max_amount);
+            if is_above_dust(to_ui(&input_mint, amount), total_liability) {
+                evidence.swapped_in_wallet.push((input_mint, amount));
+            }
+        }
+
+        let wallet_lp = balances.get(&pool.lp_mint).copied().unwrap_or(0);
+        if wallet_lp > 0 && static_addresses.get_meteora_farm(*pool_id, None).is_ok() {
+            evidence.unstaked_lp.push((*pool_id, wallet_lp));
+        }
+
+        let mut staked_lp = 0;
+        for farm in static_addresses.get_meteora_farms_by_lp_mint(&pool.lp_mint) {
+            staked_lp += connection::fetch_meteora_farm_user(ctx.rpc_client, farm)
+                .await?
+                .balance_staked;
+        }
+        if staked_lp > 0 {
+            evidence.staked_lp.push((*pool_id, staked_lp));
+        }
+    }
+
+    Ok(evidence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{MockFetcher, PositionFixture};
+
+    fn evidence(collateral: u64, liability: u64) -> PositionEvidence {
+        PositionEvidence {
+            collateral,
+            liabilities: if liability > 0 {
+                vec![(Pubkey::new_unique(), liability)]
+            } else {
+                vec![]
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn phase_without_liabilities() {
+        assert_eq!(evidence(0, 0).classify(), PositionPhase::NoPosition);
+        assert_eq!(
+            evidence(1_000, 0).classify(),
+            PositionPhase::CollateralDeposited
+        );
+        // Wallet balances do not matter before the borrow
+        let without_borrow = PositionEvidence {
+            borrowed_in_wallet: vec![(Pubkey::new_unique(), 1_000)],
+            ..evidence(1_000, 0)
+        };
+        assert_eq!(
+            without_borrow.classify(),
+            PositionPhase::CollateralDeposited
+        );
+        assert!(!PositionPhase::NoPosition.has_borrowed());
+        assert!(!PositionPhase::CollateralDeposited.has_borrowed());
+    }
+
+    #[tokio::test]
+    async fn phase_is_the_earliest_incomplete_step() {
+        let fixture = PositionFixture::new();
+        let fetcher = MockFetcher::new();
+        for (address, account) in fixture.accounts.clone() {
+            fetcher.insert(address, account);
+        }
+        let static_addresses = fixture.load_static_addresses(&fetcher).await.unwrap();
+        let pool_id = static_addresses.meteora_pool_ids().next().unwrap();
+        let mint = Pubkey::new_unique();
+
+        let mut evidence = PositionEvidence {
+            staked_lp: vec![(pool_id, 1_000)],
+            ..evidence(1_000, 1_000)
+        };
+        assert_eq!(evidence.classify(), PositionPhase::Entered);
+
+        evidence.unstaked_lp = vec![(pool_id, 1_000)];
+        assert_eq!(evidence.classify(), PositionPhase::Pooled);
+        evidence.swapped_in_wallet = vec![(mint, 1_000)];
+        assert_eq!(evidence.classify(), PositionPhase::Swapped);
+        evidence.borrowed_in_wallet = vec![(mint, 1_000)];
+        assert_eq!(evidence.classify(), PositionPhase::Borrowed);
+
+        for phase in [
+            PositionPhase::Borrowed,
+            PositionPhase::Swapped,
+            PositionPhase::Pooled,
+            PositionPhase::Entered,
+        ] {
+            assert!(phase.has_borrowed());
+        }
+    }
+
+    #[test]
+    fn dust_is_below_one_percent_of_the_liability() {
+        assert!(!is_above_dust(0.0, 0.0));
+        assert!(!is_above_dust(0.99, 100.0));
+        assert!(is_above_dust(1.0, 100.0));
+        assert!(is_above_dust(0.000001, 0.0));
+    }
+}
@@ -0,0 +1,157 @@
+use anchor_lang::prelude::Pubkey;
+use fixed::types::I80F48;
+use tracing::{info, warn};
+
+use crate::{
+    connection, metrics,
+    state::MarginfiAccountWithBanks,
+    unwind::{self, UnwindContext},
+    utils::notifier::NotificationLevel,
+    Error,
+};
+
+#[derive(Debug)]
+struct Drift {
+    kind: &'static str,
+    subject: String,
+    local: f64,
+    on_chain: f64,
+}
+
+fn exceeds_tolerance(local: I80F48, on_chain: I80F48, tolerance_bps: u64) -> bool {
+    let diff = (local - on_chain).abs();
+    if diff == I80F48::ZERO {
+        return false;
+    }
+    let base = local.abs().max(on_chain.abs());
+    diff * I80F48::from_num(10_000) > base * I80F48::from_num(tolerance_bps)
+}
+
+/// Missing and inactive balances count as zero shares
+fn compare_balances(
+    local: &MarginfiAccountWithBanks,
+    on_chain: &MarginfiAccountWithBanks,
+    tolerance_bps: u64,
+) -> Vec<Drift> {
+    let shares = |account: &MarginfiAccountWithBanks, mint: &Pubkey| {
+        account
+            .get_balance_by_mint(mint)
+            .filter(|balance| balance.is_active)
+            .map(|balance| (balance.asset_shares, balance.liability_shares))
+            .unwrap_or((I80F48::ZERO, I80F48::ZERO))
+    };
+
+    let mut mints = local
+        .balances
+        .iter()
+        .chain(on_chain.balances.iter())
+        .map(|(mint, _)| *mint)
+        .collect::<Vec<_>>();
+    mints.sort();
+    mints.dedup();
+
+    let mut drifts = vec![];
+    for mint in mints {
+        let (local_assets, local_liabilities) = shares(local, &mint);
+        let (assets, liabilities) = shares(on_chain, &mint);
+        for (kind, local_shares, on_chain_shares) in [
+            ("asset_shares", local_assets, assets),
+            ("liability_shares", local_liabilities, liabilities),
+        ] {
+            if exceeds_tolerance(local_shares, on_chain_shares, tolerance_bps) {
+                drifts.push(Drift {
+                    kind,
+                    subject: mint.to_string(),
+                    local: local_shares.to_num(),
+                    on_chain: on_chain_shares.to_num(),
+                });
+            }
+        }
+    }
+
+    drifts
+}
+
+/// Pools without ledger records are skipped
+async fn compare_lp(ctx: &UnwindContext<'_>, tolerance_bps: u64) -> Result<Vec<Drift>, Error> {
+    let ledger = ctx.send_config.ledger.get();
+    let mut drifts = vec![];
+
+    for pool_id in ctx.static_addresses.meteora_pool_ids() {
+        let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+        let Some(recorded) = ledger.lp_balance(&pool.address) else {
+            continue;
+        };
+
+        let mut held =
+            connection::fetch_token_balances(ctx.rpc_client, ctx.static_addresses, &[pool.lp_mint])
+                .await?
+                .get(&pool.lp_mint)
+                .copied()
+                .unwrap_or(0);
+        for farm in ctx
+            .static_addresses
+            .get_meteora_farms_by_lp_mint(&pool.lp_mint)
+        {
+            held += connection::fetch_meteora_farm_user(ctx.rpc_client, farm)
+                .await?
+                .balance_staked;
+        }
+
+        if exceeds_tolerance(
+            I80F48::from_num(recorded),
+            I80F48::from_num(held),
+            tolerance_bps,
+        ) {
+            drifts.push(Drift {
+                kind: "lp",
+                subject: pool.address.to_string(),
+                local: recorded as f64,
+                on_chain: held as f64,
+            });
+        }
+    }
+
+    Ok(drifts)
+}
+
+/// The local model is replaced with the fetched one
+pub async fn reconcile(
+    ctx: &UnwindContext<'_>,
+    account_with_banks: &mut MarginfiAccountWithBanks,
+    tolerance_bps: u64,
+) -> Result<(), Error> {
+    let on_chain = unwind::fetch_account_with_banks(ctx).await?;
+    let mut drifts = compare_balances(account_with_banks, &on_chain, tolerance_bps);
+    *account_with_banks = on_chain;
+    drifts.extend(compare_lp(ctx, tolerance_bps).await?);
+
+    if drifts.is_empty() {
+        info!("Local state matches chain");
+        return Ok(());
+    }
+
+    for drift in drifts.iter() {
+        warn!(
+            "Drift in {} of {}: local {}, on chain {}",
+            drift.kind, drift.subject, drift.local, drift.on_chain
+        );
+        metrics::inc_counter(metrics::RECONCILE_DRIFTS, &[("kind", drift.kind)]);
+    }
+    ctx.send_config.notifier.notify(
+        NotificationLevel::Warning,
+        format!(
+            "Local state drifted from chain, replaced with on-chain state:\n{}",
+            drifts
+                .iter()
+                .map(|drift| format!(
+                    "{} of {}: local {}, on chain {}",
+                    drift.kind, drift.subject, drift.local, drift.on_chain
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anchor_lang::{prelude::Pubkey, Discriminator};
+use marginfi::state::price::OracleSetup;
+use solana_sdk::instruction::Instruction;
+
+use crate::{
+    ledger::{self, Ledger, PositionLedger},
+    state::{MarginfiAccountWithBanks, OraclesState},
+    Error,
+};
+
+const DAILY_LOSS_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Checked independent of the flow which built the transaction
+#[derive(Debug, Clone, Default)]
+pub struct RiskLimits {
+    /// Maximum USD value of all liabilities after a transaction which borrows
+    pub max_borrow_usd: Option<f64>,
+    /// Maximum USD value a single transaction moves into or out of marginfi
+    pub max_transaction_usd: Option<f64>,
+    /// Maximum realized loss in USD of the last 24 hours, transactions which deposit or
+    /// borrow are blocked above it, so the position can still be unwound
+    pub max_daily_loss_usd: Option<f64>,
+    pub kill_switch_file: Option<PathBuf>,
+}
+
+impl RiskLimits {
+    fn has_value_limits(&self) -> bool {
+        self.max_borrow_usd.is_some()
+            || self.max_transaction_usd.is_some()
+            || self.max_daily_loss_usd.is_some()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.has_value_limits() || self.kill_switch_file.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BankPrice {
+    mint: Pubkey,
+    decimals: u8,
+    /// At the top of the confidence interval
+    price: f64,
+}
+
+impl BankPrice {
+    fn value(&self, amount: u64) -> f64 {
+        amount as f64 / 10_f64.powi(self.decimals as i32) * self.price
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TransactionFlows {
+    pub deposits_usd: f64,
+    pub borrows_usd: f64,
+    pub repays_usd: f64,
+    pub withdraws_usd: f64,
+    /// Also when the amount is zero
+    pub adds_exposure: bool,
+}
+
+impl TransactionFlows {
+    /// Whichever is larger, so a flashloan which borrows one mint to repay another counts once
+    pub fn value_usd(&self) -> f64 {
+        (self.deposits_usd + self.repays_usd).max(self.borrows_usd + self.withdraws_usd)
+    }
+}
+
+pub fn check_limits(
+    limits: &RiskLimits,
+    flows: &TransactionFlows,
+    liabilities_usd: f64,
+    daily_loss_usd: f64,
+) -> Result<(), String> {
+    if let Some(max) = limits.max_transaction_usd {
+        let value = flows.value_usd();
+        if value > max {
+            return Err(format!(
+                "transaction moves ${:.2}, the limit is ${:.2}",
+                value, max
+            ));
+        }
+    }
+    if let Some(max) = limits.max_borrow_usd {
+        let liabilities = liabilities_usd + flows.borrows_usd - flows.repays_usd;
+        if flows.borrows_usd > 0.0 && liabilities > max {
+            return Err(format!(
+                "liabilities would be ${:.2}, the limit is ${:.2}",
+                liabilities, max
+            ));
+        }
+    }
+    if let Some(max) = limits.max_daily_loss_usd {
+        if flows.adds_exposure && daily_loss_usd > max {
+            return Err(format!(
+                "realized loss of the last day is ${:.2}, the limit is ${:.2}",
+                daily_loss_usd, max
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Accrued interest is left out as it is not realized
+pub fn get_realized_loss_usd(
+    ledger: &PositionLedger,
+    timestamp: u64,
+    prices: &HashMap<Pubkey, f64>,
+    decimals: &HashMap<Pubkey, u8>,
+) -> f64 {
+    let summary = ledger.since(timestamp).summary(prices, decimals);
+    (summary.fees_usd - summary.rewards_usd - summary.swaps_usd).max(0.0)
+}
+
+#[derive(Debug, Default)]
+struct RiskState {
+    banks: HashMap<Pubkey, BankPrice>,
+    liabilities_usd: f64,
+}
+
+impl RiskState {
+    /// Every marginfi instruction takes the bank as its fourth account and the amount right
+    /// after the discriminator
+    fn get_flows(&self, instructions: &[Instruction]) -> Result<TransactionFlows, String> {
+        let mut flows = TransactionFlows::default();
+        for ix in instructions
+            .iter()
+            .filter(|ix| ix.program_id == marginfi::id())
+        {
+            let (Some(discriminator), Some(amount), Some(bank)) =
+                (ix.data.get(..8), ix.data.get(8..16), ix.accounts.get(3))
+            else {
+                continue;
+            };
+            let amount = u64::from_le_bytes(amount.try_into().unwrap());
+            let value = || {
+                self.banks
+                    .get(&bank.pubkey)
+                    .map(|bank| bank.value(amount))
+                    .ok_or_else(|| format!("no price is known for bank {}", bank.pubkey))
+            };
+
+            if discriminator == marginfi::instruction::LendingAccountDeposit::DISCRIMINATOR {
+                flows.deposits_usd += value()?;
+                flows.adds_exposure = true;
+            } else if discriminator == marginfi::instruction::LendingAccountBorrow::DISCRIMINATOR {
+                flows.borrows_usd += value()?;
+                flows.adds_exposure = true;
+            } else if discriminator == marginfi::instruction::LendingAccountRepay::DISCRIMINATOR {
+                flows.repays_usd += value()?;
+            } else if discriminator == marginfi::instruction::LendingAccountWithdraw::DISCRIMINATOR
+            {
+                flows.withdraws_usd += value()?;
+            }
+        }
+        Ok(flows)
+    }
+
+    fn get_daily_loss_usd(&self, ledger: &PositionLedger) -> f64 {
+        let prices = self
+            .banks
+            .values()
+            .map(|bank| (bank.mint, bank.price))
+            .collect();
+        let decimals = self
+            .banks
+            .values()
+            .map(|bank| (bank.mint, bank.decimals))
+            .collect();
+        get_realized_loss_usd(
+            ledger,
+            ledger::now().saturating_sub(DAILY_LOSS_WINDOW_SECS),
+            &prices,
+            &decimals,
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RiskGuard {
+    limits: RiskLimits,
+    state: Mutex<RiskState>,
+}
+
+impl RiskGuard {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limits.is_enabled()
+    }
+
+    /// Banks missing from the account keep their last price
+    pub async fn update(
+        &self,
+        account_with_banks: &MarginfiAccountWithBanks,
+        oracles_state: &Arc<OraclesState>,
+    ) -> Result<(), Error> {
+        if !self.limits.has_value_limits() {
+            return Ok(());
+        }
+
+        let mut banks = HashMap::new();
+        for (address, bank) in &account_with_banks.banks {
+            if matches!(bank.oracle_setup, OracleSetup::None) {
+                continue;
+            }
+            let Some(oracle) = oracles_state
+                .get_oracle(bank.oracle_setup, &bank.oracle_address)
+                .await
+            else {
+                continue;
+            };
+            let (_, highest_price) = oracle.get_price_range()?;
+            banks.insert(
+                *address,
+                BankPrice {
+                    mint: bank.mint,
+                    decimals: bank.mint_decimals,
+                    price: highest_price.to_num(),
+                },
+            );
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.banks.extend(banks);
+        state.liabilities_usd = account_with_banks
+            .balances
+            .iter()
+            .filter(|(_, balance)| balance.is_active)
+            .filter_map(|(_, balance)| {
+                let bank = account_with_banks.get_bank_by_address(&balance.bank_address)?;
+                let (_, liability) =
+                    balance.get_amounts(bank.asset_share_value, bank.liability_share_value);
+                let price = state.banks.get(&balance.bank_address)?;
+                Some(price.value(liability.to_num()))
+            })
+            .sum();
+        Ok(())
+    }
+
+    pub fn check(&self, instructions: &[Instruction], ledger: &Ledger) -> Result<(), String> {
+        if let Some(path) = &self.limits.kill_switch_file {
+            if path.exists() {
+                return Err(format!("kill switch file {} exists", path.display()));
+            }
+        }
+        if !self.limits.has_value_limits() {
+            return Ok(());
+        }
+
+        let state = self.state.lock().unwrap();
+        let flows = state.get_flows(instructions)?;
+        let daily_loss_usd = match self.limits.max_daily_loss_usd {
+            Some(_) if flows.adds_exposure => state.get_daily_loss_usd(&ledger.get()),
+            _ => 0.0,
+        };
+        check_limits(&self.limits, &flows, state.liabilities_usd, daily_loss_usd)
+    }
+
+    /// So the next transaction is checked against them before the next update
+    pub fn record_landed(&self, instructions: &[Instruction]) {
+        if !self.limits.has_value_limits() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Ok(flows) = state.get_flows(instructions) {
+            state.liabilities_usd =
+                (state.liabilities_usd + flows.borrows_usd - flows.repays_usd).max(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::instruction::AccountMeta;
+
+    use super::*;
+
+    fn marginfi_ix(discriminator: [u8; 8], bank: Pubkey, amount: u64) -> Instruction {
+        let mut accounts = (0..3)
+            .map(|_| AccountMeta::new(Pubkey::new_unique(), false))
+            .collect::<Vec<_>>();
+        accounts.push(AccountMeta::new(bank, false));
+        Instruction {
+            program_id: marginfi::id(),
+            accounts,
+            data: [&discriminator[..], &amount.to_le_bytes()].concat(),
+        }
+    }
+
+    fn deposit(bank: Pubkey, amount: u64) -> Instruction {
+        marginfi_ix(
+            marginfi::instruction::LendingAccountDeposit::DISCRIMINATOR,
+            bank,
+            amount,
+        )
+    }
+
+    fn borrow(bank: Pubkey, amount: u64) -> Instruction {
+        marginfi_ix(
+            marginfi::instruction::LendingAccountBorrow::DISCRIMINATOR,
+            bank,
+            amount,
+        )
+    }
+
+    fn repay(bank: Pubkey, amount: u64) -> Instruction {
+        marginfi_ix(
+            marginfi::instruction::LendingAccountRepay::DISCRIMINATOR,
+            bank,
+            amount,
+        )
+    }
+
+    /// Guard with a USDC bank at $1 and a collateral bank at $100, both with 6 decimals
+    fn new_guard(limits: RiskLimits, liabilities_usd: f64) -> (RiskGuard, Pubkey, Pubkey) {
+        let usdc_bank = Pubkey::new_unique();
+        let collateral_bank = Pubkey::new_unique();
+        let guard = RiskGuard::new(limits);
+        {
+            let mut state = guard.state.lock().unwrap();
+            state.banks.insert(
+                usdc_bank,
+                BankPrice {
+                    mint: Pubkey::new_unique(),
+                    decimals: 6,
+                    price: 1.0,
+                },
+            );
+            state.banks.insert(
+                collateral_bank,
+                BankPrice {
+                    mint: Pubkey::new_unique(),
+                    decimals: 6,
+                    price: 100.0,
+                },
+            );
+            state.liabilities_usd = liabilities_usd;
+        }
+        (guard, usdc_bank, collateral_bank)
+    }
+
+    fn new_ledger() -> Ledger {
+        Ledger::new(std::env::temp_dir().join("mmf-risk-ledger.json"), false)
+    }
+
+    #[test]
+    fn flashloan_value_counts_once() {
+        let flows = TransactionFlows {
+            deposits_usd: 0.0,
+            borrows_usd: 100.0,
+            repays_usd: 100.0,
+            withdraws_usd: 0.0,
+            adds_exposure: true,
+        };
+        assert_eq!(flows.value_usd(), 100.0);
+    }
+
+    #[test]
+    fn transaction_value_limit() {
+        let limits = RiskLimits {
+            max_transaction_usd: Some(1_000.0),
+            ..Default::default()
+        };
+        let (guard, _, collateral_bank) = new_guard(limits, 0.0);
+        let ledger = new_ledger();
+
+        assert!(guard
+            .check(&[deposit(collateral_bank, 10_000_000)], &ledger)
+            .is_ok());
+        let reason = guard
+            .check(&[deposit(collateral_bank, 10_000_001)], &ledger)
+            .unwrap_err();
+        assert!(
+            reason.starts_with("transaction moves $1000.00"),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn borrow_limit_counts_existing_liabilities() {
+        let limits = RiskLimits {
+            max_borrow_usd: Some(500.0),
+            ..Default::default()
+        };
+        let (guard, usdc_bank, _) = new_guard(limits, 400.0);
+        let ledger = new_ledger();
+
+        assert!(guard
+            .check(&[borrow(usdc_bank, 100_000_000)], &ledger)
+            .is_ok());
+        let reason = guard
+            .check(&[borrow(usdc_bank, 100_000_001)], &ledger)
+            .unwrap_err();
+        assert!(
+            reason.starts_with("liabilities would be $500.00"),
+            "{}",
+            reason
+        );
+
+        // Repaying is never blocked, even above the limit
+        let (guard, usdc_bank, _) = new_guard(
+            RiskLimits {
+                max_borrow_usd: Some(100.0),
+                ..Default::default()
+            },
+            400.0,
+        );
+        assert!(guard.check(&[repay(usdc_bank, 1_000_000)], &ledger).is_ok());
+    }
+
+    #[test]
+    fn landed_borrows_count_before_the_next_update() {
+        let limits = RiskLimits {
+            max_borrow_usd: Some(500.0),
+            ..Default::default()
+        };
+        let (guard, usdc_bank, _) = new_guard(limits, 0.0);
+        let ledger = new_ledger();
+
+        guard.record_landed(&[borrow(usdc_bank, 450_000_000)]);
+        assert!(guard
+            .check(&[borrow(usdc_bank, 100_000_000)], &ledger)
+            .is_err());
+        guard.record_landed(&[repay(usdc_bank, 450_000_000)]);
+        assert!(guard
+            .check(&[borrow(usdc_bank, 100_000_000)], &ledger)
+            .is_ok());
+    }
+
+    #[test]
+    fn daily_loss_limit_blocks_only_new_exposure() {
+        let limits = RiskLimits {
+            max_daily_loss_usd: Some(10.0),
+            ..Default::default()
+        };
+        let (guard, usdc_bank, _) = new_guard(limits, 0.0);
+        let usdc = guard.state.lock().unwrap().banks[&usdc_bank].mint;
+        let ledger = new_ledger();
+
+        // Swapped 100 USDC for 80 USDC, a realized loss of $20
+        ledger.record(|l| l.record_swap(&usdc, 100_000_000, &usdc, 80_000_000));
+
+        let reason = guard
+            .check(&[borrow(usdc_bank, 1_000_000)], &ledger)
+            .unwrap_err();
+        assert!(
+            reason.starts_with("realized loss of the last day is $20.00"),
+            "{}",
+            reason
+        );
+        assert!(guard.check(&[repay(usdc_bank, 1_000_000)], &ledger).is_ok());
+    }
+
+    #[test]
+    fn unknown_bank_is_blocked() {
+        let limits = RiskLimits {
+            max_transaction_usd: Some(1_000.0),
+            ..Default::default()
+        };
+        let (guard, _, _) = new_guard(limits, 0.0);
+        let bank = Pubkey::new_unique();
+
+        let reason = guard.check(&[deposit(bank, 1)], &new_ledger()).unwrap_err();
+        assert_eq!(reason, format!("no price is known for bank {}", bank));
+    }
+
+    #[test]
+    fn kill_switch_file_blocks_everything() {
+        let path = std::env::temp_dir().join(format!("mmf-kill-switch-{}", ledger::now()));
+        let limits = RiskLimits {
+            kill_switch_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let guard = RiskGuard::new(limits);
+        let ledger = new_ledger();
+        assert!(guard.is_enabled());
+        assert!(guard.check(&[], &ledger).is_ok());
+
+        std::fs::write(&path, "").unwrap();
+        let reason = guard.check(&[], &ledger).unwrap_err();
+        assert_eq!(
+            reason,
+            format!("kill switch file {} exists", path.display())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn realized_loss_ignores_gains_and_interest() {
+        let usdc = Pubkey::new_unique();
+        let prices = HashMap::from([(usdc, 1.0)]);
+        let decimals = HashMap::from([(usdc, 6)]);
+        let mut ledger = PositionLedger::default();
+        ledger.record_swap(&usdc, 100_000_000, &usdc, 110_000_000);
+        ledger.record_borrow(&usdc, 100_000_000);
+        ledger.record_interest(&usdc, 150_000_000);
+
+        assert_eq!(get_realized_loss_usd(&ledger, 0, &prices, &decimals), 0.0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::FutureExt;
+use tokio::time::sleep_until;
+use tracing::{error, warn};
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaintenanceJob {
+    HealthCheck,
+    InterestRefresh,
+    RewardCompound,
+    RebalanceCheck,
+    OracleAudit,
+    StopLossCheck,
+    Reconcile,
+}
+
+impl MaintenanceJob {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HealthCheck => "health check",
+            Self::InterestRefresh => "interest refresh",
+            Self::RewardCompound => "reward compound",
+            Self::RebalanceCheck => "rebalance check",
+            Self::OracleAudit => "oracle audit",
+            Self::StopLossCheck => "stop loss check",
+            Self::Reconcile => "reconcile",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceIntervals {
+    pub health_check: Duration,
+    pub interest_refresh: Duration,
+    pub reward_compound: Duration,
+    pub rebalance_check: Duration,
+    pub oracle_audit: Duration,
+    pub stop_loss_check: Duration,
+    pub reconcile: Duration,
+}
+
+#[derive(Debug)]
+pub enum JobResult {
+    Ok,
+    Failed(Error),
+    Panicked(String),
+}
+
+struct JobState {
+    job: MaintenanceJob,
+    interval: Duration,
+    next_run: Instant,
+    last_run: Option<Instant>,
+    last_success: Option<Instant>,
+    last_result: Option<JobResult>,
+    running: bool,
+}
+
+/// So jobs with equal intervals do not fire at once
+fn jitter(interval: Duration) -> Duration {
+    let max_offset = interval.as_millis() as u64 / 10;
+    if max_offset == 0 {
+        return interval;
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos() as u64;
+    interval + Duration::from_millis(seed % (2 * max_offset + 1))
+        - Duration::from_millis(max_offset)
+}
+
+pub fn get_panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// One job at a time so a job never overlaps with itself or with transactions of another job
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<JobState>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First run of the job happens one (jittered) interval from now
+    pub fn add_job(mut self, job: MaintenanceJob, interval: Duration) -> Self {
+        self.jobs.push(JobState {
+            job,
+            interval,
+            next_run: Instant::now() + jitter(interval),
+            last_run: None,
+            last_success: None,
+            last_result: None,
+            running: false,
+        });
+        self
+    }
+
+    pub async fn next_job(&mut self) -> Option<MaintenanceJob> {
+        let job_state = self
+            .jobs
+            .iter_mut()
+            .filter(|job_state| !job_state.running)
+            .min_by_key(|job_state| job_state.next_run)?;
+
+        sleep_until(job_state.next_run.into()).await;
+        job_state.running = true;
+        Some(job_state.job)
+    }
+
+    pub async fn run_job<F: Future<Output = Result<(), Error>>>(job_future: F) -> JobResult {
+        match AssertUnwindSafe(job_future).catch_unwind().await {
+            Ok(Ok(())) => JobResult::Ok,
+            Ok(Err(e)) => JobResult::Failed(e),
+            Err(payload) => JobResult::Panicked(get_panic_message(&*payload)),
+        }
+    }
+
+    pub fn complete(&mut self, job: MaintenanceJob, result: JobResult) {
+        let Some(job_state) = self.jobs.iter_mut().find(|job_state| job_state.job == job) else {
+            return;
+        };
+        let now = Instant::now();
+
+        let last_success = match job_state.last_success {
+            Some(last_success) => format!("{}s ago", (now - last_success).as_secs()),
+            None => "never".to_string(),
+        };
+        match &result {
+            JobResult::Ok => job_state.last_success = Some(now),
+            JobResult::Failed(e) => warn!(
+                "Maintenance job {} failed: {:?}, last success {}",
+                job.name(),
+                e,
+                last_success
+            ),
+            JobResult::Panicked(message) => error!(
+                "Maintenance job {} panicked: {}, last success {}",
+                job.name(),
+                message,
+                last_success
+            ),
+        }
+
+        job_state.running = false;
+        job_state.next_run = now + jitter(job_state.interval);
+        job_state.last_run = Some(now);
+        job_state.last_result = Some(result);
+    }
+
+    pub fn job_states(
+        &self,
+    ) -> impl Iterator<Item = (MaintenanceJob, Option<Instant>, Option<&JobResult>)> {
+        self.jobs.iter().map(|job_state| {
+            (
+                job_state.job,
+                job_state.last_run,
+                job_state.last_result.as_ref(),
+            )
+        })
+    }
+}
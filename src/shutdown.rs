@@ -0,0 +1,73 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle, time::timeout};
+use tracing::warn;
+
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        while !*receiver.borrow_and_update() {
+            // The sender lives as long as `self`, so the channel is never closed here
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub async fn wait_for_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT"),
+            _ = sigterm.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.map(|_| "SIGINT")
+    }
+}
+
+/// A task which does not exit within `TASK_SHUTDOWN_TIMEOUT` is aborted
+pub async fn wait_for_task<T>(name: &str, handle: &mut JoinHandle<T>) {
+    if handle.is_finished() {
+        return;
+    }
+
+    if timeout(TASK_SHUTDOWN_TIMEOUT, &mut *handle).await.is_err() {
+        warn!("Task {} did not shut down in time, aborting it", name);
+        handle.abort();
+    }
+}
@@ -0,0 +1,192 @@
+use anchor_lang::{prelude::Pubkey, AccountDeserialize, Discriminator};
+use fixed::types::I80F48;
+use marginfi::state::marginfi_group::{Bank, MarginfiGroup};
+use solana_sdk::account::Account;
+use tracing::warn;
+
+use crate::{
+    addresses::StaticAddresses,
+    connection::{get_multiple_accounts_chunked, AccountData},
+    constants,
+    state::MeteoraFarmPool,
+    utils::account_fetcher::AccountFetcher,
+    Error,
+};
+
+fn check_account<T: AccountDeserialize + Discriminator>(
+    name: &str,
+    address: &Pubkey,
+    ai: Option<&Account>,
+    owner: &Pubkey,
+    mismatches: &mut Vec<String>,
+) -> Option<T> {
+    let Some(ai) = ai else {
+        mismatches.push(format!("{} {} does not exist", name, address));
+        return None;
+    };
+
+    if &ai.owner != owner {
+        mismatches.push(format!(
+            "{} {} is owned by {} instead of {}",
+            name, address, ai.owner, owner
+        ));
+        return None;
+    }
+
+    if ai.data.len() < 8 || ai.data[..8] != T::DISCRIMINATOR {
+        mismatches.push(format!("{} {} has invalid discriminator", name, address));
+        return None;
+    }
+
+    match AccountData::from(ai).parse() {
+        Ok(account) => Some(account),
+        Err(_) => {
+            mismatches.push(format!("{} {} could not be deserialized", name, address));
+            None
+        }
+    }
+}
+
+/// All mismatches are reported at once
+pub async fn validate_constants<F: AccountFetcher>(
+    rpc_client: &F,
+    static_addresses: &StaticAddresses,
+) -> Result<(), Error> {
+    let group_address = constants::marginfi::group::id();
+    let banks = [
+        (
+            constants::marginfi::banks::bsol::id(),
+            constants::mints::bsol::id(),
+        ),
+        (
+            constants::marginfi::banks::usdc::id(),
+            constants::mints::usdc::id(),
+        ),
+        (
+            constants::marginfi::banks::usdt::id(),
+            constants::mints::usdt::id(),
+        ),
+        (
+            constants::marginfi::banks::uxd::id(),
+            constants::mints::uxd::id(),
+        ),
+    ];
+    let pool_address = constants::meteora::acusd_usdc_pool::id();
+    let farm_address = constants::meteora::acusd_usdc_farm::id();
+
+    let addresses = [group_address, pool_address, farm_address]
+        .into_iter()
+        .chain(banks.iter().map(|(bank, _)| *bank))
+        .collect::<Vec<_>>();
+    let ais = get_multiple_accounts_chunked(rpc_client, &addresses).await?;
+    let mut mismatches = vec![];
+
+    check_account::<MarginfiGroup>(
+        "Marginfi group",
+        &group_address,
+        ais[0].as_ref(),
+        &marginfi::id(),
+        &mut mismatches,
+    );
+
+    let pool = check_account::<meteora::state::Pool>(
+        "Meteora pool",
+        &pool_address,
+        ais[1].as_ref(),
+        &meteora::id(),
+        &mut mismatches,
+    );
+    let farm = check_account::<MeteoraFarmPool>(
+        "Meteora farm",
+        &farm_address,
+        ais[2].as_ref(),
+        &constants::meteora::farm::id(),
+        &mut mismatches,
+    );
+    if let (Some(pool), Some(farm)) = (pool, farm) {
+        if farm.staking_mint != pool.lp_mint {
+            mismatches.push(format!(
+                "Meteora farm {} stakes {} instead of pool LP mint {}",
+                farm_address, farm.staking_mint, pool.lp_mint
+            ));
+        }
+    }
+
+    for ((bank_address, mint), ai) in banks.iter().zip(ais[3..].iter()) {
+        let Some(bank) = check_account::<Bank>(
+            "Marginfi bank",
+            bank_address,
+            ai.as_ref(),
+            &marginfi::id(),
+            &mut mismatches,
+        ) else {
+            continue;
+        };
+
+        if &bank.mint != mint {
+            mismatches.push(format!(
+                "Marginfi bank {} has mint {} instead of {}",
+                bank_address, bank.mint, mint
+            ));
+        }
+        if bank.group != group_address {
+            mismatches.push(format!(
+                "Marginfi bank {} belongs to group {} instead of {}",
+                bank_address, bank.group, group_address
+            ));
+        }
+        if let Ok(registered_bank) = static_addresses.get_marginfi_bank(mint) {
+            if &registered_bank.address != bank_address {
+                mismatches.push(format!(
+                    "Marginfi bank for mint {} is {} instead of {}",
+                    mint, registered_bank.address, bank_address
+                ));
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    warn!("Constants do not match on-chain state:");
+    mismatches
+        .iter()
+        .for_each(|mismatch| warn!("  - {}", mismatch));
+    Err(Error::InvalidConstants(mismatches))
+}
+
+pub fn validate_borrowable_mints(
+    borrowable_mints: &[Pubkey],
+    banks: &Vec<(Pubkey, Bank)>,
+) -> Result<(), Error> {
+    for mint in borrowable_mints {
+        if !banks.iter().any(|(_, bank)| &bank.mint == mint) {
+            warn!("Borrowable mint {} has no marginfi bank", mint);
+            return Err(Error::MissingBank(*mint));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_collateral(
+    collateral_mint: &Pubkey,
+    banks: &Vec<(Pubkey, Bank)>,
+) -> Result<(), Error> {
+    let Some((bank_address, bank)) = banks.iter().find(|(_, bank)| &bank.mint == collateral_mint)
+    else {
+        warn!("Collateral mint {} has no marginfi bank", collateral_mint);
+        return Err(Error::InvalidCollateral(*collateral_mint));
+    };
+
+    if I80F48::from_bits(bank.config.asset_weight_init.value) == I80F48::ZERO {
+        warn!(
+            "Collateral bank {} for mint {} has zero asset weight",
+            bank_address, collateral_mint
+        );
+        return Err(Error::InvalidCollateral(*collateral_mint));
+    }
+
+    Ok(())
+}
@@ -1,6 +1,13 @@
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-use anchor_lang::prelude::Pubkey;
+use anchor_lang::{
+    error::ErrorCode,
+    prelude::{borsh, Pubkey},
+    AccountDeserialize, AnchorDeserialize, AnchorSerialize, Discriminator,
+};
 use fixed::types::I80F48;
 use marginfi::{
     constants::{CONF_INTERVAL_MULTIPLE, EXP_10, EXP_10_I80F48},
@@ -11,8 +18,9 @@ use tokio::{
     sync::{mpsc, Mutex},
     task::JoinHandle,
 };
+use tracing::debug;
 
-use crate::Error;
+use crate::{shutdown::Shutdown, Error};
 
 #[inline]
 fn pyth_price_components_to_i80f48(price: I80F48, exponent: i32) -> Result<I80F48, Error> {
@@ -104,6 +112,7 @@ impl PriceData for PythPriceFeed {
 
 #[derive(Clone, Debug)]
 pub struct SwitchboardPriceFeed {
+    pub last_update_slot: u64,
     pub last_update_ts: i64,
     pub resolution_mode: AggregatorResolutionMode,
     pub latest_confirmed_round_result: SwitchboardDecimal,
@@ -115,6 +124,7 @@ pub struct SwitchboardPriceFeed {
 impl From<&AggregatorAccountData> for SwitchboardPriceFeed {
     fn from(agg: &AggregatorAccountData) -> Self {
         Self {
+            last_update_slot: agg.latest_confirmed_round.round_open_slot,
             last_update_ts: agg.latest_confirmed_round.round_open_timestamp,
             resolution_mode: agg.resolution_mode,
             latest_confirmed_round_result: agg.latest_confirmed_round.result,
@@ -186,10 +196,34 @@ pub enum StateUpdate {
     SwitchboardOracle((Pubkey, SwitchboardPriceFeed)),
 }
 
+/// Latest slot of the cluster, oracle staleness is measured against it
+#[derive(Debug, Default)]
+pub struct CurrentSlot(AtomicU64);
+
+impl CurrentSlot {
+    pub fn get(&self) -> Option<u64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            slot => Some(slot),
+        }
+    }
+
+    /// Slots from different sources can arrive out of order, the slot never moves back
+    pub fn update(&self, slot: u64) {
+        self.0.fetch_max(slot, Ordering::Relaxed);
+    }
+
+    pub fn get_age(&self, slot: u64) -> Option<u64> {
+        self.get()
+            .map(|current_slot| current_slot.saturating_sub(slot))
+    }
+}
+
 #[derive(Debug)]
 pub struct OraclesState {
     pub pyth_oracles: Mutex<Vec<(Pubkey, PythPriceFeed)>>,
     pub switchboard_oracles: Mutex<Vec<(Pubkey, SwitchboardPriceFeed)>>,
+    pub current_slot: Arc<CurrentSlot>,
 }
 
 impl OraclesState {
@@ -197,6 +231,7 @@ impl OraclesState {
         Self {
             pyth_oracles: Default::default(),
             switchboard_oracles: Default::default(),
+            current_slot: Default::default(),
         }
     }
 
@@ -228,36 +263,42 @@ impl OraclesState {
         }
     }
 
+    pub async fn apply_update(&self, update: StateUpdate) {
+        match update {
+            StateUpdate::PythOracle((address, price_feed)) => {
+                let mut oracles = self.pyth_oracles.lock().await;
+
+                if let Some(saved_oracle) = oracles.iter_mut().find(|(addr, _)| addr == &address) {
+                    saved_oracle.1 = price_feed;
+                } else {
+                    oracles.push((address, price_feed));
+                }
+            }
+            StateUpdate::SwitchboardOracle((address, price_feed)) => {
+                let mut oracles = self.switchboard_oracles.lock().await;
+
+                if let Some(saved_oracle) = oracles.iter_mut().find(|(addr, _)| addr == &address) {
+                    saved_oracle.1 = price_feed;
+                } else {
+                    oracles.push((address, price_feed));
+                }
+            }
+        }
+    }
+
+    /// The receiver is shared so the listener can be restarted
     pub fn listen_to_updates(
         state: Arc<Self>,
-        mut update_receiver: mpsc::UnboundedReceiver<StateUpdate>,
+        update_receiver: Arc<Mutex<mpsc::UnboundedReceiver<StateUpdate>>>,
+        shutdown: Shutdown,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            while let Some(update) = update_receiver.recv().await {
-                match update {
-                    StateUpdate::PythOracle((address, price_feed)) => {
-                        let mut oracles = state.pyth_oracles.lock().await;
-
-                        if let Some(saved_oracle) =
-                            oracles.iter_mut().find(|(addr, _)| addr == &address)
-                        {
-                            saved_oracle.1 = price_feed;
-                        } else {
-                            oracles.push((address, price_feed));
-                        }
-                    }
-                    StateUpdate::SwitchboardOracle((address, price_feed)) => {
-                        let mut oracles = state.switchboard_oracles.lock().await;
-
-                        if let Some(saved_oracle) =
-                            oracles.iter_mut().find(|(addr, _)| addr == &address)
-                        {
-                            saved_oracle.1 = price_feed;
-                        } else {
-                            oracles.push((address, price_feed));
-                        }
-                    }
-                }
+            let mut update_receiver = update_receiver.lock().await;
+            while let Some(update) = tokio::select! {
+                update = update_receiver.recv() => update,
+                _ = shutdown.triggered() => None,
+            } {
+                state.apply_update(update).await;
             }
         })
     }
@@ -277,7 +318,7 @@ fn calc_scaled_amount(
     weighted * price / scaling_factor
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MarginfiBank {
     pub mint: Pubkey,
     pub mint_decimals: u8,
@@ -287,6 +328,8 @@ pub struct MarginfiBank {
 
     pub asset_weight_init: I80F48,
     pub liability_weight_init: I80F48,
+    pub asset_weight_maint: I80F48,
+    pub liability_weight_maint: I80F48,
 
     pub asset_share_value: I80F48,
     pub liability_share_value: I80F48,
@@ -297,6 +340,14 @@ pub struct MarginfiBank {
     pub optimal_utilization_rate: I80F48,
     pub plateau_interest_rate: I80F48,
     pub max_interest_rate: I80F48,
+    /// Fees added on top of the base rate, the `ir` ones scale with it
+    pub insurance_fee_fixed_apr: I80F48,
+    pub insurance_ir_fee: I80F48,
+    pub protocol_fixed_fee_apr: I80F48,
+    pub protocol_ir_fee: I80F48,
+
+    /// Maximum total liabilities in native units, `u64::MAX` when not capped
+    pub borrow_limit: u64,
 }
 
 impl Default for MarginfiBank {
@@ -310,6 +361,8 @@ impl Default for MarginfiBank {
 
             asset_weight_init: Default::default(),
             liability_weight_init: Default::default(),
+            asset_weight_maint: Default::default(),
+            liability_weight_maint: Default::default(),
 
             asset_share_value: Default::default(),
             liability_share_value: Default::default(),
@@ -320,6 +373,12 @@ impl Default for MarginfiBank {
             optimal_utilization_rate: Default::default(),
             plateau_interest_rate: Default::default(),
             max_interest_rate: Default::default(),
+            insurance_fee_fixed_apr: Default::default(),
+            insurance_ir_fee: Default::default(),
+            protocol_fixed_fee_apr: Default::default(),
+            protocol_ir_fee: Default::default(),
+
+            borrow_limit: u64::MAX,
         }
     }
 }
@@ -334,6 +393,8 @@ impl From<marginfi::state::marginfi_group::Bank> for MarginfiBank {
             oracle_address: bank.config.oracle_keys[0],
             asset_weight_init: I80F48::from_bits(bank.config.asset_weight_init.value),
             liability_weight_init: I80F48::from_bits(bank.config.liability_weight_init.value),
+            asset_weight_maint: I80F48::from_bits(bank.config.asset_weight_maint.value),
+            liability_weight_maint: I80F48::from_bits(bank.config.liability_weight_maint.value),
             asset_share_value: I80F48::from_bits(bank.asset_share_value.value),
             liability_share_value: I80F48::from_bits(bank.liability_share_value.value),
             total_asset_shares: I80F48::from_bits(bank.total_asset_shares.value),
@@ -350,6 +411,25 @@ impl From<marginfi::state::marginfi_group::Bank> for MarginfiBank {
             max_interest_rate: I80F48::from_bits(
                 bank.config.interest_rate_config.max_interest_rate.value,
             ),
+            insurance_fee_fixed_apr: I80F48::from_bits(
+                bank.config
+                    .interest_rate_config
+                    .insurance_fee_fixed_apr
+                    .value,
+            ),
+            insurance_ir_fee: I80F48::from_bits(
+                bank.config.interest_rate_config.insurance_ir_fee.value,
+            ),
+            protocol_fixed_fee_apr: I80F48::from_bits(
+                bank.config
+                    .interest_rate_config
+                    .protocol_fixed_fee_apr
+                    .value,
+            ),
+            protocol_ir_fee: I80F48::from_bits(
+                bank.config.interest_rate_config.protocol_ir_fee.value,
+            ),
+            borrow_limit: bank.config.borrow_limit,
         }
     }
 }
@@ -373,25 +453,65 @@ impl MarginfiBank {
         deposit_amount.min(max_deposit_amount - total_deposit_amount)
     }
 
+    /// In native units, limited by the bank borrow cap
+    pub fn get_max_borrow_amount(&self) -> I80F48 {
+        let total_deposit_amount = self.asset_share_value * self.total_asset_shares;
+        let total_borrow_amount = self.liability_share_value * self.total_liability_shares;
+        let liquidity = (total_deposit_amount - total_borrow_amount).max(I80F48::ZERO);
+
+        if self.borrow_limit == u64::MAX {
+            return liquidity;
+        }
+        let remaining_limit =
+            (I80F48::from_num(self.borrow_limit) - total_borrow_amount).max(I80F48::ZERO);
+        liquidity.min(remaining_limit)
+    }
+
+    fn get_rate_at_utilization(&self, utilization: I80F48) -> I80F48 {
+        if utilization <= self.optimal_utilization_rate {
+            utilization / self.optimal_utilization_rate * self.plateau_interest_rate
+        } else {
+            let u = utilization - self.optimal_utilization_rate;
+            let l = I80F48::ONE - self.optimal_utilization_rate;
+            (u / l) * (self.max_interest_rate - self.plateau_interest_rate)
+                + self.plateau_interest_rate
+        }
+    }
+
     pub fn get_borrow_rate(&self) -> I80F48 {
         if self.total_liability_shares == 0 {
             return I80F48::ZERO;
         }
 
         let current_utilization = self.total_liability_shares / self.total_asset_shares;
+        self.get_rate_at_utilization(current_utilization)
+    }
 
-        if current_utilization <= self.optimal_utilization_rate {
-            current_utilization / self.optimal_utilization_rate * self.plateau_interest_rate
-        } else {
-            let u = current_utilization - self.optimal_utilization_rate;
-            let l = I80F48::ONE - self.optimal_utilization_rate;
-            (u / l) * (self.max_interest_rate - self.plateau_interest_rate)
-                + self.plateau_interest_rate
+    /// Borrow rate including the protocol and insurance fees once `borrow_amount` more
+    /// is borrowed, the borrow moves the utilization and so the rate
+    pub fn get_all_in_borrow_rate_after(&self, borrow_amount: I80F48) -> I80F48 {
+        let total_deposit_amount = self.asset_share_value * self.total_asset_shares;
+        if total_deposit_amount <= I80F48::ZERO {
+            return I80F48::MAX;
         }
+        let total_borrow_amount =
+            self.liability_share_value * self.total_liability_shares + borrow_amount;
+        let utilization = (total_borrow_amount / total_deposit_amount).min(I80F48::ONE);
+
+        let base_rate = self.get_rate_at_utilization(utilization);
+        base_rate * (I80F48::ONE + self.insurance_ir_fee + self.protocol_ir_fee)
+            + self.insurance_fee_fixed_apr
+            + self.protocol_fixed_fee_apr
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthType {
+    Initial,
+    Maintenance,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct MarginfiAccountBalance {
     pub is_active: bool,
     pub bank_address: Pubkey,
@@ -442,6 +562,7 @@ impl MarginfiAccountBalance {
         &self,
         bank: &MarginfiBank,
         oracle: &Box<dyn PriceData>,
+        health_type: HealthType,
     ) -> Result<(I80F48, I80F48), Error> {
         if !self.is_active {
             return Ok((I80F48::ZERO, I80F48::ZERO));
@@ -454,21 +575,25 @@ impl MarginfiAccountBalance {
         let (asset_amount, liab_amount) =
             self.get_amounts(asset_share_value, liability_share_value);
 
+        let (asset_weight, liability_weight) = match health_type {
+            HealthType::Initial => (self.asset_weight, self.liabilities_weight),
+            HealthType::Maintenance => (bank.asset_weight_maint, bank.liability_weight_maint),
+        };
         let scaling_factor = EXP_10_I80F48[bank.mint_decimals as usize];
         let mut total_assets = calc_scaled_amount(
             asset_amount,
-            Some(self.asset_weight),
+            Some(asset_weight),
             worst_price,
             scaling_factor,
         );
         let total_liabilities = calc_scaled_amount(
             liab_amount,
-            Some(self.liabilities_weight),
+            Some(liability_weight),
             best_price,
             scaling_factor,
         );
 
-        if bank.total_asset_value_init_limit != 0 {
+        if health_type == HealthType::Initial && bank.total_asset_value_init_limit != 0 {
             let bank_total_assets = calc_scaled_amount(
                 bank.total_asset_shares * asset_share_value,
                 None,
@@ -479,7 +604,7 @@ impl MarginfiAccountBalance {
 
             if bank_total_assets > total_asset_value_init_limit {
                 let discount = total_asset_value_init_limit / bank_total_assets;
-                dbg!(discount);
+                debug!(%discount, "Discounting assets by the bank total asset value init limit");
                 total_assets = total_assets * discount;
             }
         }
@@ -491,7 +616,7 @@ impl MarginfiAccountBalance {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct MarginfiAccountWithBanks {
     pub balances: Vec<(Pubkey, MarginfiAccountBalance)>,
     pub banks: Vec<(Pubkey, MarginfiBank)>,
@@ -529,8 +654,10 @@ impl MarginfiAccountWithBanks {
         }
     }
 
-    pub fn deposit(&mut self, amount: I80F48, mint: &Pubkey) {
-        let (bank_address, bank) = &self.get_bank_by_mint(mint).unwrap();
+    pub fn deposit(&mut self, amount: I80F48, mint: &Pubkey) -> Result<(), Error> {
+        let (bank_address, bank) = &self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::MissingBank(*mint))?;
         let asset_shares = amount / bank.asset_share_value;
 
         if let Some(i) = self.balances.iter().position(|(m, _)| m == mint) {
@@ -543,22 +670,46 @@ impl MarginfiAccountWithBanks {
 
             self.balances.push((*mint, balance));
         }
+        Ok(())
     }
 
-    pub fn borrow(&mut self, amount: I80F48, mint: &Pubkey) {
-        let (bank_address, bank) = &self.get_bank_by_mint(mint).unwrap();
+    pub fn borrow(&mut self, amount: I80F48, mint: &Pubkey) -> Result<(), Error> {
+        let (bank_address, bank) = &self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::MissingBank(*mint))?;
         let liability_shares = amount / bank.liability_share_value;
 
         if let Some(i) = self.balances.iter().position(|(m, _)| m == mint) {
             let (_, balance) = &mut self.balances[i];
-            balance.asset_shares = balance.liability_shares + liability_shares;
+            balance.liability_shares = balance.liability_shares + liability_shares;
         } else {
             let mut balance = MarginfiAccountBalance::new_empty(bank_address, bank);
             balance.is_active = true;
-            balance.liabilities_weight = liability_shares;
+            balance.liability_shares = liability_shares;
 
             self.balances.push((*mint, balance));
         }
+        Ok(())
+    }
+
+    /// Repaying all closes the liability, the balance is deactivated when it has no assets
+    pub fn repay(&mut self, amount: I80F48, mint: &Pubkey, repay_all: bool) -> Result<(), Error> {
+        let (_, bank) = self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::MissingBank(*mint))?;
+        let liability_shares = amount / bank.liability_share_value;
+
+        if let Some(i) = self.balances.iter().position(|(m, _)| m == mint) {
+            let (_, balance) = &mut self.balances[i];
+            if repay_all {
+                balance.liability_shares = I80F48::ZERO;
+                balance.is_active = balance.asset_shares > I80F48::ZERO;
+            } else {
+                balance.liability_shares =
+                    (balance.liability_shares - liability_shares).max(I80F48::ZERO);
+            }
+        }
+        Ok(())
     }
 
     pub fn get_bank_by_mint(&self, mint: &Pubkey) -> Option<&(Pubkey, MarginfiBank)> {
@@ -582,23 +733,637 @@ impl MarginfiAccountWithBanks {
     pub async fn get_total_weighted_amount(
         &self,
         oracles_state: &Arc<OraclesState>,
+    ) -> Result<(I80F48, I80F48), Error> {
+        self.get_total_weighted_amount_by_type(oracles_state, HealthType::Initial)
+            .await
+    }
+
+    pub async fn get_total_weighted_amount_by_type(
+        &self,
+        oracles_state: &Arc<OraclesState>,
+        health_type: HealthType,
     ) -> Result<(I80F48, I80F48), Error> {
         let mut total_assets = I80F48::ZERO;
         let mut total_liabilities = I80F48::ZERO;
 
         for (mint, balance) in self.balances.iter() {
-            let (_, bank) = self.get_bank_by_mint(mint).unwrap();
+            let (_, bank) = self
+                .get_bank_by_mint(mint)
+                .ok_or(Error::MissingBank(*mint))?;
             let oracle = oracles_state
                 .get_oracle(bank.oracle_setup, &bank.oracle_address)
                 .await
-                .unwrap();
+                .ok_or(Error::MissingOracle(bank.oracle_address))?;
 
-            let (assets, liabilities) = balance.get_weighted_amounts(bank, &oracle)?;
+            let (assets, liabilities) = balance.get_weighted_amounts(bank, &oracle, health_type)?;
 
             total_assets = total_assets + assets;
-            total_liabilities = total_liabilities * liabilities;
+            total_liabilities = total_liabilities + liabilities;
         }
 
         Ok((total_assets, total_liabilities))
     }
+
+    /// The price assets are valued at
+    pub async fn get_low_price(
+        &self,
+        oracles_state: &Arc<OraclesState>,
+        mint: &Pubkey,
+    ) -> Result<I80F48, Error> {
+        let (_, bank) = self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::InvalidMarginfiBank)?;
+        let oracle = oracles_state
+            .get_oracle(bank.oracle_setup, &bank.oracle_address)
+            .await
+            .ok_or(Error::StaleOracles(vec![bank.oracle_address]))?;
+        Ok(oracle.get_price_range()?.0)
+    }
+
+    /// (assets - liabilities) / assets with maintenance weights, the account can be
+    /// liquidated at zero
+    pub async fn get_maintenance_health(
+        &self,
+        oracles_state: &Arc<OraclesState>,
+    ) -> Result<I80F48, Error> {
+        let (assets, liabilities) = self
+            .get_total_weighted_amount_by_type(oracles_state, HealthType::Maintenance)
+            .await?;
+        Ok(Self::calc_health(assets, liabilities))
+    }
+
+    /// Native amount of `mint` which can be borrowed so that the initial health ends at
+    /// `target_health`, not clamped by the bank liquidity
+    pub async fn get_borrow_amount_for_health(
+        &self,
+        oracles_state: &Arc<OraclesState>,
+        mint: &Pubkey,
+        target_health: I80F48,
+    ) -> Result<I80F48, Error> {
+        let (assets, liabilities) = self.get_total_weighted_amount(oracles_state).await?;
+        let (_, bank) = self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::InvalidMarginfiBank)?;
+        let oracle = oracles_state
+            .get_oracle(bank.oracle_setup, &bank.oracle_address)
+            .await
+            .ok_or(Error::MissingOracle(bank.oracle_address))?;
+        // Liabilities are valued at the top of the confidence interval
+        let (_, highest_price) = oracle.get_price_range()?;
+
+        // (assets - liabilities) / assets is the target once liabilities reach this share
+        let free_liabilities = assets * (I80F48::ONE - target_health) - liabilities;
+        if free_liabilities <= I80F48::ZERO {
+            return Ok(I80F48::ZERO);
+        }
+
+        // Weighted amounts are in USD scaled by 10^6
+        let weighted_value_per_token =
+            bank.liability_weight_init * highest_price * EXP_10_I80F48[6]
+                / EXP_10_I80F48[bank.mint_decimals as usize];
+        Ok((free_liabilities / weighted_value_per_token).floor())
+    }
+
+    /// Native amount of `mint` which can be borrowed before its liability is worth
+    /// `cap_usd`, valued at the top of the confidence interval
+    pub async fn get_borrow_amount_for_cap(
+        &self,
+        oracles_state: &Arc<OraclesState>,
+        mint: &Pubkey,
+        cap_usd: f64,
+    ) -> Result<I80F48, Error> {
+        let (_, bank) = self
+            .get_bank_by_mint(mint)
+            .ok_or(Error::InvalidMarginfiBank)?;
+        let oracle = oracles_state
+            .get_oracle(bank.oracle_setup, &bank.oracle_address)
+            .await
+            .ok_or(Error::MissingOracle(bank.oracle_address))?;
+        let (_, highest_price) = oracle.get_price_range()?;
+        let liability = self
+            .get_balance_by_mint(mint)
+            .map(|balance| {
+                balance
+                    .get_amounts(bank.asset_share_value, bank.liability_share_value)
+                    .1
+            })
+            .unwrap_or(I80F48::ZERO);
+
+        let cap_amount =
+            I80F48::from_num(cap_usd) / highest_price * EXP_10_I80F48[bank.mint_decimals as usize];
+        Ok((cap_amount - liability).max(I80F48::ZERO).floor())
+    }
+
+    pub fn calc_health(assets: I80F48, liabilities: I80F48) -> I80F48 {
+        if assets <= I80F48::ZERO {
+            return if liabilities > I80F48::ZERO {
+                I80F48::ZERO
+            } else {
+                I80F48::ONE
+            };
+        }
+        ((assets - liabilities) / assets).max(I80F48::ZERO)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MeteoraPoolState {
+    pub a_vault_lp_amount: u64,
+    pub b_vault_lp_amount: u64,
+    pub a_vault_total_amount: u64,
+    pub b_vault_total_amount: u64,
+    pub a_vault_lp_supply: u64,
+    pub b_vault_lp_supply: u64,
+    pub lp_supply: u64,
+}
+
+impl MeteoraPoolState {
+    fn get_vault_share(lp_amount: u64, total_amount: u64, lp_supply: u64) -> Option<u128> {
+        if lp_supply == 0 {
+            return Some(0);
+        }
+        (lp_amount as u128)
+            .checked_mul(total_amount as u128)?
+            .checked_div(lp_supply as u128)
+    }
+
+    pub fn get_token_amounts(&self) -> Option<(u128, u128)> {
+        Some((
+            Self::get_vault_share(
+                self.a_vault_lp_amount,
+                self.a_vault_total_amount,
+                self.a_vault_lp_supply,
+            )?,
+            Self::get_vault_share(
+                self.b_vault_lp_amount,
+                self.b_vault_total_amount,
+                self.b_vault_lp_supply,
+            )?,
+        ))
+    }
+
+    pub fn get_withdraw_amounts(&self, lp_amount: u64) -> Option<(u64, u64)> {
+        if self.lp_supply == 0 {
+            return Some((0, 0));
+        }
+
+        let (token_a_amount, token_b_amount) = self.get_token_amounts()?;
+        let share = |amount: u128| -> Option<u64> {
+            amount
+                .checked_mul(lp_amount as u128)?
+                .checked_div(self.lp_supply as u128)?
+                .try_into()
+                .ok()
+        };
+        Some((share(token_a_amount)?, share(token_b_amount)?))
+    }
+
+    /// Both tokens are valued 1:1 as in stable pools
+    pub fn get_expected_lp(&self, deposit_amount: u64) -> Option<u64> {
+        let (token_a_amount, token_b_amount) = self.get_token_amounts()?;
+        let pool_value = token_a_amount.checked_add(token_b_amount)?;
+        if pool_value == 0 || self.lp_supply == 0 {
+            return Some(deposit_amount);
+        }
+
+        (deposit_amount as u128)
+            .checked_mul(self.lp_supply as u128)?
+            .checked_div(pool_value)?
+            .try_into()
+            .ok()
+    }
+}
+
+/// Only the leading fields are deserialized
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Default, Clone)]
+pub struct MeteoraFarmPool {
+    pub staking_mint: Pubkey,
+    pub staking_vault: Pubkey,
+    pub reward_a_mint: Pubkey,
+    pub reward_a_vault: Pubkey,
+    pub reward_b_mint: Pubkey,
+    pub reward_b_vault: Pubkey,
+    pub base_key: Pubkey,
+    pub reward_duration: u64,
+    pub reward_duration_end: u64,
+    pub last_update_time: u64,
+    pub reward_a_rate: u128,
+    pub reward_b_rate: u128,
+    pub reward_a_per_token_stored: u128,
+    pub reward_b_per_token_stored: u128,
+}
+
+/// Reward per token values are scaled by this in the farm program
+const FARM_REWARD_PRECISION: u128 = u64::MAX as u128;
+
+impl MeteoraFarmPool {
+    pub fn is_active(&self, unix_timestamp: u64) -> bool {
+        self.reward_duration_end > unix_timestamp
+    }
+
+    /// `total_staked` is the staking vault balance
+    pub fn get_reward_per_token(
+        &self,
+        total_staked: u64,
+        unix_timestamp: u64,
+    ) -> Option<(u128, u128)> {
+        if total_staked == 0 {
+            return Some((
+                self.reward_a_per_token_stored,
+                self.reward_b_per_token_stored,
+            ));
+        }
+
+        let last_time_reward_applicable = unix_timestamp.min(self.reward_duration_end);
+        let time_period = last_time_reward_applicable.saturating_sub(self.last_update_time) as u128;
+        let accrued = |rate: u128| -> Option<u128> {
+            time_period
+                .checked_mul(rate)?
+                .checked_mul(FARM_REWARD_PRECISION)?
+                .checked_div(total_staked as u128)
+        };
+
+        Some((
+            self.reward_a_per_token_stored
+                .checked_add(accrued(self.reward_a_rate)?)?,
+            self.reward_b_per_token_stored
+                .checked_add(accrued(self.reward_b_rate)?)?,
+        ))
+    }
+}
+
+impl Discriminator for MeteoraFarmPool {
+    // sha256("account:Pool")[..8]
+    const DISCRIMINATOR: [u8; 8] = [241, 154, 109, 4, 17, 177, 109, 188];
+}
+
+impl AccountDeserialize for MeteoraFarmPool {
+    fn try_deserialize(buf: &mut &[u8]) -> anchor_lang::Result<Self> {
+        if buf.len() < 8 || buf[..8] != Self::DISCRIMINATOR {
+            return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+        }
+        Self::try_deserialize_unchecked(buf)
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> anchor_lang::Result<Self> {
+        let mut data = &buf[8..];
+        Self::deserialize(&mut data).map_err(|_| ErrorCode::AccountDidNotDeserialize.into())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Default, Clone)]
+pub struct MeteoraFarmUser {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub reward_a_per_token_complete: u128,
+    pub reward_b_per_token_complete: u128,
+    pub reward_a_per_token_pending: u64,
+    pub reward_b_per_token_pending: u64,
+    pub balance_staked: u64,
+    pub nonce: u8,
+}
+
+impl MeteoraFarmUser {
+    pub fn get_pending_rewards(
+        &self,
+        farm: &MeteoraFarmPool,
+        total_staked: u64,
+        unix_timestamp: u64,
+    ) -> Option<(u64, u64)> {
+        let (reward_a_per_token, reward_b_per_token) =
+            farm.get_reward_per_token(total_staked, unix_timestamp)?;
+        let earned = |per_token: u128, complete: u128, pending: u64| -> Option<u64> {
+            let earned: u64 = (self.balance_staked as u128)
+                .checked_mul(per_token.saturating_sub(complete))?
+                .checked_div(FARM_REWARD_PRECISION)?
+                .try_into()
+                .ok()?;
+            earned.checked_add(pending)
+        };
+
+        Some((
+            earned(
+                reward_a_per_token,
+                self.reward_a_per_token_complete,
+                self.reward_a_per_token_pending,
+            )?,
+            earned(
+                reward_b_per_token,
+                self.reward_b_per_token_complete,
+                self.reward_b_per_token_pending,
+            )?,
+        ))
+    }
+}
+
+impl Discriminator for MeteoraFarmUser {
+    // sha256("account:User")[..8]
+    const DISCRIMINATOR: [u8; 8] = [159, 117, 95, 227, 239, 151, 58, 236];
+}
+
+impl AccountDeserialize for MeteoraFarmUser {
+    fn try_deserialize(buf: &mut &[u8]) -> anchor_lang::Result<Self> {
+        if buf.len() < 8 || buf[..8] != Self::DISCRIMINATOR {
+            return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+        }
+        Self::try_deserialize_unchecked(buf)
+    }
+
+    fn try_deserialize_unchecked(buf: &mut &[u8]) -> anchor_lang::Result<Self> {
+        let mut data = &buf[8..];
+        Self::deserialize(&mut data).map_err(|_| ErrorCode::AccountDidNotDeserialize.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+
+    const BSOL_BANK: Pubkey = Pubkey::new_from_array([1; 32]);
+    const USDC_BANK: Pubkey = Pubkey::new_from_array([2; 32]);
+    const BSOL: Pubkey = Pubkey::new_from_array([3; 32]);
+    const USDC: Pubkey = Pubkey::new_from_array([4; 32]);
+
+    /// Balances are (bank, asset shares, liability shares)
+    fn account_with_banks(balances: &[(Pubkey, u64, u64)]) -> (MarginfiAccountWithBanks, Pubkey) {
+        let bsol_oracle = Pubkey::new_unique();
+        let banks = vec![
+            (
+                BSOL_BANK,
+                testkit::new_bank(&BSOL_BANK, BSOL, 9, bsol_oracle),
+            ),
+            (
+                USDC_BANK,
+                testkit::new_bank(&USDC_BANK, USDC, 6, Pubkey::new_unique()),
+            ),
+        ];
+        let account = testkit::new_marginfi_account(&Pubkey::new_unique(), balances);
+        (MarginfiAccountWithBanks::new(account, banks), bsol_oracle)
+    }
+
+    /// Oracles state with Pyth prices of `price * 10^PRICE_EXPO` by oracle
+    async fn oracles_state(prices: &[(Pubkey, i64)]) -> Arc<OraclesState> {
+        let oracles_state = Arc::new(OraclesState::new());
+        oracles_state.current_slot.update(testkit::MOCK_SLOT);
+        for (oracle, price) in prices {
+            oracles_state
+                .apply_update(StateUpdate::PythOracle((
+                    *oracle,
+                    testkit::new_pyth_price_feed(*price, testkit::PRICE_EXPO),
+                )))
+                .await;
+        }
+        oracles_state
+    }
+
+    fn oracle_of(account_with_banks: &MarginfiAccountWithBanks, mint: &Pubkey) -> Pubkey {
+        let (_, bank) = account_with_banks.get_bank_by_mint(mint).unwrap();
+        bank.oracle_address
+    }
+
+    #[test]
+    fn balance_changes_of_unknown_mints_are_errors() {
+        let (mut account, _) = account_with_banks(&[]);
+        let mint = Pubkey::new_unique();
+        let amount = I80F48::from_num(1_000);
+
+        assert!(matches!(
+            account.deposit(amount, &mint),
+            Err(Error::MissingBank(m)) if m == mint
+        ));
+        assert!(matches!(
+            account.borrow(amount, &mint),
+            Err(Error::MissingBank(m)) if m == mint
+        ));
+        assert!(matches!(
+            account.repay(amount, &mint, true),
+            Err(Error::MissingBank(m)) if m == mint
+        ));
+        assert!(account.balances.is_empty());
+
+        account.deposit(amount, &BSOL).unwrap();
+        account.borrow(amount, &USDC).unwrap();
+        assert_eq!(account.balances.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_oracles_are_errors() {
+        let (account, bsol_oracle) =
+            account_with_banks(&[(BSOL_BANK, 1_000, 0), (USDC_BANK, 0, 100)]);
+        let usdc_oracle = oracle_of(&account, &USDC);
+        let oracles_state = oracles_state(&[(bsol_oracle, testkit::COLLATERAL_PRICE)]).await;
+
+        assert!(matches!(
+            account.get_total_weighted_amount(&oracles_state).await,
+            Err(Error::MissingOracle(oracle)) if oracle == usdc_oracle
+        ));
+        assert!(matches!(
+            account.get_maintenance_health(&oracles_state).await,
+            Err(Error::MissingOracle(oracle)) if oracle == usdc_oracle
+        ));
+        assert!(matches!(
+            account
+                .get_borrow_amount_for_health(&oracles_state, &USDC, I80F48::from_num(2))
+                .await,
+            Err(Error::MissingOracle(oracle)) if oracle == usdc_oracle
+        ));
+        assert!(matches!(
+            account.get_low_price(&oracles_state, &USDC).await,
+            Err(Error::StaleOracles(oracles)) if oracles == vec![usdc_oracle]
+        ));
+        assert!(matches!(
+            account
+                .get_low_price(&oracles_state, &Pubkey::new_unique())
+                .await,
+            Err(Error::InvalidMarginfiBank)
+        ));
+        assert!(account.get_low_price(&oracles_state, &BSOL).await.is_ok());
+    }
+
+    #[test]
+    fn health_is_the_free_share_of_assets() {
+        let health = |assets: u64, liabilities: u64| {
+            MarginfiAccountWithBanks::calc_health(
+                I80F48::from_num(assets),
+                I80F48::from_num(liabilities),
+            )
+        };
+
+        assert_eq!(health(200, 100), I80F48::from_num(0.5));
+        assert_eq!(health(200, 0), I80F48::ONE);
+        assert_eq!(health(100, 150), I80F48::ZERO);
+        assert_eq!(health(0, 0), I80F48::ONE);
+        assert_eq!(health(0, 10), I80F48::ZERO);
+    }
+
+    #[tokio::test]
+    async fn maintenance_health_of_the_position() {
+        // 10 bSOL worth $200 against 100 USDC
+        let (account, bsol_oracle) =
+            account_with_banks(&[(BSOL_BANK, 10_000_000_000, 0), (USDC_BANK, 0, 100_000_000)]);
+        let usdc_oracle = oracle_of(&account, &USDC);
+        let oracles_state = oracles_state(&[
+            (bsol_oracle, testkit::COLLATERAL_PRICE),
+            (usdc_oracle, testkit::STABLE_PRICE),
+        ])
+        .await;
+
+        let health = account
+            .get_maintenance_health(&oracles_state)
+            .await
+            .unwrap();
+        assert!((health.to_num::<f64>() - 0.5).abs() < 0.000001);
+    }
+
+    /// Borrow amount of `mint` at a target health of 0.5, bSOL at the collateral price and
+    /// USDC at the stable price
+    async fn borrow_amount_for_health(
+        account: &MarginfiAccountWithBanks,
+        bsol_oracle: Pubkey,
+        mint: &Pubkey,
+    ) -> u64 {
+        let oracles_state = oracles_state(&[
+            (bsol_oracle, testkit::COLLATERAL_PRICE),
+            (oracle_of(account, &USDC), testkit::STABLE_PRICE),
+        ])
+        .await;
+        account
+            .get_borrow_amount_for_health(&oracles_state, mint, I80F48::from_num(0.5))
+            .await
+            .unwrap()
+            .to_num()
+    }
+
+    #[tokio::test]
+    async fn borrow_amount_lands_at_the_target_health() {
+        // Oracle prices are not exact in binary, the floored amount can be one unit lower
+        let is_close = |amount: u64, expected: u64| amount.abs_diff(expected) <= 1;
+
+        // $200 of assets carry $100 of liabilities at a health of 0.5
+        let (account, bsol_oracle) = account_with_banks(&[(BSOL_BANK, 10_000_000_000, 0)]);
+        assert!(is_close(
+            borrow_amount_for_health(&account, bsol_oracle, &USDC).await,
+            100_000_000
+        ));
+        // Sized in the decimals and price of the borrowed mint
+        assert!(is_close(
+            borrow_amount_for_health(&account, bsol_oracle, &BSOL).await,
+            5_000_000_000
+        ));
+
+        // Existing liabilities are taken from the free amount
+        let (account, bsol_oracle) =
+            account_with_banks(&[(BSOL_BANK, 10_000_000_000, 0), (USDC_BANK, 0, 40_000_000)]);
+        assert!(is_close(
+            borrow_amount_for_health(&account, bsol_oracle, &USDC).await,
+            60_000_000
+        ));
+
+        // Nothing is borrowed once the account is below the target
+        let (account, bsol_oracle) =
+            account_with_banks(&[(BSOL_BANK, 10_000_000_000, 0), (USDC_BANK, 0, 150_000_000)]);
+        assert_eq!(
+            borrow_amount_for_health(&account, bsol_oracle, &USDC).await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn initial_health_after_the_borrow_is_the_target() {
+        for (balances, target) in [
+            (&[(BSOL_BANK, 10_000_000_000, 0)][..], 0.2),
+            (&[(BSOL_BANK, 10_000_000_000, 0)][..], 0.5),
+            (
+                &[(BSOL_BANK, 10_000_000_000, 0), (USDC_BANK, 0, 40_000_000)][..],
+                0.5,
+            ),
+        ] {
+            let (mut account, bsol_oracle) = account_with_banks(balances);
+            let oracles_state = oracles_state(&[
+                (bsol_oracle, testkit::COLLATERAL_PRICE),
+                (oracle_of(&account, &USDC), testkit::STABLE_PRICE),
+            ])
+            .await;
+            let target = I80F48::from_num(target);
+
+            let amount = account
+                .get_borrow_amount_for_health(&oracles_state, &USDC, target)
+                .await
+                .unwrap();
+            account.borrow(amount, &USDC).unwrap();
+
+            // Health is reported from the same weighted amounts
+            let (assets, liabilities) = account
+                .get_total_weighted_amount_by_type(&oracles_state, HealthType::Initial)
+                .await
+                .unwrap();
+            let health = MarginfiAccountWithBanks::calc_health(assets, liabilities);
+            assert!((health - target).abs() < I80F48::from_num(0.000001));
+        }
+    }
+
+    fn pool_state(a_amount: u64, b_amount: u64) -> MeteoraPoolState {
+        MeteoraPoolState {
+            // Half of the vault LP, each vault LP token worth 2 tokens
+            a_vault_lp_amount: a_amount / 2,
+            b_vault_lp_amount: b_amount / 2,
+            a_vault_total_amount: a_amount * 2,
+            b_vault_total_amount: b_amount * 2,
+            a_vault_lp_supply: a_amount,
+            b_vault_lp_supply: b_amount,
+            lp_supply: 1_000,
+        }
+    }
+
+    #[test]
+    fn pool_token_amounts_are_the_vault_shares() {
+        assert_eq!(
+            pool_state(1_000, 500).get_token_amounts(),
+            Some((1_000, 500))
+        );
+        assert_eq!(pool_state(0, 500).get_token_amounts(), Some((0, 500)));
+    }
+
+    #[test]
+    fn expected_lp_follows_the_virtual_price() {
+        // 1500 tokens for 1000 LP, one LP is worth 1.5 tokens
+        let state = pool_state(1_000, 500);
+        assert_eq!(state.get_expected_lp(150), Some(100));
+        assert_eq!(state.get_expected_lp(1), Some(0));
+        assert_eq!(state.get_expected_lp(0), Some(0));
+
+        // 500 tokens for 1000 LP
+        assert_eq!(pool_state(250, 250).get_expected_lp(150), Some(300));
+    }
+
+    #[test]
+    fn empty_pool_mints_lp_one_to_one() {
+        assert_eq!(pool_state(0, 0).get_expected_lp(150), Some(150));
+        let state = MeteoraPoolState {
+            lp_supply: 0,
+            ..pool_state(1_000, 500)
+        };
+        assert_eq!(state.get_expected_lp(150), Some(150));
+        assert_eq!(state.get_withdraw_amounts(150), Some((0, 0)));
+    }
+
+    #[test]
+    fn withdraw_amounts_are_the_lp_share() {
+        let state = pool_state(1_000, 500);
+        assert_eq!(state.get_withdraw_amounts(100), Some((100, 50)));
+        assert_eq!(state.get_withdraw_amounts(1_000), Some((1_000, 500)));
+    }
+
+    #[test]
+    fn overflowing_amounts_are_none() {
+        let state = MeteoraPoolState {
+            a_vault_lp_amount: u64::MAX,
+            a_vault_total_amount: u64::MAX,
+            a_vault_lp_supply: 1,
+            lp_supply: 1,
+            ..Default::default()
+        };
+        assert_eq!(state.get_withdraw_amounts(u64::MAX), None);
+    }
 }
@@ -0,0 +1,502 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anchor_lang::prelude::Pubkey;
+use fixed::types::I80F48;
+use marginfi::constants::EXP_10_I80F48;
+use serde_json::{json, Value};
+use solana_client::nonblocking::rpc_client::RpcClient;
+
+use crate::{
+    addresses::StaticAddresses,
+    connection::{self, get_multiple_accounts_chunked, parse_token_account_amount},
+    ledger::{Ledger, LedgerSummary},
+    metrics,
+    state::{HealthType, MarginfiAccountWithBanks, MarginfiBank, OraclesState},
+    Error, Wallet,
+};
+
+struct LendingBalance {
+    mint: Pubkey,
+    amount: f64,
+    usd_value: f64,
+    /// In percent, only set for liabilities
+    borrow_apr: Option<f64>,
+}
+
+struct FarmPosition {
+    farm: Pubkey,
+    staked_lp: f64,
+    /// At the pool virtual price, both pool tokens valued 1:1
+    staked_value: f64,
+    pending_rewards: Vec<(Pubkey, f64)>,
+}
+
+struct PoolPosition {
+    pool: Pubkey,
+    virtual_price: f64,
+    farms: Vec<FarmPosition>,
+}
+
+struct PositionStatus {
+    marginfi_account: Pubkey,
+    collateral: Option<LendingBalance>,
+    deposits: Vec<LendingBalance>,
+    liabilities: Vec<LendingBalance>,
+    initial_health: f64,
+    maintenance_health: f64,
+    borrow_mints: Vec<(Pubkey, Option<f64>)>,
+    pools: Vec<PoolPosition>,
+    sol_balance: f64,
+    wallet_balances: Vec<(Pubkey, f64)>,
+    pnl: LedgerSummary,
+}
+
+fn to_ui_amount(amount: u64, decimals: u8) -> f64 {
+    amount as f64 / 10_f64.powi(decimals as i32)
+}
+
+async fn get_lending_balances(
+    account_with_banks: &MarginfiAccountWithBanks,
+    oracles_state: &Arc<OraclesState>,
+) -> Result<(Vec<LendingBalance>, Vec<LendingBalance>), Error> {
+    let mut deposits = vec![];
+    let mut liabilities = vec![];
+
+    for (mint, balance) in account_with_banks
+        .balances
+        .iter()
+        .filter(|(_, balance)| balance.is_active)
+    {
+        let (_, bank) = account_with_banks
+            .get_bank_by_mint(mint)
+            .ok_or(Error::InvalidMarginfiBank)?;
+        let Some(oracle) = oracles_state
+            .get_oracle(bank.oracle_setup, &bank.oracle_address)
+            .await
+        else {
+            return Err(Error::MissingOracle(bank.oracle_address));
+        };
+        let price = oracle.get_price()?;
+        let scaling_factor = EXP_10_I80F48[bank.mint_decimals as usize];
+        let (assets, liabs) =
+            balance.get_amounts(bank.asset_share_value, bank.liability_share_value);
+
+        if assets > I80F48::ZERO {
+            deposits.push(LendingBalance {
+                mint: *mint,
+                amount: (assets / scaling_factor).to_num(),
+                usd_value: (assets * price / scaling_factor).to_num(),
+                borrow_apr: None,
+            });
+        }
+        if liabs > I80F48::ZERO {
+            liabilities.push(LendingBalance {
+                mint: *mint,
+                amount: (liabs / scaling_factor).to_num(),
+                usd_value: (liabs * price / scaling_factor).to_num(),
+                borrow_apr: Some((bank.get_borrow_rate() * I80F48::from_num(100)).to_num()),
+            });
+        }
+    }
+
+    Ok((deposits, liabilities))
+}
+
+async fn get_bank_prices(
+    rpc_client: &Arc<RpcClient>,
+    static_addresses: &StaticAddresses,
+    oracles_state: &Arc<OraclesState>,
+) -> Result<(HashMap<Pubkey, f64>, HashMap<Pubkey, u8>), Error> {
+    let mints = static_addresses
+        .marginfi_banks
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let banks = connection::fetch_marginfi_banks(rpc_client, &mints, &[]).await?;
+    let mut prices = HashMap::new();
+    let mut decimals = HashMap::new();
+
+    for (_, bank) in banks {
+        let bank = MarginfiBank::from(bank);
+        decimals.insert(bank.mint, bank.mint_decimals);
+        if let Some(oracle) = oracles_state
+            .get_oracle(bank.oracle_setup, &bank.oracle_address)
+            .await
+        {
+            prices.insert(bank.mint, oracle.get_price()?.to_num());
+        }
+    }
+
+    Ok((prices, decimals))
+}
+
+async fn get_pool_positions(
+    rpc_client: &Arc<RpcClient>,
+    static_addresses: &StaticAddresses,
+    decimals: &HashMap<Pubkey, u8>,
+) -> Result<Vec<PoolPosition>, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut positions = vec![];
+
+    for pool_id in static_addresses.meteora_pool_ids() {
+        let pool = static_addresses.get_meteora_pool(pool_id)?;
+        let pool_state = connection::fetch_meteora_pool_state(rpc_client, pool).await?;
+        let a_decimals = decimals.get(&pool.a_token_mint).copied().unwrap_or(0);
+        let b_decimals = decimals.get(&pool.b_token_mint).copied().unwrap_or(0);
+        let lp_decimals = decimals.get(&pool.lp_mint).copied().unwrap_or(0);
+        let lp_value = |lp_amount: u64| -> Result<f64, Error> {
+            let (a_amount, b_amount) = pool_state
+                .get_withdraw_amounts(lp_amount)
+                .ok_or(Error::MathOverflow)?;
+            Ok(to_ui_amount(a_amount, a_decimals) + to_ui_amount(b_amount, b_decimals))
+        };
+        let one_lp = 10_u64.pow(lp_decimals as u32);
+        let virtual_price = lp_value(one_lp)?;
+
+        let farm_metas = static_addresses.get_meteora_farms_by_lp_mint(&pool.lp_mint);
+        let farm_addresses = farm_metas
+            .iter()
+            .map(|farm| farm.address)
+            .collect::<Vec<_>>();
+        let staking_vaults = farm_metas
+            .iter()
+            .map(|farm| farm.staking_vault)
+            .collect::<Vec<_>>();
+        let farms = connection::fetch_meteora_farms(rpc_client, &farm_addresses).await?;
+        let staking_vault_ais = get_multiple_accounts_chunked(rpc_client, &staking_vaults).await?;
+
+        let mut farm_positions = vec![];
+        for ((farm_meta, (_, farm)), vault_ai) in farm_metas
+            .iter()
+            .zip(farms.iter())
+            .zip(staking_vault_ais.iter())
+        {
+            let user = connection::fetch_meteora_farm_user(rpc_client, farm_meta).await?;
+            let total_staked = match vault_ai {
+                Some(ai) => parse_token_account_amount(&ai.data)?,
+                None => 0,
+            };
+            let (reward_a, reward_b) = user
+                .get_pending_rewards(farm, total_staked, now)
+                .ok_or(Error::MathOverflow)?;
+
+            farm_positions.push(FarmPosition {
+                farm: farm_meta.address,
+                staked_lp: to_ui_amount(user.balance_staked, lp_decimals),
+                staked_value: lp_value(user.balance_staked)?,
+                pending_rewards: [
+                    (farm.reward_a_mint, reward_a),
+                    (farm.reward_b_mint, reward_b),
+                ]
+                .into_iter()
+                .filter(|(mint, _)| mint != &Pubkey::default())
+                .map(|(mint, amount)| {
+                    let mint_decimals = decimals.get(&mint).copied().unwrap_or(0);
+                    (mint, to_ui_amount(amount, mint_decimals))
+                })
+                .collect(),
+            });
+        }
+
+        positions.push(PoolPosition {
+            pool: pool.address,
+            virtual_price,
+            farms: farm_positions,
+        });
+    }
+
+    Ok(positions)
+}
+
+pub async fn record_position_metrics(
+    rpc_client: &Arc<RpcClient>,
+    static_addresses: &StaticAddresses,
+    oracles_state: &Arc<OraclesState>,
+    account_with_banks: &MarginfiAccountWithBanks,
+) -> Result<(), Error> {
+    let (initial_assets, initial_liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(oracles_state, HealthType::Initial)
+        .await?;
+    let (maintenance_assets, maintenance_liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(oracles_state, HealthType::Maintenance)
+        .await?;
+    metrics::set_gauge(
+        metrics::INIT_HEALTH,
+        &[],
+        MarginfiAccountWithBanks::calc_health(initial_assets, initial_liabilities).to_num(),
+    );
+    metrics::set_gauge(
+        metrics::MAINT_HEALTH,
+        &[],
+        MarginfiAccountWithBanks::calc_health(maintenance_assets, maintenance_liabilities).to_num(),
+    );
+    metrics::set_gauge(metrics::WEIGHTED_ASSETS, &[], maintenance_assets.to_num());
+    metrics::set_gauge(
+        metrics::WEIGHTED_LIABILITIES,
+        &[],
+        maintenance_liabilities.to_num(),
+    );
+
+    for (_, bank) in &account_with_banks.banks {
+        metrics::set_gauge(
+            metrics::BORROW_RATE,
+            &[("mint", &bank.mint.to_string())],
+            bank.get_borrow_rate().to_num(),
+        );
+    }
+    let collateral_price = account_with_banks
+        .get_low_price(oracles_state, &static_addresses.collateral_mint)
+        .await?;
+    metrics::set_gauge(metrics::COLLATERAL_PRICE, &[], collateral_price.to_num());
+
+    let wallet_mints = static_addresses
+        .wallet_token_accounts
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let decimals = connection::fetch_mints_decimals(rpc_client, &wallet_mints).await?;
+    for pool in get_pool_positions(rpc_client, static_addresses, &decimals).await? {
+        for farm in pool.farms {
+            let farm_address = farm.farm.to_string();
+            metrics::set_gauge(
+                metrics::STAKED_LP,
+                &[("farm", &farm_address)],
+                farm.staked_lp,
+            );
+            for (mint, amount) in farm.pending_rewards {
+                metrics::set_gauge(
+                    metrics::PENDING_REWARDS,
+                    &[("farm", &farm_address), ("mint", &mint.to_string())],
+                    amount,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn fetch_status(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    static_addresses: &StaticAddresses,
+    ledger: &Ledger,
+) -> Result<PositionStatus, Error> {
+    let oracles_state =
+        connection::fetch_oracles_state(rpc_client, &static_addresses.marginfi_banks).await?;
+    let account_with_banks = connection::fetch_marginfi_account_with_banks(
+        rpc_client,
+        wallet,
+        &static_addresses.marginfi_account,
+    )
+    .await?;
+
+    let (mut deposits, liabilities) =
+        get_lending_balances(&account_with_banks, &oracles_state).await?;
+    let collateral = deposits
+        .iter()
+        .position(|deposit| deposit.mint == static_addresses.collateral_mint)
+        .map(|i| deposits.remove(i));
+
+    let (initial_assets, initial_liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(&oracles_state, HealthType::Initial)
+        .await?;
+    let (maintenance_assets, maintenance_liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(&oracles_state, HealthType::Maintenance)
+        .await?;
+
+    let wallet_mints = static_addresses
+        .wallet_token_accounts
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let decimals = connection::fetch_mints_decimals(rpc_client, &wallet_mints).await?;
+    let token_balances =
+        connection::fetch_token_balances(rpc_client, static_addresses, &wallet_mints).await?;
+    let mut wallet_balances = token_balances
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .map(|(mint, amount)| {
+            let mint_decimals = decimals.get(&mint).copied().unwrap_or(0);
+            (mint, to_ui_amount(amount, mint_decimals))
+        })
+        .collect::<Vec<_>>();
+    wallet_balances.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let lamports = connection::fetch_lamports_balance(rpc_client, wallet).await?;
+    let (prices, bank_decimals) =
+        get_bank_prices(rpc_client, static_addresses, &oracles_state).await?;
+
+    Ok(PositionStatus {
+        marginfi_account: static_addresses.marginfi_account,
+        collateral,
+        deposits,
+        liabilities,
+        initial_health: MarginfiAccountWithBanks::calc_health(initial_assets, initial_liabilities)
+            .to_num(),
+        maintenance_health: MarginfiAccountWithBanks::calc_health(
+            maintenance_assets,
+            maintenance_liabilities,
+        )
+        .to_num(),
+        borrow_mints: static_addresses
+            .borrowable_mints
+            .iter()
+            .map(|mint| (*mint, static_addresses.borrow_caps.get(mint).copied()))
+            .collect(),
+        pools: get_pool_positions(rpc_client, static_addresses, &decimals).await?,
+        sol_balance: to_ui_amount(lamports, 9),
+        wallet_balances,
+        pnl: ledger.get().summary(&prices, &bank_decimals),
+    })
+}
+
+fn lending_balance_to_json(balance: &LendingBalance) -> Value {
+    json!({
+        "mint": balance.mint.to_string(),
+        "amount": balance.amount,
+        "usd_value": balance.usd_value,
+        "borrow_apr": balance.borrow_apr,
+    })
+}
+
+fn status_to_json(status: &PositionStatus) -> Value {
+    json!({
+        "marginfi_account": status.marginfi_account.to_string(),
+        "collateral": status.collateral.as_ref().map(lending_balance_to_json),
+        "deposits": status.deposits.iter().map(lending_balance_to_json).collect::<Vec<_>>(),
+        "liabilities": status.liabilities.iter().map(lending_balance_to_json).collect::<Vec<_>>(),
+        "initial_health": status.initial_health,
+        "maintenance_health": status.maintenance_health,
+        "borrow_mints": status.borrow_mints.iter().map(|(mint, cap_usd)| json!({
+            "mint": mint.to_string(),
+            "cap_usd": cap_usd,
+        })).collect::<Vec<_>>(),
+        "pools": status.pools.iter().map(|pool| json!({
+            "pool": pool.pool.to_string(),
+            "virtual_price": pool.virtual_price,
+            "farms": pool.farms.iter().map(|farm| json!({
+                "farm": farm.farm.to_string(),
+                "staked_lp": farm.staked_lp,
+                "staked_value": farm.staked_value,
+                "pending_rewards": farm.pending_rewards.iter().map(|(mint, amount)| json!({
+                    "mint": mint.to_string(),
+                    "amount": amount,
+                })).collect::<Vec<_>>(),
+            })).collect::<Vec<_>>(),
+        })).collect::<Vec<_>>(),
+        "sol_balance": status.sol_balance,
+        "wallet_balances": status.wallet_balances.iter().map(|(mint, amount)| json!({
+            "mint": mint.to_string(),
+            "amount": amount,
+        })).collect::<Vec<_>>(),
+        "pnl": {
+            "rewards_usd": status.pnl.rewards_usd,
+            "interest_usd": status.pnl.interest_usd,
+            "fees_usd": status.pnl.fees_usd,
+            "swaps_usd": status.pnl.swaps_usd,
+            "net_usd": status.pnl.net_usd,
+            "unpriced": status.pnl.unpriced,
+        },
+    })
+}
+
+fn print_lending_balance(balance: &LendingBalance) {
+    print!(
+        "  {}: {:.6} (${:.2})",
+        balance.mint, balance.amount, balance.usd_value
+    );
+    match balance.borrow_apr {
+        Some(apr) => println!(", borrow APR {:.2}%", apr),
+        None => println!(),
+    }
+}
+
+fn print_status_text(status: &PositionStatus) {
+    println!("Marginfi account: {}", status.marginfi_account);
+    println!("Collateral:");
+    match &status.collateral {
+        Some(collateral) => print_lending_balance(collateral),
+        None => println!("  none"),
+    }
+    if !status.deposits.is_empty() {
+        println!("Other deposits:");
+        status.deposits.iter().for_each(print_lending_balance);
+    }
+    println!("Liabilities:");
+    if status.liabilities.is_empty() {
+        println!("  none");
+    }
+    status.liabilities.iter().for_each(print_lending_balance);
+    println!(
+        "Health: initial {:.4}, maintenance {:.4}",
+        status.initial_health, status.maintenance_health
+    );
+    println!("Borrow mints:");
+    for (mint, cap_usd) in status.borrow_mints.iter() {
+        match cap_usd {
+            Some(cap_usd) => println!("  {}: capped at ${:.2}", mint, cap_usd),
+            None => println!("  {}: no cap", mint),
+        }
+    }
+
+    for pool in status.pools.iter() {
+        println!(
+            "Meteora pool {} (virtual price {:.6})",
+            pool.pool, pool.virtual_price
+        );
+        for farm in pool.farms.iter() {
+            println!(
+                "  Farm {}: {:.6} LP staked (${:.2})",
+                farm.farm, farm.staked_lp, farm.staked_value
+            );
+            for (mint, amount) in farm.pending_rewards.iter() {
+                println!("    Pending reward {}: {:.6}", mint, amount);
+            }
+        }
+    }
+
+    println!("Wallet:");
+    println!("  SOL: {:.6}", status.sol_balance);
+    for (mint, amount) in status.wallet_balances.iter() {
+        println!("  {}: {:.6}", mint, amount);
+    }
+
+    let pnl = &status.pnl;
+    println!("P&L: ${:.2}", pnl.net_usd);
+    println!(
+        "  rewards ${:.2}, interest -${:.2}, fees -${:.2}, swaps ${:.2}",
+        pnl.rewards_usd, pnl.interest_usd, pnl.fees_usd, pnl.swaps_usd
+    );
+    if !pnl.unpriced.is_empty() {
+        println!("  without a price: {}", pnl.unpriced.join(", "));
+    }
+}
+
+/// Only reads from the RPC and sends no transactions
+pub async fn print_status(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    static_addresses: &StaticAddresses,
+    ledger: &Ledger,
+    json: bool,
+) -> Result<(), Error> {
+    let status = fetch_status(rpc_client, wallet, static_addresses, ledger).await?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&status_to_json(&status)).unwrap()
+        );
+    } else {
+        print_status_text(&status);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::{
+    metrics,
+    scheduler::get_panic_message,
+    shutdown::Shutdown,
+    utils::notifier::{NotificationLevel, Notifier},
+};
+
+/// Doubled for every restart in the window
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Further restarts wait until the oldest one leaves the window
+const MAX_RESTARTS: usize = 10;
+
+pub trait TaskOutput: Send + 'static {
+    fn into_result(self) -> Result<(), String>;
+}
+
+impl TaskOutput for () {
+    fn into_result(self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<E: Debug + Send + 'static> TaskOutput for Result<(), E> {
+    fn into_result(self) -> Result<(), String> {
+        self.map_err(|e| format!("{:?}", e))
+    }
+}
+
+pub struct SupervisedTask {
+    pub name: &'static str,
+    pub handle: JoinHandle<()>,
+}
+
+fn get_restart_delay(restarts: &VecDeque<Instant>) -> Duration {
+    let backoff = RESTART_DELAY
+        .saturating_mul(1 << restarts.len().min(16))
+        .min(MAX_RESTART_DELAY);
+    match restarts.front() {
+        Some(oldest) if restarts.len() >= MAX_RESTARTS => {
+            backoff.max(RESTART_WINDOW.saturating_sub(oldest.elapsed()))
+        }
+        _ => backoff,
+    }
+}
+
+/// A task which keeps failing is notified about but never stops the bot, which can still
+/// unwind the position
+pub fn supervise<T, F>(
+    name: &'static str,
+    shutdown: Shutdown,
+    notifier: Arc<Notifier>,
+    mut spawn: F,
+) -> SupervisedTask
+where
+    T: TaskOutput,
+    F: FnMut() -> JoinHandle<T> + Send + 'static,
+{
+    let handle = tokio::spawn(async move {
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+
+        loop {
+            let res = spawn().await;
+            if shutdown.is_triggered() {
+                return;
+            }
+
+            let reason = match res {
+                Ok(output) => match output.into_result() {
+                    Ok(()) => "exited".to_string(),
+                    Err(e) => format!("failed: {}", e),
+                },
+                Err(e) if e.is_panic() => {
+                    format!("panicked: {}", get_panic_message(&*e.into_panic()))
+                }
+                Err(e) => format!("was cancelled: {}", e),
+            };
+
+            while restarts
+                .front()
+                .is_some_and(|restart| restart.elapsed() > RESTART_WINDOW)
+            {
+                restarts.pop_front();
+            }
+            let delay = get_restart_delay(&restarts);
+            if restarts.len() >= MAX_RESTARTS {
+                error!(
+                    "Task {} {}, it was restarted {} times in {:?}, restarting in {:?}",
+                    name,
+                    reason,
+                    restarts.len(),
+                    RESTART_WINDOW,
+                    delay
+                );
+                notifier.notify(
+                    NotificationLevel::Critical,
+                    format!("Task {} keeps failing, last time it {}", name, reason),
+                );
+            } else {
+                warn!("Task {} {}, restarting in {:?}", name, reason, delay);
+            }
+            metrics::inc_counter(metrics::TASK_RESTARTS, &[("task", name)]);
+            restarts.push_back(Instant::now());
+
+            tokio::select! {
+                _ = sleep(delay) => info!("Restarting task {}", name),
+                _ = shutdown.triggered() => return,
+            }
+        }
+    });
+
+    SupervisedTask { name, handle }
+}
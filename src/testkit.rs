@@ -0,0 +1,1275 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anchor_lang::{
+    __private::bytemuck::{bytes_of, Pod, Zeroable},
+    prelude::Pubkey,
+    AnchorDeserialize, AnchorSerialize, Discriminator,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use fixed::types::I80F48;
+use futures::{SinkExt, StreamExt};
+use marginfi::state::{
+    marginfi_account::MarginfiAccount,
+    marginfi_group::{Bank, WrappedI80F48},
+    price::OracleSetup,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::{
+    client_error::Result as ClientResult,
+    nonblocking::rpc_client::RpcClient,
+    rpc_client::RpcClientConfig,
+    rpc_config::RpcProgramAccountsConfig,
+    rpc_filter::RpcFilterType,
+    rpc_request::{RpcError, RpcRequest},
+    rpc_response::RpcKeyedAccount,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    signature::Keypair,
+    signer::Signer,
+    transaction::{TransactionError, VersionedTransaction},
+};
+use switchboard_v2::{AggregatorResolutionMode, SwitchboardDecimal};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    task::JoinHandle,
+    time::{sleep, timeout, Instant},
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+use crate::{
+    addresses::{MeteoraPoolConfig, StaticAddresses},
+    config::CommitmentPolicy,
+    connection, constants,
+    ledger::{self, Ledger},
+    risk::RiskGuard,
+    shutdown::Shutdown,
+    state::{
+        CurrentSlot, MeteoraFarmPool, MeteoraFarmUser, OraclesState, PythPriceFeed, StateUpdate,
+        SwitchboardPriceFeed,
+    },
+    utils::{
+        account_fetcher::AccountFetcher,
+        notifier::Notifier,
+        transaction::{SendConfig, SendRpcClients},
+        websocket_client::{create_persisted_websocket_connection, WebsocketClient},
+    },
+    Error, Wallet,
+};
+
+pub const MOCK_SLOT: u64 = 200_000_000;
+/// The current block height is always below it, so transactions never expire
+const MOCK_LAST_VALID_BLOCK_HEIGHT: u64 = 180_000_150;
+
+/// In the format of `solana account --output json`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Fixtures {
+    One(RpcKeyedAccount),
+    Many(Vec<RpcKeyedAccount>),
+}
+
+#[derive(Default)]
+pub struct MockFetcher {
+    pub accounts: Mutex<HashMap<Pubkey, Account>>,
+    multiple_accounts_requests: Mutex<Vec<Vec<Pubkey>>>,
+}
+
+impl MockFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, pubkey: Pubkey, account: Account) {
+        self.accounts.lock().unwrap().insert(pubkey, account);
+    }
+
+    pub fn multiple_accounts_requests(&self) -> Vec<Vec<Pubkey>> {
+        self.multiple_accounts_requests.lock().unwrap().clone()
+    }
+
+    /// Accounts of later files replace earlier ones, returns the number of loaded accounts
+    pub fn load_fixtures(&self, dir: &Path) -> Result<usize, String> {
+        let mut paths = fs::read_dir(dir)
+            .map_err(|e| format!("Could not read fixtures {}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut loaded = 0;
+        for path in paths {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read fixture {}: {}", path.display(), e))?;
+            let fixtures = match serde_json::from_str(&contents)
+                .map_err(|e| format!("Could not parse fixture {}: {}", path.display(), e))?
+            {
+                Fixtures::One(account) => vec![account],
+                Fixtures::Many(accounts) => accounts,
+            };
+
+            for keyed in fixtures {
+                let pubkey = Pubkey::from_str(&keyed.pubkey).map_err(|e| {
+                    format!(
+                        "Invalid pubkey {} in {}: {}",
+                        keyed.pubkey,
+                        path.display(),
+                        e
+                    )
+                })?;
+                let account = keyed.account.decode::<Account>().ok_or_else(|| {
+                    format!("Could not decode account {} in {}", pubkey, path.display())
+                })?;
+                self.insert(pubkey, account);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+fn matches_filters(account: &Account, filters: &Option<Vec<RpcFilterType>>) -> bool {
+    let Some(filters) = filters else {
+        return true;
+    };
+
+    filters.iter().all(|filter| match filter {
+        RpcFilterType::DataSize(size) => account.data.len() as u64 == *size,
+        RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(&account.data),
+        _ => true,
+    })
+}
+
+#[async_trait]
+impl AccountFetcher for MockFetcher {
+    async fn get_account(&self, pubkey: &Pubkey) -> Result<Account, Error> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(pubkey)
+            .cloned()
+            .ok_or(Error::UnableToFetchAccount(*pubkey))
+    }
+
+    async fn get_multiple_accounts(
+        &self,
+        pubkeys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, Error> {
+        self.multiple_accounts_requests
+            .lock()
+            .unwrap()
+            .push(pubkeys.to_vec());
+        let accounts = self.accounts.lock().unwrap();
+        Ok(pubkeys
+            .iter()
+            .map(|pubkey| accounts.get(pubkey).cloned())
+            .collect())
+    }
+
+    async fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, Error> {
+        let accounts = self.accounts.lock().unwrap();
+        // Filters match the whole data, the slice is taken from the matched accounts
+        let data_slice = config.account_config.data_slice;
+        Ok(accounts
+            .iter()
+            .filter(|(_, account)| {
+                &account.owner == program_id && matches_filters(account, &config.filters)
+            })
+            .map(|(pubkey, account)| {
+                let mut account = account.clone();
+                if let Some(slice) = data_slice {
+                    account.data = account
+                        .data
+                        .into_iter()
+                        .skip(slice.offset)
+                        .take(slice.length)
+                        .collect();
+                }
+                (*pubkey, account)
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockOutcome {
+    pub result: Result<(), TransactionError>,
+    /// By mint, reported in the transaction meta
+    pub token_changes: Vec<(Pubkey, i128)>,
+    /// Written only when the transaction succeeds
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+impl Default for MockOutcome {
+    fn default() -> Self {
+        Self {
+            result: Ok(()),
+            token_changes: vec![],
+            accounts: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentTransaction {
+    pub transaction: VersionedTransaction,
+    pub result: Result<(), TransactionError>,
+    pub token_changes: Vec<(Pubkey, i128)>,
+}
+
+impl SentTransaction {
+    /// Account keys can come from lookup tables, so flows are asserted on program and data
+    pub fn instructions(&self) -> Vec<(Pubkey, Vec<u8>)> {
+        let keys = self.transaction.message.static_account_keys();
+        self.transaction
+            .message
+            .instructions()
+            .iter()
+            .map(|ix| (keys[ix.program_id_index as usize], ix.data.clone()))
+            .collect()
+    }
+}
+
+/// Sent transactions land right away with the next scripted outcome, or successfully once
+/// the script is used up. Programs are not executed, a landed transaction only writes the
+/// accounts of its outcome, so tests script what the next flow step should observe.
+#[derive(Default)]
+pub struct MockRpc {
+    pub accounts: Arc<MockFetcher>,
+    outcomes: Mutex<VecDeque<MockOutcome>>,
+    sent: Mutex<Vec<SentTransaction>>,
+}
+
+impl MockRpc {
+    pub fn new(accounts: Arc<MockFetcher>) -> Self {
+        Self {
+            accounts,
+            outcomes: Default::default(),
+            sent: Default::default(),
+        }
+    }
+
+    pub fn push_result(&self, result: Result<(), TransactionError>) {
+        self.push_outcome(MockOutcome {
+            result,
+            ..Default::default()
+        });
+    }
+
+    pub fn push_outcome(&self, outcome: MockOutcome) {
+        self.outcomes.lock().unwrap().push_back(outcome);
+    }
+
+    pub fn sent_transactions(&self) -> Vec<SentTransaction> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn sent_instructions(&self) -> Vec<(Pubkey, Vec<u8>)> {
+        self.sent_transactions()
+            .iter()
+            .flat_map(|tx| tx.instructions())
+            .collect()
+    }
+
+    fn get_result(&self, signature: &str) -> Option<Result<(), TransactionError>> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|tx| tx.transaction.signatures[0].to_string() == signature)
+            .map(|tx| tx.result.clone())
+    }
+
+    fn encode_account(&self, pubkey: &Pubkey) -> Value {
+        match self.accounts.accounts.lock().unwrap().get(pubkey) {
+            Some(account) => json!(UiAccount::encode(
+                pubkey,
+                account,
+                UiAccountEncoding::Base64,
+                None,
+                None
+            )),
+            None => Value::Null,
+        }
+    }
+
+    fn receive_transaction(&self, params: &Value) -> Result<String, String> {
+        let encoded = params[0].as_str().ok_or("missing transaction")?;
+        let bytes = match params[1]["encoding"].as_str() {
+            Some("base58") => bs58::decode(encoded)
+                .into_vec()
+                .map_err(|e| e.to_string())?,
+            _ => general_purpose::STANDARD
+                .decode(encoded)
+                .map_err(|e| e.to_string())?,
+        };
+        let transaction: VersionedTransaction =
+            bincode::deserialize(&bytes).map_err(|e| e.to_string())?;
+        let signature = transaction.signatures[0].to_string();
+        let outcome = self
+            .outcomes
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_default();
+
+        if outcome.result.is_ok() {
+            for (pubkey, account) in outcome.accounts {
+                self.accounts.insert(pubkey, account);
+            }
+        }
+        self.sent.lock().unwrap().push(SentTransaction {
+            transaction,
+            result: outcome.result,
+            token_changes: outcome.token_changes,
+        });
+        Ok(signature)
+    }
+
+    fn encode_token_balances(tx: &SentTransaction) -> (Vec<Value>, Vec<Value>) {
+        let owner = tx.transaction.message.static_account_keys()[0].to_string();
+        tx.token_changes
+            .iter()
+            .enumerate()
+            .map(|(account_index, (mint, change))| {
+                let balance = |amount: i128| {
+                    json!({
+                        "accountIndex": account_index,
+                        "mint": mint.to_string(),
+                        "owner": owner,
+                        "programId": constants::spl_token::id().to_string(),
+                        "uiTokenAmount": {
+                            "amount": amount.to_string(),
+                            "decimals": 0,
+                            "uiAmount": amount as f64,
+                            "uiAmountString": amount.to_string(),
+                        },
+                    })
+                };
+                let pre_amount = (-change).max(0);
+                (balance(pre_amount), balance(pre_amount + change))
+            })
+            .unzip()
+    }
+
+    fn respond(&self, request: RpcRequest, params: Value) -> Result<Value, String> {
+        let context = json!({ "slot": MOCK_SLOT });
+        let parse_pubkey = |param: &Value| {
+            param
+                .as_str()
+                .and_then(|pubkey| Pubkey::from_str(pubkey).ok())
+                .ok_or_else(|| format!("invalid pubkey {}", param))
+        };
+
+        match request {
+            RpcRequest::GetAccountInfo => Ok(json!({
+                "context": context,
+                "value": self.encode_account(&parse_pubkey(&params[0])?),
+            })),
+            RpcRequest::GetMultipleAccounts => {
+                let accounts = params[0]
+                    .as_array()
+                    .ok_or("missing pubkeys")?
+                    .iter()
+                    .map(|pubkey| Ok(self.encode_account(&parse_pubkey(pubkey)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(json!({ "context": context, "value": accounts }))
+            }
+            RpcRequest::GetBalance => {
+                let pubkey = parse_pubkey(&params[0])?;
+                let lamports = self
+                    .accounts
+                    .accounts
+                    .lock()
+                    .unwrap()
+                    .get(&pubkey)
+                    .map_or(0, |account| account.lamports);
+                Ok(json!({ "context": context, "value": lamports }))
+            }
+            // The client picks encodings and commitments by the node version
+            RpcRequest::GetVersion => Ok(json!({ "solana-core": "1.14.13", "feature-set": 0 })),
+            RpcRequest::GetSlot => Ok(json!(MOCK_SLOT)),
+            RpcRequest::GetBlockHeight => Ok(json!(MOCK_LAST_VALID_BLOCK_HEIGHT - 150)),
+            RpcRequest::GetLatestBlockhash => Ok(json!({
+                "context": context,
+                "value": {
+                    "blockhash": Hash::new_unique().to_string(),
+                    "lastValidBlockHeight": MOCK_LAST_VALID_BLOCK_HEIGHT,
+                },
+            })),
+            // No fees were paid for the accounts recently
+            RpcRequest::Custom {
+                method: "getRecentPrioritizationFees",
+            } => Ok(json!([])),
+            RpcRequest::SimulateTransaction => Ok(json!({
+                "context": context,
+                "value": {
+                    "err": null,
+                    "logs": [],
+                    "accounts": null,
+                    "unitsConsumed": 100_000,
+                    "returnData": null,
+                },
+            })),
+            RpcRequest::SendTransaction => Ok(json!(self.receive_transaction(&params)?)),
+            RpcRequest::GetSignatureStatuses => {
+                let statuses = params[0]
+                    .as_array()
+                    .ok_or("missing signatures")?
+                    .iter()
+                    .map(|signature| {
+                        let result = self.get_result(signature.as_str().unwrap_or_default())?;
+                        Some(json!({
+                            "slot": MOCK_SLOT,
+                            "confirmations": null,
+                            "err": result.clone().err(),
+                            "status": result,
+                            "confirmationStatus": "finalized",
+                        }))
+                    })
+                    .collect::<Vec<_>>();
+                Ok(json!({ "context": context, "value": statuses }))
+            }
+            RpcRequest::GetTransaction => {
+                let signature = params[0].as_str().unwrap_or_default();
+                let sent = self.sent.lock().unwrap();
+                let Some(tx) = sent
+                    .iter()
+                    .find(|tx| tx.transaction.signatures[0].to_string() == signature)
+                else {
+                    return Ok(Value::Null);
+                };
+                let encoded = general_purpose::STANDARD
+                    .encode(bincode::serialize(&tx.transaction).map_err(|e| e.to_string())?);
+                let (pre_token_balances, post_token_balances) = Self::encode_token_balances(tx);
+                Ok(json!({
+                    "slot": MOCK_SLOT,
+                    "blockTime": null,
+                    "version": 0,
+                    "transaction": [encoded, "base64"],
+                    "meta": {
+                        "err": tx.result.clone().err(),
+                        "status": tx.result,
+                        "fee": 5000,
+                        "preBalances": [],
+                        "postBalances": [],
+                        "innerInstructions": [],
+                        "logMessages": [],
+                        "preTokenBalances": pre_token_balances,
+                        "postTokenBalances": post_token_balances,
+                        "computeUnitsConsumed": 100_000,
+                    },
+                }))
+            }
+            request => Err(format!("{} is not supported by the mock RPC", request)),
+        }
+    }
+}
+
+struct MockSender(Arc<MockRpc>);
+
+#[async_trait]
+impl RpcSender for MockSender {
+    async fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        self.0
+            .respond(request, params)
+            .map_err(|e| RpcError::RpcRequestError(e).into())
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        RpcTransportStats::default()
+    }
+
+    fn url(&self) -> String {
+        "mock".to_string()
+    }
+}
+
+pub fn new_mock_rpc_client(rpc: Arc<MockRpc>) -> RpcClient {
+    RpcClient::new_sender(
+        MockSender(rpc),
+        RpcClientConfig::with_commitment(CommitmentConfig::confirmed()),
+    )
+}
+
+/// Header names are lowercase
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Answers every request with `200 OK` and the same body, one request per connection
+pub struct MockHttpServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockHttpServer {
+    pub async fn spawn(response_body: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response_body.len(),
+            response_body
+        );
+
+        let received = requests.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let received = received.clone();
+                let response = response.clone();
+                tokio::spawn(async move {
+                    // Recorded before the response, so the request is seen once the
+                    // client returns
+                    if let Some(request) = read_http_request(&mut stream).await {
+                        received.lock().unwrap().push(request);
+                    }
+                    stream.write_all(response.as_bytes()).await.ok();
+                    stream.shutdown().await.ok();
+                });
+            }
+        });
+
+        Self {
+            url,
+            requests,
+            accept_task,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub async fn wait_for_requests(&self, count: usize) -> Vec<HttpRequest> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.requests.lock().unwrap().len() < count {
+            assert!(Instant::now() < deadline, "{} requests not received", count);
+            sleep(Duration::from_millis(5)).await;
+        }
+        self.requests()
+    }
+}
+
+impl Drop for MockHttpServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+async fn read_http_request(stream: &mut TcpStream) -> Option<HttpRequest> {
+    let mut data = vec![];
+    let mut buf = [0; 4096];
+    let head_len = loop {
+        let read = stream.read(&mut buf).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..read]);
+        if let Some(i) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_len]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_lowercase(), value.trim().to_string()))
+        .collect::<Vec<_>>();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while data.len() < head_len + content_length {
+        let read = stream.read(&mut buf).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..read]);
+    }
+    Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&data[head_len..]).to_string(),
+    })
+}
+
+/// Websocket RPC which confirms every subscription and unsubscription it receives
+pub struct MockWsServer {
+    pub url: String,
+    connections: Arc<AtomicUsize>,
+    pub requests: mpsc::UnboundedReceiver<Value>,
+    pub outgoing: mpsc::UnboundedSender<Value>,
+    accept_task: JoinHandle<()>,
+    close: Shutdown,
+    /// Closes the connections accepted so far, later ones stay open
+    disconnect: broadcast::Sender<()>,
+}
+
+impl MockWsServer {
+    /// The first `silent_connections` are accepted but never read from, so their pings
+    /// are not answered
+    pub async fn spawn(silent_connections: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (request_sender, requests) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel::<Value>();
+        let outgoing_receiver = Arc::new(AsyncMutex::new(outgoing_receiver));
+        let close = Shutdown::new();
+        let (disconnect, _) = broadcast::channel(1);
+
+        let accepted = connections.clone();
+        let closed = close.clone();
+        let disconnects = disconnect.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut ws) = accept_async(stream).await else {
+                    continue;
+                };
+                let index = accepted.fetch_add(1, Ordering::Relaxed);
+                let request_sender = request_sender.clone();
+                let outgoing_receiver = outgoing_receiver.clone();
+                let closed = closed.clone();
+                let mut disconnected = disconnects.subscribe();
+
+                tokio::spawn(async move {
+                    if index < silent_connections {
+                        closed.triggered().await;
+                        return;
+                    }
+
+                    let mut outgoing_receiver = outgoing_receiver.lock().await;
+                    loop {
+                        tokio::select! {
+                            _ = closed.triggered() => {
+                                ws.close(None).await.ok();
+                                return;
+                            }
+                            _ = disconnected.recv() => {
+                                ws.close(None).await.ok();
+                                return;
+                            }
+                            Some(message) = outgoing_receiver.recv() => {
+                                ws.send(Message::Text(message.to_string())).await.unwrap();
+                            }
+                            message = ws.next() => {
+                                let text = match message {
+                                    Some(Ok(Message::Text(text))) => text,
+                                    Some(Ok(_)) => continue,
+                                    _ => return,
+                                };
+                                let request: Value = serde_json::from_str(&text).unwrap();
+                                let id = request["id"].as_u64().unwrap();
+                                // Subscription ids differ from the request ids
+                                let result = if request["method"]
+                                    .as_str()
+                                    .unwrap()
+                                    .ends_with("Unsubscribe")
+                                {
+                                    json!(true)
+                                } else {
+                                    json!(id + 100)
+                                };
+                                let response = json!({ "jsonrpc": "2.0", "result": result, "id": id });
+                                ws.send(Message::Text(response.to_string())).await.unwrap();
+                                request_sender.send(request).ok();
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Self {
+            url,
+            connections,
+            requests,
+            outgoing,
+            accept_task,
+            close,
+            disconnect,
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub async fn next_request(&mut self) -> Value {
+        timeout(Duration::from_secs(5), self.requests.recv())
+            .await
+            .expect("no request received")
+            .unwrap()
+    }
+
+    /// Requests of other methods are skipped
+    pub async fn next_requests_of(&mut self, method: &str, count: usize) -> Vec<Value> {
+        let mut requests = vec![];
+        while requests.len() < count {
+            let request = self.next_request().await;
+            if request["method"] == method {
+                requests.push(request);
+            }
+        }
+        requests
+    }
+
+    pub fn disconnect(&self) {
+        self.disconnect.send(()).ok();
+    }
+
+    pub async fn stop(&mut self) {
+        self.accept_task.abort();
+        (&mut self.accept_task).await.ok();
+        self.close.trigger();
+    }
+}
+
+pub async fn connect_mock_ws(
+    urls: Vec<String>,
+    liveness_timeout: Option<Duration>,
+) -> (Arc<WebsocketClient>, Shutdown) {
+    let client = Arc::new(WebsocketClient::new(
+        urls,
+        CommitmentConfig::confirmed(),
+        liveness_timeout,
+    ));
+    let shutdown = Shutdown::new();
+    create_persisted_websocket_connection(client.clone(), shutdown.clone()).unwrap();
+
+    timeout(Duration::from_secs(5), async {
+        while !client.is_connected().await {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("not connected");
+    (client, shutdown)
+}
+
+/// Stands in for the oracle subscriptions, prices pushed through it reach the oracles
+/// state through the same channel as subscription notifications
+pub struct OracleFeed {
+    sender: UnboundedSender<StateUpdate>,
+    current_slot: Arc<CurrentSlot>,
+}
+
+impl OracleFeed {
+    pub fn new(sender: UnboundedSender<StateUpdate>, current_slot: Arc<CurrentSlot>) -> Self {
+        current_slot.update(MOCK_SLOT);
+        Self {
+            sender,
+            current_slot,
+        }
+    }
+
+    fn get_slot(&self) -> u64 {
+        self.current_slot.get().unwrap_or(MOCK_SLOT)
+    }
+
+    /// Moves the cluster forward, prices pushed before age with it
+    pub fn advance_slot(&self, slots: u64) {
+        self.current_slot.update(self.get_slot() + slots);
+    }
+
+    pub fn push_pyth_price(&self, address: Pubkey, price: i64, conf: u64, expo: i32) {
+        let price_feed = PythPriceFeed {
+            last_update_slot: self.get_slot(),
+            price: pyth_sdk_solana::Price {
+                price,
+                conf,
+                expo,
+                publish_time: ledger::now() as i64,
+            },
+        };
+        self.sender
+            .send(StateUpdate::PythOracle((address, price_feed)))
+            .unwrap();
+    }
+
+    /// Result of `mantissa * 10^-scale`
+    pub fn push_switchboard_price(&self, address: Pubkey, mantissa: i128, scale: u32) {
+        let price_feed = SwitchboardPriceFeed {
+            last_update_slot: self.get_slot(),
+            last_update_ts: ledger::now() as i64,
+            resolution_mode: AggregatorResolutionMode::ModeRoundResolution,
+            latest_confirmed_round_result: SwitchboardDecimal { mantissa, scale },
+            latest_confirmed_round_num_success: 1,
+            latest_confirmed_round_std_deviation: SwitchboardDecimal {
+                mantissa: 0,
+                scale: 0,
+            },
+            min_oracle_results: 1,
+        };
+        self.sender
+            .send(StateUpdate::SwitchboardOracle((address, price_feed)))
+            .unwrap();
+    }
+}
+
+pub fn new_wallet() -> Arc<Wallet> {
+    let keypair = Keypair::new();
+    Arc::new(Wallet {
+        pubkey: keypair.pubkey(),
+        keypair,
+    })
+}
+
+/// Send config of the mock RPC, transactions are sent once without simulations, compute
+/// budget estimates or memos and confirmed by polling, the ledger is kept in memory
+pub fn new_send_config() -> SendConfig {
+    SendConfig {
+        dry_run: false,
+        max_retries: 0,
+        poll_interval: Duration::from_millis(1),
+        max_confirmation_wait: Duration::from_secs(1),
+        commitment_policy: CommitmentPolicy::default(),
+        cu_limit: 400_000,
+        cu_price_micro_lamports: 0,
+        priority_fee: None,
+        simulate_cu_limit: false,
+        cu_limit_margin_percent: 0,
+        cu_limits: Default::default(),
+        notifier: Arc::new(Notifier::disabled()),
+        ledger: Arc::new(Ledger::new(
+            std::env::temp_dir().join("mmf-testkit-ledger.json"),
+            false,
+        )),
+        risk_guard: Arc::new(RiskGuard::default()),
+        shutdown: Shutdown::new(),
+        simulate_before_send: false,
+        jito: None,
+        memo: false,
+        nonce_account: None,
+        send_rpc_clients: SendRpcClients::default(),
+        preflight: false,
+        preflight_commitment: None,
+        rpc_max_retries: None,
+        ws_client: None,
+    }
+}
+
+/// Oracle prices of the position fixture, in USD scaled by `10^PRICE_EXPO`
+pub const PRICE_EXPO: i32 = -8;
+pub const COLLATERAL_PRICE: i64 = 20_00000000;
+pub const STABLE_PRICE: i64 = 1_00000000;
+/// Native units every bank can lend
+pub const BANK_LIQUIDITY: u64 = 1_000_000_000_000_000;
+/// USDC backing the pool, one LP token is worth one native unit of USDC
+pub const POOL_LIQUIDITY: u64 = 1_000_000_000_000;
+
+pub fn new_account(owner: Pubkey, data: Vec<u8>) -> Account {
+    Account {
+        lamports: 1_000_000_000,
+        data,
+        owner,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+pub fn zero_copy_account<T: Discriminator + Pod>(owner: Pubkey, value: &T) -> Account {
+    new_account(owner, [&T::DISCRIMINATOR[..], bytes_of(value)].concat())
+}
+
+pub fn borsh_account<T: Discriminator + AnchorSerialize>(owner: Pubkey, value: &T) -> Account {
+    new_account(
+        owner,
+        [T::DISCRIMINATOR.to_vec(), value.try_to_vec().unwrap()].concat(),
+    )
+}
+
+/// For accounts of which the bot reads only a few fields
+pub fn zeroed_borsh<T: AnchorDeserialize>() -> T {
+    T::deserialize(&mut &[0u8; 10_240][..]).unwrap()
+}
+
+pub fn token_account(mint: &Pubkey, owner: &Pubkey, amount: u64) -> Account {
+    let mut data = vec![0; 165];
+    data[..32].copy_from_slice(mint.as_ref());
+    data[32..64].copy_from_slice(owner.as_ref());
+    data[64..72].copy_from_slice(&amount.to_le_bytes());
+    // Initialized
+    data[108] = 1;
+    new_account(constants::spl_token::id(), data)
+}
+
+pub fn mint_account(supply: u64, decimals: u8) -> Account {
+    let mut data = vec![0; 82];
+    data[36..44].copy_from_slice(&supply.to_le_bytes());
+    data[44] = decimals;
+    // Initialized
+    data[45] = 1;
+    new_account(constants::spl_token::id(), data)
+}
+
+/// A 56 byte meta followed by the addresses
+pub fn lookup_table_account(addresses: &[Pubkey]) -> Account {
+    let mut data = vec![0; 56];
+    // Lookup table variant of the program state
+    data[..4].copy_from_slice(&1_u32.to_le_bytes());
+    // Never deactivated
+    data[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+    for address in addresses {
+        data.extend_from_slice(address.as_ref());
+    }
+    new_account(solana_address_lookup_table_program::id(), data)
+}
+
+/// Bank of the marginfi group priced by the Pyth oracle at `oracle`, a share is worth
+/// one native unit, all weights are one and the bank can lend `BANK_LIQUIDITY`
+pub fn new_bank(address: &Pubkey, mint: Pubkey, mint_decimals: u8, oracle: Pubkey) -> Bank {
+    let one = WrappedI80F48 {
+        value: I80F48::ONE.to_bits(),
+    };
+    let derive = |seed: &str| {
+        Pubkey::find_program_address(&[seed.as_bytes(), address.as_ref()], &marginfi::id()).0
+    };
+
+    let mut bank = Bank::zeroed();
+    bank.group = constants::marginfi::group::id();
+    bank.mint = mint;
+    bank.mint_decimals = mint_decimals;
+    bank.liquidity_vault = derive(marginfi::constants::LIQUIDITY_VAULT_SEED);
+    bank.insurance_vault = derive(marginfi::constants::INSURANCE_VAULT_SEED);
+    bank.fee_vault = derive(marginfi::constants::FEE_VAULT_SEED);
+    bank.asset_share_value = one;
+    bank.liability_share_value = one;
+    bank.total_asset_shares = WrappedI80F48 {
+        value: I80F48::from_num(BANK_LIQUIDITY).to_bits(),
+    };
+    bank.config.oracle_setup = OracleSetup::PythEma;
+    bank.config.oracle_keys[0] = oracle;
+    bank.config.asset_weight_init = one;
+    bank.config.asset_weight_maint = one;
+    bank.config.liability_weight_init = one;
+    bank.config.liability_weight_maint = one;
+    bank.config.interest_rate_config.optimal_utilization_rate = WrappedI80F48 {
+        value: I80F48::from_num(0.8).to_bits(),
+    };
+    bank.config.interest_rate_config.plateau_interest_rate = WrappedI80F48 {
+        value: I80F48::from_num(0.1).to_bits(),
+    };
+    bank.config.interest_rate_config.max_interest_rate = one;
+    bank.config.borrow_limit = u64::MAX;
+    bank
+}
+
+/// Balances as (bank, asset shares, liability shares)
+pub fn new_marginfi_account(
+    authority: &Pubkey,
+    balances: &[(Pubkey, u64, u64)],
+) -> MarginfiAccount {
+    let mut account = MarginfiAccount::zeroed();
+    account.group = constants::marginfi::group::id();
+    account.authority = *authority;
+    for (balance, (bank, asset_shares, liability_shares)) in
+        account.lending_account.balances.iter_mut().zip(balances)
+    {
+        balance.active = true;
+        balance.bank_pk = *bank;
+        balance.asset_shares = WrappedI80F48 {
+            value: I80F48::from_num(*asset_shares).to_bits(),
+        };
+        balance.liability_shares = WrappedI80F48 {
+            value: I80F48::from_num(*liability_shares).to_bits(),
+        };
+    }
+    account
+}
+
+/// No confidence interval, published at the mock slot
+pub fn new_pyth_price_feed(price: i64, expo: i32) -> PythPriceFeed {
+    PythPriceFeed {
+        last_update_slot: MOCK_SLOT,
+        price: pyth_sdk_solana::Price {
+            price,
+            conf: 0,
+            expo,
+            publish_time: ledger::now() as i64,
+        },
+    }
+}
+
+pub fn write_fixtures(
+    dir: &Path,
+    name: &str,
+    accounts: &[(Pubkey, Account)],
+) -> Result<(), String> {
+    let fixtures = accounts
+        .iter()
+        .map(|(pubkey, account)| RpcKeyedAccount {
+            pubkey: pubkey.to_string(),
+            account: UiAccount::encode(pubkey, account, UiAccountEncoding::Base64, None, None),
+        })
+        .collect::<Vec<_>>();
+    let path = dir.join(format!("{}.json", name));
+    let contents = serde_json::to_string_pretty(&fixtures).map_err(|e| e.to_string())?;
+    fs::write(&path, contents)
+        .map_err(|e| format!("Could not write fixture {}: {}", path.display(), e))
+}
+
+/// Banks are stored at the addresses of the static bank list, which is what the bot
+/// falls back to as the mock RPC does not enumerate program accounts. The pool holds
+/// USDC only, so withdrawals return USDC alone and need no swap.
+pub struct PositionFixture {
+    pub wallet: Arc<Wallet>,
+    pub marginfi_account: Pubkey,
+    pub pool: Pubkey,
+    pub farm: Pubkey,
+    pub oracles: Vec<(Pubkey, Pubkey)>,
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+impl Default for PositionFixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PositionFixture {
+    /// Position which is not entered yet, the marginfi account has no balances and the
+    /// wallet holds no tokens
+    pub fn new() -> Self {
+        let wallet = new_wallet();
+        let mut accounts = vec![];
+        let mut oracles = vec![];
+
+        for (bank_address, mint, decimals) in [
+            (
+                constants::marginfi::banks::bsol::id(),
+                constants::mints::bsol::id(),
+                9,
+            ),
+            (
+                constants::marginfi::banks::usdc::id(),
+                constants::mints::usdc::id(),
+                6,
+            ),
+            (
+                constants::marginfi::banks::uxd::id(),
+                constants::mints::uxd::id(),
+                6,
+            ),
+            (
+                constants::marginfi::banks::usdt::id(),
+                constants::mints::usdt::id(),
+                6,
+            ),
+        ] {
+            let oracle = Pubkey::new_unique();
+            let bank = new_bank(&bank_address, mint, decimals, oracle);
+            accounts.push((bank_address, zero_copy_account(marginfi::id(), &bank)));
+            accounts.push((mint, mint_account(0, decimals)));
+            oracles.push((mint, oracle));
+        }
+
+        let pool = Pubkey::new_unique();
+        let mut pool_state: meteora::state::Pool = zeroed_borsh();
+        pool_state.lp_mint = Pubkey::new_unique();
+        pool_state.token_a_mint = constants::mints::usdc::id();
+        pool_state.token_b_mint = constants::mints::usdt::id();
+        pool_state.a_vault = Pubkey::new_unique();
+        pool_state.b_vault = Pubkey::new_unique();
+        pool_state.a_vault_lp = Pubkey::new_unique();
+        pool_state.b_vault_lp = Pubkey::new_unique();
+        accounts.push((pool, borsh_account(meteora::id(), &pool_state)));
+        accounts.push((pool_state.lp_mint, mint_account(POOL_LIQUIDITY, 6)));
+
+        for (vault_address, vault_lp, amount) in [
+            (pool_state.a_vault, pool_state.a_vault_lp, POOL_LIQUIDITY),
+            (pool_state.b_vault, pool_state.b_vault_lp, 0),
+        ] {
+            let mut vault: meteora_vault::state::Vault = zeroed_borsh();
+            vault.token_vault = Pubkey::new_unique();
+            vault.lp_mint = Pubkey::new_unique();
+            vault.total_amount = amount;
+            accounts.push((vault_address, borsh_account(meteora_vault::id(), &vault)));
+            accounts.push((vault.lp_mint, mint_account(amount, 6)));
+            accounts.push((vault_lp, token_account(&vault.lp_mint, &pool, amount)));
+        }
+
+        let farm = Pubkey::new_unique();
+        let farm_pool = MeteoraFarmPool {
+            staking_mint: pool_state.lp_mint,
+            staking_vault: Pubkey::new_unique(),
+            reward_a_mint: Pubkey::new_unique(),
+            reward_a_vault: Pubkey::new_unique(),
+            reward_b_mint: Pubkey::new_unique(),
+            reward_b_vault: Pubkey::new_unique(),
+            reward_duration_end: ledger::now() + 86_400,
+            ..Default::default()
+        };
+        accounts.push((
+            farm,
+            borsh_account(constants::meteora::farm::id(), &farm_pool),
+        ));
+
+        let marginfi_account = Pubkey::new_unique();
+        let mut fixture = Self {
+            wallet,
+            marginfi_account,
+            pool,
+            farm,
+            oracles,
+            accounts,
+        };
+        fixture
+            .accounts
+            .push(fixture.marginfi_account_with_balances(&[]));
+        fixture
+    }
+
+    /// Balances as (bank, asset shares, liability shares)
+    pub fn marginfi_account_with_balances(
+        &self,
+        balances: &[(Pubkey, u64, u64)],
+    ) -> (Pubkey, Account) {
+        let account = new_marginfi_account(&self.wallet.pubkey, balances);
+        (
+            self.marginfi_account,
+            zero_copy_account(marginfi::id(), &account),
+        )
+    }
+
+    pub fn farm_user_with_stake(
+        &self,
+        static_addresses: &StaticAddresses,
+        staked: u64,
+    ) -> (Pubkey, Account) {
+        let (pool_id, _) = static_addresses
+            .get_meteora_pool_by_address(&self.pool)
+            .unwrap();
+        let farm = static_addresses
+            .get_meteora_farm(pool_id, Some(&self.farm))
+            .unwrap();
+        let farm_user = MeteoraFarmUser {
+            pool: self.farm,
+            owner: self.wallet.pubkey,
+            balance_staked: staked,
+            ..Default::default()
+        };
+        (
+            farm.user_account,
+            borsh_account(constants::meteora::farm::id(), &farm_user),
+        )
+    }
+
+    pub fn wallet_token_account(
+        &self,
+        static_addresses: &StaticAddresses,
+        mint: &Pubkey,
+        amount: u64,
+    ) -> (Pubkey, Account) {
+        (
+            static_addresses.get_token_account(mint).unwrap(),
+            token_account(mint, &self.wallet.pubkey, amount),
+        )
+    }
+
+    pub async fn load_static_addresses<F: AccountFetcher>(
+        &self,
+        rpc_client: &F,
+    ) -> Result<StaticAddresses, Error> {
+        let mints = self
+            .oracles
+            .iter()
+            .map(|(mint, _)| *mint)
+            .collect::<Vec<_>>();
+        let banks = connection::fetch_marginfi_banks(rpc_client, &mints, &[]).await?;
+        let pools_and_vaults =
+            connection::fetch_meteora_pools_and_vaults(rpc_client, &[self.pool]).await?;
+        let farms = connection::fetch_meteora_farms(rpc_client, &[self.farm]).await?;
+        let pool_configs = [MeteoraPoolConfig {
+            pool: self.pool,
+            farms: vec![self.farm],
+            weight: 1,
+        }];
+
+        StaticAddresses::new(&self.wallet, HashMap::new())
+            .set_collateral_mint(&self.wallet, constants::mints::bsol::id())
+            .set_marginfi_account(self.marginfi_account)
+            .set_marginfi_banks(&banks)
+            .set_borrowable_mints(
+                &self.wallet,
+                &vec![constants::mints::usdc::id()],
+                &HashMap::new(),
+                None,
+            )
+            .set_meteora_pools_and_vaults(&self.wallet, &pools_and_vaults, &pool_configs)?
+            .set_meteora_farms(&self.wallet, &pool_configs, &farms)
+    }
+
+    pub async fn new_oracles_state(&self) -> Arc<OraclesState> {
+        let oracles_state = Arc::new(OraclesState::new());
+        oracles_state.current_slot.update(MOCK_SLOT);
+        for (mint, _) in &self.oracles {
+            let price = if mint == &constants::mints::bsol::id() {
+                COLLATERAL_PRICE
+            } else {
+                STABLE_PRICE
+            };
+            self.push_price(&oracles_state, mint, price).await;
+        }
+        oracles_state
+    }
+
+    pub async fn push_price(&self, oracles_state: &OraclesState, mint: &Pubkey, price: i64) {
+        let (_, oracle) = self.oracles.iter().find(|(m, _)| m == mint).unwrap();
+        oracles_state
+            .apply_update(StateUpdate::PythOracle((
+                *oracle,
+                new_pyth_price_feed(price, PRICE_EXPO),
+            )))
+            .await;
+    }
+}
@@ -0,0 +1,705 @@
+use std::{fmt, sync::Arc};
+
+use anchor_lang::prelude::Pubkey;
+use fixed::types::I80F48;
+use marginfi::constants::EXP_10_I80F48;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::address_lookup_table_account::AddressLookupTableAccount;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    addresses::{PoolId, StaticAddresses},
+    bot::{force_send_instructions, swap_exact_in, SWAP_SLIPPAGE_BPS},
+    connection::{self, SwapMode, SwapRouteConfig},
+    instructions::InstructionBuilder,
+    state::{HealthType, MarginfiAccountWithBanks},
+    utils::{
+        jupiter_client::JupiterClient,
+        notifier::NotificationLevel,
+        transaction::{parse_token_received, parse_token_spent, SendConfig},
+    },
+    Error, Wallet,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnwindStep {
+    FarmWithdraw,
+    PoolWithdraw,
+    Swap,
+    Repay,
+    CollateralWithdraw,
+}
+
+impl fmt::Display for UnwindStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self {
+            Self::FarmWithdraw => "farm withdraw",
+            Self::PoolWithdraw => "pool withdraw",
+            Self::Swap => "swap",
+            Self::Repay => "repay",
+            Self::CollateralWithdraw => "collateral withdraw",
+        };
+        f.write_str(step)
+    }
+}
+
+fn at_step(step: UnwindStep) -> impl FnOnce(Error) -> Error {
+    move |error| {
+        error!("Unwind stopped at the {} step: {}", step, error);
+        Error::UnwindFailed {
+            step,
+            error: Box::new(error),
+        }
+    }
+}
+
+pub struct UnwindContext<'a> {
+    pub rpc_client: &'a Arc<RpcClient>,
+    pub wallet: &'a Arc<Wallet>,
+    pub jupiter_client: &'a JupiterClient,
+    pub static_addresses: &'a StaticAddresses,
+    pub instruction_builder: &'a InstructionBuilder,
+    pub lookup_tables: &'a Vec<AddressLookupTableAccount>,
+    pub swap_route_config: &'a SwapRouteConfig,
+    pub best_effort_alts: bool,
+    pub pool_slippage_bps: u16,
+    pub send_config: &'a SendConfig,
+}
+
+pub async fn fetch_account_with_banks(
+    ctx: &UnwindContext<'_>,
+) -> Result<MarginfiAccountWithBanks, Error> {
+    connection::fetch_marginfi_account_with_banks(
+        ctx.rpc_client,
+        ctx.wallet,
+        &ctx.static_addresses.marginfi_account,
+    )
+    .await
+}
+
+/// Largest first
+pub fn get_liabilities(account_with_banks: &MarginfiAccountWithBanks) -> Vec<(Pubkey, u64)> {
+    let mut liabilities = account_with_banks
+        .balances
+        .iter()
+        .filter(|(_, balance)| balance.is_active && balance.liability_shares > I80F48::ZERO)
+        .filter_map(|(mint, balance)| {
+            let (_, bank) = account_with_banks.get_bank_by_mint(mint)?;
+            let (_, liability) =
+                balance.get_amounts(bank.asset_share_value, bank.liability_share_value);
+            Some((*mint, liability.ceil().to_num::<u64>()))
+        })
+        .collect::<Vec<_>>();
+    liabilities.sort_by(|(_, a), (_, b)| b.cmp(a));
+    liabilities
+}
+
+/// Everything when `max_amount` is `None`, returns the realized amount of LP tokens
+pub async fn withdraw_from_farms(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+    max_amount: Option<u64>,
+) -> Result<u64, Error> {
+    let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+    let mut remaining = max_amount.unwrap_or(u64::MAX);
+    let mut total_amount = 0;
+    let mut instructions = vec![];
+
+    for farm in ctx
+        .static_addresses
+        .get_meteora_farms_by_lp_mint(&pool.lp_mint)
+    {
+        if remaining == 0 {
+            break;
+        }
+
+        let farm_user = connection::fetch_meteora_farm_user(ctx.rpc_client, farm).await?;
+        let amount = farm_user.balance_staked.min(remaining);
+        if amount == 0 {
+            continue;
+        }
+
+        info!("Withdrawing {} LP from farm {}", amount, farm.address);
+        instructions.push(ctx.instruction_builder.meteora_farm_withdraw(
+            ctx.static_addresses,
+            farm,
+            &pool.lp_mint,
+            amount,
+        )?);
+        remaining -= amount;
+        total_amount += amount;
+    }
+
+    if instructions.is_empty() {
+        return Ok(0);
+    }
+
+    let (signature, tx_meta) = force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        instructions,
+        ctx.lookup_tables,
+        ctx.send_config,
+        "farm_withdraw",
+    )
+    .await?;
+    parse_token_received(&tx_meta, ctx.wallet, &pool.lp_mint)?
+        .or(ctx.send_config.dry_run.then_some(total_amount))
+        .ok_or(Error::TokenChangeNotFound {
+            signature,
+            mint: pool.lp_mint,
+        })
+}
+
+pub async fn withdraw_from_pool(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+    lp_amount: u64,
+) -> Result<(u64, u64), Error> {
+    if lp_amount == 0 {
+        return Ok((0, 0));
+    }
+
+    let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+    let pool_state = connection::fetch_meteora_pool_state(ctx.rpc_client, pool).await?;
+    let withdraw = pool.get_withdraw(&pool_state, lp_amount, ctx.pool_slippage_bps)?;
+    info!(
+        "Withdrawing {} LP from pool {}, expected {} {} (min {}) and {} {} (min {})",
+        lp_amount,
+        pool.address,
+        withdraw.expected_a,
+        pool.a_token_mint,
+        withdraw.minimum_a,
+        withdraw.expected_b,
+        pool.b_token_mint,
+        withdraw.minimum_b
+    );
+
+    let ix = ctx.instruction_builder.meteora_pool_withdraw(
+        ctx.static_addresses,
+        pool,
+        withdraw.pool_token_amount,
+        withdraw.minimum_a,
+        withdraw.minimum_b,
+    )?;
+    let (_, tx_meta) = force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        vec![ix],
+        ctx.lookup_tables,
+        ctx.send_config,
+        "pool_withdraw",
+    )
+    .await?;
+
+    ctx.send_config
+        .ledger
+        .record(|l| l.record_lp_burn(&pool.address, withdraw.pool_token_amount));
+
+    // Dry run falls back to the expected amounts when the simulation fails
+    let (fallback_a, fallback_b) = if ctx.send_config.dry_run {
+        (withdraw.expected_a, withdraw.expected_b)
+    } else {
+        (0, 0)
+    };
+    Ok((
+        parse_token_received(&tx_meta, ctx.wallet, &pool.a_token_mint)?.unwrap_or(fallback_a),
+        parse_token_received(&tx_meta, ctx.wallet, &pool.b_token_mint)?.unwrap_or(fallback_b),
+    ))
+}
+
+/// LP tokens already in the wallet are withdrawn too when `max_lp_amount` is `None`
+pub async fn unwind_pool(
+    ctx: &UnwindContext<'_>,
+    pool_id: PoolId,
+    max_lp_amount: Option<u64>,
+    repay_mint: Option<&Pubkey>,
+) -> Result<u64, Error> {
+    let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+    let (lp_mint, a_mint, b_mint) = (pool.lp_mint, pool.a_token_mint, pool.b_token_mint);
+
+    let wallet_lp_amount = match max_lp_amount {
+        Some(_) => 0,
+        None => connection::fetch_token_balances(ctx.rpc_client, ctx.static_addresses, &[lp_mint])
+            .await
+            .map_err(at_step(UnwindStep::FarmWithdraw))?
+            .get(&lp_mint)
+            .copied()
+            .unwrap_or(0),
+    };
+    let farm_lp_amount = withdraw_from_farms(ctx, pool_id, max_lp_amount)
+        .await
+        .map_err(at_step(UnwindStep::FarmWithdraw))?;
+
+    let (a_amount, b_amount) = withdraw_from_pool(ctx, pool_id, wallet_lp_amount + farm_lp_amount)
+        .await
+        .map_err(at_step(UnwindStep::PoolWithdraw))?;
+
+    let Some(repay_mint) = repay_mint else {
+        return Ok(0);
+    };
+
+    let mut repay_amount = 0;
+    for (mint, amount) in [(a_mint, a_amount), (b_mint, b_amount)] {
+        repay_amount += swap_exact_in(
+            ctx.rpc_client,
+            ctx.wallet,
+            ctx.jupiter_client,
+            ctx.swap_route_config,
+            ctx.best_effort_alts,
+            &mint,
+            repay_mint,
+            amount,
+            ctx.send_config,
+        )
+        .await
+        .map_err(at_step(UnwindStep::Swap))?
+        .amount;
+    }
+
+    Ok(repay_amount)
+}
+
+/// The whole liability is repaid with `repay_all` when the wallet holds enough
+pub async fn repay(
+    ctx: &UnwindContext<'_>,
+    mint: &Pubkey,
+    liability: u64,
+    max_amount: Option<u64>,
+) -> Result<u64, Error> {
+    let available =
+        connection::fetch_token_balances(ctx.rpc_client, ctx.static_addresses, &[*mint])
+            .await?
+            .get(mint)
+            .copied()
+            .unwrap_or(0);
+    let amount = available.min(max_amount.unwrap_or(u64::MAX)).min(liability);
+    if amount == 0 {
+        return Err(Error::InsufficientTokenBalance {
+            mint: *mint,
+            required: liability,
+            available,
+        });
+    }
+
+    let repay_all = amount == liability;
+    info!(
+        "Repaying {} {} of {} liability{}",
+        amount,
+        mint,
+        liability,
+        if repay_all { " (all)" } else { "" }
+    );
+    let ix =
+        ctx.instruction_builder
+            .marginfi_repay(ctx.static_addresses, mint, amount, repay_all)?;
+    force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        vec![ix],
+        ctx.lookup_tables,
+        ctx.send_config,
+        "repay",
+    )
+    .await?;
+    ctx.send_config
+        .ledger
+        .record(|l| l.record_repay(mint, amount, repay_all));
+
+    Ok(amount)
+}
+
+/// Used when the borrow was split across several mints and the pools were unwound to one
+async fn swap_for_repay(
+    ctx: &UnwindContext<'_>,
+    repay_mint: &Pubkey,
+    mint: &Pubkey,
+    liability: u64,
+) -> Result<(), Error> {
+    let available =
+        connection::fetch_token_balances(ctx.rpc_client, ctx.static_addresses, &[*mint])
+            .await?
+            .get(mint)
+            .copied()
+            .unwrap_or(0);
+    if available >= liability {
+        return Ok(());
+    }
+
+    // Covers interest accrued until the repay lands
+    let out_amount = liability - available + liability / 10_000 + 1;
+    let quote = connection::fetch_swap_quote(
+        ctx.jupiter_client,
+        repay_mint,
+        mint,
+        out_amount,
+        SwapMode::ExactOut,
+        SWAP_SLIPPAGE_BPS,
+        ctx.swap_route_config,
+    )
+    .await?;
+    info!(
+        "Swapping ~{} {} for {} {} to repay its liability",
+        quote.in_amount, repay_mint, out_amount, mint
+    );
+    let swap = connection::fetch_swap_instructions(
+        ctx.rpc_client,
+        ctx.jupiter_client,
+        ctx.wallet,
+        &quote,
+        ctx.best_effort_alts,
+    )
+    .await?;
+    let (_, tx_meta) = force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        swap.instructions,
+        &swap.lookup_tables,
+        ctx.send_config,
+        "repay_swap",
+    )
+    .await?;
+    let spent = parse_token_spent(&tx_meta, ctx.wallet, repay_mint)?.unwrap_or(quote.in_amount);
+    let received = parse_token_received(&tx_meta, ctx.wallet, mint)?.unwrap_or(out_amount);
+    ctx.send_config
+        .ledger
+        .record(|l| l.record_swap(repay_mint, spent, mint, received));
+
+    Ok(())
+}
+
+/// Every step reads its starting state from chain so a failed unwind can be resumed by
+/// running it again
+#[instrument(name = "unwind", skip_all)]
+pub async fn unwind(ctx: &UnwindContext<'_>) -> Result<(), Error> {
+    ctx.send_config
+        .notifier
+        .notify(NotificationLevel::Warning, "Unwinding the position");
+    let account_with_banks = fetch_account_with_banks(ctx)
+        .await
+        .map_err(at_step(UnwindStep::FarmWithdraw))?;
+    let liabilities = get_liabilities(&account_with_banks);
+    let repay_mint = liabilities.first().map(|(mint, _)| *mint);
+    if liabilities.len() > 1 {
+        info!(
+            "Account has {} liabilities, pool tokens are swapped to {}",
+            liabilities.len(),
+            repay_mint.unwrap()
+        );
+    }
+
+    for pool_id in ctx.static_addresses.meteora_pool_ids() {
+        unwind_pool(ctx, pool_id, None, repay_mint.as_ref()).await?;
+    }
+
+    let account_with_banks = fetch_account_with_banks(ctx)
+        .await
+        .map_err(at_step(UnwindStep::Repay))?;
+    // Smallest first, so the `repay_mint` liability is repaid with what is left
+    for (mint, liability) in get_liabilities(&account_with_banks).into_iter().rev() {
+        if let Some(repay_mint) = repay_mint.filter(|repay_mint| repay_mint != &mint) {
+            swap_for_repay(ctx, &repay_mint, &mint, liability)
+                .await
+                .map_err(at_step(UnwindStep::Swap))?;
+        }
+        let repaid = repay(ctx, &mint, liability, None)
+            .await
+            .map_err(at_step(UnwindStep::Repay))?;
+        if repaid < liability {
+            return Err(at_step(UnwindStep::Repay)(
+                Error::InsufficientTokenBalance {
+                    mint,
+                    required: liability,
+                    available: repaid,
+                },
+            ));
+        }
+    }
+
+    let account_with_banks = fetch_account_with_banks(ctx)
+        .await
+        .map_err(at_step(UnwindStep::CollateralWithdraw))?;
+    let collateral_mint = ctx.static_addresses.collateral_mint;
+    let collateral_amount = match (
+        account_with_banks.get_balance_by_mint(&collateral_mint),
+        account_with_banks.get_bank_by_mint(&collateral_mint),
+    ) {
+        (Some(balance), Some((_, bank))) if balance.is_active => balance
+            .get_amounts(bank.asset_share_value, bank.liability_share_value)
+            .0
+            .to_num::<u64>(),
+        _ => 0,
+    };
+
+    if collateral_amount > 0 {
+        info!(
+            "Withdrawing {} {} collateral",
+            collateral_amount, collateral_mint
+        );
+        let ix = ctx
+            .instruction_builder
+            .marginfi_withdraw(
+                ctx.static_addresses,
+                &collateral_mint,
+                collateral_amount,
+                true,
+                &account_with_banks,
+            )
+            .map_err(at_step(UnwindStep::CollateralWithdraw))?;
+        force_send_instructions(
+            ctx.rpc_client,
+            ctx.wallet,
+            vec![ix],
+            ctx.lookup_tables,
+            ctx.send_config,
+            "unwind:collateral_withdraw",
+        )
+        .await
+        .map_err(at_step(UnwindStep::CollateralWithdraw))?;
+        ctx.send_config
+            .ledger
+            .record(|l| l.record_collateral_withdraw(collateral_amount));
+    }
+
+    info!("Position unwound");
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum WithdrawShare {
+    /// Percent of the staked LP and of every liability
+    Pct(f64),
+    /// Value of the staked LP in USD, both pool tokens valued 1:1
+    AmountUsd(f64),
+}
+
+/// Liabilities which would be left below this many UI units are repaid in full
+const DUST_LIABILITY_UI: f64 = 0.01;
+
+async fn fetch_staked_lp(ctx: &UnwindContext<'_>) -> Result<Vec<(PoolId, u64, f64)>, Error> {
+    let mut staked = vec![];
+
+    for pool_id in ctx.static_addresses.meteora_pool_ids() {
+        let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+        let mut staked_lp = 0;
+        for farm in ctx
+            .static_addresses
+            .get_meteora_farms_by_lp_mint(&pool.lp_mint)
+        {
+            staked_lp += connection::fetch_meteora_farm_user(ctx.rpc_client, farm)
+                .await?
+                .balance_staked;
+        }
+
+        let pool_state = connection::fetch_meteora_pool_state(ctx.rpc_client, pool).await?;
+        let (a_amount, b_amount) = pool_state
+            .get_withdraw_amounts(staked_lp)
+            .ok_or(Error::MathOverflow)?;
+        let decimals = connection::fetch_mints_decimals(
+            ctx.rpc_client,
+            &[pool.a_token_mint, pool.b_token_mint],
+        )
+        .await?;
+        let to_ui = |amount: u64, mint: &Pubkey| {
+            amount as f64 / 10_f64.powi(decimals.get(mint).copied().unwrap_or(0) as i32)
+        };
+        staked.push((
+            pool_id,
+            staked_lp,
+            to_ui(a_amount, &pool.a_token_mint) + to_ui(b_amount, &pool.b_token_mint),
+        ));
+    }
+
+    Ok(staked)
+}
+
+/// Up to `share` of the collateral, as far as the initial health stays at `target_init_health`
+async fn withdraw_freed_collateral(
+    ctx: &UnwindContext<'_>,
+    share: f64,
+    target_init_health: f64,
+) -> Result<(), Error> {
+    let account_with_banks = fetch_account_with_banks(ctx).await?;
+    let oracles_state =
+        connection::fetch_oracles_state(ctx.rpc_client, &ctx.static_addresses.marginfi_banks)
+            .await?;
+    let mint = ctx.static_addresses.collateral_mint;
+    let (collateral_amount, bank) = match (
+        account_with_banks.get_balance_by_mint(&mint),
+        account_with_banks.get_bank_by_mint(&mint),
+    ) {
+        (Some(balance), Some((_, bank))) if balance.is_active => (
+            balance
+                .get_amounts(bank.asset_share_value, bank.liability_share_value)
+                .0
+                .to_num::<u64>(),
+            bank,
+        ),
+        _ => return Ok(()),
+    };
+
+    let (assets, liabilities) = account_with_banks
+        .get_total_weighted_amount_by_type(&oracles_state, HealthType::Initial)
+        .await?;
+    // Weighted assets above the ones which keep (assets - liabilities) / assets at the
+    // target
+    let free_assets = (assets - liabilities / (I80F48::ONE - I80F48::from_num(target_init_health)))
+        .max(I80F48::ZERO);
+    let price = account_with_banks
+        .get_low_price(&oracles_state, &mint)
+        .await?;
+    // Weighted amounts are in USD scaled by 10^6
+    let unit_value = bank.asset_weight_init * price * EXP_10_I80F48[6]
+        / EXP_10_I80F48[bank.mint_decimals as usize];
+    let max_amount: u64 = if unit_value > I80F48::ZERO {
+        (free_assets / unit_value).floor().to_num()
+    } else {
+        collateral_amount
+    };
+
+    let share_amount = (collateral_amount as f64 * share) as u64;
+    let amount = share_amount.min(max_amount);
+    if amount == 0 {
+        info!(
+            "No collateral can be withdrawn above init health {}",
+            target_init_health
+        );
+        return Ok(());
+    }
+    if amount < share_amount {
+        info!(
+            "Collateral withdrawal limited to {} of {} {} by init health {}",
+            amount, share_amount, mint, target_init_health
+        );
+    }
+
+    let withdraw_all =
+        amount == collateral_amount && get_liabilities(&account_with_banks).is_empty();
+    info!("Withdrawing {} {} collateral", amount, mint);
+    let ix = ctx.instruction_builder.marginfi_withdraw(
+        ctx.static_addresses,
+        &mint,
+        amount,
+        withdraw_all,
+        &account_with_banks,
+    )?;
+    force_send_instructions(
+        ctx.rpc_client,
+        ctx.wallet,
+        vec![ix],
+        ctx.lookup_tables,
+        ctx.send_config,
+        "withdraw:collateral",
+    )
+    .await?;
+    ctx.send_config
+        .ledger
+        .record(|l| l.record_collateral_withdraw(amount));
+
+    Ok(())
+}
+
+/// Liabilities are repaid before any collateral is withdrawn, so the init health never
+/// drops below `target_init_health`
+#[instrument(name = "withdraw", skip_all)]
+pub async fn withdraw(
+    ctx: &UnwindContext<'_>,
+    share: WithdrawShare,
+    withdraw_collateral: bool,
+    target_init_health: f64,
+) -> Result<(), Error> {
+    let staked = fetch_staked_lp(ctx)
+        .await
+        .map_err(at_step(UnwindStep::FarmWithdraw))?;
+    let total_value = staked.iter().map(|(_, _, value)| value).sum::<f64>();
+    let share = match share {
+        WithdrawShare::Pct(pct) if pct > 0.0 && pct <= 100.0 => pct / 100.0,
+        WithdrawShare::AmountUsd(amount) if amount > 0.0 && total_value > 0.0 => {
+            (amount / total_value).min(1.0)
+        }
+        WithdrawShare::Pct(value) | WithdrawShare::AmountUsd(value) => {
+            return Err(Error::InvalidWithdrawAmount(value));
+        }
+    };
+    info!(
+        "Withdrawing {:.2}% of the position, staked LP worth ${:.2}",
+        share * 100.0,
+        total_value
+    );
+    ctx.send_config.notifier.notify(
+        NotificationLevel::Info,
+        format!("Withdrawing {:.2}% of the position", share * 100.0),
+    );
+
+    let account_with_banks = fetch_account_with_banks(ctx)
+        .await
+        .map_err(at_step(UnwindStep::FarmWithdraw))?;
+    let repay_mint = get_liabilities(&account_with_banks)
+        .first()
+        .map(|(mint, _)| *mint);
+
+    for (pool_id, staked_lp, _) in staked {
+        let lp_amount = (staked_lp as f64 * share) as u64;
+        let pool = ctx.static_addresses.get_meteora_pool(pool_id)?;
+        let pool_state = connection::fetch_meteora_pool_state(ctx.rpc_client, pool)
+            .await
+            .map_err(at_step(UnwindStep::PoolWithdraw))?;
+        let (a_amount, b_amount) = pool_state
+            .get_withdraw_amounts(lp_amount)
+            .ok_or(Error::MathOverflow)?;
+        if a_amount == 0 && b_amount == 0 {
+            warn!(
+                "{} LP of pool {} is below the smallest withdrawal, skipping the pool",
+                lp_amount, pool.address
+            );
+            continue;
+        }
+
+        unwind_pool(ctx, pool_id, Some(lp_amount), repay_mint.as_ref()).await?;
+    }
+
+    let account_with_banks = fetch_account_with_banks(ctx)
+        .await
+        .map_err(at_step(UnwindStep::Repay))?;
+    // Smallest first, so the `repay_mint` liability is repaid with what is left
+    for (mint, liability) in get_liabilities(&account_with_banks).into_iter().rev() {
+        let (_, bank) = account_with_banks
+            .get_bank_by_mint(&mint)
+            .ok_or(Error::InvalidMarginfiBank)
+            .map_err(at_step(UnwindStep::Repay))?;
+        let dust = (DUST_LIABILITY_UI * 10_f64.powi(bank.mint_decimals as i32)) as u64;
+        let mut amount = ((liability as f64 * share).ceil() as u64).min(liability);
+        if liability - amount <= dust {
+            info!(
+                "Remaining {} {} liability is dust, repaying all of it",
+                liability - amount,
+                mint
+            );
+            amount = liability;
+        }
+
+        if let Some(repay_mint) = repay_mint.filter(|repay_mint| repay_mint != &mint) {
+            swap_for_repay(ctx, &repay_mint, &mint, amount)
+                .await
+                .map_err(at_step(UnwindStep::Swap))?;
+        }
+        let repaid = repay(ctx, &mint, liability, Some(amount))
+            .await
+            .map_err(at_step(UnwindStep::Repay))?;
+        if repaid < amount {
+            warn!(
+                "Repaid {} of {} {}, the pools returned less than the share of the liability",
+                repaid, amount, mint
+            );
+        }
+    }
+
+    if withdraw_collateral {
+        withdraw_freed_collateral(ctx, share, target_init_health)
+            .await
+            .map_err(at_step(UnwindStep::CollateralWithdraw))?;
+    }
+
+    info!("Withdrew {:.2}% of the position", share * 100.0);
+    Ok(())
+}
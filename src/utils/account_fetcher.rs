@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use anchor_lang::prelude::Pubkey;
+use async_trait::async_trait;
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcProgramAccountsConfig};
+use solana_sdk::{account::Account, commitment_config::CommitmentConfig};
+
+use crate::Error;
+
+/// So the fetch functions in `connection` can run against something other than a live RPC
+#[async_trait]
+pub trait AccountFetcher: Send + Sync {
+    async fn get_account(&self, pubkey: &Pubkey) -> Result<Account, Error>;
+
+    async fn get_multiple_accounts(
+        &self,
+        pubkeys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, Error>;
+
+    async fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, Error>;
+
+    fn commitment(&self) -> CommitmentConfig {
+        CommitmentConfig::confirmed()
+    }
+}
+
+#[async_trait]
+impl AccountFetcher for RpcClient {
+    async fn get_account(&self, pubkey: &Pubkey) -> Result<Account, Error> {
+        Ok(RpcClient::get_account(self, pubkey).await?)
+    }
+
+    async fn get_multiple_accounts(
+        &self,
+        pubkeys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, Error> {
+        Ok(RpcClient::get_multiple_accounts(self, pubkeys).await?)
+    }
+
+    async fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, Error> {
+        Ok(RpcClient::get_program_accounts_with_config(self, program_id, config).await?)
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        RpcClient::commitment(self)
+    }
+}
+
+#[async_trait]
+impl<T: AccountFetcher + ?Sized> AccountFetcher for Arc<T> {
+    async fn get_account(&self, pubkey: &Pubkey) -> Result<Account, Error> {
+        (**self).get_account(pubkey).await
+    }
+
+    async fn get_multiple_accounts(
+        &self,
+        pubkeys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, Error> {
+        (**self).get_multiple_accounts(pubkeys).await
+    }
+
+    async fn get_program_accounts_with_config(
+        &self,
+        program_id: &Pubkey,
+        config: RpcProgramAccountsConfig,
+    ) -> Result<Vec<(Pubkey, Account)>, Error> {
+        (**self)
+            .get_program_accounts_with_config(program_id, config)
+            .await
+    }
+
+    fn commitment(&self) -> CommitmentConfig {
+        (**self).commitment()
+    }
+}
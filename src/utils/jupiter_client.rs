@@ -0,0 +1,441 @@
+use std::time::{Duration, Instant};
+
+use reqwest::{header::RETRY_AFTER, Client, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{sync::Mutex, time::sleep};
+use tracing::warn;
+
+use crate::{metrics, Error};
+
+const JUPITER_API_KEY_HEADER: &'static str = "x-api-key";
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+const MAX_TOTAL_RETRY_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct JupiterApiConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub max_requests_per_second: f64,
+}
+
+impl Default for JupiterApiConfig {
+    fn default() -> Self {
+        Self {
+            url: Self::DEFAULT_URL.to_string(),
+            api_key: None,
+            max_requests_per_second: Self::DEFAULT_MAX_REQUESTS_PER_SECOND,
+        }
+    }
+}
+
+impl JupiterApiConfig {
+    pub const DEFAULT_URL: &'static str = "https://quote-api.jup.ag/v6";
+    pub const DEFAULT_MAX_REQUESTS_PER_SECOND: f64 = 5.0;
+
+    pub fn new(
+        url: String,
+        api_key: Option<String>,
+        max_requests_per_second: f64,
+    ) -> Result<Self, String> {
+        reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+
+        if max_requests_per_second <= 0.0 {
+            return Err("Max requests per second has to be positive".to_string());
+        }
+
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            max_requests_per_second,
+        })
+    }
+
+    pub fn quote_url(&self, params: &str) -> String {
+        format!("{}/quote?{params}", self.url)
+    }
+
+    pub fn swap_instructions_url(&self) -> String {
+        format!("{}/swap-instructions", self.url)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JupiterError {
+    #[error("no route found")]
+    NoRoute,
+    #[error("amount is too small to route")]
+    AmountTooSmall,
+    #[error("rate limited")]
+    RateLimited,
+    #[error("{0}")]
+    Other(String),
+}
+
+const NO_ROUTE_ERROR_CODES: [&'static str; 3] = [
+    "COULD_NOT_FIND_ANY_ROUTE",
+    "NO_ROUTES_FOUND",
+    "TOKEN_NOT_TRADABLE",
+];
+const AMOUNT_TOO_SMALL_ERROR_CODES: [&'static str; 2] = [
+    "AMOUNT_TOO_SMALL",
+    "ROUTE_PLAN_DOES_NOT_CONSUME_ALL_THE_AMOUNT",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JupiterErrorBody {
+    error: Option<String>,
+    error_code: Option<String>,
+}
+
+impl JupiterErrorBody {
+    fn is_error(&self) -> bool {
+        self.error.is_some() || self.error_code.is_some()
+    }
+
+    fn into_error(self) -> JupiterError {
+        let code = self.error_code.clone().unwrap_or_default();
+        let message = self.error.unwrap_or_default();
+        let matches = |codes: &[&str]| {
+            codes
+                .iter()
+                .any(|c| code == *c || message.to_uppercase().contains(c))
+        };
+
+        if matches(&NO_ROUTE_ERROR_CODES) {
+            JupiterError::NoRoute
+        } else if matches(&AMOUNT_TOO_SMALL_ERROR_CODES) {
+            JupiterError::AmountTooSmall
+        } else if code.is_empty() {
+            JupiterError::Other(message)
+        } else {
+            JupiterError::Other(format!("{code}: {message}"))
+        }
+    }
+}
+
+fn parse_error_body(status: StatusCode, body: String) -> JupiterError {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return JupiterError::RateLimited;
+    }
+
+    match serde_json::from_str::<JupiterErrorBody>(&body) {
+        Ok(error_body) if error_body.is_error() => error_body.into_error(),
+        _ => JupiterError::Other(format!("{}: {}", status, body)),
+    }
+}
+
+/// The body is checked for a Jupiter error before it is parsed as `T`
+pub fn parse_response<T: DeserializeOwned>(body: &str) -> Result<T, Error> {
+    if let Ok(error_body) = serde_json::from_str::<JupiterErrorBody>(body) {
+        if error_body.is_error() {
+            return Err(error_body.into_error().into());
+        }
+    }
+
+    serde_json::from_str(body).map_err(|_| Error::UnableToDeserialize {
+        expected: std::any::type_name::<T>(),
+        account: None,
+    })
+}
+
+/// Capacity of one second worth of requests
+struct Pacer {
+    tokens: f64,
+    capacity: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    fn new(max_requests_per_second: f64) -> Self {
+        let capacity = max_requests_per_second.max(1.0);
+
+        Self {
+            tokens: capacity,
+            capacity,
+            refill_per_second: max_requests_per_second,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Returns how long the caller has to wait before sending
+    fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.refill_per_second)
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn parse_retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+pub struct JupiterClient {
+    client: Client,
+    config: JupiterApiConfig,
+    pacer: Mutex<Pacer>,
+}
+
+impl JupiterClient {
+    pub fn new(config: JupiterApiConfig) -> Self {
+        Self {
+            client: Client::new(),
+            pacer: Mutex::new(Pacer::new(config.max_requests_per_second)),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &JupiterApiConfig {
+        &self.config
+    }
+
+    pub async fn get(&self, url: String) -> Result<String, Error> {
+        self.send_with_retries(|| self.client.get(&url)).await
+    }
+
+    pub async fn post(&self, url: String, body: String) -> Result<String, Error> {
+        self.send_with_retries(|| self.client.post(&url).body(body.clone()))
+            .await
+    }
+
+    async fn wait_for_pacer(&self) {
+        let wait = self.pacer.lock().await.take();
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    async fn send_with_retries<F: Fn() -> RequestBuilder>(
+        &self,
+        build_request: F,
+    ) -> Result<String, Error> {
+        let start = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            self.wait_for_pacer().await;
+
+            let mut request = build_request();
+            if let Some(api_key) = &self.config.api_key {
+                request = request.header(JUPITER_API_KEY_HEADER, api_key);
+            }
+
+            let request_start = Instant::now();
+            let res = request.send().await;
+            metrics::observe_duration(
+                metrics::JUPITER_REQUEST_DURATION,
+                &[],
+                request_start.elapsed(),
+            );
+            let status_label = match &res {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            metrics::inc_counter(metrics::JUPITER_REQUESTS, &[("status", &status_label)]);
+
+            let (wait, last_error) = match res {
+                Ok(response) => {
+                    let status = response.status();
+
+                    if status.is_success() {
+                        return Ok(response.text().await?);
+                    }
+
+                    let retry_after = parse_retry_after(&response);
+                    let body = response.text().await.unwrap_or_default();
+
+                    if !is_retryable_status(status) {
+                        return Err(parse_error_body(status, body).into());
+                    }
+
+                    warn!("Jupiter request failed ({}), retrying", status);
+                    (
+                        retry_after.unwrap_or(backoff),
+                        parse_error_body(status, body).into(),
+                    )
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => {
+                    warn!("Jupiter request failed ({}), retrying", e);
+                    (backoff, Error::JupiterApiError(e))
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if start.elapsed() + wait > MAX_TOTAL_RETRY_DURATION {
+                return Err(last_error);
+            }
+
+            sleep(wait).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testkit::MockHttpServer;
+
+    fn parse(status: StatusCode, body: &str) -> JupiterError {
+        parse_error_body(status, body.to_string())
+    }
+
+    #[test]
+    fn error_codes_are_classified() {
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                r#"{"error":"Could not find any route","errorCode":"COULD_NOT_FIND_ANY_ROUTE"}"#
+            ),
+            JupiterError::NoRoute
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                r#"{"error":"The token is not tradable","errorCode":"TOKEN_NOT_TRADABLE"}"#
+            ),
+            JupiterError::NoRoute
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                r#"{"errorCode":"ROUTE_PLAN_DOES_NOT_CONSUME_ALL_THE_AMOUNT"}"#
+            ),
+            JupiterError::AmountTooSmall
+        ));
+    }
+
+    #[test]
+    fn error_messages_without_a_code_are_classified() {
+        assert!(matches!(
+            parse(StatusCode::BAD_REQUEST, r#"{"error":"no_routes_found"}"#),
+            JupiterError::NoRoute
+        ));
+        assert!(matches!(
+            parse(StatusCode::BAD_REQUEST, r#"{"error":"Amount too small"}"#),
+            JupiterError::Other(message) if message == "Amount too small"
+        ));
+        assert!(matches!(
+            parse(
+                StatusCode::BAD_REQUEST,
+                r#"{"error":"Slippage is invalid","errorCode":"INVALID_SLIPPAGE"}"#
+            ),
+            JupiterError::Other(message) if message == "INVALID_SLIPPAGE: Slippage is invalid"
+        ));
+    }
+
+    #[test]
+    fn rate_limit_and_unknown_bodies() {
+        assert!(matches!(
+            parse(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"errorCode":"COULD_NOT_FIND_ANY_ROUTE"}"#
+            ),
+            JupiterError::RateLimited
+        ));
+        assert!(matches!(
+            parse(StatusCode::BAD_GATEWAY, "<html>bad gateway</html>"),
+            JupiterError::Other(message) if message == "502 Bad Gateway: <html>bad gateway</html>"
+        ));
+        assert!(matches!(
+            parse(StatusCode::BAD_REQUEST, r#"{"message":"unexpected"}"#),
+            JupiterError::Other(message) if message.ends_with(r#"{"message":"unexpected"}"#)
+        ));
+    }
+
+    #[test]
+    fn successful_response_with_an_error_body_is_an_error() {
+        assert!(matches!(
+            parse_response::<Value>(r#"{"error":"Could not find any route"}"#),
+            Err(Error::JupiterError(JupiterError::NoRoute))
+        ));
+        assert!(matches!(
+            parse_response::<u64>("not json"),
+            Err(Error::UnableToDeserialize { .. })
+        ));
+        assert_eq!(
+            parse_response::<Value>(r#"{"outAmount":"1"}"#).unwrap()["outAmount"],
+            "1"
+        );
+    }
+
+    #[test]
+    fn urls_are_built_from_a_custom_base() {
+        for url in [
+            "https://jupiter.example.com/v6",
+            "https://jupiter.example.com/v6/",
+        ] {
+            let config = JupiterApiConfig::new(url.to_string(), None, 1.0).unwrap();
+
+            assert_eq!(
+                config.quote_url("inputMint=a&outputMint=b"),
+                "https://jupiter.example.com/v6/quote?inputMint=a&outputMint=b"
+            );
+            assert_eq!(
+                config.swap_instructions_url(),
+                "https://jupiter.example.com/v6/swap-instructions"
+            );
+        }
+        assert!(JupiterApiConfig::new("not a url".to_string(), None, 1.0).is_err());
+        assert!(
+            JupiterApiConfig::new(JupiterApiConfig::DEFAULT_URL.to_string(), None, 0.0).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn api_key_is_sent_as_a_header() {
+        let server = MockHttpServer::spawn("{}").await;
+        let config = |api_key: Option<&str>| {
+            JupiterApiConfig::new(
+                format!("{}/v6/", server.url),
+                api_key.map(str::to_string),
+                100.0,
+            )
+            .unwrap()
+        };
+
+        let client = JupiterClient::new(config(Some("secret")));
+        client
+            .get(client.config().quote_url("amount=1"))
+            .await
+            .unwrap();
+        let client = JupiterClient::new(config(None));
+        client
+            .post(client.config().swap_instructions_url(), "{}".to_string())
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].path, "/v6/quote?amount=1");
+        assert_eq!(requests[0].header(JUPITER_API_KEY_HEADER), Some("secret"));
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].path, "/v6/swap-instructions");
+        assert_eq!(requests[1].header(JUPITER_API_KEY_HEADER), None);
+    }
+}
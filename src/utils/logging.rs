@@ -0,0 +1,21 @@
+use tracing_subscriber::EnvFilter;
+
+/// Dependencies only log warnings
+const DEFAULT_FILTER: &'static str = "warn,mfi_met_farmer=info";
+
+/// JSON output carries the current span and its parents with every event
+pub fn init(json: bool) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    if json {
+        subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init();
+    } else {
+        subscriber.init();
+    }
+}
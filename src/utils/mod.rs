@@ -1,2 +1,7 @@
+pub mod account_fetcher;
+pub mod jupiter_client;
+pub mod logging;
+pub mod notifier;
+pub mod rpc_failover;
 pub mod transaction;
 pub mod websocket_client;
@@ -0,0 +1,213 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde_json::json;
+use tracing::warn;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationLevel {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl NotificationLevel {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::Info => "[INFO]",
+            Self::Warning => "[WARNING]",
+            Self::Critical => "[CRITICAL]",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RateLimitState {
+    sent_at: VecDeque<Instant>,
+    dropped: u64,
+}
+
+/// The payload has the message in both `content` and `text`, which is accepted by Discord
+/// and Slack webhooks
+#[derive(Debug)]
+pub struct Notifier {
+    client: Client,
+    url: Option<String>,
+    max_per_minute: usize,
+    rate_limit_state: Mutex<RateLimitState>,
+}
+
+impl Notifier {
+    pub const DEFAULT_MAX_PER_MINUTE: usize = 20;
+
+    pub fn new(url: Option<String>, max_per_minute: usize) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            max_per_minute,
+            rate_limit_state: Mutex::default(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(None, 0)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// `None` when rate limited, critical notifications are never dropped
+    fn prepare_message(&self, level: NotificationLevel, message: &str) -> Option<String> {
+        let mut state = self.rate_limit_state.lock().unwrap();
+        let now = Instant::now();
+        while let Some(sent_at) = state.sent_at.front() {
+            if now.duration_since(*sent_at) < RATE_LIMIT_WINDOW {
+                break;
+            }
+            state.sent_at.pop_front();
+        }
+
+        if state.sent_at.len() >= self.max_per_minute && level != NotificationLevel::Critical {
+            state.dropped += 1;
+            return None;
+        }
+        state.sent_at.push_back(now);
+
+        let mut message = format!("{} {}", level.prefix(), message);
+        if state.dropped > 0 {
+            message.push_str(&format!(
+                "\n({} notifications were dropped by the rate limit)",
+                state.dropped
+            ));
+            state.dropped = 0;
+        }
+        Some(message)
+    }
+
+    async fn post(client: Client, url: String, message: String) {
+        let body = json!({ "content": message, "text": message });
+        match client
+            .post(&url)
+            .timeout(REQUEST_TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+        {
+            Ok(res) if !res.status().is_success() => {
+                warn!("Notification failed with status {}", res.status())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Unable to send notification: {}", e),
+        }
+    }
+
+    /// Never blocks or fails the caller
+    pub fn notify(&self, level: NotificationLevel, message: impl AsRef<str>) {
+        let Some(url) = &self.url else {
+            return;
+        };
+        let Some(message) = self.prepare_message(level, message.as_ref()) else {
+            return;
+        };
+
+        tokio::spawn(Self::post(self.client.clone(), url.clone(), message));
+    }
+
+    /// Used right before the process exits
+    pub async fn notify_and_wait(&self, level: NotificationLevel, message: impl AsRef<str>) {
+        let Some(url) = &self.url else {
+            return;
+        };
+        let Some(message) = self.prepare_message(level, message.as_ref()) else {
+            return;
+        };
+
+        Self::post(self.client.clone(), url.clone(), message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testkit::{HttpRequest, MockHttpServer};
+
+    fn message_of(request: &HttpRequest) -> String {
+        let body: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["content"], body["text"]);
+        body["content"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn notification_is_posted_as_json() {
+        let server = MockHttpServer::spawn("").await;
+        let notifier = Notifier::new(Some(server.url.clone()), 20);
+
+        notifier.notify(NotificationLevel::Warning, "Health is low");
+
+        let requests = server.wait_for_requests(1).await;
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&requests[0].body).unwrap(),
+            json!({ "content": "[WARNING] Health is low", "text": "[WARNING] Health is low" })
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limited_notifications_are_counted_in_the_next_one() {
+        let server = MockHttpServer::spawn("").await;
+        let notifier = Notifier::new(Some(server.url.clone()), 2);
+
+        for i in 0..4 {
+            notifier
+                .notify_and_wait(NotificationLevel::Info, format!("Info {}", i))
+                .await;
+        }
+        assert_eq!(server.requests().len(), 2);
+
+        // Critical notifications bypass the limit and report what was dropped
+        notifier
+            .notify_and_wait(NotificationLevel::Critical, "Unwinding")
+            .await;
+        notifier
+            .notify_and_wait(NotificationLevel::Critical, "Unwound")
+            .await;
+
+        let messages = server.requests().iter().map(message_of).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                "[INFO] Info 0".to_string(),
+                "[INFO] Info 1".to_string(),
+                "[CRITICAL] Unwinding\n(2 notifications were dropped by the rate limit)"
+                    .to_string(),
+                "[CRITICAL] Unwound".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_notifier_sends_nothing() {
+        let server = MockHttpServer::spawn("").await;
+        let notifier = Notifier::disabled();
+
+        assert!(!notifier.is_enabled());
+        notifier.notify(NotificationLevel::Critical, "Unwinding");
+        notifier
+            .notify_and_wait(NotificationLevel::Critical, "Unwound")
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde_json::Value;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    http_sender::HttpSender,
+    nonblocking::rpc_client::RpcClient,
+    rpc_client::RpcClientConfig,
+    rpc_request::RpcRequest,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+use solana_sdk::commitment_config::CommitmentConfig;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::metrics;
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Slots an endpoint can be behind the most advanced endpoint and still be healthy
+const MAX_SLOT_LAG: u64 = 50;
+/// How long the status of a sent transaction is queried on the endpoint it was sent to
+const STICKY_ROUTE_TTL: Duration = Duration::from_secs(120);
+
+struct Endpoint {
+    sender: HttpSender,
+    /// The full url can hold an API key
+    name: String,
+    healthy: AtomicBool,
+}
+
+struct Endpoints {
+    /// In order of priority
+    endpoints: Vec<Endpoint>,
+    active: AtomicUsize,
+    sticky: Mutex<HashMap<String, (usize, Instant)>>,
+}
+
+impl Endpoints {
+    /// The active endpoint is kept when none is healthy
+    fn select_active(&self) -> usize {
+        let previous = self.active.load(Ordering::Relaxed);
+        let Some(next) = self
+            .endpoints
+            .iter()
+            .position(|endpoint| endpoint.healthy.load(Ordering::Relaxed))
+        else {
+            return previous;
+        };
+
+        if self.active.swap(next, Ordering::Relaxed) != next {
+            warn!(
+                "RPC failover from {} to {}",
+                self.endpoints[previous].name, self.endpoints[next].name
+            );
+            metrics::inc_counter(
+                metrics::RPC_FAILOVERS,
+                &[("endpoint", &self.endpoints[next].name)],
+            );
+        }
+        next
+    }
+
+    fn mark_unhealthy(&self, index: usize, error: &ClientError) {
+        let endpoint = &self.endpoints[index];
+        if endpoint.healthy.swap(false, Ordering::Relaxed) {
+            warn!("RPC {} is unhealthy: {}", endpoint.name, error);
+        }
+        self.select_active();
+    }
+
+    /// Only while that endpoint is still healthy
+    fn get_sticky_endpoint(&self, request: RpcRequest, params: &Value) -> Option<usize> {
+        let signature = match request {
+            RpcRequest::GetSignatureStatuses => params.get(0)?.get(0)?.as_str()?,
+            RpcRequest::GetTransaction => params.get(0)?.as_str()?,
+            _ => return None,
+        };
+        let (index, _) = *self.sticky.lock().unwrap().get(signature)?;
+        self.endpoints[index]
+            .healthy
+            .load(Ordering::Relaxed)
+            .then_some(index)
+    }
+
+    async fn check_health(&self) {
+        let checks = join_all(self.endpoints.iter().map(|endpoint| async move {
+            let healthy = endpoint
+                .sender
+                .send(RpcRequest::GetHealth, Value::Null)
+                .await
+                .is_ok();
+            let slot = endpoint
+                .sender
+                .send(RpcRequest::GetSlot, Value::Null)
+                .await
+                .ok()
+                .and_then(|slot| slot.as_u64());
+            (healthy, slot)
+        }))
+        .await;
+        let max_slot = checks.iter().filter_map(|(_, slot)| *slot).max();
+
+        for (endpoint, (healthy, slot)) in self.endpoints.iter().zip(checks) {
+            let healthy = healthy
+                && matches!((slot, max_slot), (Some(slot), Some(max_slot)) if slot + MAX_SLOT_LAG >= max_slot);
+            match (endpoint.healthy.swap(healthy, Ordering::Relaxed), healthy) {
+                (false, true) => info!("RPC {} is healthy again", endpoint.name),
+                (true, false) => warn!(
+                    "RPC {} is unhealthy at slot {:?}, the highest slot is {:?}",
+                    endpoint.name, slot, max_slot
+                ),
+                _ => {}
+            }
+        }
+        self.select_active();
+
+        self.sticky
+            .lock()
+            .unwrap()
+            .retain(|_, (_, sent_at)| sent_at.elapsed() < STICKY_ROUTE_TTL);
+    }
+}
+
+fn is_transport_error(error: &ClientError) -> bool {
+    matches!(
+        error.kind(),
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_)
+    )
+}
+
+/// Used instead of the url, which can hold an API key
+pub fn get_endpoint_name(url: &str, index: usize) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_else(|| format!("endpoint {}", index))
+}
+
+/// A request which fails to reach the active endpoint is retried on the next healthy one
+struct FailoverSender {
+    endpoints: Arc<Endpoints>,
+}
+
+#[async_trait]
+impl RpcSender for FailoverSender {
+    async fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        let mut index = self
+            .endpoints
+            .get_sticky_endpoint(request, &params)
+            .unwrap_or_else(|| self.endpoints.active.load(Ordering::Relaxed));
+
+        for _ in 1..self.endpoints.endpoints.len() {
+            match self.endpoints.endpoints[index]
+                .sender
+                .send(request, params.clone())
+                .await
+            {
+                Err(e) if is_transport_error(&e) => {
+                    self.endpoints.mark_unhealthy(index, &e);
+                    let next = self.endpoints.active.load(Ordering::Relaxed);
+                    if next == index {
+                        return Err(e);
+                    }
+                    index = next;
+                }
+                res => return self.record_send(request, index, res),
+            }
+        }
+
+        let res = self.endpoints.endpoints[index]
+            .sender
+            .send(request, params)
+            .await;
+        self.record_send(request, index, res)
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.endpoints.endpoints[self.endpoints.active.load(Ordering::Relaxed)]
+            .sender
+            .get_transport_stats()
+    }
+
+    fn url(&self) -> String {
+        self.endpoints.endpoints[self.endpoints.active.load(Ordering::Relaxed)]
+            .sender
+            .url()
+    }
+}
+
+impl FailoverSender {
+    /// Sent transactions are confirmed on the endpoint which received them
+    fn record_send(
+        &self,
+        request: RpcRequest,
+        index: usize,
+        res: ClientResult<Value>,
+    ) -> ClientResult<Value> {
+        if let (RpcRequest::SendTransaction, Ok(signature)) = (request, &res) {
+            if let Some(signature) = signature.as_str() {
+                self.endpoints
+                    .sticky
+                    .lock()
+                    .unwrap()
+                    .insert(signature.to_string(), (index, Instant::now()));
+            }
+        }
+        res
+    }
+}
+
+async fn check_health_periodically(endpoints: Weak<Endpoints>) {
+    loop {
+        sleep(HEALTH_CHECK_INTERVAL).await;
+        let Some(endpoints) = endpoints.upgrade() else {
+            return;
+        };
+        endpoints.check_health().await;
+    }
+}
+
+/// A single url gives a plain client
+pub fn new_failover_rpc_client(urls: Vec<String>, commitment: CommitmentConfig) -> RpcClient {
+    if urls.len() <= 1 {
+        return RpcClient::new_with_commitment(
+            urls.into_iter().next().unwrap_or_default(),
+            commitment,
+        );
+    }
+
+    let endpoints = Arc::new(Endpoints {
+        endpoints: urls
+            .iter()
+            .enumerate()
+            .map(|(index, url)| Endpoint {
+                sender: HttpSender::new(url.clone()),
+                name: get_endpoint_name(url, index),
+                healthy: AtomicBool::new(true),
+            })
+            .collect(),
+        active: AtomicUsize::new(0),
+        sticky: Mutex::new(HashMap::new()),
+    });
+    tokio::spawn(check_health_periodically(Arc::downgrade(&endpoints)));
+
+    RpcClient::new_sender(
+        FailoverSender { endpoints },
+        RpcClientConfig::with_commitment(commitment),
+    )
+}
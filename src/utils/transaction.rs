@@ -1,86 +1,245 @@
 use std::{
-    sync::Arc,
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use anchor_lang::prelude::Pubkey;
+use base64::{engine::general_purpose, Engine};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use solana_account_decoder::{parse_token::UiTokenAmount, UiAccountEncoding};
 use solana_client::{
     client_error::{ClientError, ClientErrorKind},
     nonblocking::rpc_client::RpcClient,
-    rpc_config::{RpcSendTransactionConfig, RpcTransactionConfig},
+    rpc_config::{
+        RpcSendTransactionConfig, RpcSignatureSubscribeConfig,
+        RpcSimulateTransactionAccountsConfig, RpcSimulateTransactionConfig, RpcTransactionConfig,
+    },
+    rpc_custom_error::{
+        JSON_RPC_SERVER_ERROR_MIN_CONTEXT_SLOT_NOT_REACHED, JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+    },
+    rpc_request::{RpcError, RpcRequest, RpcResponseErrorData},
 };
 use solana_sdk::{
     address_lookup_table_account::AddressLookupTableAccount,
-    commitment_config::CommitmentConfig,
-    instruction::Instruction,
+    commitment_config::CommitmentLevel,
+    compute_budget::{self, ComputeBudgetInstruction},
+    hash::Hash,
+    instruction::{Instruction, InstructionError},
     message::{v0::Message, VersionedMessage},
-    signature::Signature,
+    packet::PACKET_DATA_SIZE,
+    signature::{Keypair, Signature},
+    system_instruction,
     transaction::{TransactionError, VersionedTransaction},
 };
 use solana_transaction_status::{
     option_serializer::OptionSerializer, UiTransactionEncoding, UiTransactionStatusMeta,
     UiTransactionTokenBalance,
 };
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
 
-use crate::{Error, Wallet};
+use super::{notifier::Notifier, websocket_client::WebsocketClient};
+use crate::{
+    config::CommitmentPolicy,
+    connection::{parse_token_account_amount, AccountData},
+    constants,
+    ledger::Ledger,
+    nonce,
+    risk::RiskGuard,
+    shutdown::Shutdown,
+    Error, Wallet,
+};
 
-pub fn parse_transaction_token_change(
+fn parse_token_balance_amount(balance: &UiTransactionTokenBalance) -> Result<i128, Error> {
+    let amount = &balance.ui_token_amount.amount;
+    amount
+        .parse()
+        .map_err(|_| Error::InvalidTokenAmount(amount.clone()))
+}
+
+/// Created accounts have no pre balance and closed ones no post balance, both count as zero
+pub fn parse_transaction_token_changes_by_account(
     meta: &UiTransactionStatusMeta,
     wallet: &Arc<Wallet>,
     mint: &Pubkey,
-    is_positive: bool,
-) -> Option<u64> {
-    match (&meta.pre_token_balances, &meta.post_token_balances) {
-        (
-            OptionSerializer::Some(pre_token_balances),
-            OptionSerializer::Some(post_token_balances),
-        ) => {
-            let wallet_str = wallet.pubkey.to_string();
-            let mint_str = mint.to_string();
-
-            let is_correct_token_balance = |b: &UiTransactionTokenBalance| {
-                if &b.mint != &mint_str {
-                    return false;
-                }
-                match &b.owner {
-                    OptionSerializer::Some(owner) => owner == &wallet_str,
-                    _ => false,
-                }
-            };
+) -> Result<Vec<(u8, i128)>, Error> {
+    let (OptionSerializer::Some(pre_token_balances), OptionSerializer::Some(post_token_balances)) =
+        (&meta.pre_token_balances, &meta.post_token_balances)
+    else {
+        return Ok(vec![]);
+    };
+    let wallet_str = wallet.pubkey.to_string();
+    let mint_str = mint.to_string();
 
-            match (
-                pre_token_balances
-                    .iter()
-                    .find(|b| is_correct_token_balance(b)),
-                post_token_balances
-                    .iter()
-                    .find(|b| is_correct_token_balance(b)),
-            ) {
-                (Some(pre_balance), Some(post_balance)) => {
-                    let pre_token_amount: u64 = pre_balance.ui_token_amount.amount.parse().unwrap();
-                    let post_token_amount: u64 =
-                        post_balance.ui_token_amount.amount.parse().unwrap();
-
-                    if is_positive {
-                        Some(post_token_amount - pre_token_amount)
-                    } else {
-                        Some(pre_token_amount - post_token_amount)
-                    }
-                }
-                _ => None,
+    let is_correct_token_balance = |b: &&UiTransactionTokenBalance| {
+        if &b.mint != &mint_str {
+            return false;
+        }
+        match &b.owner {
+            OptionSerializer::Some(owner) => owner == &wallet_str,
+            _ => false,
+        }
+    };
+
+    let mut changes: Vec<(u8, i128)> = vec![];
+    for (balances, sign) in [(post_token_balances, 1), (pre_token_balances, -1)] {
+        for balance in balances.iter().filter(is_correct_token_balance) {
+            let amount = parse_token_balance_amount(balance)? * sign;
+            match changes
+                .iter_mut()
+                .find(|(index, _)| *index == balance.account_index)
+            {
+                Some((_, change)) => *change += amount,
+                None => changes.push((balance.account_index, amount)),
             }
         }
-        _ => None,
     }
+    Ok(changes)
 }
 
-#[derive(Debug)]
+/// Summed over every wallet token account of the mint, like a temporary wrapped SOL
+/// account next to the associated one
+pub fn parse_transaction_token_change(
+    meta: &UiTransactionStatusMeta,
+    wallet: &Arc<Wallet>,
+    mint: &Pubkey,
+) -> Result<Option<i128>, Error> {
+    let changes = parse_transaction_token_changes_by_account(meta, wallet, mint)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(changes.iter().map(|(_, change)| change).sum()))
+}
+
+pub fn parse_token_received(
+    meta: &UiTransactionStatusMeta,
+    wallet: &Arc<Wallet>,
+    mint: &Pubkey,
+) -> Result<Option<u64>, Error> {
+    match parse_transaction_token_change(meta, wallet, mint)? {
+        Some(change) if change < 0 => Err(Error::UnexpectedTokenChange {
+            mint: *mint,
+            change,
+        }),
+        change => Ok(change.map(|change| change as u64)),
+    }
+}
+
+pub fn parse_token_spent(
+    meta: &UiTransactionStatusMeta,
+    wallet: &Arc<Wallet>,
+    mint: &Pubkey,
+) -> Result<Option<u64>, Error> {
+    match parse_transaction_token_change(meta, wallet, mint)? {
+        Some(change) if change > 0 => Err(Error::UnexpectedTokenChange {
+            mint: *mint,
+            change,
+        }),
+        change => Ok(change.map(|change| (-change) as u64)),
+    }
+}
+
+pub fn fee_paid(meta: &UiTransactionStatusMeta) -> u64 {
+    meta.fee
+}
+
+/// Fees included, addresses loaded from lookup tables are taken from the meta
+pub fn parse_transaction_sol_change(
+    meta: &UiTransactionStatusMeta,
+    account_keys: &[Pubkey],
+    wallet: &Pubkey,
+) -> Option<i64> {
+    let loaded_addresses = match &meta.loaded_addresses {
+        OptionSerializer::Some(loaded) => loaded
+            .writable
+            .iter()
+            .chain(loaded.readonly.iter())
+            .filter_map(|address| Pubkey::from_str(address).ok())
+            .collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    let index = account_keys
+        .iter()
+        .chain(loaded_addresses.iter())
+        .position(|key| key == wallet)?;
+
+    let pre_balance = *meta.pre_balances.get(index)?;
+    let post_balance = *meta.post_balances.get(index)?;
+    Some(post_balance as i64 - pre_balance as i64)
+}
+
+pub fn get_wallet_token_changes(
+    meta: &UiTransactionStatusMeta,
+    wallet: &Arc<Wallet>,
+) -> Result<Vec<(String, i128)>, Error> {
+    let (OptionSerializer::Some(pre_token_balances), OptionSerializer::Some(post_token_balances)) =
+        (&meta.pre_token_balances, &meta.post_token_balances)
+    else {
+        return Ok(vec![]);
+    };
+    let wallet_str = wallet.pubkey.to_string();
+    let is_wallet_balance = |b: &&UiTransactionTokenBalance| match &b.owner {
+        OptionSerializer::Some(owner) => owner == &wallet_str,
+        _ => false,
+    };
+    let find_balance = |balances: &[UiTransactionTokenBalance], account_index: u8| {
+        balances
+            .iter()
+            .filter(is_wallet_balance)
+            .find(|balance| balance.account_index == account_index)
+            .cloned()
+    };
+
+    let mut account_indexes = post_token_balances
+        .iter()
+        .chain(pre_token_balances.iter())
+        .filter(is_wallet_balance)
+        .map(|balance| balance.account_index)
+        .collect::<Vec<_>>();
+    account_indexes.sort_unstable();
+    account_indexes.dedup();
+
+    let mut changes: Vec<(String, i128)> = vec![];
+    for account_index in account_indexes {
+        let pre = find_balance(pre_token_balances, account_index);
+        let post = find_balance(post_token_balances, account_index);
+        let (mint, change) = match (pre, post) {
+            (Some(pre), Some(post)) => (
+                post.mint.clone(),
+                parse_token_balance_amount(&post)? - parse_token_balance_amount(&pre)?,
+            ),
+            // Created by the transaction
+            (None, Some(post)) => (post.mint.clone(), parse_token_balance_amount(&post)?),
+            // Closed by the transaction
+            (Some(pre), None) => (pre.mint.clone(), -parse_token_balance_amount(&pre)?),
+            (None, None) => unreachable!(),
+        };
+        match changes.iter_mut().find(|(m, _)| m == &mint) {
+            Some((_, total)) => *total += change,
+            None => changes.push((mint, change)),
+        }
+    }
+    changes.retain(|(_, change)| *change != 0);
+    Ok(changes)
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum ClientTransactionError {
+    #[error("instructions could not be compiled into a message")]
     UnableToCompile,
+    #[error("a signer of the message is missing")]
     MissingSigner,
+    #[error("a signature of the transaction is missing")]
     MissingSignature,
+    #[error("fetching the blockhash or nonce failed")]
     RpcError,
+    #[error("nonce account is not initialized")]
+    MissingNonce,
 }
 
 impl From<ClientError> for ClientTransactionError {
@@ -94,8 +253,70 @@ pub async fn build_signed_transaction(
     signer: &Arc<Wallet>,
     instructions: &[Instruction],
     address_lookup_tables: &[AddressLookupTableAccount],
+) -> Result<VersionedTransaction, ClientTransactionError> {
+    build_signed_transaction_with_signers(
+        rpc_client,
+        signer,
+        &[],
+        instructions,
+        address_lookup_tables,
+    )
+    .await
+}
+
+pub async fn build_signed_transaction_with_signers(
+    rpc_client: &Arc<RpcClient>,
+    signer: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: &[Instruction],
+    address_lookup_tables: &[AddressLookupTableAccount],
 ) -> Result<VersionedTransaction, ClientTransactionError> {
     let blockhash = rpc_client.get_latest_blockhash().await?;
+    sign_transaction(
+        signer,
+        extra_signers,
+        instructions,
+        address_lookup_tables,
+        blockhash,
+    )
+}
+
+/// The advance nonce instruction goes first, the signer has to be the nonce authority
+pub async fn build_signed_transaction_with_nonce(
+    rpc_client: &Arc<RpcClient>,
+    signer: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    nonce_account: &Pubkey,
+    instructions: &[Instruction],
+    address_lookup_tables: &[AddressLookupTableAccount],
+) -> Result<VersionedTransaction, ClientTransactionError> {
+    let nonce = nonce::fetch_nonce(rpc_client, nonce_account)
+        .await
+        .map_err(|_| ClientTransactionError::RpcError)?
+        .ok_or(ClientTransactionError::MissingNonce)?;
+    let instructions = [system_instruction::advance_nonce_account(
+        nonce_account,
+        &signer.pubkey,
+    )]
+    .into_iter()
+    .chain(instructions.iter().cloned())
+    .collect::<Vec<_>>();
+    sign_transaction(
+        signer,
+        extra_signers,
+        &instructions,
+        address_lookup_tables,
+        nonce,
+    )
+}
+
+pub fn sign_transaction(
+    signer: &Arc<Wallet>,
+    extra_signers: &[&Keypair],
+    instructions: &[Instruction],
+    address_lookup_tables: &[AddressLookupTableAccount],
+    blockhash: Hash,
+) -> Result<VersionedTransaction, ClientTransactionError> {
     let message = Message::try_compile(
         &signer.pubkey,
         instructions,
@@ -104,7 +325,10 @@ pub async fn build_signed_transaction(
     )
     .map_err(|_| ClientTransactionError::UnableToCompile)?;
 
-    let tx = VersionedTransaction::try_new(VersionedMessage::V0(message), &[&signer.keypair])
+    let mut signers = vec![&signer.keypair];
+    signers.extend_from_slice(extra_signers);
+
+    let tx = VersionedTransaction::try_new(VersionedMessage::V0(message), &signers)
         .map_err(|_| ClientTransactionError::MissingSigner)?;
 
     tx.sanitize(true)
@@ -113,63 +337,1745 @@ pub async fn build_signed_transaction(
     Ok(tx)
 }
 
-const POLL_TIMEOUT: Duration = Duration::from_secs(2);
-const TX_VALIDITY_DURATION: u64 = 40;
+const MAX_TRANSACTION_ACCOUNTS: usize = 64;
+
+pub fn fits_in_transaction(
+    payer: &Pubkey,
+    instructions: &[Instruction],
+    address_lookup_tables: &[AddressLookupTableAccount],
+) -> bool {
+    let Ok(message) =
+        Message::try_compile(payer, instructions, address_lookup_tables, Hash::default())
+    else {
+        return false;
+    };
+    let accounts_count = message.account_keys.len()
+        + message
+            .address_table_lookups
+            .iter()
+            .map(|lookup| lookup.writable_indexes.len() + lookup.readonly_indexes.len())
+            .sum::<usize>();
+
+    let message = VersionedMessage::V0(message);
+    let signatures_count = message.header().num_required_signatures as usize;
+    // Signatures are prefixed by their count, which fits in a single byte
+    let size = 1 + signatures_count * 64 + message.serialize().len();
+
+    size <= PACKET_DATA_SIZE && accounts_count <= MAX_TRANSACTION_ACCOUNTS
+}
+
+/// Each group stays in one transaction, `overhead` is added to every transaction
+pub fn split_instructions(
+    payer: &Pubkey,
+    groups: Vec<Vec<Instruction>>,
+    address_lookup_tables: &[AddressLookupTableAccount],
+    overhead: &[Instruction],
+) -> Result<Vec<Vec<Instruction>>, Error> {
+    let fits = |instructions: &[Instruction]| {
+        let candidate = overhead
+            .iter()
+            .chain(instructions.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        fits_in_transaction(payer, &candidate, address_lookup_tables)
+    };
+
+    let mut transactions: Vec<Vec<Instruction>> = vec![];
+    for group in groups {
+        if !fits(&group) {
+            return Err(Error::InstructionGroupTooLarge {
+                instructions: group.len(),
+            });
+        }
+        match transactions.last_mut() {
+            Some(last) if fits(&[&last[..], &group[..]].concat()) => last.extend(group),
+            _ => transactions.push(group),
+        }
+    }
+    Ok(transactions)
+}
+
+const TOKEN_ACCOUNT_OWNER_OFFSET: usize = 32;
+
+fn new_token_balance(
+    index: usize,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) -> UiTransactionTokenBalance {
+    UiTransactionTokenBalance {
+        account_index: index as u8,
+        mint: mint.to_string(),
+        ui_token_amount: UiTokenAmount {
+            ui_amount: None,
+            decimals: 0,
+            amount: amount.to_string(),
+            ui_amount_string: String::new(),
+        },
+        owner: OptionSerializer::Some(owner.to_string()),
+        program_id: OptionSerializer::Skip,
+    }
+}
+
+pub fn get_writable_accounts(instructions: &[Instruction]) -> Vec<Pubkey> {
+    let mut writable_accounts: Vec<Pubkey> = vec![];
+    instructions
+        .iter()
+        .flat_map(|ix| ix.accounts.iter())
+        .filter(|meta| meta.is_writable)
+        .for_each(|meta| {
+            if !writable_accounts.contains(&meta.pubkey) {
+                writable_accounts.push(meta.pubkey);
+            }
+        });
+    writable_accounts
+}
+
+/// Token balances of the returned meta parse like the ones of a sent transaction, they
+/// are missing when the simulation fails
+pub async fn simulate_transaction(
+    rpc_client: &Arc<RpcClient>,
+    wallet: &Arc<Wallet>,
+    tx: &VersionedTransaction,
+    instructions: &[Instruction],
+) -> Result<UiTransactionStatusMeta, Error> {
+    let writable_accounts = get_writable_accounts(instructions);
+    let accounts = rpc_client.get_multiple_accounts(&writable_accounts).await?;
+
+    // (token account, mint, amount)
+    let wallet_token_accounts = writable_accounts
+        .iter()
+        .zip(accounts.iter())
+        .filter_map(|(address, ai)| {
+            let ai = ai.as_ref()?;
+            if ai.owner != constants::spl_token::id() && ai.owner != constants::token_2022::id() {
+                return None;
+            }
+            let owner = ai
+                .data
+                .get(TOKEN_ACCOUNT_OWNER_OFFSET..TOKEN_ACCOUNT_OWNER_OFFSET + 32)?;
+            if owner != wallet.pubkey.as_ref() {
+                return None;
+            }
+            let mint = Pubkey::new(&ai.data[..32]);
+            Some((*address, mint, parse_token_account_amount(&ai.data).ok()?))
+        })
+        .collect::<Vec<_>>();
+
+    let res = rpc_client
+        .simulate_transaction_with_config(
+            tx,
+            RpcSimulateTransactionConfig {
+                sig_verify: false,
+                replace_recent_blockhash: true,
+                commitment: Some(rpc_client.commitment()),
+                encoding: Some(UiTransactionEncoding::Base64),
+                accounts: Some(RpcSimulateTransactionAccountsConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    addresses: wallet_token_accounts
+                        .iter()
+                        .map(|(address, _, _)| address.to_string())
+                        .collect(),
+                }),
+                min_context_slot: None,
+            },
+        )
+        .await?
+        .value;
+
+    info!(
+        "Simulated transaction, units consumed: {:?}, error: {:?}",
+        res.units_consumed, res.err
+    );
+    res.logs.iter().flatten().for_each(|log| info!("  {}", log));
+
+    let pre_token_balances = wallet_token_accounts
+        .iter()
+        .enumerate()
+        .map(|(i, (_, mint, amount))| new_token_balance(i, mint, &wallet.pubkey, *amount))
+        .collect::<Vec<_>>();
+    let post_token_balances = match (&res.err, &res.accounts) {
+        (None, Some(post_accounts)) => OptionSerializer::Some(
+            wallet_token_accounts
+                .iter()
+                .zip(post_accounts.iter())
+                .enumerate()
+                .map(|(i, ((_, mint, pre_amount), ai))| {
+                    let amount = ai
+                        .as_ref()
+                        .and_then(|ai| AccountData::decode(&ai.data).ok())
+                        .and_then(|data| parse_token_account_amount(&data).ok())
+                        .unwrap_or(0);
+                    if amount != *pre_amount {
+                        info!("  Token change {}: {} -> {}", mint, pre_amount, amount);
+                    }
+                    new_token_balance(i, mint, &wallet.pubkey, amount)
+                })
+                .collect::<Vec<_>>(),
+        ),
+        _ => OptionSerializer::None,
+    };
+
+    Ok(UiTransactionStatusMeta {
+        err: res.err.clone(),
+        status: res.err.map_or(Ok(()), Err),
+        fee: 0,
+        pre_balances: vec![],
+        post_balances: vec![],
+        inner_instructions: OptionSerializer::None,
+        log_messages: res.logs.into(),
+        pre_token_balances: OptionSerializer::Some(pre_token_balances),
+        post_token_balances,
+        rewards: OptionSerializer::None,
+        loaded_addresses: OptionSerializer::Skip,
+        return_data: OptionSerializer::Skip,
+        compute_units_consumed: res.units_consumed.into(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct SendConfig {
+    pub dry_run: bool,
+    pub max_retries: u32,
+    pub poll_interval: Duration,
+    pub max_confirmation_wait: Duration,
+    /// A transaction counts as landed at the confirmation commitment
+    pub commitment_policy: CommitmentPolicy,
+    pub cu_limit: u32,
+    pub cu_price_micro_lamports: u64,
+    /// `cu_price_micro_lamports` is the fallback when the estimate fails
+    pub priority_fee: Option<PriorityFeeConfig>,
+    /// `cu_limit` is used when the simulation fails
+    pub simulate_cu_limit: bool,
+    pub cu_limit_margin_percent: u64,
+    pub cu_limits: Arc<CuLimitCache>,
+    pub notifier: Arc<Notifier>,
+    pub ledger: Arc<Ledger>,
+    pub risk_guard: Arc<RiskGuard>,
+    /// In-flight transactions are still confirmed once triggered
+    pub shutdown: Shutdown,
+    pub simulate_before_send: bool,
+    pub jito: Option<JitoConfig>,
+    pub memo: bool,
+    /// Durable nonce transactions do not expire while they are retried
+    pub nonce_account: Option<Pubkey>,
+    /// Confirmations are only polled from the primary RPC
+    pub send_rpc_clients: SendRpcClients,
+    pub preflight: bool,
+    pub preflight_commitment: Option<CommitmentLevel>,
+    pub rpc_max_retries: Option<usize>,
+    pub ws_client: Option<Arc<WebsocketClient>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PriorityFeeConfig {
+    /// 0 to 100
+    pub percentile: u8,
+    pub min_micro_lamports: u64,
+    pub max_micro_lamports: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcPrioritizationFee {
+    prioritization_fee: u64,
+}
+
+fn fee_percentile(mut fees: Vec<u64>, percentile: u8) -> Option<u64> {
+    if fees.is_empty() {
+        return None;
+    }
+    fees.sort_unstable();
+    let rank = (fees.len() * percentile.min(100) as usize + 99) / 100;
+    Some(fees[rank.saturating_sub(1)])
+}
+
+/// In micro lamports, the minimum is used when no fees were paid recently
+pub async fn estimate_priority_fee(
+    rpc_client: &Arc<RpcClient>,
+    writable_accounts: &[Pubkey],
+    config: &PriorityFeeConfig,
+) -> Result<u64, Error> {
+    let addresses = writable_accounts
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>();
+    let fees: Vec<RpcPrioritizationFee> = rpc_client
+        .send(
+            RpcRequest::Custom {
+                method: "getRecentPrioritizationFees",
+            },
+            json!([addresses]),
+        )
+        .await?;
+
+    let fee = fee_percentile(
+        fees.into_iter().map(|fee| fee.prioritization_fee).collect(),
+        config.percentile,
+    )
+    .unwrap_or(0);
+    Ok(fee.clamp(config.min_micro_lamports, config.max_micro_lamports))
+}
+
+#[derive(Debug, Clone)]
+pub struct JitoConfig {
+    pub tip_lamports: u64,
+    pub block_engine_url: Option<String>,
+    pub client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct BlockEngineResponse {
+    error: Option<serde_json::Value>,
+}
+
+async fn send_to_block_engine(
+    block_engine_url: &str,
+    client: &reqwest::Client,
+    tx: &VersionedTransaction,
+) -> Result<Signature, String> {
+    let serialized = bincode::serialize(tx).map_err(|e| e.to_string())?;
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "sendTransaction",
+        "params": [general_purpose::STANDARD.encode(serialized), { "encoding": "base64" }],
+    });
+    let res = client
+        .post(format!(
+            "{}/api/v1/transactions",
+            block_engine_url.trim_end_matches('/')
+        ))
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .json::<BlockEngineResponse>()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match res.error {
+        Some(error) => Err(error.to_string()),
+        None => Ok(tx.signatures[0]),
+    }
+}
+
+pub fn has_compute_budget_instructions(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .any(|ix| ix.program_id == compute_budget::id())
+}
+
+fn is_compute_budget_ix(ix: &Instruction, template: &Instruction) -> bool {
+    ix.program_id == compute_budget::id()
+        && ix.data.len() == template.data.len()
+        && ix.data[0] == template.data[0]
+}
+
+pub fn get_cu_price(instructions: &[Instruction]) -> Option<u64> {
+    let template = ComputeBudgetInstruction::set_compute_unit_price(0);
+    instructions
+        .iter()
+        .find(|ix| is_compute_budget_ix(ix, &template))
+        .map(|ix| u64::from_le_bytes(ix.data[1..].try_into().unwrap()))
+}
+
+/// Instruction indexes never shift, instructions without a price are left as they are
+pub fn set_cu_price(instructions: &mut [Instruction], cu_price: u64) -> bool {
+    let price_ix = ComputeBudgetInstruction::set_compute_unit_price(cu_price);
+    match instructions
+        .iter_mut()
+        .find(|ix| is_compute_budget_ix(ix, &price_ix))
+    {
+        Some(ix) => {
+            *ix = price_ix;
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn get_cu_limit(instructions: &[Instruction]) -> Option<u32> {
+    let template = ComputeBudgetInstruction::set_compute_unit_limit(0);
+    instructions
+        .iter()
+        .find(|ix| is_compute_budget_ix(ix, &template))
+        .map(|ix| u32::from_le_bytes(ix.data[1..].try_into().unwrap()))
+}
+
+/// Prepended when the instructions do not set a limit
+pub fn set_cu_limit(mut instructions: Vec<Instruction>, cu_limit: u32) -> Vec<Instruction> {
+    let limit_ix = ComputeBudgetInstruction::set_compute_unit_limit(cu_limit);
+    match instructions
+        .iter_mut()
+        .find(|ix| is_compute_budget_ix(ix, &limit_ix))
+    {
+        Some(ix) => *ix = limit_ix,
+        None => instructions.insert(0, limit_ix),
+    }
+    instructions
+}
+
+/// Sets the limit only when it is above the limit the instructions already set, so a
+/// limit measured for the transaction never lowers the limit Jupiter picked for a swap
+pub fn raise_cu_limit(instructions: Vec<Instruction>, cu_limit: u32) -> Vec<Instruction> {
+    match get_cu_limit(&instructions) {
+        Some(current) if current >= cu_limit => instructions,
+        _ => set_cu_limit(instructions, cu_limit),
+    }
+}
+
+/// Keyed by the flow step, so repeated steps are not simulated every time
+#[derive(Debug, Default)]
+pub struct CuLimitCache {
+    limits: Mutex<HashMap<String, u32>>,
+}
+
+impl CuLimitCache {
+    pub fn get(&self, step: &str) -> Option<u32> {
+        self.limits.lock().unwrap().get(step).copied()
+    }
+
+    pub fn insert(&self, step: &str, cu_limit: u32) {
+        self.limits
+            .lock()
+            .unwrap()
+            .insert(step.to_string(), cu_limit);
+    }
+
+    pub fn invalidate(&self, step: &str) {
+        self.limits.lock().unwrap().remove(step);
+    }
+}
+
+#[derive(Debug)]
+pub struct Simulation {
+    pub err: Option<TransactionError>,
+    pub units_consumed: Option<u64>,
+    pub logs: Vec<String>,
+}
+
+pub async fn simulate(
+    rpc_client: &Arc<RpcClient>,
+    tx: &VersionedTransaction,
+) -> Result<Simulation, Error> {
+    let res = rpc_client
+        .simulate_transaction_with_config(
+            tx,
+            RpcSimulateTransactionConfig {
+                sig_verify: false,
+                replace_recent_blockhash: true,
+                commitment: Some(rpc_client.commitment()),
+                encoding: Some(UiTransactionEncoding::Base64),
+                ..Default::default()
+            },
+        )
+        .await?
+        .value;
+
+    Ok(Simulation {
+        err: res.err,
+        units_consumed: res.units_consumed,
+        logs: res.logs.unwrap_or_default(),
+    })
+}
+
+pub async fn simulate_units_consumed(
+    rpc_client: &Arc<RpcClient>,
+    tx: &VersionedTransaction,
+) -> Result<Option<u64>, Error> {
+    let simulation = simulate(rpc_client, tx).await?;
+
+    if let Some(e) = simulation.err {
+        warn!("Unable to simulate compute units: {:?}", e);
+        return Ok(None);
+    }
+    Ok(simulation.units_consumed)
+}
+
+#[derive(Debug, Clone)]
+pub struct FailureReport {
+    pub instruction_index: Option<u8>,
+    /// Innermost program, the CPI callee when the error came from one
+    pub failed_program_id: Option<Pubkey>,
+    pub error_code: Option<u32>,
+    pub error_name: Option<String>,
+    pub error_message: Option<String>,
+    pub logs: Vec<String>,
+}
+
+impl fmt::Display for FailureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(index) = self.instruction_index {
+            write!(f, "instruction {}", index)?;
+        }
+        if let Some(program_id) = self.failed_program_id {
+            write!(f, " in program {}", program_id)?;
+        }
+        match (&self.error_name, self.error_code) {
+            (Some(name), Some(code)) => write!(f, ": {} ({})", name, code)?,
+            (Some(name), None) => write!(f, ": {}", name)?,
+            (None, Some(code)) => write!(f, ": custom program error {}", code)?,
+            (None, None) => {}
+        }
+        if let Some(message) = &self.error_message {
+            write!(f, " - {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_anchor_error(logs: &[String]) -> Option<(String, u32, String)> {
+    logs.iter().rev().find_map(|log| {
+        let (_, error) = log.split_once("AnchorError")?;
+        let (_, error) = error.split_once("Error Code: ")?;
+        let (name, error) = error.split_once(". Error Number: ")?;
+        let (number, message) = error.split_once(". Error Message: ")?;
+        Some((
+            name.to_string(),
+            number.parse().ok()?,
+            message.trim_end_matches('.').to_string(),
+        ))
+    })
+}
+
+/// Programs log their failure from the innermost outwards
+fn parse_failed_program_id(logs: &[String]) -> Option<Pubkey> {
+    logs.iter().find_map(|log| {
+        let (program, _) = log.strip_prefix("Program ")?.split_once(" failed: ")?;
+        Pubkey::from_str(program).ok()
+    })
+}
+
+/// For failures whose logs were truncated before the Anchor error
+fn known_program_error(program_id: &Pubkey, code: u32) -> Option<(String, String)> {
+    use marginfi::errors::MarginfiError;
+    use meteora::error::PoolError;
+
+    if *program_id == marginfi::id() {
+        [
+            MarginfiError::BadAccountHealth,
+            MarginfiError::BankAssetCapacityExceeded,
+            MarginfiError::BankLiabilityCapacityExceeded,
+            MarginfiError::StaleOracle,
+            MarginfiError::MissingPythOrBankAccount,
+            MarginfiError::LendingAccountBalanceNotFound,
+            MarginfiError::AccountInFlashloan,
+            MarginfiError::IllegalFlashloan,
+        ]
+        .into_iter()
+        .find(|error| u32::from(*error) == code)
+        .map(|error| (error.name(), error.to_string()))
+    } else if *program_id == meteora::id() {
+        [
+            PoolError::ExceededSlippage,
+            PoolError::PoolDisabled,
+            PoolError::MathOverflow,
+        ]
+        .into_iter()
+        .find(|error| u32::from(*error) == code)
+        .map(|error| (error.name(), error.to_string()))
+    } else {
+        None
+    }
+}
+
+pub fn explain_failure_logs(error: &TransactionError, logs: Vec<String>) -> FailureReport {
+    let (instruction_index, custom_code) = match error {
+        TransactionError::InstructionError(index, InstructionError::Custom(code)) => {
+            (Some(*index), Some(*code))
+        }
+        TransactionError::InstructionError(index, _) => (Some(*index), None),
+        _ => (None, None),
+    };
+    let failed_program_id = parse_failed_program_id(&logs);
+
+    let (error_code, error_name, error_message) = match parse_anchor_error(&logs) {
+        Some((name, number, message)) => (Some(number), Some(name), Some(message)),
+        None => {
+            let known = failed_program_id
+                .zip(custom_code)
+                .and_then(|(program_id, code)| known_program_error(&program_id, code));
+            match known {
+                Some((name, message)) => (custom_code, Some(name), Some(message)),
+                None => (custom_code, None, None),
+            }
+        }
+    };
+
+    FailureReport {
+        instruction_index,
+        failed_program_id,
+        error_code,
+        error_name,
+        error_message,
+        logs,
+    }
+}
+
+pub fn explain_failure(meta: &UiTransactionStatusMeta) -> Option<FailureReport> {
+    let error = meta.err.as_ref()?;
+    let logs = match &meta.log_messages {
+        OptionSerializer::Some(logs) => logs.clone(),
+        _ => vec![],
+    };
+    Some(explain_failure_logs(error, logs))
+}
+
+pub fn log_failure_report(report: &FailureReport) {
+    report.logs.iter().for_each(|log| error!("  {}", log));
+    error!("Failed {}", report);
+}
+
+pub fn get_failed_program_id(
+    tx: &VersionedTransaction,
+    error: &TransactionError,
+) -> Option<Pubkey> {
+    let TransactionError::InstructionError(index, _) = error else {
+        return None;
+    };
+    tx.message
+        .instructions()
+        .get(*index as usize)
+        .and_then(|ix| {
+            tx.message
+                .static_account_keys()
+                .get(ix.program_id_index as usize)
+        })
+        .copied()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TransactionLifetime {
+    Blockhash {
+        blockhash: Hash,
+        last_valid_block_height: u64,
+    },
+    Nonce {
+        account: Pubkey,
+        nonce: Hash,
+    },
+}
+
+impl TransactionLifetime {
+    /// An expired transaction can still have landed
+    pub async fn is_expired(&self, rpc_client: &Arc<RpcClient>) -> Result<bool, Error> {
+        match self {
+            Self::Blockhash {
+                last_valid_block_height,
+                ..
+            } => Ok(rpc_client.get_block_height().await? > *last_valid_block_height),
+            Self::Nonce { account, nonce } => {
+                Ok(nonce::fetch_nonce(rpc_client, account).await? != Some(*nonce))
+            }
+        }
+    }
+}
 
 pub enum TransactionResult {
     Success(Signature, UiTransactionStatusMeta),
-    Error(Signature, TransactionError),
+    Error(Signature, TransactionError, FailureReport),
     Timeout(Signature),
+    Expired(Signature),
 }
 
-pub async fn send_and_confirm_transaction(
-    rpc_client: &Arc<RpcClient>,
+#[derive(Clone, Default)]
+pub struct SendRpcClients(pub Vec<Arc<RpcClient>>);
+
+impl fmt::Debug for SendRpcClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|client| client.url()))
+            .finish()
+    }
+}
+
+/// So a hung endpoint does not hold up the send
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn parse_preflight_failure(e: &ClientError) -> Option<(TransactionError, Vec<String>)> {
+    let ClientErrorKind::RpcError(RpcError::RpcResponseError {
+        data: RpcResponseErrorData::SendTransactionPreflightFailure(simulation),
+        ..
+    }) = e.kind()
+    else {
+        return None;
+    };
+    Some((
+        simulation.err.clone()?,
+        simulation.logs.clone().unwrap_or_default(),
+    ))
+}
+
+/// A transaction rebuilt with an older blockhash can pass these
+pub fn is_stale_blockhash_error(e: &ClientError) -> bool {
+    if let Some((TransactionError::BlockhashNotFound, _)) = parse_preflight_failure(e) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY
+                || *code == JSON_RPC_SERVER_ERROR_MIN_CONTEXT_SLOT_NOT_REACHED
+    )
+}
+
+fn explain_preflight_failure(tx: &VersionedTransaction, e: &ClientError) -> Option<Error> {
+    let (error, logs) = parse_preflight_failure(e)?;
+    let report = explain_failure_logs(&error, logs);
+    Some(Error::SimulationFailed {
+        program_id: get_failed_program_id(tx, &error),
+        error,
+        report: Box::new(report),
+    })
+}
+
+fn classify_send_error(tx: &VersionedTransaction, e: ClientError) -> Error {
+    if is_stale_blockhash_error(&e) {
+        return Error::StaleBlockhash(Box::new(e));
+    }
+    explain_preflight_failure(tx, &e).unwrap_or_else(|| e.into())
+}
+
+async fn send_to_rpc(
+    rpc_client: &RpcClient,
     tx: &VersionedTransaction,
-) -> Result<TransactionResult, Error> {
-    let signature = rpc_client
+    send_config: &SendConfig,
+) -> Result<Signature, ClientError> {
+    let res = rpc_client
         .send_transaction_with_config(
             tx,
             RpcSendTransactionConfig {
-                skip_preflight: true,
-                max_retries: Some(20),
+                skip_preflight: !send_config.preflight,
+                preflight_commitment: send_config.preflight_commitment,
+                max_retries: send_config.rpc_max_retries,
                 ..Default::default()
             },
         )
-        .await?;
-    println!("Sent transaction: {}", signature);
+        .await;
+
+    match res {
+        // A copy of the transaction was processed already, it is confirmed like a new send
+        Err(e)
+            if matches!(
+                parse_preflight_failure(&e),
+                Some((TransactionError::AlreadyProcessed, _))
+            ) =>
+        {
+            Ok(tx.signatures[0])
+        }
+        res => res,
+    }
+}
+
+/// (error, endpoints which returned it)
+type BroadcastErrors = Vec<(String, Vec<String>)>;
+
+fn add_broadcast_error(errors: &mut BroadcastErrors, url: String, error: String) {
+    match errors.iter_mut().find(|(e, _)| e == &error) {
+        Some((_, urls)) => urls.push(url),
+        None => errors.push((error, vec![url])),
+    }
+}
+
+fn log_broadcast_errors(errors: &BroadcastErrors) {
+    for (error, urls) in errors {
+        warn!(
+            "Unable to send transaction to {}: {}",
+            urls.join(", "),
+            error
+        );
+    }
+}
+
+/// The first endpoint to accept the transaction wins, when all of them reject it a
+/// failed preflight is returned before a stale blockhash
+async fn broadcast_transaction(
+    rpc_client: &Arc<RpcClient>,
+    tx: &VersionedTransaction,
+    send_config: &SendConfig,
+) -> Result<Signature, Error> {
+    if send_config.send_rpc_clients.0.is_empty() {
+        return send_to_rpc(rpc_client, tx, send_config)
+            .await
+            .map_err(|e| classify_send_error(tx, e));
+    }
+
+    let mut sends = [rpc_client]
+        .into_iter()
+        .chain(send_config.send_rpc_clients.0.iter())
+        .map(|client| {
+            let client = client.clone();
+            let tx = tx.clone();
+            let send_config = send_config.clone();
+            tokio::spawn(async move {
+                match timeout(BROADCAST_TIMEOUT, send_to_rpc(&client, &tx, &send_config)).await {
+                    Ok(Ok(signature)) => Ok(signature),
+                    Ok(Err(e)) => Err((client.url(), e)),
+                    Err(_) => Err((
+                        client.url(),
+                        ClientErrorKind::Custom("timed out".to_string()).into(),
+                    )),
+                }
+            })
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let mut errors: BroadcastErrors = vec![];
+    let mut cause = None;
+    loop {
+        let Some(res) = sends.next().await else {
+            break;
+        };
+        match res {
+            Ok(Ok(signature)) => {
+                tokio::spawn(async move {
+                    while let Some(res) = sends.next().await {
+                        if let Ok(Err((url, e))) = res {
+                            add_broadcast_error(&mut errors, url, e.to_string());
+                        }
+                    }
+                    log_broadcast_errors(&errors);
+                });
+                return Ok(signature);
+            }
+            Ok(Err((url, e))) => {
+                add_broadcast_error(&mut errors, url, e.to_string());
+                match classify_send_error(tx, e) {
+                    e @ Error::SimulationFailed { .. }
+                        if !matches!(cause, Some(Error::SimulationFailed { .. })) =>
+                    {
+                        cause = Some(e)
+                    }
+                    e @ Error::StaleBlockhash(_) if cause.is_none() => cause = Some(e),
+                    _ => {}
+                }
+            }
+            Err(e) => add_broadcast_error(&mut errors, "unknown".to_string(), e.to_string()),
+        }
+    }
+
+    log_broadcast_errors(&errors);
+    if let Some(e) = cause {
+        return Err(e);
+    }
+    Err(Error::BroadcastFailed(
+        errors.into_iter().map(|(error, _)| error).collect(),
+    ))
+}
+
+/// Falls back to the RPC when the block engine rejects the transaction
+pub async fn send_and_confirm_transaction(
+    rpc_client: &Arc<RpcClient>,
+    tx: &VersionedTransaction,
+    lifetime: &TransactionLifetime,
+    send_config: &SendConfig,
+) -> Result<TransactionResult, Error> {
+    let block_engine_signature = match &send_config.jito {
+        Some(JitoConfig {
+            block_engine_url: Some(url),
+            client,
+            ..
+        }) => match send_to_block_engine(url, client, tx).await {
+            Ok(signature) => Some(signature),
+            Err(e) => {
+                warn!(
+                    "Block engine did not accept the transaction, sending to RPC: {}",
+                    e
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+    let signature = match block_engine_signature {
+        Some(signature) => signature,
+        None => broadcast_transaction(rpc_client, tx, send_config).await?,
+    };
+    info!("Sent transaction: {}", signature);
+    wait_for_transaction(rpc_client, signature, lifetime, send_config).await
+}
+
+const SIGNATURE_SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The status is polled next to the subscription, so a dropped websocket does not hold
+/// up the confirmation
+pub async fn wait_for_transaction(
+    rpc_client: &Arc<RpcClient>,
+    signature: Signature,
+    lifetime: &TransactionLifetime,
+    send_config: &SendConfig,
+) -> Result<TransactionResult, Error> {
+    let Some(ws_client) = &send_config.ws_client else {
+        return poll_transaction(rpc_client, signature, lifetime, send_config).await;
+    };
+    let config = RpcSignatureSubscribeConfig {
+        commitment: Some(send_config.commitment_policy.confirmation),
+        enable_received_notification: Some(false),
+    };
+    let (subscription_id, mut notifications) = match timeout(
+        SIGNATURE_SUBSCRIBE_TIMEOUT,
+        ws_client.signature_subscribe(signature, config),
+    )
+    .await
+    {
+        Ok(Ok(subscription)) => subscription,
+        Ok(Err(e)) => {
+            debug!("Unable to subscribe to signature {}: {}", signature, e);
+            return poll_transaction(rpc_client, signature, lifetime, send_config).await;
+        }
+        Err(_) => {
+            debug!("Subscription to signature {} timed out", signature);
+            return poll_transaction(rpc_client, signature, lifetime, send_config).await;
+        }
+    };
+
+    let res = tokio::select! {
+        Some(_) = notifications.next() => {
+            fetch_transaction_result(rpc_client, signature, send_config).await
+        }
+        res = poll_transaction(rpc_client, signature, lifetime, send_config) => res,
+    };
+    ws_client.unsubscribe(subscription_id).await;
+    res
+}
+
+/// Returns `Expired` as soon as the cluster has not seen the transaction and its
+/// `lifetime` expired
+async fn poll_transaction(
+    rpc_client: &Arc<RpcClient>,
+    signature: Signature,
+    lifetime: &TransactionLifetime,
+    send_config: &SendConfig,
+) -> Result<TransactionResult, Error> {
     let start = Instant::now();
 
     loop {
-        if start.elapsed().as_secs() > TX_VALIDITY_DURATION {
-            break Ok(TransactionResult::Timeout(signature));
+        sleep(send_config.poll_interval).await;
+        let status = rpc_client
+            .get_signature_statuses(&[signature])
+            .await?
+            .value
+            .pop()
+            .flatten();
+
+        match status {
+            Some(status)
+                if status.err.is_some()
+                    || status.satisfies_commitment(send_config.commitment_policy.confirmation) =>
+            {
+                return fetch_transaction_result(rpc_client, signature, send_config).await;
+            }
+            Some(_) => {}
+            None => {
+                if lifetime.is_expired(rpc_client).await? {
+                    return Ok(TransactionResult::Expired(signature));
+                }
+            }
         }
 
-        sleep(POLL_TIMEOUT).await;
+        if start.elapsed() > send_config.max_confirmation_wait {
+            return Ok(TransactionResult::Timeout(signature));
+        }
+    }
+}
+
+/// RPC nodes can serve the status a moment before the transaction
+const TX_FETCH_ATTEMPTS: u32 = 5;
+
+async fn fetch_transaction_result(
+    rpc_client: &Arc<RpcClient>,
+    signature: Signature,
+    send_config: &SendConfig,
+) -> Result<TransactionResult, Error> {
+    let mut attempt = 1;
+
+    loop {
         let res = rpc_client
             .get_transaction_with_config(
                 &signature,
                 RpcTransactionConfig {
                     encoding: Some(UiTransactionEncoding::Base64),
-                    commitment: Some(CommitmentConfig::confirmed()),
+                    commitment: Some(send_config.commitment_policy.confirmation),
                     max_supported_transaction_version: Some(0),
                 },
             )
             .await;
 
         match res {
-            Err(e) => match e.kind {
-                ClientErrorKind::SerdeJson(_) => {}
-                _ => Err(e)?,
-            },
             Ok(res) => {
                 let meta = res.transaction.meta.ok_or(Error::TransactionError)?;
 
-                if let Some(e) = meta.err {
-                    return Ok(TransactionResult::Error(signature, e));
+                if let (Some(e), Some(report)) = (meta.err.clone(), explain_failure(&meta)) {
+                    return Ok(TransactionResult::Error(signature, e, report));
                 } else {
                     return Ok(TransactionResult::Success(signature, meta));
                 }
             }
+            Err(e) if attempt < TX_FETCH_ATTEMPTS => {
+                debug!("Transaction {} not available yet: {}", signature, e);
+                attempt += 1;
+                sleep(send_config.poll_interval).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+pub async fn fetch_landed_transaction(
+    rpc_client: &Arc<RpcClient>,
+    signature: &Signature,
+    lifetime: &TransactionLifetime,
+    send_config: &SendConfig,
+) -> Result<Option<TransactionResult>, Error> {
+    let status = rpc_client
+        .get_signature_statuses(&[*signature])
+        .await?
+        .value
+        .pop()
+        .flatten();
+
+    match status {
+        Some(_) => Ok(Some(
+            wait_for_transaction(rpc_client, *signature, lifetime, send_config).await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use solana_sdk::instruction::AccountMeta;
+
+    use super::*;
+    use crate::testkit;
+
+    fn token_balance(account_index: u8, mint: &Pubkey, owner: &Pubkey, amount: &str) -> Value {
+        json!({
+            "accountIndex": account_index,
+            "mint": mint.to_string(),
+            "owner": owner.to_string(),
+            "uiTokenAmount": {
+                "amount": amount,
+                "decimals": 6,
+                "uiAmount": null,
+                "uiAmountString": amount,
+            },
+        })
+    }
+
+    fn new_meta(
+        pre_token_balances: Vec<Value>,
+        post_token_balances: Vec<Value>,
+    ) -> UiTransactionStatusMeta {
+        serde_json::from_value(json!({
+            "err": null,
+            "status": { "Ok": null },
+            "fee": 5000,
+            "preBalances": [],
+            "postBalances": [],
+            "preTokenBalances": pre_token_balances,
+            "postTokenBalances": post_token_balances,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn wallet_token_changes_of_created_and_closed_accounts() {
+        let wallet = testkit::new_wallet();
+        let (created, closed, changed) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let meta = new_meta(
+            vec![
+                token_balance(2, &closed, &wallet.pubkey, "40"),
+                token_balance(3, &changed, &wallet.pubkey, "100"),
+            ],
+            vec![
+                token_balance(1, &created, &wallet.pubkey, "25"),
+                token_balance(3, &changed, &wallet.pubkey, "30"),
+            ],
+        );
+
+        let mut changes = get_wallet_token_changes(&meta, &wallet).unwrap();
+        changes.sort();
+        let mut expected = vec![
+            (created.to_string(), 25),
+            (closed.to_string(), -40),
+            (changed.to_string(), -70),
+        ];
+        expected.sort();
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn wallet_token_changes_are_summed_by_mint() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let other_owner = Pubkey::new_unique();
+        let meta = new_meta(
+            vec![
+                token_balance(1, &mint, &wallet.pubkey, "10"),
+                token_balance(3, &mint, &other_owner, "500"),
+            ],
+            vec![
+                token_balance(1, &mint, &wallet.pubkey, "15"),
+                // Temporary account next to the associated one
+                token_balance(2, &mint, &wallet.pubkey, "7"),
+                token_balance(3, &mint, &other_owner, "0"),
+            ],
+        );
+
+        assert_eq!(
+            get_wallet_token_changes(&meta, &wallet).unwrap(),
+            vec![(mint.to_string(), 12)]
+        );
+    }
+
+    #[test]
+    fn unchanged_wallet_balances_are_omitted() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let meta = new_meta(
+            vec![token_balance(1, &mint, &wallet.pubkey, "10")],
+            vec![token_balance(1, &mint, &wallet.pubkey, "10")],
+        );
+
+        assert!(get_wallet_token_changes(&meta, &wallet).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_token_amount_is_an_error() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let meta = new_meta(
+            vec![token_balance(1, &mint, &wallet.pubkey, "ten")],
+            vec![token_balance(1, &mint, &wallet.pubkey, "10")],
+        );
+
+        assert!(matches!(
+            get_wallet_token_changes(&meta, &wallet),
+            Err(Error::InvalidTokenAmount(amount)) if amount == "ten"
+        ));
+    }
+
+    #[test]
+    fn created_account_balance_is_received() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let meta = new_meta(vec![], vec![token_balance(1, &mint, &wallet.pubkey, "25")]);
+
+        assert_eq!(
+            parse_token_received(&meta, &wallet, &mint).unwrap(),
+            Some(25)
+        );
+        assert!(matches!(
+            parse_token_spent(&meta, &wallet, &mint),
+            Err(Error::UnexpectedTokenChange { mint: m, change: 25 }) if m == mint
+        ));
+    }
+
+    #[test]
+    fn closed_account_balance_is_spent() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let meta = new_meta(vec![token_balance(1, &mint, &wallet.pubkey, "40")], vec![]);
+
+        assert_eq!(parse_token_spent(&meta, &wallet, &mint).unwrap(), Some(40));
+        assert!(matches!(
+            parse_token_received(&meta, &wallet, &mint),
+            Err(Error::UnexpectedTokenChange { mint: m, change: -40 }) if m == mint
+        ));
+    }
+
+    #[test]
+    fn untouched_mint_has_no_change() {
+        let wallet = testkit::new_wallet();
+        let (mint, other_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let meta = new_meta(
+            vec![token_balance(1, &other_mint, &wallet.pubkey, "10")],
+            vec![token_balance(1, &other_mint, &wallet.pubkey, "20")],
+        );
+
+        assert_eq!(parse_token_received(&meta, &wallet, &mint).unwrap(), None);
+        assert_eq!(parse_token_spent(&meta, &wallet, &mint).unwrap(), None);
+    }
+
+    #[test]
+    fn token_change_is_summed_over_accounts_of_the_same_mint() {
+        let wallet = testkit::new_wallet();
+        let mint = Pubkey::new_unique();
+        let other_owner = Pubkey::new_unique();
+        let meta = new_meta(
+            vec![
+                token_balance(1, &mint, &wallet.pubkey, "100"),
+                token_balance(4, &mint, &other_owner, "1000"),
+            ],
+            vec![
+                token_balance(1, &mint, &wallet.pubkey, "160"),
+                // Temporary wrapped SOL account next to the associated one
+                token_balance(2, &mint, &wallet.pubkey, "15"),
+                token_balance(4, &mint, &other_owner, "925"),
+            ],
+        );
+
+        let mut changes =
+            parse_transaction_token_changes_by_account(&meta, &wallet, &mint).unwrap();
+        changes.sort();
+        assert_eq!(changes, vec![(1, 60), (2, 15)]);
+        assert_eq!(
+            parse_transaction_token_change(&meta, &wallet, &mint).unwrap(),
+            Some(75)
+        );
+    }
+
+    fn sol_meta(
+        pre_balances: &[u64],
+        post_balances: &[u64],
+        loaded: &[Pubkey],
+    ) -> UiTransactionStatusMeta {
+        serde_json::from_value(json!({
+            "err": null,
+            "status": { "Ok": null },
+            "fee": 5000,
+            "preBalances": pre_balances,
+            "postBalances": post_balances,
+            "loadedAddresses": {
+                "writable": loaded.iter().map(|address| address.to_string()).collect::<Vec<_>>(),
+                "readonly": [],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sol_change_of_a_static_account_includes_the_fee() {
+        let wallet = Pubkey::new_unique();
+        let account_keys = [wallet, Pubkey::new_unique()];
+        let meta = sol_meta(&[1_000_000, 0], &[985_000, 10_000], &[]);
+
+        assert_eq!(fee_paid(&meta), 5000);
+        assert_eq!(
+            parse_transaction_sol_change(&meta, &account_keys, &wallet),
+            Some(-15_000)
+        );
+    }
+
+    #[test]
+    fn sol_change_of_a_lookup_table_account() {
+        let wallet = Pubkey::new_unique();
+        let account_keys = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let meta = sol_meta(
+            &[1_000_000, 0, 2_039_280],
+            &[995_000, 0, 4_078_560],
+            &[wallet],
+        );
+
+        assert_eq!(
+            parse_transaction_sol_change(&meta, &account_keys, &wallet),
+            Some(2_039_280)
+        );
+    }
+
+    #[test]
+    fn sol_change_of_a_missing_wallet_is_none() {
+        let account_keys = [Pubkey::new_unique()];
+        let meta = sol_meta(&[1_000_000], &[995_000], &[]);
+
+        assert_eq!(
+            parse_transaction_sol_change(&meta, &account_keys, &Pubkey::new_unique()),
+            None
+        );
+    }
+
+    #[test]
+    fn fee_percentile_is_the_nearest_rank() {
+        let fees = vec![50, 10, 40, 20, 30];
+
+        assert_eq!(fee_percentile(fees.clone(), 0), Some(10));
+        assert_eq!(fee_percentile(fees.clone(), 20), Some(10));
+        assert_eq!(fee_percentile(fees.clone(), 50), Some(30));
+        assert_eq!(fee_percentile(fees.clone(), 75), Some(40));
+        assert_eq!(fee_percentile(fees.clone(), 100), Some(50));
+        assert_eq!(fee_percentile(fees, 255), Some(50));
+    }
+
+    #[test]
+    fn fee_percentile_without_fees_is_none() {
+        assert_eq!(fee_percentile(vec![], 75), None);
+    }
+
+    #[tokio::test]
+    async fn priority_fee_without_recent_fees_is_the_minimum() {
+        let config = PriorityFeeConfig {
+            percentile: 75,
+            min_micro_lamports: 1_000,
+            max_micro_lamports: 100_000,
+        };
+
+        assert_eq!(
+            estimate_priority_fee(&new_rpc_client(), &[Pubkey::new_unique()], &config)
+                .await
+                .unwrap(),
+            1_000
+        );
+    }
+
+    fn data_instruction(program_id: &Pubkey, data_len: usize) -> Instruction {
+        Instruction::new_with_bytes(*program_id, &vec![1; data_len], vec![])
+    }
+
+    #[test]
+    fn instructions_within_the_packet_size_fit() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+
+        assert!(fits_in_transaction(
+            &payer,
+            &[data_instruction(&program_id, 1000)],
+            &[]
+        ));
+        assert!(!fits_in_transaction(
+            &payer,
+            &[data_instruction(&program_id, 1200)],
+            &[]
+        ));
+    }
+
+    #[test]
+    fn lookup_table_accounts_count_towards_the_account_limit() {
+        let payer = Pubkey::new_unique();
+        let lookup_table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: (0..70).map(|_| Pubkey::new_unique()).collect(),
+        };
+        let instruction = |accounts: usize| {
+            Instruction::new_with_bytes(
+                Pubkey::new_unique(),
+                &[],
+                lookup_table.addresses[..accounts]
+                    .iter()
+                    .map(|address| AccountMeta::new_readonly(*address, false))
+                    .collect(),
+            )
+        };
+
+        assert!(fits_in_transaction(
+            &payer,
+            &[instruction(60)],
+            &[lookup_table.clone()]
+        ));
+        assert!(!fits_in_transaction(
+            &payer,
+            &[instruction(70)],
+            &[lookup_table.clone()]
+        ));
+    }
+
+    #[test]
+    fn groups_are_packed_in_order() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let groups = (0..5)
+            .map(|i| vec![data_instruction(&program_id, 400 + i)])
+            .collect::<Vec<_>>();
+
+        let transactions = split_instructions(&payer, groups.clone(), &[], &[]).unwrap();
+        assert_eq!(
+            transactions,
+            vec![
+                [&groups[0][..], &groups[1][..]].concat(),
+                [&groups[2][..], &groups[3][..]].concat(),
+                groups[4].clone(),
+            ]
+        );
+    }
+
+    #[test]
+    fn overhead_is_counted_in_every_transaction() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let groups = (0..3)
+            .map(|_| vec![data_instruction(&program_id, 400)])
+            .collect::<Vec<_>>();
+        let overhead = [data_instruction(&program_id, 300)];
+
+        let transactions = split_instructions(&payer, groups.clone(), &[], &overhead).unwrap();
+        assert_eq!(transactions, groups);
+    }
+
+    #[test]
+    fn group_which_does_not_fit_on_its_own_is_an_error() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let groups = vec![
+            vec![data_instruction(&program_id, 100)],
+            vec![
+                data_instruction(&program_id, 700),
+                data_instruction(&program_id, 700),
+            ],
+        ];
+
+        assert!(matches!(
+            split_instructions(&payer, groups, &[], &[]),
+            Err(Error::InstructionGroupTooLarge { instructions: 2 })
+        ));
+    }
+
+    fn new_instructions(cu_limit: Option<u32>) -> Vec<Instruction> {
+        let mut instructions = vec![
+            ComputeBudgetInstruction::set_compute_unit_price(1_000),
+            Instruction::new_with_bytes(Pubkey::new_unique(), &[1], vec![]),
+        ];
+        if let Some(cu_limit) = cu_limit {
+            instructions.insert(
+                1,
+                ComputeBudgetInstruction::set_compute_unit_limit(cu_limit),
+            );
         }
+        instructions
+    }
+
+    #[test]
+    fn set_cu_limit_replaces_the_limit_instruction() {
+        let instructions = set_cu_limit(new_instructions(Some(200_000)), 300_000);
+
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(
+            instructions[1],
+            ComputeBudgetInstruction::set_compute_unit_limit(300_000)
+        );
+        assert_eq!(get_cu_price(&instructions), Some(1_000));
+    }
+
+    #[test]
+    fn set_cu_limit_prepends_a_missing_limit_instruction() {
+        let instructions = set_cu_limit(new_instructions(None), 300_000);
+
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(
+            instructions[0],
+            ComputeBudgetInstruction::set_compute_unit_limit(300_000)
+        );
+        assert_eq!(get_cu_limit(&instructions), Some(300_000));
+    }
+
+    #[test]
+    fn raise_cu_limit_keeps_a_higher_limit() {
+        let instructions = new_instructions(Some(400_000));
+
+        assert_eq!(raise_cu_limit(instructions.clone(), 300_000), instructions);
+    }
+
+    #[test]
+    fn raise_cu_limit_replaces_a_lower_limit() {
+        let instructions = raise_cu_limit(new_instructions(Some(200_000)), 300_000);
+
+        assert_eq!(get_cu_limit(&instructions), Some(300_000));
+        assert_eq!(instructions.len(), 3);
+    }
+
+    #[test]
+    fn invalidated_cu_limit_is_measured_again() {
+        let cache = CuLimitCache::default();
+        cache.insert("borrow", 300_000);
+        assert_eq!(cache.get("borrow"), Some(300_000));
+
+        cache.invalidate("borrow");
+        assert_eq!(cache.get("borrow"), None);
+    }
+
+    #[test]
+    fn failure_is_explained_from_the_anchor_error_log() {
+        let program_id = marginfi::id();
+        let logs = vec![
+            format!("Program {} invoke [1]", program_id),
+            "Program log: AnchorError thrown in programs/marginfi/src/state/marginfi_account.rs:411. \
+             Error Code: BadAccountHealth. Error Number: 6009. Error Message: Bad account health."
+                .to_string(),
+            format!("Program {} failed: custom program error: 0x1779", program_id),
+        ];
+        let error = TransactionError::InstructionError(2, InstructionError::Custom(6009));
+
+        let report = explain_failure_logs(&error, logs.clone());
+        assert_eq!(report.instruction_index, Some(2));
+        assert_eq!(report.failed_program_id, Some(program_id));
+        assert_eq!(report.error_code, Some(6009));
+        assert_eq!(report.error_name.as_deref(), Some("BadAccountHealth"));
+        assert_eq!(report.error_message.as_deref(), Some("Bad account health"));
+        assert_eq!(report.logs, logs);
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "instruction 2 in program {}: BadAccountHealth (6009) - Bad account health",
+                program_id
+            )
+        );
+    }
+
+    #[test]
+    fn truncated_failure_is_explained_from_the_innermost_program() {
+        use meteora::error::PoolError;
+
+        let code = u32::from(PoolError::ExceededSlippage);
+        let outer_program_id = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {} invoke [1]", outer_program_id),
+            "Log truncated".to_string(),
+            format!(
+                "Program {} failed: custom program error: {:#x}",
+                meteora::id(),
+                code
+            ),
+            format!(
+                "Program {} failed: custom program error: {:#x}",
+                outer_program_id, code
+            ),
+        ];
+        let error = TransactionError::InstructionError(0, InstructionError::Custom(code));
+
+        let report = explain_failure_logs(&error, logs);
+        assert_eq!(report.failed_program_id, Some(meteora::id()));
+        assert_eq!(report.error_code, Some(code));
+        assert_eq!(report.error_name, Some(PoolError::ExceededSlippage.name()));
+        assert_eq!(
+            report.error_message,
+            Some(PoolError::ExceededSlippage.to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_failure_keeps_the_error_code() {
+        let program_id = Pubkey::new_unique();
+        let logs = vec![format!(
+            "Program {} failed: custom program error: 0x1",
+            program_id
+        )];
+        let error = TransactionError::InstructionError(1, InstructionError::Custom(1));
+
+        let report = explain_failure_logs(&error, logs);
+        assert_eq!(report.failed_program_id, Some(program_id));
+        assert_eq!(report.error_code, Some(1));
+        assert_eq!(report.error_name, None);
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "instruction 1 in program {}: custom program error 1",
+                program_id
+            )
+        );
+    }
+
+    #[test]
+    fn explain_failure_reads_the_meta_logs() {
+        assert!(explain_failure(&new_meta(vec![], vec![])).is_none());
+
+        let meta: UiTransactionStatusMeta = serde_json::from_value(json!({
+            "err": { "InstructionError": [0, "InvalidAccountData"] },
+            "status": { "Err": { "InstructionError": [0, "InvalidAccountData"] } },
+            "fee": 5000,
+            "preBalances": [],
+            "postBalances": [],
+            "logMessages": ["Program log: Error: InvalidAccountData"],
+        }))
+        .unwrap();
+
+        let report = explain_failure(&meta).unwrap();
+        assert_eq!(report.instruction_index, Some(0));
+        assert_eq!(report.error_code, None);
+        assert_eq!(report.logs, vec!["Program log: Error: InvalidAccountData"]);
+    }
+
+    fn rpc_response_error(code: i64, data: RpcResponseErrorData) -> ClientError {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code,
+            message: "rejected".to_string(),
+            data,
+        })
+        .into()
+    }
+
+    fn preflight_failure(error: TransactionError, logs: Vec<String>) -> ClientError {
+        let simulation = serde_json::from_value(json!({
+            "err": error,
+            "logs": logs,
+            "accounts": null,
+            "unitsConsumed": null,
+            "returnData": null,
+        }))
+        .unwrap();
+        rpc_response_error(
+            -32002,
+            RpcResponseErrorData::SendTransactionPreflightFailure(simulation),
+        )
+    }
+
+    #[test]
+    fn blockhash_not_found_preflight_is_a_stale_blockhash() {
+        let e = preflight_failure(TransactionError::BlockhashNotFound, vec![]);
+        let tx = sign_transaction(
+            &testkit::new_wallet(),
+            &[],
+            &new_instructions(None),
+            &[],
+            Hash::new_unique(),
+        )
+        .unwrap();
+
+        assert!(is_stale_blockhash_error(&e));
+        assert!(matches!(
+            classify_send_error(&tx, e),
+            Error::StaleBlockhash(_)
+        ));
+    }
+
+    #[test]
+    fn node_behind_errors_are_a_stale_blockhash() {
+        for code in [
+            JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+            JSON_RPC_SERVER_ERROR_MIN_CONTEXT_SLOT_NOT_REACHED,
+        ] {
+            assert!(is_stale_blockhash_error(&rpc_response_error(
+                code,
+                RpcResponseErrorData::Empty
+            )));
+        }
+    }
+
+    #[test]
+    fn failed_preflight_is_a_simulation_failure() {
+        let instructions = new_instructions(None);
+        let program_id = instructions[1].program_id;
+        let tx = sign_transaction(
+            &testkit::new_wallet(),
+            &[],
+            &instructions,
+            &[],
+            Hash::new_unique(),
+        )
+        .unwrap();
+        let e = preflight_failure(
+            TransactionError::InstructionError(1, InstructionError::Custom(6009)),
+            vec![format!(
+                "Program {} failed: custom program error: 0x1779",
+                program_id
+            )],
+        );
+
+        assert!(!is_stale_blockhash_error(&e));
+        assert!(matches!(
+            classify_send_error(&tx, e),
+            Error::SimulationFailed { program_id: Some(id), report, .. }
+                if id == program_id && report.error_code == Some(6009)
+        ));
+    }
+
+    #[test]
+    fn other_send_errors_are_rpc_errors() {
+        let tx = sign_transaction(
+            &testkit::new_wallet(),
+            &[],
+            &new_instructions(None),
+            &[],
+            Hash::new_unique(),
+        )
+        .unwrap();
+        let e = rpc_response_error(-32603, RpcResponseErrorData::Empty);
+
+        assert!(!is_stale_blockhash_error(&e));
+        assert!(matches!(classify_send_error(&tx, e), Error::RpcError(_)));
+    }
+
+    fn new_rpc_client() -> Arc<RpcClient> {
+        let mock_rpc = Arc::new(testkit::MockRpc::new(Arc::new(testkit::MockFetcher::new())));
+        Arc::new(testkit::new_mock_rpc_client(mock_rpc))
+    }
+
+    async fn send_with_result(result: Result<(), TransactionError>) -> TransactionResult {
+        let mock_rpc = Arc::new(testkit::MockRpc::new(Arc::new(testkit::MockFetcher::new())));
+        mock_rpc.push_result(result);
+        let rpc_client = Arc::new(testkit::new_mock_rpc_client(mock_rpc));
+        let instruction = Instruction::new_with_bytes(Pubkey::new_unique(), &[], vec![]);
+        let tx = build_signed_transaction(&rpc_client, &testkit::new_wallet(), &[instruction], &[])
+            .await
+            .unwrap();
+        let lifetime = TransactionLifetime::Blockhash {
+            blockhash: *tx.message.recent_blockhash(),
+            last_valid_block_height: rpc_client.get_block_height().await.unwrap(),
+        };
+
+        send_and_confirm_transaction(&rpc_client, &tx, &lifetime, &testkit::new_send_config())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn confirmed_transaction_is_a_success() {
+        assert!(matches!(
+            send_with_result(Ok(())).await,
+            TransactionResult::Success(_, meta) if meta.err.is_none()
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_transaction_is_an_error_with_a_report() {
+        let error = TransactionError::InstructionError(1, InstructionError::Custom(6009));
+
+        assert!(matches!(
+            send_with_result(Err(error.clone())).await,
+            TransactionResult::Error(_, e, report)
+                if e == error
+                    && report.instruction_index == Some(1)
+                    && report.error_code == Some(6009)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unseen_transaction_with_an_expired_blockhash_is_expired() {
+        let rpc_client = new_rpc_client();
+        let lifetime = TransactionLifetime::Blockhash {
+            blockhash: Hash::new_unique(),
+            last_valid_block_height: rpc_client.get_block_height().await.unwrap() - 1,
+        };
+
+        assert!(matches!(
+            poll_transaction(
+                &rpc_client,
+                Signature::new_unique(),
+                &lifetime,
+                &testkit::new_send_config()
+            )
+            .await
+            .unwrap(),
+            TransactionResult::Expired(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unseen_transaction_with_a_valid_blockhash_times_out() {
+        let rpc_client = new_rpc_client();
+        let lifetime = TransactionLifetime::Blockhash {
+            blockhash: Hash::new_unique(),
+            last_valid_block_height: rpc_client.get_block_height().await.unwrap(),
+        };
+        let send_config = SendConfig {
+            max_confirmation_wait: Duration::from_millis(10),
+            ..testkit::new_send_config()
+        };
+
+        assert!(matches!(
+            poll_transaction(
+                &rpc_client,
+                Signature::new_unique(),
+                &lifetime,
+                &send_config
+            )
+            .await
+            .unwrap(),
+            TransactionResult::Timeout(_)
+        ));
     }
 }
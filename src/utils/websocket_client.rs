@@ -1,20 +1,53 @@
-use std::{collections::HashMap, future::ready, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::ready,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use futures::{SinkExt, StreamExt};
+use futures::{stream::unfold, SinkExt, Stream, StreamExt};
 use futures_util::stream::BoxStream;
 use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::{json, Map, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
 use solana_client::{
-    rpc_config::RpcProgramAccountsConfig,
-    rpc_response::{Response, RpcKeyedAccount, SlotInfo},
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSignatureSubscribeConfig},
+    rpc_response::{Response, RpcKeyedAccount, RpcSignatureResult, SlotInfo},
 };
-use solana_sdk::pubkey::Pubkey;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature};
 use tokio::{
     sync::{broadcast, mpsc, Mutex},
     task::JoinHandle,
+    time::{interval, sleep, timeout},
 };
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, warn};
+
+use super::rpc_failover::get_endpoint_name;
+use crate::{metrics, shutdown::Shutdown};
+
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Time the server has to answer a ping before the connection counts as dead
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Doubled on every failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Consecutive failed attempts to connect to an endpoint before the next one is tried
+const MAX_ENDPOINT_FAILURES: u32 = 3;
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(60);
+const FAILBACK_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Notifications a consumer of a shared subscription can fall behind by before it skips
+/// them
+const SHARED_SUBSCRIPTION_CAPACITY: usize = 1024;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct RpcErrorObject {
@@ -22,14 +55,20 @@ pub(crate) struct RpcErrorObject {
     pub message: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum WebsocketError {
+    #[error("already connected")]
     AlreadyConnected,
+    #[error("not connected")]
     NotConnected,
+    #[error("subscription failed: {0}")]
     SubscriptionFailed(String),
+    #[error("connection could not be established: {0}")]
     ConnectionCouldNotBeEstablished(String),
 
+    #[error("unable to send message: {0}")]
     SendError(tokio_tungstenite::tungstenite::Error),
+    #[error("unable to parse message: {0}")]
     MessageParseError(serde_json::error::Error),
 }
 
@@ -52,6 +91,14 @@ enum SubscribeParams {
         program_id: Pubkey,
         config: RpcProgramAccountsConfig,
     },
+    Account {
+        pubkey: Pubkey,
+        config: RpcAccountInfoConfig,
+    },
+    Signature {
+        signature: Signature,
+        config: RpcSignatureSubscribeConfig,
+    },
 }
 
 impl SubscribeParams {
@@ -59,14 +106,24 @@ impl SubscribeParams {
         match method.as_str() {
             "slotNotification" => "slotUnsubscribe",
             "programNotification" => "programUnsubscribe",
+            "accountNotification" => "accountUnsubscribe",
+            "signatureNotification" => "signatureUnsubscribe",
             _ => unreachable!(),
         }
     }
 
-    pub fn build_unsubscribe_request(method: String, subscription_id: u64) -> String {
+    pub fn subscribe_into_unsub_method(method: &str) -> String {
+        method.replace("Subscribe", "Unsubscribe")
+    }
+
+    pub fn build_unsubscribe_request(
+        method: String,
+        request_id: u64,
+        subscription_id: u64,
+    ) -> String {
         json!({
             "jsonrpc": "2.0",
-            "id": 1,
+            "id": request_id,
             "method": method,
             "params": [subscription_id]
         })
@@ -92,25 +149,188 @@ impl SubscribeParams {
                     ],
                 })
             }
+            Self::Account { pubkey, config } => {
+                m = "accountSubscribe".to_string();
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": request_id,
+                    "method": m,
+                    "params": [
+                        pubkey.to_string(),
+                        config,
+                    ],
+                })
+            }
+            Self::Signature { signature, config } => {
+                m = "signatureSubscribe".to_string();
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": request_id,
+                    "method": m,
+                    "params": [
+                        signature.to_string(),
+                        config,
+                    ],
+                })
+            }
         }
         .to_string();
         (r, m)
     }
+
+    /// Keyed by the subscribe request, so account and program subscriptions with the same
+    /// params share one RPC subscription
+    fn shared_key(&self) -> Option<String> {
+        match self {
+            Self::Program { .. } | Self::Account { .. } => {
+                Some(self.build_subscribe_request_and_method(0).0)
+            }
+            Self::Slot | Self::Signature { .. } => None,
+        }
+    }
 }
 
 pub type NotificationSender = mpsc::UnboundedSender<Value>;
 pub type NotificationReceiver = mpsc::UnboundedReceiver<Value>;
+/// Subscription id, reconnect count of the connection it was confirmed on and the
+/// notifications
 pub type SubscriptionStatusSender =
-    mpsc::Sender<Result<(u64, NotificationReceiver), WebsocketError>>;
+    mpsc::Sender<Result<(u64, u64, NotificationReceiver), WebsocketError>>;
 pub type UnsubscriptionStatusSender = mpsc::Sender<()>;
 
 type SubscribeRequest = (SubscribeParams, SubscriptionStatusSender);
 pub type UnsubscribeRequest = (u64, UnsubscriptionStatusSender);
 
+#[derive(Debug, Clone)]
+pub struct AccountNotification {
+    pub pubkey: Pubkey,
+    pub slot: u64,
+    pub account: UiAccount,
+}
+
+/// Unsubscribed once the stream is dropped
 pub type SubscribeResponse<'a, T> = (u64, BoxStream<'a, T>);
 
+/// Unsubscribes when dropped, unless the connection the subscription was made on is gone
+#[derive(Debug)]
+pub struct SubscriptionGuard {
+    subscription_id: u64,
+    /// Reconnect count when the subscription was made, ids of another connection can
+    /// belong to other subscriptions
+    connection: u64,
+    reconnects: Arc<AtomicU64>,
+    unsubscribe_sender: broadcast::Sender<UnsubscribeRequest>,
+}
+
+impl SubscriptionGuard {
+    pub fn subscription_id(&self) -> u64 {
+        self.subscription_id
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        if self.reconnects.load(Ordering::Relaxed) != self.connection {
+            return;
+        }
+        // Nobody waits for the confirmation
+        let (status_sender, _) = mpsc::channel(1);
+        self.unsubscribe_sender
+            .send((self.subscription_id, status_sender))
+            .ok();
+    }
+}
+
+struct GuardedStream<'a, T> {
+    stream: BoxStream<'a, T>,
+    _guard: SubscriptionGuard,
+}
+
+impl<T> Stream for GuardedStream<'_, T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+#[derive(Debug)]
+struct SharedSubscription {
+    subscription_id: u64,
+    connection: u64,
+    notification_sender: broadcast::Sender<Value>,
+    consumers: usize,
+    _guard: SubscriptionGuard,
+}
+
+type SharedSubscriptions = Arc<StdMutex<HashMap<String, SharedSubscription>>>;
+
+struct SharedSubscriptionConsumer {
+    key: String,
+    subscription_id: u64,
+    connection: u64,
+    shared_subscriptions: SharedSubscriptions,
+}
+
+impl Drop for SharedSubscriptionConsumer {
+    fn drop(&mut self) {
+        let mut shared_subscriptions = self.shared_subscriptions.lock().unwrap();
+        let is_last = match shared_subscriptions.get_mut(&self.key) {
+            Some(shared)
+                if shared.subscription_id == self.subscription_id
+                    && shared.connection == self.connection =>
+            {
+                shared.consumers -= 1;
+                shared.consumers == 0
+            }
+            _ => false,
+        };
+        if is_last {
+            shared_subscriptions.remove(&self.key);
+        }
+    }
+}
+
+fn join_shared_subscription(
+    shared_subscriptions: &SharedSubscriptions,
+    locked: &mut HashMap<String, SharedSubscription>,
+    key: &str,
+    connection: u64,
+) -> Option<(u64, BoxStream<'static, Value>)> {
+    let shared = locked
+        .get_mut(key)
+        .filter(|shared| shared.connection == connection)?;
+    shared.consumers += 1;
+
+    let receiver = shared.notification_sender.subscribe();
+    let consumer = SharedSubscriptionConsumer {
+        key: key.to_string(),
+        subscription_id: shared.subscription_id,
+        connection,
+        shared_subscriptions: shared_subscriptions.clone(),
+    };
+    let stream = unfold(
+        (receiver, consumer),
+        |(mut receiver, consumer)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(value) => return Some((value, (receiver, consumer))),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!(
+                        "Consumer of subscription {} lagged, {} notifications skipped",
+                        consumer.subscription_id, skipped
+                    ),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Some((shared.subscription_id, stream.boxed()))
+}
+
 pub struct PendingSubscription {
     method: String,
+    /// Sent again when the connection drops before the subscription is confirmed
+    params: SubscribeParams,
     status_sender: SubscriptionStatusSender,
 }
 
@@ -120,7 +340,7 @@ pub struct ActiveSubscription {
     notification_sender: NotificationSender,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum ConnectionStatus {
     Connected,
     Reconnecting,
@@ -134,49 +354,294 @@ impl Default for ConnectionStatus {
     }
 }
 
+#[derive(Debug)]
 pub struct WebsocketClient {
-    url: String,
+    /// In order of priority
+    urls: Vec<String>,
+    /// Hosts of the urls, the full url can hold an API key
+    endpoint_names: Vec<String>,
+    active_endpoint: AtomicUsize,
+    commitment: CommitmentConfig,
     connection_status: Mutex<ConnectionStatus>,
+    /// The connection is reconnected when no subscription other than signature
+    /// subscriptions is notified for this long, `None` disables the check
+    liveness_timeout: Option<Duration>,
+    reconnects: Arc<AtomicU64>,
+    disconnects: AtomicU64,
+    active_subscriptions: AtomicUsize,
+    reconnect_sender: broadcast::Sender<u64>,
+    last_notifications: StdMutex<HashMap<&'static str, Instant>>,
 
     unsubscribe_sender: broadcast::Sender<UnsubscribeRequest>,
     subscribe_sender: broadcast::Sender<SubscribeRequest>,
     pending_backlog: Mutex<Vec<SubscribeRequest>>,
+    shared_subscriptions: SharedSubscriptions,
+    /// It can be started again once it exits
+    task_running: AtomicBool,
+    connected_before: AtomicBool,
 }
 
 impl WebsocketClient {
-    pub fn new(url: String) -> Self {
+    /// The connection fails over to the next url when an endpoint can not be reached
+    pub fn new(
+        urls: Vec<String>,
+        commitment: CommitmentConfig,
+        liveness_timeout: Option<Duration>,
+    ) -> Self {
         let (subscribe_sender, _) = broadcast::channel(100);
         let (unsubscribe_sender, _) = broadcast::channel(100);
+        let (reconnect_sender, _) = broadcast::channel(16);
 
         Self {
             connection_status: Default::default(),
             pending_backlog: Default::default(),
-            url,
+            endpoint_names: urls
+                .iter()
+                .enumerate()
+                .map(|(index, url)| get_endpoint_name(url, index))
+                .collect(),
+            urls,
+            active_endpoint: Default::default(),
+            commitment,
+            liveness_timeout,
+            reconnects: Default::default(),
+            disconnects: Default::default(),
+            active_subscriptions: Default::default(),
+            reconnect_sender,
+            last_notifications: Default::default(),
+            shared_subscriptions: Default::default(),
+            task_running: Default::default(),
+            connected_before: Default::default(),
             subscribe_sender,
             unsubscribe_sender,
         }
     }
 
+    pub fn commitment(&self) -> CommitmentConfig {
+        self.commitment
+    }
+
+    /// Connections established again after the first one
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnects(&self) -> u64 {
+        self.disconnects.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(test, feature = "testkit"))]
+    pub async fn is_connected(&self) -> bool {
+        *self.connection_status.lock().await == ConnectionStatus::Connected
+    }
+
+    fn fail_over(&self, next: usize) -> usize {
+        let previous = self.active_endpoint.swap(next, Ordering::Relaxed);
+        if previous != next {
+            warn!(
+                "Ws failover from {} to {}",
+                self.endpoint_names[previous], self.endpoint_names[next]
+            );
+            metrics::inc_counter(
+                metrics::WEBSOCKET_FAILOVERS,
+                &[("endpoint", &self.endpoint_names[next])],
+            );
+        }
+        next
+    }
+
+    pub fn active_subscriptions(&self) -> usize {
+        self.active_subscriptions.load(Ordering::Relaxed)
+    }
+
+    fn set_active_subscriptions(&self, count: usize) {
+        self.active_subscriptions.store(count, Ordering::Relaxed);
+        metrics::set_gauge(metrics::WEBSOCKET_SUBSCRIPTIONS, &[], count as f64);
+    }
+
+    /// Subscriptions made before a reconnect have ended and notifications in between are
+    /// lost, so subscribers resubscribe and refetch what they watch
+    pub fn reconnect_events(&self) -> broadcast::Receiver<u64> {
+        self.reconnect_sender.subscribe()
+    }
+
+    pub fn record_notification(&self, name: &'static str) {
+        self.last_notifications
+            .lock()
+            .unwrap()
+            .insert(name, Instant::now());
+    }
+
+    pub fn last_notification(&self, name: &str) -> Option<Instant> {
+        self.last_notifications.lock().unwrap().get(name).copied()
+    }
+
+    pub fn last_notifications(&self) -> Vec<(&'static str, Instant)> {
+        self.last_notifications
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, at)| (*name, *at))
+            .collect()
+    }
+
+    /// Encoding and commitment the config leaves unset are base64 and the commitment of
+    /// the client
+    fn with_defaults(&self, mut config: RpcAccountInfoConfig) -> RpcAccountInfoConfig {
+        config.encoding.get_or_insert(UiAccountEncoding::Base64);
+        config.commitment.get_or_insert(self.commitment);
+        config
+    }
+
     pub async fn program_subscribe(
         &self,
         program_id: Pubkey,
-        config: RpcProgramAccountsConfig,
-    ) -> Result<SubscribeResponse<Response<RpcKeyedAccount>>, WebsocketError> {
-        self.subscribe(SubscribeParams::Program { program_id, config })
-            .await
+        mut config: RpcProgramAccountsConfig,
+    ) -> Result<SubscribeResponse<AccountNotification>, WebsocketError> {
+        config.account_config = self.with_defaults(config.account_config);
+        let (subscription_id, stream) = self
+            .subscribe::<Response<RpcKeyedAccount>>(SubscribeParams::Program { program_id, config })
+            .await?;
+
+        let stream =
+            stream.filter_map(|response| {
+                ready(Pubkey::from_str(&response.value.pubkey).ok().map(|pubkey| {
+                    AccountNotification {
+                        pubkey,
+                        slot: response.context.slot,
+                        account: response.value.account,
+                    }
+                }))
+            });
+        Ok((subscription_id, stream.boxed()))
+    }
+
+    pub async fn account_subscribe(
+        &self,
+        pubkey: Pubkey,
+        config: RpcAccountInfoConfig,
+    ) -> Result<SubscribeResponse<AccountNotification>, WebsocketError> {
+        let config = self.with_defaults(config);
+        let (subscription_id, stream) = self
+            .subscribe::<Response<UiAccount>>(SubscribeParams::Account { pubkey, config })
+            .await?;
+
+        let stream = stream.map(move |response| AccountNotification {
+            pubkey,
+            slot: response.context.slot,
+            account: response.value,
+        });
+        Ok((subscription_id, stream.boxed()))
     }
 
     pub async fn slot_subscribe(&self) -> Result<SubscribeResponse<SlotInfo>, WebsocketError> {
         self.subscribe(SubscribeParams::Slot).await
     }
 
+    /// The RPC removes the subscription after the notification
+    pub async fn signature_subscribe(
+        &self,
+        signature: Signature,
+        config: RpcSignatureSubscribeConfig,
+    ) -> Result<SubscribeResponse<Response<RpcSignatureResult>>, WebsocketError> {
+        self.subscribe(SubscribeParams::Signature { signature, config })
+            .await
+    }
+
     async fn subscribe<'a, T: DeserializeOwned + Send + 'a>(
         &self,
         params: SubscribeParams,
     ) -> Result<(u64, BoxStream<'a, T>), WebsocketError> {
+        let (subscription_id, notifications) = match params.shared_key() {
+            Some(key) => self.subscribe_shared(key, params).await?,
+            None => {
+                let (subscription_id, receiver, guard) = self.subscribe_upstream(params).await?;
+                let stream = GuardedStream {
+                    stream: UnboundedReceiverStream::new(receiver).boxed(),
+                    _guard: guard,
+                };
+                (subscription_id, stream.boxed())
+            }
+        };
+
+        let stream = notifications
+            .filter_map(|value| ready(serde_json::from_value::<T>(value).ok()))
+            .boxed();
+        Ok((subscription_id, stream))
+    }
+
+    async fn subscribe_shared(
+        &self,
+        key: String,
+        params: SubscribeParams,
+    ) -> Result<(u64, BoxStream<'static, Value>), WebsocketError> {
+        let shared_subscriptions = &self.shared_subscriptions;
+        // The lock is released before awaiting the subscription
+        let joined = join_shared_subscription(
+            shared_subscriptions,
+            &mut shared_subscriptions.lock().unwrap(),
+            &key,
+            self.reconnects(),
+        );
+        if let Some(joined) = joined {
+            return Ok(joined);
+        }
+
+        let (subscription_id, mut receiver, guard) = self.subscribe_upstream(params).await?;
+        let connection = guard.connection;
+        let (notification_sender, _) = broadcast::channel(SHARED_SUBSCRIPTION_CAPACITY);
+
+        let mut locked = shared_subscriptions.lock().unwrap();
+        // Another subscriber made the same subscription meanwhile, the guard unsubscribes
+        // this one
+        if let Some(joined) =
+            join_shared_subscription(shared_subscriptions, &mut locked, &key, connection)
+        {
+            return Ok(joined);
+        }
+        locked.insert(
+            key.clone(),
+            SharedSubscription {
+                subscription_id,
+                connection,
+                notification_sender: notification_sender.clone(),
+                consumers: 0,
+                _guard: guard,
+            },
+        );
+        let joined =
+            join_shared_subscription(shared_subscriptions, &mut locked, &key, connection).unwrap();
+        drop(locked);
+
+        let shared_subscriptions = shared_subscriptions.clone();
+        tokio::spawn(async move {
+            while let Some(value) = receiver.recv().await {
+                // Fails only while no consumer is subscribed
+                notification_sender.send(value).ok();
+            }
+
+            // The subscription ended with its connection, dropping the last sender ends
+            // the streams of the consumers
+            let mut shared_subscriptions = shared_subscriptions.lock().unwrap();
+            if shared_subscriptions.get(&key).is_some_and(|shared| {
+                shared.subscription_id == subscription_id && shared.connection == connection
+            }) {
+                shared_subscriptions.remove(&key);
+            }
+        });
+
+        Ok(joined)
+    }
+
+    async fn subscribe_upstream(
+        &self,
+        params: SubscribeParams,
+    ) -> Result<(u64, NotificationReceiver, SubscriptionGuard), WebsocketError> {
         let status = self.connection_status.lock().await.clone();
 
-        let (subscription_id, receiver) = match status {
+        // The connection is the one which confirmed the subscription, a reconnect can
+        // happen while the confirmation is awaited
+        let (subscription_id, connection, receiver) = match status {
             ConnectionStatus::Disconnected => {
                 return Err(WebsocketError::NotConnected);
             }
@@ -195,13 +660,17 @@ impl WebsocketClient {
             }
         };
 
-        let stream = UnboundedReceiverStream::new(receiver)
-            .filter_map(|value| ready(serde_json::from_value::<T>(value).ok()))
-            .boxed();
-
-        Ok((subscription_id, stream))
+        let guard = SubscriptionGuard {
+            subscription_id,
+            connection,
+            reconnects: self.reconnects.clone(),
+            unsubscribe_sender: self.unsubscribe_sender.clone(),
+        };
+        Ok((subscription_id, receiver, guard))
     }
 
+    /// Dropping the stream unsubscribes without waiting, a shared subscription ends for every
+    /// consumer
     pub async fn unsubscribe(&self, subscription_id: u64) {
         let status = self.connection_status.lock().await.clone();
 
@@ -221,16 +690,60 @@ impl WebsocketClient {
     }
 }
 
-pub async fn create_persisted_websocket_connection(
+/// Every reason other than the shutdown reconnects
+#[derive(Debug, thiserror::Error)]
+enum Disconnect {
+    #[error("shutdown")]
+    Shutdown,
+    #[error("closed by the server")]
+    Closed,
+    #[error("no pong within {0:?}")]
+    PongTimeout(Duration),
+    #[error("no notification within {0:?}")]
+    Stale(Duration),
+    #[error("failing back to the primary endpoint")]
+    Failback,
+    #[error("{0}")]
+    Error(WebsocketError),
+}
+
+impl Disconnect {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::Shutdown => "shutdown",
+            Self::Closed => "closed",
+            Self::PongTimeout(_) => "pong_timeout",
+            Self::Stale(_) => "stale",
+            Self::Failback => "failback",
+            Self::Error(_) => "error",
+        }
+    }
+}
+
+/// Marks the connection task as stopped when it exits or panics
+struct ConnectionTaskGuard(Arc<WebsocketClient>);
+
+impl Drop for ConnectionTaskGuard {
+    fn drop(&mut self) {
+        self.0.task_running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Endpoints are tried in order of priority, the next one after repeated failures to
+/// connect, and the primary one is reconnected to once it is reachable again
+///
+/// Streams of active subscriptions end on a reconnect, subscriptions which were not
+/// confirmed yet are sent again
+pub fn create_persisted_websocket_connection(
     client: Arc<WebsocketClient>,
+    shutdown: Shutdown,
 ) -> Result<JoinHandle<Result<(), WebsocketError>>, WebsocketError> {
-    let status = client.connection_status.lock().await;
-    if *status == ConnectionStatus::Connected || *status == ConnectionStatus::Reconnecting {
+    if client.task_running.swap(true, Ordering::Relaxed) {
         return Err(WebsocketError::AlreadyConnected);
     }
-    drop(status);
 
     let handle: JoinHandle<Result<(), WebsocketError>> = tokio::spawn(async move {
+        let _task_running = ConnectionTaskGuard(client.clone());
         type RequestId = u64;
         type SubscriptionId = u64;
 
@@ -239,15 +752,96 @@ pub async fn create_persisted_websocket_connection(
 
         let mut subscribe_receiver = client.subscribe_sender.subscribe();
         let mut unsubscribe_receiver = client.unsubscribe_sender.subscribe();
+        // A restarted connection task reconnects
+        let mut connected_before = client.connected_before.load(Ordering::Relaxed);
+        if connected_before {
+            *client.connection_status.lock().await = ConnectionStatus::Reconnecting;
+        }
+        let mut endpoint = client.active_endpoint.load(Ordering::Relaxed);
+        let mut endpoint_failures: u32 = 0;
+        // Connection to the primary endpoint made while connected to a fallback
+        let mut failback_ws = None;
 
         loop {
-            println!("Connecting to ws");
             let mut conn_status = client.connection_status.lock().await;
-            let (mut ws, _response) = connect_async(&client.url)
-                .await
-                .map_err(|e| WebsocketError::ConnectionCouldNotBeEstablished(e.to_string()))?;
+            let connection = match failback_ws.take() {
+                Some(ws) => Ok(ws),
+                None => {
+                    debug!("Connecting to ws {}", client.endpoint_names[endpoint]);
+                    connect_async(&client.urls[endpoint])
+                        .await
+                        .map(|(ws, _response)| ws)
+                }
+            };
+            let mut ws = match connection {
+                Ok(ws) => ws,
+                // Later connections are retried until an endpoint is back, with a delay
+                // which grows with the failures of the endpoint
+                Err(e) if connected_before => {
+                    drop(conn_status);
+                    endpoint_failures += 1;
+                    if endpoint_failures >= MAX_ENDPOINT_FAILURES && client.urls.len() > 1 {
+                        warn!(
+                            "Unable to reconnect ws {} after {} attempts: {}",
+                            client.endpoint_names[endpoint], endpoint_failures, e
+                        );
+                        endpoint = client.fail_over((endpoint + 1) % client.urls.len());
+                        endpoint_failures = 0;
+                        continue;
+                    }
+
+                    let delay = RECONNECT_DELAY
+                        .saturating_mul(1 << (endpoint_failures - 1).min(16))
+                        .min(MAX_RECONNECT_DELAY);
+                    warn!(
+                        "Unable to reconnect ws {}, retrying in {:?}: {}",
+                        client.endpoint_names[endpoint], delay, e
+                    );
+                    tokio::select! {
+                        _ = sleep(delay) => continue,
+                        _ = shutdown.triggered() => return Ok(()),
+                    }
+                }
+                // The first connection has to succeed on one of the endpoints
+                Err(e) if endpoint + 1 < client.urls.len() => {
+                    drop(conn_status);
+                    warn!(
+                        "Unable to connect ws {}: {}",
+                        client.endpoint_names[endpoint], e
+                    );
+                    endpoint = client.fail_over(endpoint + 1);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(WebsocketError::ConnectionCouldNotBeEstablished(
+                        e.to_string(),
+                    ))
+                }
+            };
+            endpoint_failures = 0;
             *conn_status = ConnectionStatus::Connected;
             drop(conn_status);
+            if connected_before {
+                let reconnects = client.reconnects.fetch_add(1, Ordering::Relaxed) + 1;
+                metrics::inc_counter(metrics::WEBSOCKET_RECONNECTS, &[]);
+                info!(
+                    "Reconnected ws {} ({} reconnects)",
+                    client.endpoint_names[endpoint], reconnects
+                );
+                client.reconnect_sender.send(reconnects).ok();
+            }
+            connected_before = true;
+            client.connected_before.store(true, Ordering::Relaxed);
+            // Subscriptions confirmed on this connection are made on it
+            let connection = client.reconnects();
+
+            // Unsubscriptions requested while disconnected belong to the dropped connection
+            loop {
+                match unsubscribe_receiver.try_recv() {
+                    Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
 
             let mut active_subscriptions: HashMap<SubscriptionId, ActiveSubscription> =
                 HashMap::new();
@@ -256,141 +850,433 @@ pub async fn create_persisted_websocket_connection(
 
             {
                 let mut backlog = client.pending_backlog.lock().await;
-                dbg!(&backlog);
+                debug!(backlog = backlog.len(), "Resending subscription backlog");
                 let backlog_len = backlog.len();
                 backlog.drain(0..backlog_len).for_each(|req| {
                     client.subscribe_sender.send(req).ok();
                 });
             }
 
-            loop {
-                tokio::select! {
-                    Ok((subscription_id, status_sender)) = unsubscribe_receiver.recv() => {
-                        let Some(ActiveSubscription { method, .. }) = active_subscriptions.remove(&subscription_id) else {
-                            status_sender.send(()).await.ok();
-                            continue;
-                        };
-
-                        println!("Unusbcribing {}: rid {}", subscription_id, request_id);
+            let mut keepalive = interval(KEEPALIVE_CHECK_INTERVAL);
+            let mut last_ping = Instant::now();
+            let mut ping_sent_at: Option<Instant> = None;
+            let mut last_notification = Instant::now();
+            let mut last_failback_attempt = Instant::now();
 
-                        let req = SubscribeParams::build_unsubscribe_request(method, request_id);
-                        ws.send(Message::Text(req)).await?;
+            let disconnect = async {
+                let disconnect = loop {
+                    tokio::select! {
+                        _ = shutdown.triggered() => {
+                            debug!("Closing ws");
+                            ws.close(None).await.ok();
+                            break Disconnect::Shutdown;
+                        }
+                        _ = keepalive.tick() => {
+                            match ping_sent_at {
+                                Some(sent_at) if sent_at.elapsed() > PONG_TIMEOUT => {
+                                    break Disconnect::PongTimeout(PONG_TIMEOUT);
+                                }
+                                None if last_ping.elapsed() >= PING_INTERVAL => {
+                                    ws.send(Message::Ping(vec![])).await?;
+                                    last_ping = Instant::now();
+                                    ping_sent_at = Some(last_ping);
+                                }
+                                _ => {}
+                            }
 
-                        request_id += 1;
-                    }
-                    Ok((subscribe_params, status_sender)) = subscribe_receiver.recv() => {
-                        let (req, method) = subscribe_params.build_subscribe_request_and_method(request_id);
-                        ws.send(Message::Text(req)).await?;
-                        println!("Subscribing {}: {}", &method, request_id);
-                        pending_subscriptions.insert(request_id, PendingSubscription { method, status_sender });
-                        request_id += 1;
-                    }
-                    Some(msg) = ws.next() => {
-                        let text = match msg? {
-                            Message::Text(v) => v,
-                            Message::Ping(data) => {
-                                ws.send(Message::Pong(data)).await?;
-                                continue;
+                            // Signature subscriptions are notified once, so only the others
+                            // are expected to be notified regularly
+                            let expects_notifications = active_subscriptions
+                                .values()
+                                .any(|subscription| subscription.method != "signatureSubscribe");
+                            if let Some(timeout) = client.liveness_timeout {
+                                if expects_notifications && last_notification.elapsed() > timeout {
+                                    break Disconnect::Stale(timeout);
+                                }
                             }
-                            Message::Close(_) => {
-                                break;
+
+                            // Subscriptions move to the primary endpoint once it accepts
+                            // connections again
+                            if endpoint != 0 && last_failback_attempt.elapsed() >= FAILBACK_INTERVAL {
+                                last_failback_attempt = Instant::now();
+                                if let Ok(Ok((primary_ws, _))) = timeout(FAILBACK_CONNECT_TIMEOUT, connect_async(&client.urls[0])).await {
+                                    failback_ws = Some(primary_ws);
+                                    break Disconnect::Failback;
+                                }
                             }
-                            _ => {
+                        }
+                        Ok((subscription_id, status_sender)) = unsubscribe_receiver.recv() => {
+                            let Some(ActiveSubscription { method, .. }) = active_subscriptions.remove(&subscription_id) else {
+                                status_sender.send(()).await.ok();
                                 continue;
-                            }
-                        };
-                        let response: Map<String, Value> = serde_json::from_str(&text)?;
-
-                        match response.get("id").map(|id| id.as_u64()).flatten() {
-                            Some(r_id) => {
-                                let err = response.get("error").map(|error_object| {
-                                    match serde_json::from_value::<RpcErrorObject>(error_object.clone()) {
-                                        Ok(rpc_error_object) => {
-                                            format!("{} ({})",  rpc_error_object.message, rpc_error_object.code)
-                                        }
-                                        Err(err) => format!(
-                                            "Failed to deserialize RPC error response: {} [{}]",
-                                            serde_json::to_string(error_object).unwrap(),
-                                            err
-                                        )
-                                    }
-                                });
+                            };
+                            client.set_active_subscriptions(active_subscriptions.len());
+
+                            debug!("Unusbcribing {}: rid {}", subscription_id, request_id);
 
-                                if let Some(status_sender) = pending_unsubscriptions.remove(&r_id) {
-                                    println!("confirming unsub {}", r_id);
-                                    status_sender.send(()).await.ok();
+                            let unsub_method = SubscribeParams::subscribe_into_unsub_method(&method);
+                            let req = SubscribeParams::build_unsubscribe_request(
+                                unsub_method,
+                                request_id,
+                                subscription_id,
+                            );
+                            ws.send(Message::Text(req)).await?;
+                            pending_unsubscriptions.insert(request_id, status_sender);
+
+                            request_id += 1;
+                        }
+                        Ok((subscribe_params, status_sender)) = subscribe_receiver.recv() => {
+                            let (req, method) = subscribe_params.build_subscribe_request_and_method(request_id);
+                            ws.send(Message::Text(req)).await?;
+                            debug!("Subscribing {}: {}", &method, request_id);
+                            pending_subscriptions.insert(request_id, PendingSubscription { method, params: subscribe_params, status_sender });
+                            request_id += 1;
+                        }
+                        msg = ws.next() => {
+                            let Some(msg) = msg else {
+                                break Disconnect::Closed;
+                            };
+                            let text = match msg? {
+                                Message::Text(v) => v,
+                                Message::Ping(data) => {
+                                    ws.send(Message::Pong(data)).await?;
+                                    continue;
+                                }
+                                Message::Pong(_) => {
+                                    ping_sent_at = None;
+                                    continue;
+                                }
+                                Message::Close(_) => {
+                                    break Disconnect::Closed;
+                                }
+                                _ => {
                                     continue;
                                 }
+                            };
+                            let response: Map<String, Value> = serde_json::from_str(&text)?;
 
-                                if let Some(PendingSubscription { method, status_sender }) = pending_subscriptions.remove(&r_id) {
-                                    match err {
-                                        Some(msg) => {
-                                            status_sender.send(Err(WebsocketError::SubscriptionFailed(format!("{}: {}", msg, text.clone())))).await.ok();
-                                            continue;
+                            match response.get("id").map(|id| id.as_u64()).flatten() {
+                                Some(r_id) => {
+                                    let err = response.get("error").map(|error_object| {
+                                        match serde_json::from_value::<RpcErrorObject>(error_object.clone()) {
+                                            Ok(rpc_error_object) => {
+                                                format!("{} ({})",  rpc_error_object.message, rpc_error_object.code)
+                                            }
+                                            Err(err) => format!(
+                                                "Failed to deserialize RPC error response: {} [{}]",
+                                                serde_json::to_string(error_object).unwrap(),
+                                                err
+                                            )
                                         }
-                                        _ => {
-                                            let Some(s_id) = response.get("result").map(|id| id.as_u64()).flatten() else {
-                                                status_sender.send(Err(WebsocketError::SubscriptionFailed(format!("Invalid result field: {}", text.clone())))).await.ok();
+                                    });
+
+                                    if let Some(status_sender) = pending_unsubscriptions.remove(&r_id) {
+                                        debug!("confirming unsub {}", r_id);
+                                        status_sender.send(()).await.ok();
+                                        continue;
+                                    }
+
+                                    if let Some(PendingSubscription { method, status_sender, .. }) = pending_subscriptions.remove(&r_id) {
+                                        match err {
+                                            Some(msg) => {
+                                                status_sender.send(Err(WebsocketError::SubscriptionFailed(format!("{}: {}", msg, text.clone())))).await.ok();
                                                 continue;
-                                            };
+                                            }
+                                            _ => {
+                                                let Some(s_id) = response.get("result").map(|id| id.as_u64()).flatten() else {
+                                                    status_sender.send(Err(WebsocketError::SubscriptionFailed(format!("Invalid result field: {}", text.clone())))).await.ok();
+                                                    continue;
+                                                };
 
-                                            println!("Confirmed subscription {}, {}", &method, r_id);
+                                                debug!("Confirmed subscription {}, {}", &method, r_id);
 
-                                            let (notification_sender, notification_receiver) = mpsc::unbounded_channel();
+                                                let (notification_sender, notification_receiver) = mpsc::unbounded_channel();
 
-                                            if status_sender.send(Ok((s_id, notification_receiver))).await.is_ok() {
-                                                active_subscriptions.insert(s_id, ActiveSubscription { method, notification_sender });
+                                                if status_sender.send(Ok((s_id, connection, notification_receiver))).await.is_ok() {
+                                                    // The liveness timeout starts with the first subscription
+                                                    if active_subscriptions.is_empty() {
+                                                        last_notification = Instant::now();
+                                                    }
+                                                    active_subscriptions.insert(s_id, ActiveSubscription { method, notification_sender });
+                                                    client.set_active_subscriptions(active_subscriptions.len());
+                                                } else {
+                                                    // The subscriber gave up waiting, e.g. on a timeout
+                                                    debug!("Subscriber of {} is gone, unsub", s_id);
+                                                    let unsub_method = SubscribeParams::subscribe_into_unsub_method(&method);
+                                                    let req = SubscribeParams::build_unsubscribe_request(unsub_method, request_id, s_id);
+                                                    ws.send(Message::Text(req)).await?;
+                                                    request_id += 1;
+                                                }
                                             }
                                         }
+                                        continue;
                                     }
-                                    continue;
                                 }
-                            }
-                            None => {
-                                let Some(Value::Object(params)) = response.get("params") else {
-                                    continue;
-                                };
+                                None => {
+                                    let Some(Value::Object(params)) = response.get("params") else {
+                                        continue;
+                                    };
 
-                                let s_id = params.get("subscription").map(|id| id.as_u64()).flatten();
-                                let result = params.get("result");
-                                let method = response.get("method").map(|m| m.as_str()).flatten();
+                                    let s_id = params.get("subscription").map(|id| id.as_u64()).flatten();
+                                    let result = params.get("result");
+                                    let method = response.get("method").map(|m| m.as_str()).flatten();
 
-                                match (s_id, result, method) {
-                                    (Some(s_id), Some(result), Some(method)) => {
-                                        let mut should_unsub = false;
+                                    match (s_id, result, method) {
+                                        (Some(s_id), Some(result), Some(method)) => {
+                                            last_notification = Instant::now();
+                                            let mut should_unsub = false;
 
-                                        if let Some(subscription) = active_subscriptions.get(&s_id) {
-                                            if !subscription.notification_sender.send(result.clone()).is_ok() {
-                                                println!("Subscription no longer active, remove");
-                                                active_subscriptions.remove(&s_id);
+                                            if let Some(subscription) = active_subscriptions.get(&s_id) {
+                                                if !subscription.notification_sender.send(result.clone()).is_ok() {
+                                                    debug!("Subscription no longer active, remove");
+                                                    active_subscriptions.remove(&s_id);
+                                                    client.set_active_subscriptions(active_subscriptions.len());
+                                                    should_unsub = true;
+                                                }
+                                            } else {
                                                 should_unsub = true;
                                             }
-                                        } else {
-                                            should_unsub = true;
-                                        }
 
-                                        if should_unsub {
-                                            println!("Subscription no longer active, unsub");
-                                            let unsub_method = SubscribeParams::notification_into_unsub_method(method.to_string());
-                                            let req = SubscribeParams::build_unsubscribe_request(unsub_method.to_string(), request_id);
+                                            if should_unsub {
+                                                debug!("Subscription no longer active, unsub");
+                                                let unsub_method = SubscribeParams::notification_into_unsub_method(method.to_string());
+                                                let req = SubscribeParams::build_unsubscribe_request(unsub_method.to_string(), request_id, s_id);
 
-                                            ws.send(Message::Text(req)).await?;
+                                                ws.send(Message::Text(req)).await?;
 
-                                            request_id += 1;
+                                                request_id += 1;
+                                            }
                                         }
+                                        _ => ()
                                     }
-                                    _ => ()
                                 }
                             }
-                        }
 
+                        }
                     }
-                }
+                };
+                Ok::<_, WebsocketError>(disconnect)
             }
+            .await
+            .unwrap_or_else(Disconnect::Error);
 
+            if let Disconnect::Shutdown = disconnect {
+                return Ok(());
+            }
+            if let Disconnect::Failback = disconnect {
+                endpoint = client.fail_over(0);
+            }
+
+            // A stale connection can be half open, so closing it is not waited for long
+            if !matches!(disconnect, Disconnect::Closed) {
+                timeout(CLOSE_TIMEOUT, ws.close(None)).await.ok();
+            }
             *client.connection_status.lock().await = ConnectionStatus::Reconnecting;
+            client.set_active_subscriptions(0);
+            let disconnects = client.disconnects.fetch_add(1, Ordering::Relaxed) + 1;
+            metrics::inc_counter(
+                metrics::WEBSOCKET_DISCONNECTS,
+                &[("reason", disconnect.reason())],
+            );
+            warn!(
+                "Ws disconnected: {}, reconnecting ({} disconnects)",
+                disconnect, disconnects
+            );
+
+            // Requests of the dropped connection are never answered, pending subscriptions
+            // are sent again once reconnected, dropped unsubscription senders confirm the
+            // unsubscriptions and dropped notification senders end the active streams
+            client.pending_backlog.lock().await.extend(
+                pending_subscriptions
+                    .drain()
+                    .map(|(_, pending)| (pending.params, pending.status_sender)),
+            );
+            drop(pending_unsubscriptions);
+            drop(active_subscriptions);
         }
     });
 
     Ok(handle)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{connect_mock_ws, MockWsServer};
+
+    fn account_notification(subscription_id: u64, slot: u64) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "accountNotification",
+            "params": {
+                "result": {
+                    "context": { "slot": slot },
+                    "value": {
+                        "lamports": 1,
+                        "data": ["", "base64"],
+                        "owner": Pubkey::default().to_string(),
+                        "executable": false,
+                        "rentEpoch": 0,
+                    },
+                },
+                "subscription": subscription_id,
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn shared_subscription_unsubscribes_after_last_consumer() {
+        let mut server = MockWsServer::spawn(0).await;
+        let (client, _shutdown) = connect_mock_ws(vec![server.url.clone()], None).await;
+        let pubkey = Pubkey::new_unique();
+
+        let (first_id, mut first) = client
+            .account_subscribe(pubkey, RpcAccountInfoConfig::default())
+            .await
+            .unwrap();
+        let (second_id, mut second) = client
+            .account_subscribe(pubkey, RpcAccountInfoConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(first_id, second_id);
+
+        let request = server.next_request().await;
+        assert_eq!(request["method"], "accountSubscribe");
+        assert_eq!(request["params"][0], pubkey.to_string());
+        assert_eq!(client.active_subscriptions(), 1);
+
+        server
+            .outgoing
+            .send(account_notification(first_id, 5))
+            .unwrap();
+        for stream in [&mut first, &mut second] {
+            let notification = timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(notification.pubkey, pubkey);
+            assert_eq!(notification.slot, 5);
+        }
+
+        // The second consumer keeps the subscription
+        drop(first);
+        sleep(Duration::from_millis(100)).await;
+        assert!(server.requests.try_recv().is_err());
+        assert_eq!(client.active_subscriptions(), 1);
+
+        drop(second);
+        let request = server.next_request().await;
+        assert_eq!(request["method"], "accountUnsubscribe");
+        assert_eq!(request["params"], json!([first_id]));
+        sleep(Duration::from_millis(100)).await;
+        assert!(server.requests.try_recv().is_err());
+        assert_eq!(client.active_subscriptions(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_pong_reconnects() {
+        let server = MockWsServer::spawn(1).await;
+        let (client, _shutdown) = connect_mock_ws(vec![server.url.clone()], None).await;
+        let mut reconnects = client.reconnect_events();
+
+        // The ping is sent after the interval and the pong is awaited for the timeout
+        let started = tokio::time::Instant::now();
+        let reconnect = timeout(Duration::from_secs(60), reconnects.recv())
+            .await
+            .expect("no reconnect")
+            .unwrap();
+        assert_eq!(reconnect, 1);
+        assert!(started.elapsed() >= PING_INTERVAL + PONG_TIMEOUT);
+        assert_eq!(client.disconnects(), 1);
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_notifications_reconnect() {
+        let mut server = MockWsServer::spawn(0).await;
+        let liveness_timeout = Duration::from_secs(30);
+        let (client, _shutdown) =
+            connect_mock_ws(vec![server.url.clone()], Some(liveness_timeout)).await;
+        let mut reconnects = client.reconnect_events();
+
+        let (_, mut slots) = client.slot_subscribe().await.unwrap();
+        assert_eq!(server.next_request().await["method"], "slotSubscribe");
+
+        let started = tokio::time::Instant::now();
+        let reconnect = timeout(Duration::from_secs(120), reconnects.recv())
+            .await
+            .expect("no reconnect")
+            .unwrap();
+        assert_eq!(reconnect, 1);
+        assert!(started.elapsed() >= liveness_timeout);
+        assert_eq!(client.disconnects(), 1);
+        assert_eq!(server.connections(), 2);
+        // Streams of the dropped connection end
+        assert!(slots.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_unsubscribes() {
+        let mut server = MockWsServer::spawn(0).await;
+        let (client, _shutdown) = connect_mock_ws(vec![server.url.clone()], None).await;
+
+        let (subscription_id, slots) = client.slot_subscribe().await.unwrap();
+        let request = server.next_request().await;
+        assert_eq!(request["method"], "slotSubscribe");
+        assert_eq!(subscription_id, request["id"].as_u64().unwrap() + 100);
+        assert_eq!(client.active_subscriptions(), 1);
+
+        drop(slots);
+        let request = server.next_request().await;
+        assert_eq!(request["method"], "slotUnsubscribe");
+        assert_eq!(request["params"], json!([subscription_id]));
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.active_subscriptions(), 0);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_waits_for_confirmation() {
+        let mut server = MockWsServer::spawn(0).await;
+        let (client, _shutdown) = connect_mock_ws(vec![server.url.clone()], None).await;
+
+        let (subscription_id, _signatures) = client
+            .signature_subscribe(Signature::default(), RpcSignatureSubscribeConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(server.next_request().await["method"], "signatureSubscribe");
+
+        timeout(Duration::from_secs(5), client.unsubscribe(subscription_id))
+            .await
+            .expect("unsubscription not confirmed");
+        let request = server.next_request().await;
+        assert_eq!(request["method"], "signatureUnsubscribe");
+        assert_eq!(request["params"], json!([subscription_id]));
+        assert_eq!(client.active_subscriptions(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_over_after_repeated_failures() {
+        let mut primary = MockWsServer::spawn(0).await;
+        let fallback = MockWsServer::spawn(0).await;
+        let (client, _shutdown) =
+            connect_mock_ws(vec![primary.url.clone(), fallback.url.clone()], None).await;
+        let mut reconnects = client.reconnect_events();
+        assert_eq!(client.active_endpoint.load(Ordering::Relaxed), 0);
+
+        primary.stop().await;
+        let started = tokio::time::Instant::now();
+        let reconnect = timeout(Duration::from_secs(60), reconnects.recv())
+            .await
+            .expect("no reconnect")
+            .unwrap();
+        assert_eq!(reconnect, 1);
+        assert_eq!(client.active_endpoint.load(Ordering::Relaxed), 1);
+        assert_eq!(primary.connections(), 1);
+        assert_eq!(fallback.connections(), 1);
+        // Every failure but the last one is retried after the growing delay
+        let retry_delays = (0..MAX_ENDPOINT_FAILURES - 1)
+            .map(|failures| RECONNECT_DELAY * (1 << failures))
+            .sum::<Duration>();
+        assert!(started.elapsed() >= retry_delays);
+    }
+}